@@ -0,0 +1,231 @@
+//! Parsing listening addresses and creating listening sockets
+//!
+//! ```ignore
+//! let binds = try!(["127.0.0.1:80", "[::1]:80"].iter()
+//!     .map(|x| Bind::parse(x)).collect::<Result<Vec<_>, _>>());
+//! let (service, machines) = try!(bind::serve_all(&binds));
+//! for m in machines {
+//!     try!(handler.add_machine(&mut eloop, m));
+//! }
+//! // later, from any thread
+//! service.close();
+//! ```
+//!
+//! The `"*:port"` address means all interfaces, both IPv4 and IPv6 (it's
+//! bound as `[::]:port` with `IPV6_V6ONLY` disabled). Other IPv6 sockets
+//! are IPv6-only regardless of the system default.
+use std::io;
+use std::mem;
+use std::net::{SocketAddr, SocketAddrV6, Ipv6Addr};
+use std::os::unix::io::{RawFd, FromRawFd};
+
+use libc;
+use mio::tcp::{TcpListener, TcpStream};
+
+use EventMachine;
+use fd_flags::{set_flag, Flag};
+use transports::accept::{Serve, Init, Control};
+
+/// The default backlog of the listening socket
+const BACKLOG: libc::c_int = 1024;
+
+
+/// The configuration of the listening socket
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Bind {
+    addr: SocketAddr,
+    v6only: bool,
+    reuse_port: bool,
+    backlog: i32,
+}
+
+/// The handle to the listeners created by `serve_all`
+///
+/// Like `Control`, it may be used from any thread
+#[derive(Clone)]
+pub struct Service {
+    addrs: Vec<SocketAddr>,
+    controls: Vec<Control>,
+}
+
+/// Parses the `host:port` address
+///
+/// The host is an IPv4 address, an IPv6 address in brackets, or `*` which
+/// means all interfaces. Host names are not resolved.
+pub fn parse_addr(value: &str) -> io::Result<SocketAddr> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput,
+        format!("Invalid address {:?}, host:port expected", value));
+    if value.starts_with("*:") {
+        let port = try!(value[2..].parse().map_err(|_| invalid()));
+        return Ok(SocketAddr::V6(SocketAddrV6::new(
+            Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), port, 0, 0)));
+    }
+    value.parse().map_err(|_| invalid())
+}
+
+/// Creates listeners for all the `binds` and machines accepting on them
+pub fn serve_all<M, C>(binds: &[Bind])
+    -> io::Result<(Service, Vec<Serve<TcpListener, M, C>>)>
+    where M: Init<TcpStream, C>, M: EventMachine<C>
+{
+    let mut service = Service {
+        addrs: Vec::with_capacity(binds.len()),
+        controls: Vec::with_capacity(binds.len()),
+    };
+    let mut machines = Vec::with_capacity(binds.len());
+    for bind in binds {
+        let listener = try!(bind.listen());
+        let machine = Serve::new(listener);
+        service.addrs.push(bind.addr);
+        service.controls.extend(machine.control());
+        machines.push(machine);
+    }
+    Ok((service, machines))
+}
+
+impl Bind {
+    pub fn new(addr: SocketAddr) -> Bind {
+        Bind {
+            addr: addr,
+            v6only: true,
+            reuse_port: false,
+            backlog: BACKLOG,
+        }
+    }
+    /// Parses the address with `parse_addr`
+    ///
+    /// For `"*:port"` dual-stack socket is configured
+    pub fn parse(value: &str) -> io::Result<Bind> {
+        let bind = Bind::new(try!(parse_addr(value)));
+        Ok(bind.v6only(!value.starts_with("*:")))
+    }
+    /// Sets `IPV6_V6ONLY` of the IPv6 socket, true by default
+    pub fn v6only(mut self, value: bool) -> Bind {
+        self.v6only = value;
+        self
+    }
+    /// Sets `SO_REUSEPORT`, so several sockets may be bound to the address
+    pub fn reuse_port(mut self, value: bool) -> Bind {
+        self.reuse_port = value;
+        self
+    }
+    pub fn backlog(mut self, value: i32) -> Bind {
+        self.backlog = value;
+        self
+    }
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+    /// Creates the non-blocking, close-on-exec listening socket
+    pub fn listen(&self) -> io::Result<TcpListener> {
+        let family = match self.addr {
+            SocketAddr::V4(_) => libc::AF_INET,
+            SocketAddr::V6(_) => libc::AF_INET6,
+        };
+        unsafe {
+            let fd = libc::socket(family, libc::SOCK_STREAM, 0);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            match self.configure(fd) {
+                Ok(()) => Ok(TcpListener::from_raw_fd(fd)),
+                Err(e) => {
+                    libc::close(fd);
+                    Err(e)
+                }
+            }
+        }
+    }
+    unsafe fn configure(&self, fd: RawFd) -> io::Result<()> {
+        try!(set_flag(fd, Flag::CloseOnExec, true));
+        try!(set_flag(fd, Flag::NonBlocking, true));
+        try!(set_option(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, true));
+        if self.reuse_port {
+            try!(set_option(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, true));
+        }
+        let result = match self.addr {
+            SocketAddr::V4(ref a) => {
+                let mut sin: libc::sockaddr_in = mem::zeroed();
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = a.port().to_be();
+                sin.sin_addr.s_addr = u32::from(*a.ip()).to_be();
+                libc::bind(fd, &sin as *const _ as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in>() as libc::socklen_t)
+            }
+            SocketAddr::V6(ref a) => {
+                try!(set_option(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY,
+                                self.v6only));
+                let mut sin6: libc::sockaddr_in6 = mem::zeroed();
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = a.port().to_be();
+                sin6.sin6_addr.s6_addr = a.ip().octets();
+                sin6.sin6_flowinfo = a.flowinfo();
+                sin6.sin6_scope_id = a.scope_id();
+                libc::bind(fd, &sin6 as *const _ as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t)
+            }
+        };
+        if result < 0 || libc::listen(fd, self.backlog) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Service {
+    /// Addresses of the listeners, in the order of the binds
+    pub fn addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+    /// Pauses all the listeners, see `Control::pause`
+    pub fn pause(&self) -> bool {
+        self.controls.iter().fold(true, |ok, c| c.pause() && ok)
+    }
+    /// Resumes all the listeners, see `Control::resume`
+    pub fn resume(&self) -> bool {
+        self.controls.iter().fold(true, |ok, c| c.resume() && ok)
+    }
+    /// Closes all the listeners, see `Control::close`
+    pub fn close(&self) -> bool {
+        self.controls.iter().fold(true, |ok, c| c.close() && ok)
+    }
+}
+
+unsafe fn set_option(fd: RawFd, level: libc::c_int, name: libc::c_int,
+    value: bool)
+    -> io::Result<()>
+{
+    let value = value as libc::c_int;
+    if libc::setsockopt(fd, level, name,
+        &value as *const _ as *const libc::c_void,
+        mem::size_of::<libc::c_int>() as libc::socklen_t) < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_addr, Bind};
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_addr("127.0.0.1:80").unwrap(),
+                   "127.0.0.1:80".parse().unwrap());
+        assert_eq!(parse_addr("[::1]:8080").unwrap(),
+                   "[::1]:8080".parse().unwrap());
+        assert_eq!(parse_addr("*:53").unwrap(), "[::]:53".parse().unwrap());
+        assert!(parse_addr("::1:80").is_err());
+        assert!(parse_addr("localhost:80").is_err());
+        assert!(parse_addr("*:http").is_err());
+    }
+
+    #[test]
+    fn dual_stack() {
+        assert_eq!(Bind::parse("*:80").unwrap(),
+            Bind::new("[::]:80".parse().unwrap()).v6only(false));
+        assert_eq!(Bind::parse("[::]:80").unwrap(),
+            Bind::new("[::]:80".parse().unwrap()));
+    }
+}
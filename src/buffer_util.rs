@@ -1,4 +1,16 @@
+use std::cmp::min;
+use std::env;
+use std::fs::{File, OpenOptions, remove_file};
+use std::io::{self, Read, Write, Seek, SeekFrom, Cursor};
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
 use memchr::memchr;
+use netbuf::Buf;
+
+/// Counter for the names of the spool files
+static SPOOL_FILES: AtomicUsize = AtomicUsize::new(0);
 
 
 /// Finds subslice in a slice of a buffer. It's included here as a means
@@ -33,9 +45,559 @@ pub fn find_substr<B:AsRef<[u8]>, N:AsRef<[u8]>>(haystack: B, needle: N)
     }
 }
 
+/// Finds the position of the delimiter in the buffer
+///
+/// Returns offset of the first byte of the delimiter, so the data before
+/// the delimiter is `&buf[..pos]` and it's consumed with
+/// `buf.consume(pos + delimiter.len())`
+pub fn find_delimiter(buf: &Buf, delimiter: &[u8]) -> Option<usize> {
+    find_substr(&buf[..], delimiter)
+}
+
+/// Removes the first `n` bytes of the buffer and returns them
+///
+/// Panics if there are less than `n` bytes in the buffer
+pub fn split_off_front(buf: &mut Buf, n: usize) -> Vec<u8> {
+    let result = buf[..n].to_vec();
+    buf.consume(n);
+    result
+}
+
+/// Compares two byte strings ignoring ASCII case
+pub fn eq_ignore_case(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() &&
+        a.iter().zip(b.iter()).all(|(x, y)| lower(*x) == lower(*y))
+}
+
+fn lower(x: u8) -> u8 {
+    match x {
+        b'A'...b'Z' => x + (b'a' - b'A'),
+        _ => x,
+    }
+}
+
+/// Finds the value of the header in the `Name: value` lines
+///
+/// The name is matched ignoring ASCII case, as in HTTP and similar
+/// protocols. Lines may be terminated either by `\r\n` or by `\n`. Returns
+/// the value of the first matching header with whitespace trimmed.
+pub fn find_header<'x>(headers: &'x [u8], name: &[u8]) -> Option<&'x [u8]> {
+    for line in headers.split(|&x| x == b'\n') {
+        if line.len() > name.len() && line[name.len()] == b':'
+            && eq_ignore_case(&line[..name.len()], name)
+        {
+            return Some(trim(&line[name.len()+1..]));
+        }
+    }
+    None
+}
+
+fn trim(mut data: &[u8]) -> &[u8] {
+    while data.len() > 0 && is_space(data[0]) {
+        data = &data[1..];
+    }
+    while data.len() > 0 && is_space(data[data.len()-1]) {
+        data = &data[..data.len()-1];
+    }
+    data
+}
+
+fn is_space(x: u8) -> bool {
+    x == b' ' || x == b'\t' || x == b'\r'
+}
+
+/// A view into the buffer which consumes data without copying
+///
+/// The data is consumed from the buffer at once when the window is
+/// dropped, so parsing many small items doesn't move the rest of the
+/// buffer each time.
+pub struct Window<'a> {
+    buf: &'a mut Buf,
+    offset: usize,
+}
+
+impl<'a> Window<'a> {
+    pub fn new(buf: &'a mut Buf) -> Window<'a> {
+        Window {
+            buf: buf,
+            offset: 0,
+        }
+    }
+    /// The data which is not consumed yet
+    pub fn data(&self) -> &[u8] {
+        &self.buf[self.offset..]
+    }
+    /// Number of bytes consumed so far
+    pub fn consumed(&self) -> usize {
+        self.offset
+    }
+    /// Marks `n` bytes as consumed
+    ///
+    /// Panics if there are less than `n` bytes left
+    pub fn consume(&mut self, n: usize) {
+        assert!(n <= self.buf.len() - self.offset);
+        self.offset += n;
+    }
+    /// Returns next `n` bytes and marks them as consumed
+    ///
+    /// Returns `None` if there are less than `n` bytes left
+    pub fn take(&mut self, n: usize) -> Option<&[u8]> {
+        if self.buf.len() - self.offset < n {
+            return None;
+        }
+        self.offset += n;
+        Some(&self.buf[self.offset-n..self.offset])
+    }
+    /// Returns the data up to the delimiter and consumes it with delimiter
+    pub fn take_until(&mut self, delimiter: &[u8]) -> Option<&[u8]> {
+        find_substr(&self.buf[self.offset..], delimiter).map(move |pos| {
+            let start = self.offset;
+            self.offset += pos + delimiter.len();
+            &self.buf[start..start+pos]
+        })
+    }
+}
+
+impl<'a> Drop for Window<'a> {
+    fn drop(&mut self) {
+        self.buf.consume(self.offset);
+    }
+}
+
+/// Accumulates a large message, spilling it to a temporary file
+///
+/// The data is kept in memory until it's larger than the threshold, then
+/// it's moved to an unlinked file in the temporary directory, so a giant
+/// upload can't exhaust the memory. Use `into_reader` to read the message
+/// when it's complete. The writes to the file are blocking, which is
+/// usually fine as they go to the page cache, but put the directory on a
+/// local disk.
+pub struct Spool {
+    memory: Vec<u8>,
+    file: Option<File>,
+    threshold: usize,
+    dir: PathBuf,
+    len: u64,
+}
+
+/// The data of the `Spool`
+pub enum SpoolReader {
+    Memory(Cursor<Vec<u8>>),
+    File(File),
+}
+
+impl Spool {
+    /// Creates a spool which keeps up to `threshold` bytes in memory
+    pub fn new(threshold: usize) -> Spool {
+        Spool {
+            memory: Vec::new(),
+            file: None,
+            threshold: threshold,
+            dir: env::temp_dir(),
+            len: 0,
+        }
+    }
+    /// Sets the directory for the file, the system temporary directory
+    /// is used by default
+    pub fn in_dir<P: Into<PathBuf>>(mut self, dir: P) -> Spool {
+        self.dir = dir.into();
+        self
+    }
+    /// Appends the data to the message
+    pub fn extend(&mut self, data: &[u8]) -> io::Result<()> {
+        let total = self.memory.len() + data.len();
+        if self.file.is_none() && total > self.threshold {
+            let mut file = try!(self.create_file());
+            try!(file.write_all(&self.memory));
+            self.memory = Vec::new();
+            self.file = Some(file);
+        }
+        match self.file {
+            Some(ref mut file) => try!(file.write_all(data)),
+            None => self.memory.extend(data),
+        }
+        self.len += data.len() as u64;
+        Ok(())
+    }
+    /// Moves first `n` bytes of the buffer to the message
+    ///
+    /// Panics if there are less than `n` bytes in the buffer
+    pub fn consume_from(&mut self, buf: &mut Buf, n: usize) -> io::Result<()>
+    {
+        try!(self.extend(&buf[..n]));
+        buf.consume(n);
+        Ok(())
+    }
+    /// The size of the message so far
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// Returns true if the message is written to the file
+    pub fn is_spilled(&self) -> bool {
+        self.file.is_some()
+    }
+    /// Returns the reader of the whole message
+    pub fn into_reader(self) -> io::Result<SpoolReader> {
+        match self.file {
+            Some(mut file) => {
+                try!(file.seek(SeekFrom::Start(0)));
+                Ok(SpoolReader::File(file))
+            }
+            None => Ok(SpoolReader::Memory(Cursor::new(self.memory))),
+        }
+    }
+    /// Creates the file and unlinks it right away, so it's removed when
+    /// closed, even if the process crashes
+    fn create_file(&self) -> io::Result<File> {
+        loop {
+            let path = self.dir.join(format!("rotor-spool-{}-{}",
+                process::id(), SPOOL_FILES.fetch_add(1, Ordering::Relaxed)));
+            match OpenOptions::new().read(true).write(true).create_new(true)
+                .open(&path)
+            {
+                Ok(file) => {
+                    try!(remove_file(&path));
+                    return Ok(file);
+                }
+                // Left by a previous process with the same pid
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Read for SpoolReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            SpoolReader::Memory(ref mut cursor) => cursor.read(buf),
+            SpoolReader::File(ref mut file) => file.read(buf),
+        }
+    }
+}
+
+/// An incremental checksum, see `Running`
+///
+/// Implemented for `Crc32` and `Adler32`, implement it for the hashers of
+/// other crates to use them with `Running`.
+pub trait Checksum {
+    fn update(&mut self, data: &[u8]);
+}
+
+/// CRC-32 (IEEE 802.3), as used by zlib, gzip, PNG and many others
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32(u32);
+
+/// Adler-32, as used by zlib
+#[derive(Clone, Copy, Debug)]
+pub struct Adler32 {
+    a: u32,
+    b: u32,
+}
+
+/// Computes the checksum of the frame in the buffer as the data arrives
+///
+/// The checksummed data is tracked by the offset in the buffer, so don't
+/// consume the buffer until the frame is complete. E.g. for the frame
+/// with the trailing CRC:
+///
+/// ```ignore
+/// self.crc.update(transport.input(), frame_len);  // on each data_received
+/// if transport.input().len() >= frame_len + 4 {
+///     let crc = self.crc.into_inner().value();
+///     // compare with the trailer and consume the frame
+/// }
+/// ```
+pub struct Running<C> {
+    checksum: C,
+    offset: usize,
+}
+
+/// The CRC-32 of every half-byte, for the reversed polynomial
+const CRC32_NIBBLES: [u32; 16] = [
+    0x00000000, 0x1DB71064, 0x3B6E20C8, 0x26D930AC,
+    0x76DC4190, 0x6B6B51F4, 0x4DB26158, 0x5005713C,
+    0xEDB88320, 0xF00F9344, 0xD6D6A3E8, 0xCB61B38C,
+    0x9B64C2B0, 0x86D3D2D4, 0xA00AE278, 0xBDBDF21C,
+];
+const ADLER_MOD: u32 = 65521;
+/// The largest number of bytes which can't overflow the sums of Adler-32
+const ADLER_CHUNK: usize = 5552;
+
+impl Crc32 {
+    pub fn new() -> Crc32 {
+        Crc32(!0)
+    }
+    /// The checksum of the data so far
+    pub fn value(&self) -> u32 {
+        !self.0
+    }
+}
+
+impl Checksum for Crc32 {
+    fn update(&mut self, data: &[u8]) {
+        let mut crc = self.0;
+        for &byte in data {
+            crc ^= byte as u32;
+            crc = (crc >> 4) ^ CRC32_NIBBLES[(crc & 15) as usize];
+            crc = (crc >> 4) ^ CRC32_NIBBLES[(crc & 15) as usize];
+        }
+        self.0 = crc;
+    }
+}
+
+impl Adler32 {
+    pub fn new() -> Adler32 {
+        Adler32 { a: 1, b: 0 }
+    }
+    /// The checksum of the data so far
+    pub fn value(&self) -> u32 {
+        self.b << 16 | self.a
+    }
+}
+
+impl Checksum for Adler32 {
+    fn update(&mut self, data: &[u8]) {
+        for chunk in data.chunks(ADLER_CHUNK) {
+            for &byte in chunk {
+                self.a += byte as u32;
+                self.b += self.a;
+            }
+            self.a %= ADLER_MOD;
+            self.b %= ADLER_MOD;
+        }
+    }
+}
+
+impl<C: Checksum> Running<C> {
+    pub fn new(checksum: C) -> Running<C> {
+        Running {
+            checksum: checksum,
+            offset: 0,
+        }
+    }
+    /// Feeds the bytes of the buffer from the previous offset up to `end`
+    ///
+    /// The `end` is limited by the length of the buffer, so it's fine to
+    /// pass the length of the frame before it's received in full
+    pub fn update(&mut self, buf: &Buf, end: usize) {
+        let end = min(end, buf.len());
+        if end > self.offset {
+            self.checksum.update(&buf[self.offset..end]);
+            self.offset = end;
+        }
+    }
+    /// The number of bytes checksummed so far
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+    pub fn into_inner(self) -> C {
+        self.checksum
+    }
+}
+
+/// Decoder of the HTTP chunked transfer encoding
+///
+/// Feed it the body as it arrives. `decode` extracts the data of the
+/// chunks, `pass_through` copies the encoded body as is, for proxies which
+/// only need to know where the body ends:
+///
+/// ```ignore
+/// if try!(self.chunked.decode(transport.input(), &mut self.body)) {
+///     // the body is complete, the trailers are in `trailers()`
+/// }
+/// ```
+pub struct ChunkedDecoder {
+    state: Chunk,
+    trailers: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Chunk {
+    Size,
+    /// Bytes left in the current chunk
+    Data(u64),
+    /// The line end after the data of the chunk
+    DataEnd,
+    Trailers,
+    Done,
+}
+
+/// Maximum length of the chunk size line, including extensions
+const MAX_CHUNK_LINE: usize = 4096;
+/// Maximum size of the trailers
+const MAX_TRAILERS: usize = 65536;
+
+/// Writes `data` as a single chunk, empty data is skipped
+pub fn write_chunk(out: &mut Buf, data: &[u8]) {
+    if data.len() == 0 {
+        return;
+    }
+    write!(out, "{:x}\r\n", data.len()).unwrap();
+    out.extend(data);
+    out.extend(b"\r\n");
+}
+
+/// Writes the last chunk, finishing the body
+///
+/// The `trailers` are header lines, each terminated by `\r\n`, or empty
+pub fn write_last_chunk(out: &mut Buf, trailers: &[u8]) {
+    out.extend(b"0\r\n");
+    out.extend(trailers);
+    out.extend(b"\r\n");
+}
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Parses the chunk size line without the line end
+fn parse_chunk_size(line: &[u8]) -> io::Result<u64> {
+    let line = match line.iter().position(|&x| x == b';') {
+        Some(pos) => &line[..pos],
+        None => line,
+    };
+    let digits = trim(line);
+    if digits.len() == 0 || digits.len() > 15 {
+        return Err(invalid("invalid chunk size"));
+    }
+    let mut size = 0;
+    for &x in digits {
+        let digit = match x {
+            b'0'...b'9' => x - b'0',
+            b'a'...b'f' => x - b'a' + 10,
+            b'A'...b'F' => x - b'A' + 10,
+            _ => return Err(invalid("invalid chunk size")),
+        };
+        size = size << 4 | digit as u64;
+    }
+    Ok(size)
+}
+
+impl ChunkedDecoder {
+    pub fn new() -> ChunkedDecoder {
+        ChunkedDecoder {
+            state: Chunk::Size,
+            trailers: Vec::new(),
+        }
+    }
+    /// Returns true when the last chunk and the trailers are received
+    pub fn is_done(&self) -> bool {
+        self.state == Chunk::Done
+    }
+    /// The trailer lines, each terminated by `\r\n`
+    pub fn trailers(&self) -> &[u8] {
+        &self.trailers
+    }
+    /// Moves the data of the chunks from `input` to `output`
+    ///
+    /// Returns true when the body is complete, the bytes after the body
+    /// are left in the `input`
+    pub fn decode(&mut self, input: &mut Buf, output: &mut Buf)
+        -> io::Result<bool>
+    {
+        let n = try!(self.advance(&input[..],
+                                  &mut |data| output.extend(data)));
+        input.consume(n);
+        Ok(self.is_done())
+    }
+    /// Moves the encoded body from `input` to `output`
+    ///
+    /// Returns true when the body is complete, the bytes after the body
+    /// are left in the `input`
+    pub fn pass_through(&mut self, input: &mut Buf, output: &mut Buf)
+        -> io::Result<bool>
+    {
+        let n = try!(self.advance(&input[..], &mut |_| {}));
+        output.extend(&input[..n]);
+        input.consume(n);
+        Ok(self.is_done())
+    }
+    /// Parses the `input`, passing the data of the chunks to `sink`
+    ///
+    /// Returns the number of bytes of the input parsed
+    fn advance(&mut self, input: &[u8], sink: &mut FnMut(&[u8]))
+        -> io::Result<usize>
+    {
+        let mut pos = 0;
+        loop {
+            let rest = &input[pos..];
+            match self.state {
+                Chunk::Size | Chunk::Trailers => {
+                    let end = match memchr(b'\n', rest) {
+                        Some(end) => end,
+                        None if rest.len() > MAX_CHUNK_LINE => {
+                            return Err(invalid("chunk line is too long"));
+                        }
+                        None => return Ok(pos),
+                    };
+                    let line = &rest[..end];
+                    let line = if line.last() == Some(&b'\r') {
+                        &line[..end-1]
+                    } else {
+                        line
+                    };
+                    pos += end + 1;
+                    if self.state == Chunk::Trailers {
+                        if line.len() == 0 {
+                            self.state = Chunk::Done;
+                        } else if self.trailers.len() + line.len()
+                            > MAX_TRAILERS
+                        {
+                            return Err(invalid("trailers are too long"));
+                        } else {
+                            self.trailers.extend(line);
+                            self.trailers.extend(b"\r\n");
+                        }
+                    } else {
+                        self.state = match try!(parse_chunk_size(line)) {
+                            0 => Chunk::Trailers,
+                            size => Chunk::Data(size),
+                        };
+                    }
+                }
+                Chunk::Data(left) => {
+                    if rest.len() == 0 {
+                        return Ok(pos);
+                    }
+                    let n = min(left, rest.len() as u64) as usize;
+                    sink(&rest[..n]);
+                    pos += n;
+                    self.state = if n as u64 == left {
+                        Chunk::DataEnd
+                    } else {
+                        Chunk::Data(left - n as u64)
+                    };
+                }
+                Chunk::DataEnd => {
+                    if rest.starts_with(b"\r\n") {
+                        pos += 2;
+                    } else if rest.starts_with(b"\n") {
+                        pos += 1;
+                    } else if rest == b"" || rest == b"\r" {
+                        return Ok(pos);
+                    } else {
+                        return Err(invalid("no line end after chunk"));
+                    }
+                    self.state = Chunk::Size;
+                }
+                Chunk::Done => return Ok(pos),
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::find_substr;
+    use std::io::Read;
+    use netbuf::Buf;
+    use super::{find_substr, find_delimiter, split_off_front, find_header};
+    use super::{Window, Spool};
+    use super::{Checksum, Crc32, Adler32, Running};
+    use super::{ChunkedDecoder, write_chunk, write_last_chunk};
 
     #[test]
     fn middle() {
@@ -50,4 +612,131 @@ mod test {
     fn partial() {
         assert_eq!(find_substr("hello\r\nworld\r\n\r\n", "\r\n\r\n"), Some(12));
     }
+
+    #[test]
+    fn delimiter() {
+        let mut buf = Buf::new();
+        buf.extend(b"hello\r\nworld");
+        assert_eq!(find_delimiter(&buf, b"\r\n"), Some(5));
+        assert_eq!(find_delimiter(&buf, b"\n\n"), None);
+    }
+    #[test]
+    fn split_front() {
+        let mut buf = Buf::new();
+        buf.extend(b"hello world");
+        assert_eq!(split_off_front(&mut buf, 6), b"hello ".to_vec());
+        assert_eq!(&buf[..], b"world");
+    }
+    #[test]
+    fn header() {
+        let headers = b"Host: example.com\r\ncontent-length:  12 \r\n";
+        assert_eq!(find_header(headers, b"Content-Length"), Some(&b"12"[..]));
+        assert_eq!(find_header(headers, b"host"), Some(&b"example.com"[..]));
+        assert_eq!(find_header(headers, b"Hos"), None);
+    }
+    #[test]
+    fn window() {
+        let mut buf = Buf::new();
+        buf.extend(b"3\nabc4\nrest");
+        {
+            let mut wnd = Window::new(&mut buf);
+            assert_eq!(wnd.take_until(b"\n"), Some(&b"3"[..]));
+            assert_eq!(wnd.take(3), Some(&b"abc"[..]));
+            assert_eq!(wnd.take_until(b"\n"), Some(&b"4"[..]));
+            assert_eq!(wnd.take(5), None);
+            assert_eq!(wnd.data(), b"rest");
+            assert_eq!(wnd.consumed(), 7);
+        }
+        assert_eq!(&buf[..], b"rest");
+    }
+    #[test]
+    fn spool() {
+        let mut buf = Buf::new();
+        buf.extend(b"hello world");
+        let mut spool = Spool::new(8);
+        spool.consume_from(&mut buf, 6).unwrap();
+        assert!(!spool.is_spilled());
+        spool.consume_from(&mut buf, 5).unwrap();
+        assert!(spool.is_spilled());
+        assert_eq!(spool.len(), 11);
+        assert_eq!(buf.len(), 0);
+        let mut data = Vec::new();
+        spool.into_reader().unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"hello world");
+    }
+    #[test]
+    fn checksums() {
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.value(), 0xCBF43926);
+        let mut adler = Adler32::new();
+        adler.update(b"Wikipedia");
+        assert_eq!(adler.value(), 0x11E60398);
+        let mut adler = Adler32::new();
+        adler.update(&[0xFF; 100000]);
+        assert_eq!(adler.value(), 0x149A302C);
+    }
+    #[test]
+    fn running() {
+        let mut buf = Buf::new();
+        let mut crc = Running::new(Crc32::new());
+        buf.extend(b"1234");
+        crc.update(&buf, 9);
+        buf.extend(b"56789trailer");
+        crc.update(&buf, 9);
+        assert_eq!(crc.offset(), 9);
+        assert_eq!(crc.into_inner().value(), 0xCBF43926);
+    }
+    #[test]
+    fn chunked() {
+        let mut buf = Buf::new();
+        write_chunk(&mut buf, b"hello ");
+        write_chunk(&mut buf, b"");
+        write_chunk(&mut buf, b"world, 0123456789");
+        write_last_chunk(&mut buf, b"Expires: never\r\n");
+        buf.extend(b"GET /");
+        assert_eq!(&buf[..8], b"6\r\nhello");
+        let mut out = Buf::new();
+        let mut dec = ChunkedDecoder::new();
+        assert!(dec.decode(&mut buf, &mut out).unwrap());
+        assert_eq!(&out[..], b"hello world, 0123456789");
+        assert_eq!(dec.trailers(), b"Expires: never\r\n");
+        assert_eq!(&buf[..], b"GET /");
+    }
+    #[test]
+    fn chunked_incremental() {
+        let data = b"5;ext=1\r\nhello\r\n1A\n\
+                     abcdefghijklmnopqrstuvwxyz\n0\r\nX: y\r\n\r\nrest";
+        let mut dec = ChunkedDecoder::new();
+        let mut input = Buf::new();
+        let mut out = Buf::new();
+        let mut raw = Buf::new();
+        let mut copy = ChunkedDecoder::new();
+        let mut copy_input = Buf::new();
+        for &byte in &data[..] {
+            input.extend(&[byte]);
+            copy_input.extend(&[byte]);
+            dec.decode(&mut input, &mut out).unwrap();
+            copy.pass_through(&mut copy_input, &mut raw).unwrap();
+        }
+        assert!(dec.is_done());
+        assert!(copy.is_done());
+        assert_eq!(&out[..], b"helloabcdefghijklmnopqrstuvwxyz");
+        assert_eq!(dec.trailers(), b"X: y\r\n");
+        assert_eq!(&input[..], b"rest");
+        assert_eq!(&raw[..], &data[..data.len()-4]);
+        assert_eq!(&copy_input[..], b"rest");
+    }
+    #[test]
+    fn chunked_invalid() {
+        let mut out = Buf::new();
+        for data in &[&b"x\r\n"[..], b"\r\n", b"1\r\nab",
+                      b"10000000000000000\r\n"]
+        {
+            let mut buf = Buf::new();
+            buf.extend(data);
+            assert!(ChunkedDecoder::new().decode(&mut buf, &mut out).is_err());
+        }
+    }
 }
@@ -0,0 +1,212 @@
+//! Serde-based codecs for RPC-like protocols
+//!
+//! Messages are framed by a 4-byte big-endian length prefix. Implement
+//! `Service` and use `Parsed<Codec<MyService, Json>>` as a `greedy_stream`
+//! protocol. Clients may use `encode_frame` and `Codec::parse_frame` with
+//! their own parsers.
+//!
+//! Formats are enabled by features: `codec-json`, `codec-bincode` and
+//! `codec-msgpack`.
+use std::io::{self, Error, ErrorKind};
+use std::marker::PhantomData;
+
+use netbuf::Buf;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use BaseMachine;
+use transports::greedy_stream::Transport;
+use transports::parser::{Parser, Parse};
+
+/// Size of the length prefix of the frame
+const PREFIX: usize = 4;
+
+
+/// A serialization format
+pub trait Format {
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> io::Result<T>;
+    fn encode<T: Serialize>(value: &T) -> io::Result<Vec<u8>>;
+}
+
+/// JSON format (feature `codec-json`)
+#[cfg(feature="codec-json")]
+pub struct Json;
+
+/// Bincode format (feature `codec-bincode`)
+#[cfg(feature="codec-bincode")]
+pub struct Bincode;
+
+/// MessagePack format (feature `codec-msgpack`)
+#[cfg(feature="codec-msgpack")]
+pub struct MsgPack;
+
+/// A request-response service, see module docs
+pub trait Service<C>: BaseMachine + Sized {
+    type Request: DeserializeOwned;
+    type Response: Serialize;
+    /// Returns new state machine for new accepted connection
+    fn accepted(ctx: &mut C) -> Self;
+    /// A request has been received, send responses using `responder`
+    fn request(self, request: Self::Request,
+        responder: &mut Responder<Self::Response>, ctx: &mut C)
+        -> Option<Self>;
+    /// Maximum size of the message, larger ones close the connection
+    fn max_message(&self) -> usize {
+        1 << 20
+    }
+    /// Fatal error on connection happened, including decoding errors
+    ///
+    /// Default action is to log error on the info level
+    fn error_happened(self, e: Error, _ctx: &mut C) {
+        info!("Error when handling connection: {}", e);
+    }
+}
+
+/// A handle to send responses from `Service::request`
+pub struct Responder<'a, 'b: 'a, R> {
+    transport: &'a mut Transport<'b>,
+    encode: fn(&R, &mut Buf) -> io::Result<()>,
+}
+
+/// A `Parser` which decodes requests of the `Service` in the format `F`
+pub struct Codec<S, F> {
+    service: S,
+    phantom: PhantomData<F>,
+}
+
+/// Serializes the value and puts the frame into the buffer
+pub fn encode_frame<F: Format, T: Serialize>(value: &T, buf: &mut Buf)
+    -> io::Result<()>
+{
+    let data = try!(F::encode(value));
+    if data.len() > u32::max_value() as usize {
+        return Err(Error::new(ErrorKind::InvalidInput,
+            "Message is too large"));
+    }
+    let len = data.len() as u32;
+    buf.extend(&[(len >> 24) as u8, (len >> 16) as u8,
+                 (len >> 8) as u8, len as u8]);
+    buf.extend(&data);
+    Ok(())
+}
+
+impl<S, F: Format> Codec<S, F> {
+    /// Decodes a frame at the start of `data`
+    pub fn parse_frame<T: DeserializeOwned>(data: &[u8]) -> Parse<T> {
+        if data.len() < PREFIX {
+            return Parse::NeedMore(PREFIX - data.len());
+        }
+        let len = ((data[0] as usize) << 24) | ((data[1] as usize) << 16) |
+                  ((data[2] as usize) << 8) | (data[3] as usize);
+        if data.len() < PREFIX + len {
+            return Parse::NeedMore(PREFIX + len - data.len());
+        }
+        match F::decode(&data[PREFIX..PREFIX + len]) {
+            Ok(value) => Parse::Done(value, PREFIX + len),
+            Err(e) => Parse::Error(e),
+        }
+    }
+}
+
+impl<'a, 'b, R: Serialize> Responder<'a, 'b, R> {
+    /// Serializes the response into the output buffer
+    pub fn send(&mut self, response: &R) -> io::Result<()> {
+        (self.encode)(response, self.transport.output())
+    }
+    /// Returns the underlying transport
+    pub fn transport(&mut self) -> &mut Transport<'b> {
+        self.transport
+    }
+}
+
+impl<S: BaseMachine, F> BaseMachine for Codec<S, F> {
+    type Timeout = S::Timeout;
+}
+
+impl<S: Service<C>, F: Format, C> Parser<C> for Codec<S, F> {
+    type Output = S::Request;
+    fn accepted(ctx: &mut C) -> Self {
+        Codec {
+            service: S::accepted(ctx),
+            phantom: PhantomData,
+        }
+    }
+    fn parse(&mut self, data: &[u8]) -> Parse<S::Request> {
+        Codec::<S, F>::parse_frame(data)
+    }
+    fn packet_received(self, packet: S::Request, transport: &mut Transport,
+        ctx: &mut C)
+        -> Option<Self>
+    {
+        let mut responder = Responder {
+            transport: transport,
+            encode: encode_frame::<F, S::Response>,
+        };
+        self.service.request(packet, &mut responder, ctx)
+            .map(|s| Codec { service: s, phantom: PhantomData })
+    }
+    fn max_buffer(&self) -> usize {
+        self.service.max_message() + PREFIX
+    }
+    fn error_happened(self, e: Error, ctx: &mut C) {
+        self.service.error_happened(e, ctx)
+    }
+}
+
+#[cfg(feature="codec-json")]
+impl Format for Json {
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> io::Result<T> {
+        ::serde_json::from_slice(data)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+    fn encode<T: Serialize>(value: &T) -> io::Result<Vec<u8>> {
+        ::serde_json::to_vec(value)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+    }
+}
+
+#[cfg(feature="codec-bincode")]
+impl Format for Bincode {
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> io::Result<T> {
+        ::bincode::deserialize(data)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+    fn encode<T: Serialize>(value: &T) -> io::Result<Vec<u8>> {
+        ::bincode::serialize(value)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+    }
+}
+
+#[cfg(feature="codec-msgpack")]
+impl Format for MsgPack {
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> io::Result<T> {
+        ::rmp_serde::from_slice(data)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+    fn encode<T: Serialize>(value: &T) -> io::Result<Vec<u8>> {
+        ::rmp_serde::to_vec(value)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+    }
+}
+
+#[cfg(all(test, feature="codec-json"))]
+mod test {
+    use netbuf::Buf;
+    use transports::parser::Parse;
+    use super::{Codec, Json, encode_frame};
+
+    #[test]
+    fn roundtrip() {
+        let mut buf = Buf::new();
+        encode_frame::<Json, _>(&vec![1u32, 2, 3], &mut buf).unwrap();
+        assert_eq!(&buf[..4], &[0, 0, 0, 7]);
+        match Codec::<(), Json>::parse_frame::<Vec<u32>>(&buf[..5]) {
+            Parse::NeedMore(6) => {}
+            _ => panic!("partial frame is parsed"),
+        }
+        match Codec::<(), Json>::parse_frame::<Vec<u32>>(&buf[..]) {
+            Parse::Done(value, 11) => assert_eq!(value, vec![1, 2, 3]),
+            _ => panic!("frame is not parsed"),
+        }
+    }
+}
@@ -43,6 +43,16 @@ macro_rules! rotor_compose_state_machines {
                     unreachable!();
                 })
             }
+            fn add_child(&mut self, m: $curtyp)
+                -> Result<::mio::Token, $curtyp>
+            {
+                self.0.add_child($name::$cursub(m))
+                .map_err(|x| if let $name::$cursub(c) = x {
+                    c
+                } else {
+                    unreachable!();
+                })
+            }
             fn add_timeout_ms(&mut self, delay: u64,
                 t: <$curtyp as $crate::BaseMachine>::Timeout)
                 -> Result<::mio::Timeout, ::mio::TimerError>
@@ -52,6 +62,11 @@ macro_rules! rotor_compose_state_machines {
             fn clear_timeout(&mut self, timeout: ::mio::Timeout) -> bool {
                 self.0.clear_timeout(timeout)
             }
+            fn spawn_after(&mut self, delay: u64, m: $curtyp)
+                -> Result<::mio::Timeout, ::mio::TimerError>
+            {
+                self.0.spawn_after(delay, $name::$cursub(m))
+            }
             fn register<E: ?Sized>(&mut self, io: &E,
                 interest: ::mio::EventSet, opt: ::mio::PollOpt)
                 -> Result<(), ::std::io::Error>
@@ -59,6 +74,105 @@ macro_rules! rotor_compose_state_machines {
             {
                 self.0.register(io, interest, opt)
             }
+            fn reregister<E: ?Sized>(&mut self, io: &E,
+                interest: ::mio::EventSet, opt: ::mio::PollOpt)
+                -> Result<(), ::std::io::Error>
+                where E: ::mio::Evented
+            {
+                self.0.reregister(io, interest, opt)
+            }
+            fn deregister<E: ?Sized>(&mut self, io: &E)
+                -> Result<(), ::std::io::Error>
+                where E: ::mio::Evented
+            {
+                self.0.deregister(io)
+            }
+            fn token(&self) -> ::mio::Token {
+                self.0.token()
+            }
+            fn id(&self) -> $crate::handler::MachineId {
+                self.0.id()
+            }
+            fn is_alive(&self, id: $crate::handler::MachineId) -> bool {
+                self.0.is_alive(id)
+            }
+            fn now(&self) -> ::std::time::Instant {
+                self.0.now()
+            }
+            fn now_ms(&self) -> u64 {
+                self.0.now_ms()
+            }
+            fn loop_stats(&self) -> Option<&$crate::stats::Stats> {
+                self.0.loop_stats()
+            }
+            fn notifier(&self) -> $crate::handler::Notifier {
+                self.0.notifier()
+            }
+            fn replace_self(&mut self, m: $curtyp) {
+                self.0.replace_self($name::$cursub(m))
+            }
+            fn for_each_machine<F>(&self, f: F)
+                where F: FnMut(::mio::Token)
+            {
+                self.0.for_each_machine(f)
+            }
+            fn wakeup(&mut self, token: ::mio::Token)
+                -> Result<(), $crate::handler::NotifyError>
+            {
+                self.0.wakeup(token)
+            }
+            fn shutdown_loop(&mut self) {
+                self.0.shutdown_loop()
+            }
+            fn exit_loop(&mut self, status: i32) {
+                self.0.exit_loop(status)
+            }
+            fn shutdown_self(&mut self) {
+                self.0.shutdown_self()
+            }
+            fn slot_data<T>(&mut self) -> &mut T
+                where T: ::std::any::Any + Default
+            {
+                self.0.slot_data()
+            }
+            fn remove_slot_data<T>(&mut self) -> Option<T>
+                where T: ::std::any::Any
+            {
+                self.0.remove_slot_data()
+            }
+            fn request_tick(&mut self) {
+                self.0.request_tick()
+            }
+            fn dump_machines(&self, out: &mut ::std::io::Write)
+                -> ::std::io::Result<()>
+            {
+                self.0.dump_machines(out)
+            }
+            fn set_max_lifetime(&mut self, ms: u64)
+                -> Result<(), ::mio::TimerError>
+            {
+                self.0.set_max_lifetime(ms)
+            }
+            fn migrate<T>(&mut self, token: ::mio::Token, target: T) -> bool
+                where T: $crate::handler::Target<$curtyp> + 'static
+            {
+                self.0.migrate(token, move |m| {
+                    if let $name::$cursub(c) = m {
+                        target.send_machine(c).map_err($name::$cursub)
+                    } else {
+                        Err(m)
+                    }
+                })
+            }
+            fn reserve_slot(&mut self) -> Option<::mio::Token> {
+                self.0.reserve_slot()
+            }
+            fn switch_slot(&mut self, token: ::mio::Token) -> ::mio::Token {
+                self.0.switch_slot(token)
+            }
+            fn fill_slot(&mut self, token: ::mio::Token, m: Option<$curtyp>) {
+                self.0.fill_slot(token, m.map($name::$cursub))
+            }
         }
     };
     ($name: ident <$context:ty>  { $( $subname:ident($subtype:ty), )* }) => {
@@ -77,7 +191,7 @@ macro_rules! rotor_compose_state_machines {
         impl $crate::EventMachine<$context> for $name<$($subtype),*> {
             fn ready<S>(self, events: ::mio::EventSet,
                 context: &mut $context, scope: &mut S)
-                -> Option<Self>
+                -> $crate::Response<Self>
                 where S: $crate::Scope<Self>
             {
                 match self {
@@ -88,6 +202,102 @@ macro_rules! rotor_compose_state_machines {
                     )*
                 }
             }
+            fn timeout<S>(self, timeout: Self::Timeout,
+                context: &mut $context, scope: &mut S)
+                -> $crate::Response<Self>
+                where S: $crate::Scope<Self>
+            {
+                match (self, timeout) {
+                    $(
+                        ($name::$subname(m), $name::$subname(t))
+                        => m.timeout(t, context, &mut scope::$subname(scope))
+                                               .map($name::$subname),
+                    )*
+                    // Timeout of the machine which was replaced
+                    (m, _) => $crate::Response::Continue(m),
+                }
+            }
+            fn wakeup<S>(self, context: &mut $context, scope: &mut S)
+                -> $crate::Response<Self>
+                where S: $crate::Scope<Self>
+            {
+                match self {
+                    $(
+                        $name::$subname(m)
+                        => m.wakeup(context, &mut scope::$subname(scope))
+                                               .map($name::$subname),
+                    )*
+                }
+            }
+            fn shutdown<S>(self, context: &mut $context, scope: &mut S)
+                -> $crate::Response<Self>
+                where S: $crate::Scope<Self>
+            {
+                match self {
+                    $(
+                        $name::$subname(m)
+                        => m.shutdown(context, &mut scope::$subname(scope))
+                                               .map($name::$subname),
+                    )*
+                }
+            }
+            fn tick<S>(self, context: &mut $context, scope: &mut S)
+                -> $crate::Response<Self>
+                where S: $crate::Scope<Self>
+            {
+                match self {
+                    $(
+                        $name::$subname(m)
+                        => m.tick(context, &mut scope::$subname(scope))
+                                               .map($name::$subname),
+                    )*
+                }
+            }
+            fn stalled<S>(self, context: &mut $context, scope: &mut S)
+                -> $crate::Response<Self>
+                where S: $crate::Scope<Self>
+            {
+                match self {
+                    $(
+                        $name::$subname(m)
+                        => m.stalled(context, &mut scope::$subname(scope))
+                                               .map($name::$subname),
+                    )*
+                }
+            }
+            fn child_terminated<S>(self, child: ::mio::Token,
+                context: &mut $context, scope: &mut S)
+                -> $crate::Response<Self>
+                where S: $crate::Scope<Self>
+            {
+                match self {
+                    $(
+                        $name::$subname(m)
+                        => m.child_terminated(child, context,
+                                              &mut scope::$subname(scope))
+                                               .map($name::$subname),
+                    )*
+                }
+            }
+            fn drain<S>(self, context: &mut $context, scope: &mut S)
+                -> $crate::Response<Self>
+                where S: $crate::Scope<Self>
+            {
+                match self {
+                    $(
+                        $name::$subname(m)
+                        => m.drain(context, &mut scope::$subname(scope))
+                                             .map($name::$subname),
+                    )*
+                }
+            }
+            fn is_draining(&self) -> bool {
+                match self {
+                    $(
+                        &$name::$subname(ref m) => m.is_draining(),
+                    )*
+                }
+            }
             fn register<S>(&mut self, scope: &mut S)
                 -> Result<(), ::std::io::Error>
                 where S: $crate::Scope<Self>
@@ -99,6 +309,33 @@ macro_rules! rotor_compose_state_machines {
                     )*
                 }
             }
+            fn name(&self) -> &'static str {
+                match self {
+                    $(
+                        &$name::$subname(ref m) => m.name(),
+                    )*
+                }
+            }
+            fn debug(&self, f: &mut ::std::fmt::Formatter)
+                -> ::std::fmt::Result
+            {
+                match self {
+                    $(
+                        &$name::$subname(ref m) => m.debug(f),
+                    )*
+                }
+            }
+            fn deregister<S>(&mut self, scope: &mut S)
+                -> Result<(), ::std::io::Error>
+                where S: $crate::Scope<Self>
+            {
+                match self {
+                    $(
+                        &mut $name::$subname(ref mut m)
+                        => m.deregister(&mut scope::$subname(scope)),
+                    )*
+                }
+            }
         }
     };
 }
@@ -0,0 +1,312 @@
+//! Helpers for sharing the context between several event loops
+//!
+//! Each `Handler` owns its context, so the usual way to run multiple loops
+//! (e.g. one per CPU core) and to share some state (counters, routing tables)
+//! between them is to split the context into the loop-local part, which is
+//! accessed without any synchronization, and the shared part, which is put
+//! into an `Arc` and uses `Mutex`, `RwLock` or atomics inside.
+//!
+//! A machine written for a part of the context is mounted into the loop
+//! with `Project`.
+use std::any::Any;
+use std::fmt;
+use std::io::{self, Error};
+use std::marker::PhantomData;
+use std::time::Instant;
+use std::sync::Arc;
+
+use mio::{Token, Timeout, TimerError, EventSet, PollOpt, Evented};
+
+use {BaseMachine, EventMachine, Scope, Response};
+use handler::{Abort, MachineId, Notifier, NotifyError, Target};
+use stats::Stats;
+
+
+/// The context split into the loop-local and the shared halves
+///
+/// ```ignore
+/// let shared = Arc::new(RwLock::new(Routes::new()));
+/// for _ in 0..threads {
+///     let ctx = Split::new(Local::new(), shared.clone());
+///     // ... create event loop and handler with `ctx` in a new thread
+/// }
+/// ```
+pub struct Split<L, S> {
+    /// The part which belongs to a single loop
+    pub local: L,
+    /// The part which is shared between loops
+    pub shared: Arc<S>,
+}
+
+impl<L, S> Split<L, S> {
+    pub fn new(local: L, shared: Arc<S>) -> Split<L, S> {
+        Split {
+            local: local,
+            shared: shared,
+        }
+    }
+    /// Creates a context for another loop sharing the same shared part
+    pub fn share<L2>(&self, local: L2) -> Split<L2, S> {
+        Split {
+            local: local,
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+/// Mounts a machine written for the context `C` into a loop whose context
+/// contains `C`
+///
+/// Useful to reuse third-party machines, and to compose machines which
+/// expect different contexts. The context of the loop implements
+/// `AsMut<C>`:
+///
+/// ```ignore
+/// impl AsMut<cache::Context> for Context {
+///     fn as_mut(&mut self) -> &mut cache::Context { &mut self.cache }
+/// }
+/// rotor_compose_state_machines!(Fsm<Context> {
+///     Cache(Project<cache::Expire, cache::Context>),
+///     Http(Server),
+/// });
+/// ```
+///
+/// Machines which don't use the context at all are better written generic
+/// over it (like `machines::Ticker`), then they don't need a projection.
+pub struct Project<M, C> {
+    machine: M,
+    phantom: PhantomData<fn(&mut C)>,
+}
+
+struct ScopeProxy<'a, S: 'a, C>(&'a mut S, PhantomData<fn(&mut C)>);
+
+impl<M, C> Project<M, C> {
+    pub fn new(machine: M) -> Project<M, C> {
+        Project {
+            machine: machine,
+            phantom: PhantomData,
+        }
+    }
+    pub fn get(&self) -> &M {
+        &self.machine
+    }
+    pub fn get_mut(&mut self) -> &mut M {
+        &mut self.machine
+    }
+    pub fn into_inner(self) -> M {
+        self.machine
+    }
+}
+
+impl<M: BaseMachine, C> BaseMachine for Project<M, C> {
+    type Timeout = M::Timeout;
+}
+
+impl<M, C, B> EventMachine<B> for Project<M, C>
+    where M: EventMachine<C> + 'static, C: 'static, B: AsMut<C>,
+{
+    fn ready<S>(self, events: EventSet, context: &mut B, scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        self.machine.ready(events, context.as_mut(),
+            &mut ScopeProxy(scope, PhantomData))
+            .map(Project::new)
+    }
+    fn register<S>(&mut self, scope: &mut S) -> Result<(), Error>
+        where S: Scope<Self>
+    {
+        self.machine.register(&mut ScopeProxy(scope, PhantomData))
+    }
+    fn wakeup<S>(self, context: &mut B, scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        self.machine.wakeup(context.as_mut(),
+            &mut ScopeProxy(scope, PhantomData))
+            .map(Project::new)
+    }
+    fn tick<S>(self, context: &mut B, scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        self.machine.tick(context.as_mut(),
+            &mut ScopeProxy(scope, PhantomData))
+            .map(Project::new)
+    }
+    fn stalled<S>(self, context: &mut B, scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        self.machine.stalled(context.as_mut(),
+            &mut ScopeProxy(scope, PhantomData))
+            .map(Project::new)
+    }
+    fn child_terminated<S>(self, child: Token, context: &mut B,
+        scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        self.machine.child_terminated(child, context.as_mut(),
+            &mut ScopeProxy(scope, PhantomData))
+            .map(Project::new)
+    }
+    fn timeout<S>(self, timeout: Self::Timeout, context: &mut B,
+        scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        self.machine.timeout(timeout, context.as_mut(),
+            &mut ScopeProxy(scope, PhantomData))
+            .map(Project::new)
+    }
+    fn drain<S>(self, context: &mut B, scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        self.machine.drain(context.as_mut(),
+            &mut ScopeProxy(scope, PhantomData))
+            .map(Project::new)
+    }
+    fn is_draining(&self) -> bool {
+        self.machine.is_draining()
+    }
+    fn name(&self) -> &'static str {
+        self.machine.name()
+    }
+    fn debug(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.machine.debug(f)
+    }
+    fn deregister<S>(&mut self, scope: &mut S) -> Result<(), Error>
+        where S: Scope<Self>
+    {
+        self.machine.deregister(&mut ScopeProxy(scope, PhantomData))
+    }
+    fn shutdown<S>(self, context: &mut B, scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        self.machine.shutdown(context.as_mut(),
+            &mut ScopeProxy(scope, PhantomData))
+            .map(Project::new)
+    }
+    fn abort<S>(self, reason: Abort, context: &mut B, scope: &mut S)
+        where S: Scope<Self>
+    {
+        self.machine.abort(reason, context.as_mut(),
+            &mut ScopeProxy(scope, PhantomData))
+    }
+}
+
+impl<'a, M, S, C> Scope<M> for ScopeProxy<'a, S, C>
+    where S: Scope<Project<M, C>> + 'a, M: BaseMachine + 'static, C: 'static,
+{
+    fn async_add_machine(&mut self, m: M) -> Result<(), M> {
+        self.0.async_add_machine(Project::new(m))
+            .map_err(Project::into_inner)
+    }
+    fn add_child(&mut self, m: M) -> Result<Token, M> {
+        self.0.add_child(Project::new(m))
+            .map_err(Project::into_inner)
+    }
+    fn add_timeout_ms(&mut self, delay: u64, t: M::Timeout)
+        -> Result<Timeout, TimerError>
+    {
+        self.0.add_timeout_ms(delay, t)
+    }
+    fn clear_timeout(&mut self, timeout: Timeout) -> bool {
+        self.0.clear_timeout(timeout)
+    }
+    fn spawn_after(&mut self, delay: u64, m: M)
+        -> Result<Timeout, TimerError>
+    {
+        self.0.spawn_after(delay, Project::new(m))
+    }
+    fn register<E: ?Sized>(&mut self, io: &E, interest: EventSet, opt: PollOpt)
+        -> Result<(), Error>
+        where E: Evented
+    {
+        self.0.register(io, interest, opt)
+    }
+    fn reregister<E: ?Sized>(&mut self, io: &E, interest: EventSet,
+        opt: PollOpt)
+        -> Result<(), Error>
+        where E: Evented
+    {
+        self.0.reregister(io, interest, opt)
+    }
+    fn deregister<E: ?Sized>(&mut self, io: &E) -> Result<(), Error>
+        where E: Evented
+    {
+        self.0.deregister(io)
+    }
+    fn token(&self) -> Token {
+        self.0.token()
+    }
+    fn id(&self) -> MachineId {
+        self.0.id()
+    }
+    fn is_alive(&self, id: MachineId) -> bool {
+        self.0.is_alive(id)
+    }
+    fn now(&self) -> Instant {
+        self.0.now()
+    }
+    fn now_ms(&self) -> u64 {
+        self.0.now_ms()
+    }
+    fn loop_stats(&self) -> Option<&Stats> {
+        self.0.loop_stats()
+    }
+    fn notifier(&self) -> Notifier {
+        self.0.notifier()
+    }
+    fn replace_self(&mut self, m: M) {
+        self.0.replace_self(Project::new(m))
+    }
+    fn for_each_machine<F>(&self, f: F)
+        where F: FnMut(Token)
+    {
+        self.0.for_each_machine(f)
+    }
+    fn wakeup(&mut self, token: Token) -> Result<(), NotifyError> {
+        self.0.wakeup(token)
+    }
+    fn shutdown_loop(&mut self) {
+        self.0.shutdown_loop()
+    }
+    fn exit_loop(&mut self, status: i32) {
+        self.0.exit_loop(status)
+    }
+    fn shutdown_self(&mut self) {
+        self.0.shutdown_self()
+    }
+    fn slot_data<T: Any + Default>(&mut self) -> &mut T {
+        self.0.slot_data()
+    }
+    fn remove_slot_data<T: Any>(&mut self) -> Option<T> {
+        self.0.remove_slot_data()
+    }
+    fn request_tick(&mut self) {
+        self.0.request_tick()
+    }
+    fn dump_machines(&self, out: &mut io::Write)
+        -> io::Result<()>
+    {
+        self.0.dump_machines(out)
+    }
+    fn set_max_lifetime(&mut self, ms: u64) -> Result<(), TimerError> {
+        self.0.set_max_lifetime(ms)
+    }
+    fn migrate<T>(&mut self, token: Token, target: T) -> bool
+        where T: Target<M> + 'static
+    {
+        self.0.migrate(token, move |m: Project<M, C>| {
+            target.send_machine(m.into_inner()).map_err(Project::new)
+        })
+    }
+    fn reserve_slot(&mut self) -> Option<Token> {
+        self.0.reserve_slot()
+    }
+    fn switch_slot(&mut self, token: Token) -> Token {
+        self.0.switch_slot(token)
+    }
+    fn fill_slot(&mut self, token: Token, m: Option<M>) {
+        self.0.fill_slot(token, m.map(Project::new))
+    }
+}
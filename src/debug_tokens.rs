@@ -0,0 +1,158 @@
+//! History of the slab slots for the `debug-tokens` feature
+//!
+//! With the feature enabled, the loop records allocations and frees of
+//! every slot, and panics with the history of the slot when:
+//!
+//! * a token is out of range of the slab at dispatch time
+//! * the slab and the counters of the history disagree at dispatch time
+//! * `Scope::wakeup` targets a slot which is not allocated, which usually
+//!   means a stale token is kept in the context
+//!
+//! Without the feature all the methods are no-ops.
+#[cfg(feature="debug-tokens")]
+use std::collections::{HashMap, VecDeque};
+#[cfg(feature="debug-tokens")]
+use std::fmt::Write;
+#[cfg(feature="debug-tokens")]
+use std::time::Instant;
+
+use mio::Token;
+
+
+/// Number of events kept for every slot
+#[cfg(feature="debug-tokens")]
+const HISTORY_LEN: usize = 8;
+
+#[cfg(feature="debug-tokens")]
+pub struct History {
+    slots: HashMap<Token, Slot>,
+}
+
+#[cfg(not(feature="debug-tokens"))]
+pub struct History;
+
+#[cfg(feature="debug-tokens")]
+#[derive(Default)]
+struct Slot {
+    allocated: u64,
+    freed: u64,
+    log: VecDeque<(Instant, &'static str, u64)>,
+}
+
+#[cfg(feature="debug-tokens")]
+impl Slot {
+    fn record(&mut self, action: &'static str, generation: u64) {
+        if self.log.len() >= HISTORY_LEN {
+            self.log.pop_front();
+        }
+        self.log.push_back((Instant::now(), action, generation));
+    }
+}
+
+#[cfg(feature="debug-tokens")]
+impl History {
+    pub fn new() -> History {
+        History { slots: HashMap::new() }
+    }
+    pub fn allocated(&mut self, token: Token) {
+        let slot = self.slots.entry(token).or_insert_with(Slot::default);
+        slot.allocated += 1;
+        let generation = slot.allocated;
+        slot.record("allocated", generation);
+    }
+    pub fn freed(&mut self, token: Token) {
+        let slot = self.slots.entry(token).or_insert_with(Slot::default);
+        slot.freed += 1;
+        let generation = slot.freed;
+        slot.record("freed", generation);
+    }
+    /// Checks the token which is going to be dispatched
+    ///
+    /// `occupied` is `None` if the token is out of range of the slab
+    pub fn check_dispatch(&self, token: Token, occupied: Option<bool>) {
+        let occupied = match occupied {
+            Some(occupied) => occupied,
+            None => panic!("Token {:?} is out of range of the slab\n{}",
+                           token, self.report(token)),
+        };
+        if occupied != self.is_alive(token) {
+            panic!("Slab and token counters disagree: slot is {}\n{}",
+                   if occupied { "occupied" } else { "vacant" },
+                   self.report(token));
+        }
+    }
+    /// Checks the target of `Scope::wakeup`
+    pub fn check_wakeup(&self, token: Token, occupied: bool) {
+        if !occupied {
+            panic!("Wakeup of the stale token {:?}\n{}",
+                   token, self.report(token));
+        }
+    }
+    fn is_alive(&self, token: Token) -> bool {
+        self.slots.get(&token)
+            .map(|s| s.allocated > s.freed)
+            .unwrap_or(false)
+    }
+    /// Returns the human-readable history of the slot
+    pub fn report(&self, token: Token) -> String {
+        let slot = match self.slots.get(&token) {
+            Some(slot) => slot,
+            None => return format!("{:?}: never allocated", token),
+        };
+        let mut buf = format!("{:?}: allocated {} times, freed {} times",
+                              token, slot.allocated, slot.freed);
+        let now = Instant::now();
+        for &(time, action, generation) in &slot.log {
+            write!(buf, "\n  {:?} ago: {} (generation {})",
+                   now.duration_since(time), action, generation).unwrap();
+        }
+        buf
+    }
+}
+
+#[cfg(not(feature="debug-tokens"))]
+impl History {
+    pub fn new() -> History {
+        History
+    }
+    #[inline(always)]
+    pub fn allocated(&mut self, _token: Token) {}
+    #[inline(always)]
+    pub fn freed(&mut self, _token: Token) {}
+    #[inline(always)]
+    pub fn check_dispatch(&self, _token: Token, _occupied: Option<bool>) {}
+    #[inline(always)]
+    pub fn check_wakeup(&self, _token: Token, _occupied: bool) {}
+}
+
+#[cfg(all(test, feature="debug-tokens"))]
+mod test {
+    use mio::Token;
+    use super::History;
+
+    #[test]
+    fn consistent() {
+        let mut history = History::new();
+        history.allocated(Token(1));
+        history.check_dispatch(Token(1), Some(true));
+        history.freed(Token(1));
+        history.check_dispatch(Token(1), Some(false));
+        assert!(history.report(Token(1))
+            .starts_with("Token(1): allocated 1 times, freed 1 times"));
+    }
+
+    #[test]
+    #[should_panic(expected="stale token")]
+    fn stale_wakeup() {
+        let mut history = History::new();
+        history.allocated(Token(1));
+        history.freed(Token(1));
+        history.check_wakeup(Token(1), false);
+    }
+
+    #[test]
+    #[should_panic(expected="out of range")]
+    fn out_of_range() {
+        History::new().check_dispatch(Token(100), None);
+    }
+}
@@ -0,0 +1,82 @@
+//! The crate-wide error type
+//!
+//! Machines return it in `Response::Error` to be removed from the loop with
+//! the reason logged, instead of disappearing silently.
+use std::io;
+use std::fmt;
+use std::error::Error as StdError;
+
+use mio::TimerError;
+
+use handler::NotifyError;
+
+
+#[derive(Debug)]
+pub enum Error {
+    /// Can't register a socket in the event loop
+    Register(io::Error),
+    /// Can't set a timeout
+    Timer(TimerError),
+    /// Can't deliver a notification
+    Notify(NotifyError),
+    /// There is no room for a new machine in the loop
+    NoSlabSpace,
+    /// I/O error on the machine's socket
+    Io(io::Error),
+    /// Any other error reported by the machine
+    Other(Box<StdError + Send + Sync>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+        match *self {
+            Register(ref e) => write!(f, "Can't register socket: {}", e),
+            Timer(ref e) => write!(f, "Can't add timeout: {:?}", e),
+            Notify(ref e) => write!(f, "Can't notify machine: {}", e),
+            NoSlabSpace => write!(f, "No slab space for a new machine"),
+            Io(ref e) => write!(f, "I/O error: {}", e),
+            Other(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        use self::Error::*;
+        match *self {
+            Register(_) => "can't register socket",
+            Timer(_) => "can't add timeout",
+            Notify(_) => "can't notify machine",
+            NoSlabSpace => "no slab space",
+            Io(ref e) => e.description(),
+            Other(ref e) => e.description(),
+        }
+    }
+    fn cause(&self) -> Option<&StdError> {
+        use self::Error::*;
+        match *self {
+            Register(ref e) | Io(ref e) => Some(e),
+            Other(ref e) => Some(&**e),
+            Timer(_) | Notify(_) | NoSlabSpace => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<TimerError> for Error {
+    fn from(e: TimerError) -> Error {
+        Error::Timer(e)
+    }
+}
+
+impl From<NotifyError> for Error {
+    fn from(e: NotifyError) -> Error {
+        Error::Notify(e)
+    }
+}
@@ -0,0 +1,62 @@
+//! Setting the flags of file descriptors with `fcntl`
+use std::io;
+use std::os::unix::io::RawFd;
+
+use libc;
+
+
+/// The flag of the file descriptor
+#[derive(Clone, Copy, Debug)]
+pub enum Flag {
+    /// `O_NONBLOCK` of the file status flags
+    NonBlocking,
+    /// `FD_CLOEXEC` of the descriptor flags
+    CloseOnExec,
+}
+
+/// Sets or clears the `flag` of the descriptor, other flags are kept
+///
+/// An invalid descriptor is reported as `EBADF`
+pub fn set_flag(fd: RawFd, flag: Flag, value: bool) -> io::Result<()> {
+    let (get, set, bit) = match flag {
+        Flag::NonBlocking => (libc::F_GETFL, libc::F_SETFL, libc::O_NONBLOCK),
+        Flag::CloseOnExec => (libc::F_GETFD, libc::F_SETFD, libc::FD_CLOEXEC),
+    };
+    let flags = unsafe { libc::fcntl(fd, get) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let nflags = if value { flags | bit } else { flags & !bit };
+    if nflags != flags && unsafe { libc::fcntl(fd, set, nflags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use libc;
+    use super::{set_flag, Flag};
+
+    #[test]
+    fn set_and_clear() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let fd = fds[0];
+        set_flag(fd, Flag::NonBlocking, true).unwrap();
+        set_flag(fd, Flag::CloseOnExec, true).unwrap();
+        unsafe {
+            assert!(libc::fcntl(fd, libc::F_GETFL) & libc::O_NONBLOCK != 0);
+            assert!(libc::fcntl(fd, libc::F_GETFD) & libc::FD_CLOEXEC != 0);
+        }
+        set_flag(fd, Flag::CloseOnExec, false).unwrap();
+        unsafe {
+            assert!(libc::fcntl(fd, libc::F_GETFL) & libc::O_NONBLOCK != 0);
+            assert!(libc::fcntl(fd, libc::F_GETFD) & libc::FD_CLOEXEC == 0);
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+        assert_eq!(set_flag(-1, Flag::NonBlocking, true).unwrap_err()
+            .raw_os_error(), Some(libc::EBADF));
+    }
+}
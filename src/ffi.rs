@@ -0,0 +1,349 @@
+//! C API for embedding the event loop (feature `ffi`)
+//!
+//! The loop serves TCP connections with the protocol implemented by C
+//! callbacks. Link a `staticlib` or `cdylib` crate which depends on rotor
+//! with this feature enabled. The declarations are:
+//!
+//! ```c
+//! struct rotor_callbacks {
+//!     void *data;
+//!     /* returns the data of the connection, passed to other callbacks */
+//!     void *(*on_accept)(void *data);
+//!     /* returns number of bytes consumed, or negative to close */
+//!     ssize_t (*on_data)(void *data, void *conn_data, void *conn,
+//!                        const uint8_t *buf, size_t len);
+//!     /* called once for every accepted connection, error is errno or 0 */
+//!     void (*on_close)(void *data, void *conn_data, int error);
+//! };
+//! void *rotor_loop_new(const struct rotor_callbacks *callbacks);
+//! int rotor_loop_listen_tcp(void *loop, const char *addr);
+//! int rotor_loop_run(void *loop);
+//! void rotor_loop_free(void *loop);
+//! void *rotor_loop_stopper(void *loop);
+//! int rotor_stopper_stop(void *stopper);
+//! void rotor_stopper_free(void *stopper);
+//! int rotor_conn_write(void *conn, const uint8_t *buf, size_t len);
+//! ```
+//!
+//! Functions returning `int` return zero on success and `-1` on failure,
+//! the reason is logged. Panics don't unwind into the C code, they are
+//! logged and reported as failures (null for the functions returning
+//! pointers), the loop should be freed after that. The `conn` is valid
+//! only during `on_data`. The stopper may be used from any thread, it
+//! starts graceful shutdown of the loop, after which `rotor_loop_run`
+//! returns.
+use std::ffi::CStr;
+use std::net::SocketAddr;
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use libc;
+use mio::{EventLoop, Sender};
+use mio::tcp::{TcpListener, TcpStream};
+
+use BaseMachine;
+use handler::{Handler, Notify};
+use transports::accept::Serve;
+use transports::greedy_stream::{Stream, Protocol, Transport};
+
+
+/// Callbacks of the protocol, see module docs
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Callbacks {
+    pub data: *mut c_void,
+    pub on_accept: Option<extern "C" fn(*mut c_void) -> *mut c_void>,
+    pub on_data: Option<extern "C" fn(*mut c_void, *mut c_void, *mut c_void,
+                                      *const u8, usize) -> isize>,
+    pub on_close: Option<extern "C" fn(*mut c_void, *mut c_void, c_int)>,
+}
+
+pub struct Context {
+    callbacks: Callbacks,
+}
+
+/// The protocol which calls the C callbacks
+pub struct Connection {
+    callbacks: Callbacks,
+    data: *mut c_void,
+    error: c_int,
+}
+
+pub type Machine = Serve<TcpListener, Stream<TcpStream, Connection, Context>,
+                         Context>;
+
+/// The event loop with the handler, returned by `rotor_loop_new`
+pub struct Loop {
+    eloop: EventLoop<Handler<Context, Machine>>,
+    handler: Handler<Context, Machine>,
+}
+
+/// The handle to stop the loop from any thread
+pub struct Stopper(Sender<Notify<Machine>>);
+
+impl BaseMachine for Connection {
+    type Timeout = ();
+}
+
+impl Protocol<Context> for Connection {
+    fn accepted(ctx: &mut Context) -> Self {
+        let cb = ctx.callbacks;
+        Connection {
+            callbacks: cb,
+            data: cb.on_accept.map(|f| f(cb.data))
+                .unwrap_or(ptr::null_mut()),
+            error: 0,
+        }
+    }
+    fn data_received(self, transport: &mut Transport, _ctx: &mut Context)
+        -> Option<Self>
+    {
+        let on_data = match self.callbacks.on_data {
+            Some(f) => f,
+            None => {
+                // Nobody reads the data
+                let n = transport.input().len();
+                transport.input().consume(n);
+                return Some(self);
+            }
+        };
+        let (ptr, len) = {
+            let data = &transport.input()[..];
+            (data.as_ptr(), data.len())
+        };
+        let consumed = on_data(self.callbacks.data, self.data,
+            transport as *mut Transport as *mut c_void, ptr, len);
+        if consumed < 0 {
+            return None;
+        }
+        let consumed = consumed as usize;
+        transport.input().consume(if consumed > len { len } else { consumed });
+        Some(self)
+    }
+    fn error_happened(mut self, e: ::std::io::Error, _ctx: &mut Context) {
+        self.error = e.raw_os_error().unwrap_or(libc::EIO);
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Some(on_close) = self.callbacks.on_close {
+            on_close(self.callbacks.data, self.data, self.error);
+        }
+    }
+}
+
+/// Runs the body of the API call, returns `on_panic` if it panics
+fn guard<T, F: FnOnce() -> T>(on_panic: T, f: F) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(value) => value,
+        Err(_) => {
+            error!("Panic in the rotor API call");
+            on_panic
+        }
+    }
+}
+
+/// Creates the loop, returns null on failure
+#[no_mangle]
+pub unsafe extern "C" fn rotor_loop_new(callbacks: *const Callbacks)
+    -> *mut Loop
+{
+    guard(ptr::null_mut(), || {
+        if callbacks.is_null() {
+            return ptr::null_mut();
+        }
+        let mut eloop = match EventLoop::new() {
+            Ok(eloop) => eloop,
+            Err(e) => {
+                error!("Can't create event loop: {}", e);
+                return ptr::null_mut();
+            }
+        };
+        let context = Context {
+            callbacks: *callbacks,
+        };
+        let handler = Handler::new(context, &mut eloop);
+        Box::into_raw(Box::new(Loop {
+            eloop: eloop,
+            handler: handler,
+        }))
+    })
+}
+
+/// Starts listening on the `addr` (e.g. `"127.0.0.1:8080"`)
+#[no_mangle]
+pub unsafe extern "C" fn rotor_loop_listen_tcp(lp: *mut Loop,
+    addr: *const c_char)
+    -> c_int
+{
+    guard(-1, || {
+        if lp.is_null() || addr.is_null() {
+            return -1;
+        }
+        let lp = &mut *lp;
+        let addr = CStr::from_ptr(addr);
+        let addr: SocketAddr = match addr.to_str().ok()
+            .and_then(|a| a.parse().ok())
+        {
+            Some(addr) => addr,
+            None => {
+                error!("Invalid address {:?}", addr);
+                return -1;
+            }
+        };
+        let listener = match TcpListener::bind(&addr) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Can't listen on {}: {}", addr, e);
+                return -1;
+            }
+        };
+        match lp.handler.add_machine(&mut lp.eloop, Serve::new(listener)) {
+            Ok(_) => 0,
+            Err(e) => {
+                error!("Can't add listener {}: {}", addr, e);
+                -1
+            }
+        }
+    })
+}
+
+/// Runs the loop until it's stopped
+#[no_mangle]
+pub unsafe extern "C" fn rotor_loop_run(lp: *mut Loop) -> c_int {
+    guard(-1, || {
+        if lp.is_null() {
+            return -1;
+        }
+        let lp = &mut *lp;
+        match lp.eloop.run(&mut lp.handler) {
+            Ok(()) => 0,
+            Err(e) => {
+                error!("Event loop failed: {}", e);
+                -1
+            }
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rotor_loop_free(lp: *mut Loop) {
+    guard((), || {
+        if !lp.is_null() {
+            drop(Box::from_raw(lp));
+        }
+    })
+}
+
+/// Returns the handle to stop the loop, it must be freed separately
+#[no_mangle]
+pub unsafe extern "C" fn rotor_loop_stopper(lp: *mut Loop)
+    -> *mut Stopper
+{
+    guard(ptr::null_mut(), || {
+        if lp.is_null() {
+            return ptr::null_mut();
+        }
+        let lp = &*lp;
+        Box::into_raw(Box::new(Stopper(lp.eloop.channel())))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rotor_stopper_stop(stopper: *mut Stopper)
+    -> c_int
+{
+    guard(-1, || {
+        if stopper.is_null() {
+            return -1;
+        }
+        match (*stopper).0.send(Notify::Shutdown) {
+            Ok(()) => 0,
+            Err(_) => {
+                error!("Can't stop the loop, notification queue is full or \
+                        the loop is closed");
+                -1
+            }
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rotor_stopper_free(stopper: *mut Stopper) {
+    guard((), || {
+        if !stopper.is_null() {
+            drop(Box::from_raw(stopper));
+        }
+    })
+}
+
+/// Puts the data into the output buffer of the connection
+#[no_mangle]
+pub unsafe extern "C" fn rotor_conn_write(conn: *mut c_void, buf: *const u8,
+    len: usize)
+    -> c_int
+{
+    guard(-1, || {
+        if conn.is_null() || (buf.is_null() && len > 0) {
+            return -1;
+        }
+        let transport = &mut *(conn as *mut Transport);
+        if len > 0 {
+            transport.output().extend(slice::from_raw_parts(buf, len));
+        }
+        0
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::ptr;
+    use super::{Callbacks, guard};
+    use super::{rotor_loop_new, rotor_loop_run, rotor_loop_free};
+    use super::{rotor_loop_stopper, rotor_stopper_stop, rotor_stopper_free};
+    use super::{rotor_loop_listen_tcp, rotor_conn_write};
+
+    #[test]
+    fn create_run_destroy() {
+        let callbacks = Callbacks {
+            data: ptr::null_mut(),
+            on_accept: None,
+            on_data: None,
+            on_close: None,
+        };
+        unsafe {
+            let lp = rotor_loop_new(&callbacks);
+            assert!(!lp.is_null());
+            let stopper = rotor_loop_stopper(lp);
+            assert!(!stopper.is_null());
+            assert_eq!(rotor_stopper_stop(stopper), 0);
+            assert_eq!(rotor_loop_run(lp), 0);
+            rotor_stopper_free(stopper);
+            rotor_loop_free(lp);
+        }
+    }
+
+    #[test]
+    fn null_arguments() {
+        unsafe {
+            assert!(rotor_loop_new(ptr::null()).is_null());
+            assert_eq!(rotor_loop_listen_tcp(ptr::null_mut(), ptr::null()),
+                       -1);
+            assert_eq!(rotor_loop_run(ptr::null_mut()), -1);
+            assert!(rotor_loop_stopper(ptr::null_mut()).is_null());
+            assert_eq!(rotor_stopper_stop(ptr::null_mut()), -1);
+            assert_eq!(rotor_conn_write(ptr::null_mut(), ptr::null(), 0),
+                       -1);
+            rotor_stopper_free(ptr::null_mut());
+            rotor_loop_free(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn panic_is_caught() {
+        assert_eq!(guard(-1, || -> i32 { panic!("test") }), -1);
+    }
+}
@@ -1,42 +1,339 @@
-use std::io::Error;
+use std::io::{self, Error, ErrorKind};
+use std::error::Error as StdError;
+use std::cmp::max;
+use std::fmt;
+use std::mem;
 use std::usize;
+use std::any::{Any, TypeId};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::marker::PhantomData;
+use std::time::{Duration, Instant};
 
 use mio::{self, EventLoop, Token, EventSet, Evented, PollOpt};
 use mio::util::Slab;
 use mio::{Sender, Timeout, TimerError};
 
 use {Scope, BaseMachine};
+use tracer::Tracer;
+use hook::LoopHook;
+use stats::Stats;
+use response::Response;
+use error::Error as RotorError;
+use waker::{self, Waker, Wakeups};
+use debug_tokens::History;
 
 
-#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+/// The reason the machine is dropped without being run, see
+/// `EventMachine::abort`
+#[derive(Debug)]
 pub enum Abort {
+    /// There is no room for the machine in the loop
     NoSlabSpace,
-    RegisterFailed,
+    /// `EventMachine::register` has failed
+    RegisterFailed(Error),
+    /// The machine can't be queued to the loop
     MachineAddError,
 }
 
+impl fmt::Display for Abort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Abort::NoSlabSpace => write!(f, "No slab space for the machine"),
+            Abort::RegisterFailed(ref e) => {
+                write!(f, "Can't register the machine: {}", e)
+            }
+            Abort::MachineAddError => write!(f, "Can't add the machine"),
+        }
+    }
+}
+
+impl StdError for Abort {
+    fn description(&self) -> &str {
+        match *self {
+            Abort::NoSlabSpace => "no slab space",
+            Abort::RegisterFailed(_) => "can't register machine",
+            Abort::MachineAddError => "can't add machine",
+        }
+    }
+    fn cause(&self) -> Option<&StdError> {
+        match *self {
+            Abort::RegisterFailed(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// The class of the machine for ordering the dispatch of I/O events
+///
+/// Set by `Scope::set_priority`, takes effect when the handler has
+/// priorities enabled, see `Handler::enable_priorities`
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Priority {
+    /// Dispatched as soon as the event is polled (listeners, control
+    /// channels)
+    Control,
+    /// Dispatched after all the events of the iteration are polled
+    Interactive,
+    /// Dispatched after interactive machines, may be deferred to the next
+    /// iteration, see `Handler::set_bulk_budget`
+    Bulk,
+}
+
+impl Default for Priority {
+    fn default() -> Priority {
+        Priority::Interactive
+    }
+}
+
+/// The reason the loop has stopped, returned by `Handler::run`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exit {
+    /// All machines are removed after the shutdown
+    Shutdown,
+    /// The shutdown deadline has expired with this number of machines left
+    DeadlineExpired(usize),
+    /// A machine has stopped the loop with `Scope::exit_loop`
+    Status(i32),
+}
+
+/// Error returned by `Scope::add_machine_with`
+#[derive(Debug)]
+pub enum SpawnError<E> {
+    NoSlabSpace,
+    /// The error returned by the closure
+    Failed(E),
+}
+
+/// Error of sending a notification to the event loop
+#[derive(Debug)]
+pub enum NotifyError {
+    /// The notification queue of the event loop is full
+    Full,
+    /// The event loop is shut down
+    Closed,
+    /// Error waking up the event loop, the notification is queued anyway
+    Io(Error),
+}
+
 pub enum Notify<T> {
-    NewMachine(T),
+    /// Adds a machine to the loop, use `Box::new(machine)` for machines
+    /// which are `Send`
+    NewMachine(Box<Seed<T>>),
+    /// Calls `EventMachine::wakeup` for the machine with the id
+    ///
+    /// Dropped if the machine is removed, so it never reaches the next
+    /// machine in the slot
+    Wakeup(MachineId),
+    /// Calls `EventMachine::wakeup` for every machine, see
+    /// `Handler::broadcast`
+    Broadcast,
+    /// Starts shutdown of the loop, see `Scope::shutdown_loop`
+    Shutdown,
+    /// Drains the machines, see `Handler::drain`
+    Drain,
+    /// Logs loop statistics if they are enabled, see `Handler::enable_stats`
+    DumpStats,
+}
+
+/// The timeouts of the `Handler`
+pub enum Timer<T, M> {
+    /// Timeout of the machine set by `Scope::add_timeout_ms`, and the time
+    /// it's scheduled for
+    ///
+    /// Dropped if the machine is removed, so it never reaches the next
+    /// machine in the slot
+    Machine(MachineId, T, Instant),
+    /// The machine added by `Scope::spawn_after`
+    Spawn(M),
+    /// Calls `EventMachine::shutdown` again for machines which are still
+    /// shutting down
+    ShutdownPoll,
+    /// Machines didn't finish in time, the loop is stopped
+    ShutdownDeadline,
+    /// Checks whether the loop is idle, see `Handler::set_idle_timeout`
+    Idle,
+    /// Looks for stalled machines, see `Handler::set_watchdog`
+    Watchdog,
+    /// Calls `LoopHook::snapshot`, see `Handler::set_snapshot_interval`
+    Snapshot,
+    /// The machine reached its maximum lifetime, see
+    /// `Scope::set_max_lifetime`
+    Lifetime(MachineId),
+}
+
+/// A state machine which is sent to the event loop from another thread
+///
+/// It's implemented for every machine which is `Send`. Machines which are
+/// created inside the loop (i.e. by `Scope::async_add_machine`) don't need
+/// to be `Send`.
+pub trait Seed<M>: Send {
+    fn create(self: Box<Self>) -> M;
+}
+
+/// A handle which may be used to wake up a machine from any thread
+///
+/// Use `Scope::notifier` to get one.
+pub struct Notifier {
+    id: MachineId,
+    channel: Box<Wakeup>,
 }
 
-struct RootScope<'a, H: mio::Handler>
-    where H::Timeout: 'a, H::Message: 'a, H:'a
+/// The identity of a machine in the loop, see `Scope::id`
+///
+/// It's the token of the machine and the generation of its slot, which is
+/// incremented every time the slot is freed, so ids of removed machines
+/// are never equal to the ids of the machines which reuse the slot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MachineId {
+    token: Token,
+    generation: u64,
+}
+
+/// A destination of `Scope::migrate`
+///
+/// It's implemented for the `Sender` of the event loop (i.e. the result of
+/// `EventLoop::channel()`) and for closures, which are useful to wrap the
+/// machine into the type of the machine of the other loop.
+pub trait Target<M> {
+    /// Sends the machine, returns it back if the machine can't be sent
+    fn send_machine(&self, m: M) -> Result<(), M>;
+}
+
+/// The channel which delivers wakeups of the `Notifier` to the loop
+///
+/// Implemented by the waker and by the `Sender` of the loop, and by the
+/// `test_support::Channel` to test the notification logic without a loop.
+pub trait Wakeup: Send {
+    fn wakeup(&self, id: MachineId) -> Result<(), NotifyError>;
+    fn clone_box(&self) -> Box<Wakeup>;
+}
+
+struct RootScope<'a, C: 'a, M: 'static>
+    where M: EventMachine<C>
 {
-    channel: &'a Sender<H::Message>,
-    eloop: &'a mut EventLoop<H>,
+    state: &'a mut LoopState<M>,
+    eloop: &'a mut EventLoop<Handler<C, M>>,
+    now: Instant,
     token: Token,
+    replacement: Option<M>,
+    /// The machine of `Response::Replace` is being registered
+    replacing: bool,
+    shutdown_self: bool,
+    migration: Option<Box<Target<M>>>,
 }
 
-pub struct Handler<Ctx, M: Send> {
-    slab: Slab<M>,
-    context: Ctx,
+/// The part of the handler which is available to the scope of a callback
+struct LoopState<M> {
+    slab: Slab<Option<M>>,
     channel: Sender<Notify<M>>,
+    waker: Option<(Waker, Wakeups)>,
+    pending: VecDeque<M>,
+    /// Children added by `Scope::add_child` into the reserved slots
+    adopted: VecDeque<(Token, M)>,
+    family: Family,
+    ticks: VecDeque<Token>,
+    tracer: Option<Box<Tracer>>,
+    shutdown_requested: bool,
+    /// The status passed to `Scope::exit_loop`
+    exit_status: Option<i32>,
+    /// Machines which are shutting down, and their deadlines
+    draining: HashMap<Token, Instant>,
+    slot_data: HashMap<Token, SlotData>,
+    stats: Option<Stats>,
+    history: History,
+    /// The number of times every slot is freed, see `MachineId`
+    generations: HashMap<Token, u64>,
+    /// Allocated slots, so the loop doesn't iterate over the vacant ones
+    occupied: BTreeSet<Token>,
+    /// The time the handler is created, see `Scope::now_ms`
+    epoch: Instant,
+}
+
+/// Links between parent and child machines, see `Scope::add_child`
+#[derive(Default)]
+struct Family {
+    parents: HashMap<Token, Token>,
+    children: HashMap<Token, Vec<Token>>,
+    /// Changes which are reported after the current callback
+    events: VecDeque<Kin>,
+}
+
+enum Kin {
+    /// The parent of the machine is removed
+    Orphaned(Token),
+    /// The child of the machine is removed, (parent, child)
+    ChildRemoved(Token, Token),
 }
 
-pub trait EventMachine<C>: BaseMachine + Send + Sized {
+/// The time of the last call of the machine, kept in the slot data when
+/// the watchdog is enabled
+struct Activity(Instant);
+
+/// The deadline set by `Scope::set_max_lifetime`
+///
+/// It's checked when the timer fires, so the timer of the previous limit
+/// is ignored when the limit is changed
+struct Lifetime(Instant);
+
+/// Displays `EventMachine::debug` of the machine
+struct Details<'a, C, M: 'a>(&'a M, PhantomData<fn(&mut C)>);
+
+/// Values of `Scope::slot_data` of a single machine, by type
+type SlotData = HashMap<TypeId, Box<Any>>;
+
+/// The token of the waker in the event loop, see `waker` module
+const WAKER_TOKEN: Token = Token(usize::MAX - 1);
+
+/// Maximum number of wakeups delivered at once
+///
+/// The rest is delivered on the next iteration of the loop, so a burst of
+/// wakeups can't delay accepting connections and control messages
+const WAKEUP_BATCH: usize = 256;
+
+/// Interval of calling `EventMachine::shutdown` of machines which are
+/// still shutting down
+const SHUTDOWN_POLL_MS: u64 = 100;
+
+pub struct Handler<Ctx, M> {
+    state: LoopState<M>,
+    context: Ctx,
+    slow_callback: Option<Duration>,
+    /// Number of machines left when the shutdown deadline has expired
+    abandoned: Option<usize>,
+    shutting_down: bool,
+    shutdown_deadline: u64,
+    shutdown_poll: bool,
+    /// Machines which are finishing their work after `Handler::drain`,
+    /// `None` when the loop is not draining
+    drainers: Option<HashSet<Token>>,
+    priorities: bool,
+    bulk_budget: Option<usize>,
+    /// Events of interactive and bulk machines waiting for dispatch
+    ///
+    /// Events of the machines removed in the meantime are dropped
+    deferred: VecDeque<(MachineId, EventSet)>,
+    deferred_bulk: VecDeque<(MachineId, EventSet)>,
+    hook: Option<Box<LoopHook<Ctx>>>,
+    idle_timeout: Option<u64>,
+    /// The period of `Handler::set_snapshot_interval`
+    snapshot_interval: Option<u64>,
+    /// A machine was called since the last idle check
+    busy: bool,
+    /// The period of `Handler::set_watchdog`
+    watchdog: Option<u64>,
+    notify_budget: Option<usize>,
+    /// Wakeups handled in the current iteration
+    notified: usize,
+    /// Wakeups which are over the budget of the iteration
+    deferred_notify: VecDeque<MachineId>,
+    /// The time of the current iteration of the loop, see `Scope::now`
+    clock: Option<Instant>,
+}
+
+pub trait EventMachine<C>: BaseMachine + Sized {
     /// Socket readiness notification
     fn ready<S>(self, events: EventSet, context: &mut C, scope: &mut S)
-        -> Option<Self>
+        -> Response<Self>
         where S: Scope<Self>;
 
     /// Gives socket a chance to register in event loop
@@ -44,125 +341,1996 @@ pub trait EventMachine<C>: BaseMachine + Send + Sized {
         -> Result<(), Error>
         where S: Scope<Self>;
 
+    /// Called when the machine is woken up by a `Notifier`
+    ///
+    /// Note that wakeups may be coalesced or spurious, so the machine should
+    /// check its state (or the context) to find out what has changed
+    fn wakeup<S>(self, _context: &mut C, _scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        Response::Continue(self)
+    }
+
+    /// Low priority work requested by `Scope::request_tick`
+    fn tick<S>(self, _context: &mut C, _scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        Response::Continue(self)
+    }
+
+    /// Timeout set by `Scope::add_timeout_ms` happened
+    fn timeout<S>(self, _timeout: Self::Timeout, _context: &mut C,
+        _scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        Response::Continue(self)
+    }
+
+    /// The name of the kind of machine, used in diagnostic messages
+    fn name(&self) -> &'static str {
+        "unnamed"
+    }
+
+    /// Writes the details of the state for `Handler::dump`
+    ///
+    /// E.g. the sizes of the buffers. Keep it on a single line. Writes
+    /// nothing by default.
+    fn debug(&self, _f: &mut fmt::Formatter) -> fmt::Result {
+        Ok(())
+    }
+
+    /// Called before the machine is moved to another loop
+    ///
+    /// The machine should deregister its sockets and clear its timeouts
+    /// here. `register` is called by the loop which receives the machine.
+    /// See `Scope::migrate`.
+    fn deregister<S>(&mut self, _scope: &mut S) -> Result<(), Error>
+        where S: Scope<Self>
+    {
+        Ok(())
+    }
+
+    /// The event loop is shutting down, or the machine requested shutdown
+    /// with `Scope::shutdown_self`
+    ///
+    /// Return `Response::Remove` to be removed right away, or keep the
+    /// machine to finish the work. While the machine is kept, this method is
+    /// called again every 100 ms. The machine is removed anyway when the
+    /// deadline expires, see `Handler::set_shutdown_deadline`.
+    fn shutdown<S>(self, _context: &mut C, _scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        Response::Remove
+    }
+
+    /// The machine was not called for the period set by
+    /// `Handler::set_watchdog`
+    ///
+    /// Usually it means that the peer has gone without closing the
+    /// connection, and the machine has no timeout to notice it. Default
+    /// action is to log a warning and keep the machine.
+    fn stalled<S>(self, _context: &mut C, scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        warn!("Machine {} {:?} is stalled", self.name(), scope.token());
+        Response::Continue(self)
+    }
+
+    /// The loop is draining, see `Handler::drain`
+    ///
+    /// The machine should stop taking new work, finish the work in
+    /// progress and remove itself. Machines which have nothing to finish
+    /// (e.g. listeners) ignore it, which is the default.
+    fn drain<S>(self, _context: &mut C, _scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        Response::Continue(self)
+    }
+
+    /// Returns true while the machine finishes its work after `drain`
+    ///
+    /// The loop is drained when no such machine is left. Returns false by
+    /// default.
+    fn is_draining(&self) -> bool {
+        false
+    }
+
+    /// The child added by `Scope::add_child` is removed or moved to
+    /// another loop
+    ///
+    /// The token may be reused by a new machine already
+    fn child_terminated<S>(self, _child: Token, _context: &mut C,
+        _scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        Response::Continue(self)
+    }
+
     /// Abnormal termination of event machine
+    ///
+    /// The machine is dropped after the call. Default action is to log the
+    /// reason on the error level
     fn abort<S>(self, reason: Abort, _context: &mut C, _scope: &mut S)
         where S: Scope<Self>
     {
-        // TODO(tailhook) use Display instead of Debug
-        error!("Connection aborted: {:?}", reason);
+        error!("Machine {} aborted: {}", self.name(), reason);
     }
 }
 
-impl<C, M:Send> Handler<C, M>
-    where M: EventMachine<C>
+impl<C, M> Handler<C, M>
+    where M: EventMachine<C> + 'static
 {
     pub fn new(context: C, eloop: &mut EventLoop<Handler<C, M>>)
         -> Handler<C, M>
     {
         // TODO(tailhook) create default config from the ulimit data instead
         // of using real defaults
+        let waker = waker::new().and_then(|(waker, wakeups)| {
+            try!(eloop.register_opt(wakeups.io(), WAKER_TOKEN,
+                EventSet::readable(), PollOpt::level()));
+            Ok((waker, wakeups))
+        }).map_err(|e| {
+            error!("Can't create waker, notifications will use mio queue: \
+                {}", e);
+        }).ok();
         Handler {
-            slab: Slab::new(4096),
+            state: LoopState {
+                slab: Slab::new(4096),
+                channel: eloop.channel(),
+                waker: waker,
+                pending: VecDeque::new(),
+                adopted: VecDeque::new(),
+                family: Family::default(),
+                ticks: VecDeque::new(),
+                tracer: None,
+                shutdown_requested: false,
+                exit_status: None,
+                draining: HashMap::new(),
+                slot_data: HashMap::new(),
+                stats: None,
+                history: History::new(),
+                generations: HashMap::new(),
+                occupied: BTreeSet::new(),
+                epoch: Instant::now(),
+            },
             context: context,
-            channel: eloop.channel(),
+            slow_callback: None,
+            abandoned: None,
+            shutting_down: false,
+            shutdown_deadline: 5000,
+            shutdown_poll: false,
+            drainers: None,
+            priorities: false,
+            bulk_budget: None,
+            deferred: VecDeque::new(),
+            deferred_bulk: VecDeque::new(),
+            hook: None,
+            idle_timeout: None,
+            snapshot_interval: None,
+            busy: false,
+            watchdog: None,
+            notify_budget: None,
+            notified: 0,
+            deferred_notify: VecDeque::new(),
+            clock: None,
         }
     }
+    /// Runs the loop until it's shut down
+    ///
+    /// Returns the reason the loop has stopped and the context
+    pub fn run(mut self, eloop: &mut EventLoop<Self>)
+        -> Result<(Exit, C), Error>
+    {
+        try!(eloop.run(&mut self));
+        let exit = match (self.state.exit_status, self.abandoned) {
+            (Some(status), _) => Exit::Status(status),
+            (None, Some(left)) => Exit::DeadlineExpired(left),
+            (None, None) => Exit::Shutdown,
+        };
+        Ok((exit, self.context))
+    }
+    /// Adds a machine to the loop and registers it right away
+    ///
+    /// This is how listeners and other initial machines are added before
+    /// calling `EventLoop::run`. Unlike machines added by the scope, the
+    /// errors are returned instead of calling `EventMachine::abort`, and
+    /// the machines which the failed one has added through the scope are
+    /// aborted.
+    ///
+    /// Requests of `register` to replace, migrate or shut down the machine
+    /// are handled as in the other callbacks, so the returned token may be
+    /// free already.
+    pub fn add_machine(&mut self, eloop: &mut EventLoop<Self>, mut fsm: M)
+        -> Result<Token, RotorError>
+    {
+        let tok = try!(self.state.allocate_slot()
+            .ok_or(RotorError::NoSlabSpace));
+        let now = self.now();
+        let queued = (self.state.pending.len(), self.state.adopted.len());
+        let result = {
+            let ref mut scope = RootScope::new(&mut self.state, eloop, now,
+                tok);
+            fsm.register(scope).map(|()| {
+                scope.state.tracer.as_mut().map(|t| t.machine_created(tok));
+                (scope.registered(fsm), scope.shutdown_self)
+            })
+        };
+        match result {
+            Ok((fsm, shutdown_self)) => {
+                let alive = fsm.is_some();
+                self.put(tok, fsm);
+                if shutdown_self && alive {
+                    self.start_draining(eloop, tok);
+                }
+                self.add_pending(eloop);
+                self.check_shutdown(eloop);
+                Ok(tok)
+            }
+            Err(e) => {
+                self.abort_queued(eloop, queued);
+                self.state.release_slot(tok);
+                Err(RotorError::Register(e))
+            }
+        }
+    }
+    /// Returns the id of the machine with the `token`
+    ///
+    /// Use it to send `Notify::Wakeup` to a machine added by `add_machine`
+    pub fn machine_id(&self, token: Token) -> MachineId {
+        self.state.id(token)
+    }
+    /// Returns the context, to check it in tests, see `test_support`
+    #[cfg(any(test, feature="test-support"))]
+    pub fn context(&mut self) -> &mut C {
+        &mut self.context
+    }
+    /// Sets the time returned by `Scope::now` until the next iteration
+    ///
+    /// Timers run on the real clock, so tests deliver timeouts themselves,
+    /// see `test_support`
+    #[cfg(any(test, feature="test-support"))]
+    pub fn set_clock(&mut self, now: Instant) {
+        self.clock = Some(now);
+    }
+    /// Pre-allocates resources for `n` more machines
+    ///
+    /// The slab of the loop is grown so that there are at least `n` free
+    /// slots (there are 4096 slots initially), and the tables of the
+    /// handler are sized for them. Call it at startup to avoid allocation
+    /// spikes on bursts of connections. The I/O buffers are not
+    /// allocated here, they are owned by the connections.
+    pub fn reserve(&mut self, n: usize) {
+        let remaining = self.state.slab.remaining();
+        if remaining < n {
+            self.state.slab.grow(n - remaining);
+        }
+        self.state.pending.reserve(n);
+        self.state.slot_data.reserve(n);
+    }
+    /// Starts collecting loop statistics, see `stats` module
+    pub fn enable_stats(&mut self) {
+        self.state.stats = Some(Stats::new());
+    }
+    /// Returns loop statistics if they are enabled
+    pub fn stats(&self) -> Option<&Stats> {
+        self.state.stats.as_ref()
+    }
+    /// Returns the number of machines and the number of slots for them
+    ///
+    /// Slots are allocated when the handler is created, or by `reserve`,
+    /// and are never freed, since tokens are the slot numbers. Use
+    /// `Stats::max_machines` to choose the number to `reserve`.
+    pub fn occupancy(&self) -> (usize, usize) {
+        let slab = &self.state.slab;
+        (slab.count(), slab.count() + slab.remaining())
+    }
+    /// Sets how long machines may finish their work on shutdown
+    ///
+    /// The deadline applies both to the loop and to every machine shut
+    /// down by `Scope::shutdown_self`
+    ///
+    /// The default is 5 seconds
+    pub fn set_shutdown_deadline(&mut self, ms: u64) {
+        self.shutdown_deadline = ms;
+    }
+    /// Starts shutdown of the loop
+    ///
+    /// `EventMachine::shutdown` is called for every machine. The loop is
+    /// stopped when all machines are removed or the deadline expires.
+    /// Machines added afterwards are not notified.
+    pub fn shutdown(&mut self, eloop: &mut EventLoop<Self>) {
+        if self.shutting_down {
+            return;
+        }
+        self.shutting_down = true;
+        info!("Shutting down the loop");
+        if let Err(e) = eloop.timeout_ms(Timer::ShutdownDeadline,
+                                         self.shutdown_deadline)
+        {
+            error!("Can't set shutdown deadline: {:?}", e);
+        }
+        for token in self.state.tokens() {
+            self.start_draining(eloop, token);
+        }
+        self.check_drained();
+        self.check_shutdown(eloop);
+    }
+    /// Log a warning when a callback of a machine takes longer than `limit`
+    ///
+    /// Useful to find protocols which accidentally block the loop. The
+    /// warning includes the token and the `EventMachine::name`.
+    pub fn set_slow_callback_threshold(&mut self, limit: Duration) {
+        self.slow_callback = Some(limit);
+    }
+    /// Dispatches I/O events in the order of `Priority` of the machines
+    ///
+    /// Events of `Control` machines are dispatched right away, the others
+    /// are queued until all the events of the iteration are polled. Then
+    /// interactive machines are run, and bulk ones after them.
+    pub fn enable_priorities(&mut self) {
+        self.priorities = true;
+    }
+    /// Limits the number of events of bulk machines dispatched per
+    /// iteration of the loop
+    ///
+    /// The rest is dispatched on the next iterations, so bulk transfers
+    /// can't delay interactive machines under load. Enables priorities.
+    pub fn set_bulk_budget(&mut self, events: usize) {
+        self.priorities = true;
+        self.bulk_budget = Some(events);
+    }
+    /// Sets a tracer which is notified of the lifecycle of every machine
+    pub fn set_tracer(&mut self, tracer: Box<Tracer>) {
+        self.state.tracer = Some(tracer);
+    }
+    /// Limits the number of wakeups of the notification queue handled
+    /// per iteration of the loop
+    ///
+    /// The rest of the wakeups are kept in the handler and handled on the
+    /// next iterations, so a flood of wakeups can't starve I/O events. The
+    /// order of the wakeups is preserved. Control messages (new machines,
+    /// broadcast, shutdown, drain) are not limited, and are handled right
+    /// away even if wakeups are deferred.
+    pub fn set_notify_budget(&mut self, messages: usize) {
+        self.notify_budget = Some(messages);
+    }
+    /// Sets a hook which is called with the context on every iteration
+    pub fn set_loop_hook(&mut self, hook: Box<LoopHook<C>>) {
+        self.hook = Some(hook);
+    }
+    /// Calls `LoopHook::idle` when no machine was called for `ms`
+    /// milliseconds, and then every `ms` while the loop is idle
+    pub fn set_idle_timeout(&mut self, eloop: &mut EventLoop<Self>, ms: u64)
+    {
+        let running = self.idle_timeout.is_some();
+        self.idle_timeout = Some(ms);
+        if !running {
+            self.schedule_idle(eloop);
+        }
+    }
+    /// Calls `LoopHook::snapshot` every `ms` milliseconds
+    ///
+    /// The hook runs between dispatches, as any other timeout, so it may
+    /// export the state of the context without locking
+    pub fn set_snapshot_interval(&mut self, eloop: &mut EventLoop<Self>,
+        ms: u64)
+    {
+        let running = self.snapshot_interval.is_some();
+        self.snapshot_interval = Some(ms);
+        if !running {
+            self.schedule_snapshot(eloop);
+        }
+    }
+    /// Calls `EventMachine::stalled` for machines which were not called
+    /// for `ms` milliseconds
+    ///
+    /// Machines are checked every `ms / 2` milliseconds, so the stall is
+    /// detected in `1.5 * ms` at most. The hook is called again every `ms`
+    /// while the machine is stalled.
+    pub fn set_watchdog(&mut self, eloop: &mut EventLoop<Self>, ms: u64) {
+        let running = self.watchdog.is_some();
+        self.watchdog = Some(ms);
+        if !running {
+            self.schedule_watchdog(eloop);
+        }
+    }
+    /// Writes a line for every machine in the loop
+    ///
+    /// The line contains the token, the `EventMachine::name`, the time since
+    /// the last call of the machine if the watchdog is enabled (see
+    /// `set_watchdog`), and the `EventMachine::debug` output. Use
+    /// `Scope::dump_machines` to get the same report from a machine.
+    pub fn dump(&self, out: &mut io::Write) -> io::Result<()> {
+        dump::<C, M>(&self.state, None, out)
+    }
+    /// Calls `EventMachine::wakeup` for every machine in the loop
+    ///
+    /// Useful to reload configuration or to close idle connections. Put the
+    /// message into the context before calling this, or send
+    /// `Notify::Broadcast` from another thread. Machines added while
+    /// iterating may be skipped, removed ones are skipped.
+    pub fn broadcast(&mut self, eloop: &mut EventLoop<Self>) {
+        for token in self.state.tokens() {
+            self.dispatch(eloop, token, |fsm, ctx, scope| {
+                fsm.wakeup(ctx, scope)
+            });
+        }
+    }
+    /// Calls `EventMachine::drain` for every machine in the loop
+    ///
+    /// Use it for rolling restarts: stop accepting connections, drain the
+    /// loop and exit when `LoopHook::drained` is called. The loop is
+    /// drained when every machine which `is_draining` is removed. Machines
+    /// added afterwards are not drained. Send `Notify::Drain` to drain
+    /// the loop from another thread.
+    pub fn drain(&mut self, eloop: &mut EventLoop<Self>) {
+        info!("Draining the loop");
+        self.drainers = None;
+        let mut drainers = HashSet::new();
+        for token in self.state.tokens() {
+            self.dispatch(eloop, token, |fsm, ctx, scope| {
+                fsm.drain(ctx, scope)
+            });
+            if let Some(&Some(ref fsm)) = self.state.slab.get(token) {
+                if fsm.is_draining() {
+                    drainers.insert(token);
+                }
+            }
+        }
+        self.drainers = Some(drainers);
+        self.check_drained();
+    }
 }
 
-impl<'a, C, M> Scope<M> for RootScope<'a, Handler<C, M>>
-    where M: 'a, M: EventMachine<C>,
-          M::Timeout: 'a,
+/// Writes the report of `Handler::dump`
+///
+/// The `current` machine is out of the slab during the callback, so only
+/// its token is written
+fn dump<C, M>(state: &LoopState<M>, current: Option<Token>,
+    out: &mut io::Write)
+    -> io::Result<()>
+    where M: EventMachine<C>
 {
-    fn async_add_machine(&mut self, m: M) -> Result<(), M> {
+    let now = Instant::now();
+    for &token in &state.occupied {
+        let fsm = match state.slab.get(token) {
+            Some(&Some(ref fsm)) => fsm,
+            Some(&None) if Some(token) == current => {
+                try!(writeln!(out, "{:?} (current)", token));
+                continue;
+            }
+            _ => continue,
+        };
+        try!(write!(out, "{:?} {}", token, fsm.name()));
+        let activity = state.slot_data.get(&token)
+            .and_then(|data| data.get(&TypeId::of::<Activity>()))
+            .and_then(|value| value.downcast_ref::<Activity>());
+        if let Some(&Activity(last)) = activity {
+            let idle = now.duration_since(last);
+            try!(write!(out, " idle={}ms", millis(idle)));
+        }
+        try!(writeln!(out, " {}", Details::<C, M>(fsm, PhantomData)));
+    }
+    Ok(())
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1000000
+}
+
+impl<'a, C, M: EventMachine<C>> fmt::Display for Details<'a, C, M> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.debug(f)
+    }
+}
+
+impl<M: Send> Seed<M> for M {
+    fn create(self: Box<Self>) -> M {
+        *self
+    }
+}
+
+impl<M: Send + 'static> Target<M> for Sender<Notify<M>> {
+    fn send_machine(&self, m: M) -> Result<(), M> {
         use mio::NotifyError::*;
-        match self.channel.send(Notify::NewMachine(m)) {
+        match self.send(Notify::NewMachine(Box::new(m))) {
             Ok(()) => Ok(()),
             Err(Io(e)) => {
-                // We would probably do something better here, but mio doesn't
-                // give us a message. But anyway it's probably never happen
-                panic!("Io error when sending notify: {}", e);
+                // The machine is in the queue, but the loop may be not
+                // woken up until the next event
+                error!("Io error when sending notify: {}", e);
+                Ok(())
+            }
+            Err(Full(Notify::NewMachine(seed))) => Err(seed.create()),
+            Err(Closed(Some(Notify::NewMachine(seed)))) => Err(seed.create()),
+            Err(_) => unreachable!(),
+        }
+    }
+}
+
+impl<M, F> Target<M> for F
+    where F: Fn(M) -> Result<(), M>
+{
+    fn send_machine(&self, m: M) -> Result<(), M> {
+        self(m)
+    }
+}
+
+impl MachineId {
+    /// Returns the token of the machine
+    ///
+    /// Use it to wake up the machine or to migrate it, after checking
+    /// `Scope::is_alive`
+    pub fn token(&self) -> Token {
+        self.token
+    }
+    /// Creates the id of a machine, see `test_support`
+    #[cfg(any(test, feature="test-support"))]
+    pub fn new(token: Token, generation: u64) -> MachineId {
+        MachineId {
+            token: token,
+            generation: generation,
+        }
+    }
+}
+
+impl Notifier {
+    /// Creates a notifier with a custom channel, see `test_support`
+    #[cfg(any(test, feature="test-support"))]
+    pub fn with_channel(id: MachineId, channel: Box<Wakeup>) -> Notifier {
+        Notifier {
+            id: id,
+            channel: channel,
+        }
+    }
+    /// Returns the id of the machine which is woken up
+    pub fn id(&self) -> MachineId {
+        self.id
+    }
+    /// Schedules `EventMachine::wakeup` call for the machine
+    ///
+    /// Fails if notification queue of the event loop is full or the loop is
+    /// already shut down.
+    pub fn wakeup(&self) -> Result<(), NotifyError> {
+        self.channel.wakeup(self.id)
+    }
+}
+
+impl Wakeup for Waker {
+    fn wakeup(&self, id: MachineId) -> Result<(), NotifyError> {
+        match Waker::wakeup(self, id) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == ErrorKind::BrokenPipe => {
+                Err(NotifyError::Closed)
             }
-            Err(Full(Notify::NewMachine(m))) => Err(m),
-            Err(Closed(_)) => {
-                // It should never happen because we usually send from the
-                // inside of a main loop
-                panic!("Sending to closed channel. Main loop is already shut \
-                    down");
+            Err(e) => Err(NotifyError::Io(e)),
+        }
+    }
+    fn clone_box(&self) -> Box<Wakeup> {
+        Box::new(self.clone())
+    }
+}
+
+impl fmt::Display for NotifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NotifyError::Full => write!(f, "notification queue is full"),
+            NotifyError::Closed => write!(f, "event loop is shut down"),
+            NotifyError::Io(ref e) => write!(f, "can't wake up loop: {}", e),
+        }
+    }
+}
+
+impl Clone for Notifier {
+    fn clone(&self) -> Notifier {
+        Notifier {
+            id: self.id,
+            channel: self.channel.clone_box(),
+        }
+    }
+}
+
+impl<M: 'static> Wakeup for Sender<Notify<M>> {
+    fn wakeup(&self, id: MachineId) -> Result<(), NotifyError> {
+        use mio::NotifyError::*;
+        match self.send(Notify::Wakeup(id)) {
+            Ok(()) => Ok(()),
+            Err(Io(e)) => Err(NotifyError::Io(e)),
+            Err(Full(_)) => Err(NotifyError::Full),
+            Err(Closed(_)) => Err(NotifyError::Closed),
+        }
+    }
+    fn clone_box(&self) -> Box<Wakeup> {
+        Box::new(self.clone())
+    }
+}
+
+impl<C, M> Handler<C, M>
+    where M: EventMachine<C> + 'static
+{
+    fn dispatch<F>(&mut self, eloop: &mut EventLoop<Self>, token: Token, f: F)
+        where F: FnOnce(M, &mut C, &mut RootScope<C, M>) -> Response<M>
+    {
+        // The machine is taken out of the slot for the time of the callback,
+        // so the slab is available to the scope. Spurious events are ok in
+        // mio, as well as events for the slots reserved by add_machine_with
+        let capacity = self.state.slab.count() + self.state.slab.remaining();
+        self.state.history.check_dispatch(token,
+            if token.0 < capacity {
+                Some(self.state.slab.contains(token))
+            } else {
+                None
+            });
+        let fsm = match self.state.slab.get_mut(token).and_then(|x| x.take())
+        {
+            Some(fsm) => fsm,
+            None => return,
+        };
+        self.busy = true;
+        let name = fsm.name();
+        let start = Instant::now();
+        if self.watchdog.is_some() {
+            self.touch(token, start);
+        }
+        let now = self.now();
+        let (fsm, shutdown_self) = {
+            let ref mut scope = RootScope::new(&mut self.state, eloop, now,
+                token);
+            let fsm = match f(fsm, &mut self.context, scope) {
+                Response::Continue(fsm) => Some(fsm),
+                Response::Remove => None,
+                Response::Error(e) => {
+                    error!("Machine {} {:?} failed: {}", name, token, e);
+                    None
+                }
+                Response::Replace(mut fsm) => {
+                    if scope.replacement.take().is_some() {
+                        error!("Machine {} {:?} called replace_self and \
+                            returned Response::Replace, the replacement \
+                            is dropped", name, token);
+                    }
+                    scope.replacing = true;
+                    let result = fsm.register(scope);
+                    scope.replacing = false;
+                    match result {
+                        Ok(()) => Some(fsm),
+                        Err(e) => {
+                            fsm.abort(Abort::RegisterFailed(e),
+                                &mut self.context, scope);
+                            None
+                        }
+                    }
+                }
+            };
+            let fsm = scope.replacement.take().or(fsm);
+            let fsm = match (fsm, scope.migration.take()) {
+                (Some(fsm), Some(target)) => scope.send_away(fsm, &*target),
+                (fsm, _) => fsm,
+            };
+            (fsm, scope.shutdown_self)
+        };
+        let elapsed = start.elapsed();
+        self.state.stats.as_mut().map(|s| s.dispatched(elapsed));
+        if let Some(limit) = self.slow_callback {
+            if elapsed > limit {
+                warn!("Slow callback of {} machine {:?}: {:?}",
+                    name, token, elapsed);
+            }
+        }
+        let alive = fsm.is_some();
+        self.put(token, fsm);
+        if shutdown_self && alive {
+            self.start_draining(eloop, token);
+        }
+        self.add_pending(eloop);
+        self.check_drained();
+        self.check_shutdown(eloop);
+    }
+    /// Calls `EventMachine::shutdown` for the first time
+    fn start_draining(&mut self, eloop: &mut EventLoop<Self>, token: Token) {
+        match self.state.slab.get(token) {
+            Some(&Some(_)) => {}
+            _ => return,
+        }
+        if self.state.draining.contains_key(&token) {
+            return;
+        }
+        let deadline = Instant::now() +
+            Duration::from_millis(self.shutdown_deadline);
+        self.state.draining.insert(token, deadline);
+        self.dispatch(eloop, token, |fsm, ctx, scope| {
+            fsm.shutdown(ctx, scope)
+        });
+        if self.state.draining.contains_key(&token) && !self.shutdown_poll {
+            self.schedule_shutdown_poll(eloop);
+        }
+    }
+    fn schedule_shutdown_poll(&mut self, eloop: &mut EventLoop<Self>) {
+        match eloop.timeout_ms(Timer::ShutdownPoll, SHUTDOWN_POLL_MS) {
+            Ok(_) => self.shutdown_poll = true,
+            Err(e) => error!("Can't set shutdown poll timer: {:?}", e),
+        }
+    }
+    /// Calls `EventMachine::shutdown` again, and removes machines which
+    /// are over the deadline
+    fn poll_draining(&mut self, eloop: &mut EventLoop<Self>) {
+        self.shutdown_poll = false;
+        // Forget machines which have been migrated or removed by the scope
+        let slab = &self.state.slab;
+        self.state.draining.retain(|&token, _| slab.get(token).is_some());
+        let now = Instant::now();
+        let tokens: Vec<_> = self.state.draining.iter()
+            .map(|(&token, &deadline)| (token, deadline)).collect();
+        for (token, deadline) in tokens {
+            if now < deadline {
+                self.dispatch(eloop, token, |fsm, ctx, scope| {
+                    fsm.shutdown(ctx, scope)
+                });
+            } else {
+                if let Some(&Some(ref fsm)) = self.state.slab.get(token) {
+                    warn!("Machine {} {:?} hasn't shut down in time, \
+                        removing", fsm.name(), token);
+                }
+                self.put(token, None);
+            }
+        }
+        self.notify_family(eloop);
+        if !self.state.draining.is_empty() {
+            self.schedule_shutdown_poll(eloop);
+        }
+        self.check_drained();
+        self.check_shutdown(eloop);
+    }
+    /// Calls `LoopHook::drained` when the last draining machine is removed
+    fn check_drained(&mut self) {
+        let done = match self.drainers {
+            Some(ref mut drainers) => {
+                let slab = &self.state.slab;
+                // The token may be reused, so the new machine is checked
+                drainers.retain(|&token| match slab.get(token) {
+                    Some(&Some(ref fsm)) => fsm.is_draining(),
+                    // The machine is being called
+                    Some(&None) => true,
+                    None => false,
+                });
+                drainers.is_empty()
+            }
+            None => false,
+        };
+        if done {
+            self.drainers = None;
+            info!("The loop is drained");
+            if let Some(ref mut hook) = self.hook {
+                hook.drained(&mut self.context);
+            }
+        }
+    }
+    /// Starts shutdown if requested, and stops the loop when it's done
+    fn check_shutdown(&mut self, eloop: &mut EventLoop<Self>) {
+        if self.state.shutdown_requested && !self.shutting_down {
+            self.shutdown(eloop);
+        } else if self.shutting_down && self.state.slab.count() == 0 {
+            eloop.shutdown();
+        }
+    }
+    fn put(&mut self, token: Token, fsm: Option<M>) {
+        match fsm {
+            Some(fsm) => self.state.slab[token] = Some(fsm),
+            None => self.state.release_slot(token),
+        }
+    }
+    fn insert(&mut self, eloop: &mut EventLoop<Self>, fsm: M) {
+        match self.state.allocate_slot() {
+            Some(tok) => self.start(eloop, tok, fsm),
+            None => {
+                // TODO(tailhook) it should be global scope instead
+                // of FSM-bound scope
+                let now = self.now();
+                let ref mut scope = RootScope::new(&mut self.state, eloop,
+                    now, Token(usize::MAX));
+                fsm.abort(Abort::NoSlabSpace, &mut self.context, scope);
+            }
+        }
+    }
+    /// Returns the time of the current iteration, see `Scope::now`
+    fn now(&mut self) -> Instant {
+        match self.clock {
+            Some(now) => now,
+            None => {
+                let now = Instant::now();
+                self.clock = Some(now);
+                now
+            }
+        }
+    }
+    /// Registers the machine in the allocated slot
+    fn start(&mut self, eloop: &mut EventLoop<Self>, tok: Token, mut fsm: M) {
+        let now = self.now();
+        let fsm = {
+            let ref mut scope = RootScope::new(&mut self.state, eloop, now,
+                tok);
+            match fsm.register(scope) {
+                Ok(()) => {
+                    scope.state.tracer.as_mut()
+                        .map(|t| t.machine_created(tok));
+                    scope.registered(fsm).map(|fsm| (fsm, scope.shutdown_self))
+                }
+                Err(e) => {
+                    fsm.abort(Abort::RegisterFailed(e), &mut self.context,
+                        scope);
+                    None
+                }
+            }
+        };
+        match fsm {
+            Some((fsm, shutdown_self)) => {
+                self.put(tok, Some(fsm));
+                if shutdown_self {
+                    self.start_draining(eloop, tok);
+                }
+            }
+            None => self.put(tok, None),
+        }
+    }
+    /// Aborts the machines queued by the scope of a machine which has
+    /// failed to register
+    ///
+    /// The `queued` are the lengths of the pending and the adopted queues
+    /// before the machine was registered.
+    fn abort_queued(&mut self, eloop: &mut EventLoop<Self>,
+        queued: (usize, usize))
+    {
+        let now = self.now();
+        let pending: Vec<_> = self.state.pending.drain(queued.0..).collect();
+        for fsm in pending {
+            let ref mut scope = RootScope::new(&mut self.state, eloop,
+                now, Token(usize::MAX));
+            fsm.abort(Abort::MachineAddError, &mut self.context, scope);
+        }
+        let adopted: Vec<_> = self.state.adopted.drain(queued.1..).collect();
+        for (tok, fsm) in adopted {
+            {
+                let ref mut scope = RootScope::new(&mut self.state, eloop,
+                    now, tok);
+                fsm.abort(Abort::MachineAddError, &mut self.context, scope);
+            }
+            self.state.release_slot(tok);
+        }
+    }
+    /// Delivers wakeups sent through the waker
+    fn wakeup_all(&mut self, eloop: &mut EventLoop<Self>) {
+        self.state.waker.as_mut().map(|&mut (_, ref mut w)| w.reset());
+        for _ in 0..WAKEUP_BATCH {
+            let next = self.state.waker.as_mut().and_then(|w| w.1.next());
+            let id = match next {
+                Some(id) => id,
+                None => return,
+            };
+            self.state.tracer.as_mut()
+                .map(|t| t.notify_received(Some(id.token)));
+            if !self.state.is_current(id) {
+                debug!("Wakeup of removed machine {:?}", id);
+                continue;
             }
+            self.dispatch(eloop, id.token, |fsm, ctx, scope| {
+                fsm.wakeup(ctx, scope)
+            });
         }
+        // Some wakeups are left in the queue
+        self.signal_waker();
+    }
+    /// Wakes up the loop on the next iteration
+    fn signal_waker(&mut self) {
+        if let Some((ref waker, _)) = self.state.waker {
+            waker.signal().map_err(|e|
+                error!("Error signalling waker: {}", e)).ok();
+        }
+    }
+    fn priority(&self, token: Token) -> Priority {
+        self.state.slot_data.get(&token)
+            .and_then(|data| data.get(&TypeId::of::<Priority>()))
+            .and_then(|value| value.downcast_ref::<Priority>())
+            .cloned()
+            .unwrap_or(Priority::Interactive)
+    }
+    fn dispatch_ready(&mut self, eloop: &mut EventLoop<Self>, token: Token,
+        events: EventSet)
+    {
+        let start = Instant::now();
+        self.dispatch(eloop, token, |fsm, ctx, scope| {
+            fsm.ready(events, ctx, scope)
+        });
+        if let Some(ref mut tracer) = self.state.tracer {
+            tracer.event_dispatched(token, events, start.elapsed());
+        }
+    }
+    fn handle_notify(&mut self, eloop: &mut EventLoop<Self>, msg: Notify<M>) {
+        use self::Notify::*;
+        match msg {
+            NewMachine(seed) => {
+                self.insert(eloop, seed.create());
+                self.add_pending(eloop);
+            }
+            Wakeup(id) => {
+                if !self.state.is_current(id) {
+                    debug!("Wakeup of removed machine {:?}", id);
+                    return;
+                }
+                self.dispatch(eloop, id.token, |fsm, ctx, scope| {
+                    fsm.wakeup(ctx, scope)
+                });
+            }
+            Broadcast => self.broadcast(eloop),
+            Shutdown => self.shutdown(eloop),
+            Drain => self.drain(eloop),
+            DumpStats => match self.state.stats {
+                Some(ref stats) => info!("Loop statistics: {:?}", stats),
+                None => warn!("Loop statistics are not enabled"),
+            },
+        }
+    }
+    /// Dispatches events queued by priorities
+    fn dispatch_deferred(&mut self, eloop: &mut EventLoop<Self>) {
+        while let Some((id, events)) = self.deferred.pop_front() {
+            if self.state.is_current(id) {
+                self.dispatch_ready(eloop, id.token, events);
+            }
+        }
+        let mut budget = self.bulk_budget.unwrap_or(usize::MAX);
+        while budget > 0 {
+            match self.deferred_bulk.pop_front() {
+                Some((id, events)) => {
+                    if !self.state.is_current(id) {
+                        continue;
+                    }
+                    self.dispatch_ready(eloop, id.token, events)
+                }
+                None => break,
+            }
+            budget -= 1;
+        }
+    }
+    fn schedule_idle(&mut self, eloop: &mut EventLoop<Self>) {
+        if let Some(ms) = self.idle_timeout {
+            if let Err(e) = eloop.timeout_ms(Timer::Idle, ms) {
+                error!("Can't set idle timer: {:?}", e);
+                self.idle_timeout = None;
+            }
+        }
+    }
+    fn schedule_snapshot(&mut self, eloop: &mut EventLoop<Self>) {
+        if let Some(ms) = self.snapshot_interval {
+            if let Err(e) = eloop.timeout_ms(Timer::Snapshot, ms) {
+                error!("Can't set snapshot timer: {:?}", e);
+                self.snapshot_interval = None;
+            }
+        }
+    }
+    fn schedule_watchdog(&mut self, eloop: &mut EventLoop<Self>) {
+        if let Some(ms) = self.watchdog {
+            if let Err(e) = eloop.timeout_ms(Timer::Watchdog, max(ms / 2, 1)) {
+                error!("Can't set watchdog timer: {:?}", e);
+                self.watchdog = None;
+            }
+        }
+    }
+    /// Checks the deadline set by `Scope::set_max_lifetime`
+    fn lifetime_expired(&self, token: Token) -> bool {
+        self.state.slot_data.get(&token)
+            .and_then(|data| data.get(&TypeId::of::<Lifetime>()))
+            .and_then(|value| value.downcast_ref::<Lifetime>())
+            .map(|&Lifetime(deadline)| deadline <= Instant::now())
+            .unwrap_or(false)
+    }
+    /// Records the activity of the machine, returns the previous time
+    fn touch(&mut self, token: Token, now: Instant) -> Option<Instant> {
+        let data = self.state.slot_data.entry(token)
+            .or_insert_with(HashMap::new);
+        if let Some(value) = data.get_mut(&TypeId::of::<Activity>()) {
+            if let Some(activity) = value.downcast_mut::<Activity>() {
+                return Some(mem::replace(&mut activity.0, now));
+            }
+        }
+        data.insert(TypeId::of::<Activity>(), Box::new(Activity(now)));
+        None
+    }
+    /// Calls `EventMachine::stalled` for machines which are not active
+    fn check_stalled(&mut self, eloop: &mut EventLoop<Self>, ms: u64) {
+        let period = Duration::from_millis(ms);
+        let now = Instant::now();
+        for token in self.state.tokens() {
+            match self.state.slab.get(token) {
+                Some(&Some(_)) => {}
+                _ => continue,
+            }
+            // Machines which were never called are checked since now
+            let last = match self.touch(token, now) {
+                Some(last) => last,
+                None => continue,
+            };
+            if now.duration_since(last) < period {
+                self.touch(token, last);
+                continue;
+            }
+            self.dispatch(eloop, token, |fsm, ctx, scope| {
+                fsm.stalled(ctx, scope)
+            });
+        }
+    }
+    /// Adds machines created by `Scope::async_add_machine` and
+    /// `Scope::add_child`
+    fn add_pending(&mut self, eloop: &mut EventLoop<Self>) {
+        loop {
+            if let Some(fsm) = self.state.pending.pop_front() {
+                self.insert(eloop, fsm);
+            } else if let Some((tok, fsm)) = self.state.adopted.pop_front() {
+                self.start(eloop, tok, fsm);
+            } else {
+                break;
+            }
+        }
+        self.notify_family(eloop);
+    }
+    /// Shuts down orphaned children and notifies parents of removed ones
+    fn notify_family(&mut self, eloop: &mut EventLoop<Self>) {
+        while let Some(kin) = self.state.family.events.pop_front() {
+            match kin {
+                Kin::Orphaned(token) => self.start_draining(eloop, token),
+                Kin::ChildRemoved(parent, child) => {
+                    self.dispatch(eloop, parent, |fsm, ctx, scope| {
+                        fsm.child_terminated(child, ctx, scope)
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl Family {
+    fn link(&mut self, parent: Token, child: Token) {
+        self.parents.insert(child, parent);
+        self.children.entry(parent).or_insert_with(Vec::new).push(child);
+    }
+    /// Unlinks the removed machine from its parent and its children
+    fn removed(&mut self, token: Token) {
+        // Pending events of the machine are stale, the token may be reused
+        self.events.retain(|kin| match *kin {
+            Kin::Orphaned(child) => child != token,
+            Kin::ChildRemoved(parent, _) => parent != token,
+        });
+        if let Some(parent) = self.parents.remove(&token) {
+            let empty = match self.children.get_mut(&parent) {
+                Some(children) => {
+                    children.retain(|&child| child != token);
+                    children.is_empty()
+                }
+                None => false,
+            };
+            if empty {
+                self.children.remove(&parent);
+            }
+            self.events.push_back(Kin::ChildRemoved(parent, token));
+        }
+        if let Some(children) = self.children.remove(&token) {
+            for child in children {
+                self.parents.remove(&child);
+                self.events.push_back(Kin::Orphaned(child));
+            }
+        }
+    }
+}
+
+impl<M> LoopState<M> {
+    /// Takes a free slot of the slab for a new machine
+    fn allocate_slot(&mut self) -> Option<Token> {
+        let token = self.slab.insert(None).ok();
+        if let Some(token) = token {
+            self.history.allocated(token);
+            self.occupied.insert(token);
+        }
+        token
+    }
+    /// Frees the slot and forgets everything kept for its machine
+    ///
+    /// Every path which removes a machine (or gives up a reserved slot)
+    /// goes through here, so the next machine in the slot starts clean
+    fn release_slot(&mut self, token: Token) {
+        self.slab.remove(token);
+        self.history.freed(token);
+        self.slot_data.remove(&token);
+        self.draining.remove(&token);
+        self.family.removed(token);
+        self.tracer.as_mut().map(|t| t.machine_removed(token));
+        *self.generations.entry(token).or_insert(0) += 1;
+        self.occupied.remove(&token);
+    }
+    /// Returns the tokens of the allocated slots
+    ///
+    /// The list is copied, so it's safe to remove and add machines when
+    /// iterating
+    fn tokens(&self) -> Vec<Token> {
+        self.occupied.iter().cloned().collect()
+    }
+    fn id(&self, token: Token) -> MachineId {
+        MachineId {
+            token: token,
+            generation: self.generations.get(&token).cloned().unwrap_or(0),
+        }
+    }
+    /// Returns true if the machine with the `id` is not removed yet
+    fn is_current(&self, id: MachineId) -> bool {
+        self.slab.contains(id.token) && self.id(id.token) == id
+    }
+}
+
+impl<'a, C, M> RootScope<'a, C, M>
+    where M: EventMachine<C> + 'static
+{
+    fn new(state: &'a mut LoopState<M>,
+        eloop: &'a mut EventLoop<Handler<C, M>>, now: Instant, token: Token)
+        -> RootScope<'a, C, M>
+    {
+        RootScope {
+            state: state,
+            eloop: eloop,
+            now: now,
+            token: token,
+            replacement: None,
+            replacing: false,
+            shutdown_self: false,
+            migration: None,
+        }
+    }
+    /// Applies `replace_self` and `migrate` requested by the machine
+    /// which is just registered
+    ///
+    /// Returns the machine to put into the slot
+    fn registered(&mut self, fsm: M) -> Option<M> {
+        let fsm = self.replacement.take().unwrap_or(fsm);
+        match self.migration.take() {
+            Some(target) => self.send_away(fsm, &*target),
+            None => Some(fsm),
+        }
+    }
+    /// Deregisters the machine and sends it to the target
+    ///
+    /// Returns the machine back, registered again, if it can't be sent
+    fn send_away(&mut self, mut fsm: M, target: &Target<M>) -> Option<M> {
+        if let Err(e) = fsm.deregister(self) {
+            warn!("Error when deregistering migrating machine: {}", e);
+        }
+        match target.send_machine(fsm) {
+            Ok(()) => None,
+            Err(mut fsm) => {
+                error!("Can't migrate machine, queue is full or closed");
+                match fsm.register(self) {
+                    Ok(()) => Some(fsm),
+                    Err(e) => {
+                        error!("Can't register machine back: {}", e);
+                        None
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<'a, C, M> Scope<M> for RootScope<'a, C, M>
+    where M: EventMachine<C> + 'static
+{
+    fn async_add_machine(&mut self, m: M) -> Result<(), M> {
+        // Machine is added after the current callback returns, because
+        // the slab is borrowed by the callback, so the slots of the machines
+        // queued before are taken into account
+        if self.state.pending.len() >= self.state.slab.remaining() {
+            return Err(m);
+        }
+        self.state.pending.push_back(m);
+        Ok(())
+    }
+    fn add_child(&mut self, m: M) -> Result<Token, M> {
+        let token = match self.state.allocate_slot() {
+            Some(token) => token,
+            None => return Err(m),
+        };
+        self.state.family.link(self.token, token);
+        self.state.adopted.push_back((token, m));
+        Ok(token)
     }
     fn add_timeout_ms(&mut self, delay: u64, t: M::Timeout)
         -> Result<Timeout, TimerError>
     {
-        self.eloop.timeout_ms(t, delay)
+        let deadline = self.now + Duration::from_millis(delay);
+        let id = self.id();
+        self.eloop.timeout_ms(Timer::Machine(id, t, deadline), delay)
     }
     fn clear_timeout(&mut self, timeout: Timeout) -> bool {
         self.eloop.clear_timeout(timeout)
     }
+    fn spawn_after(&mut self, delay: u64, m: M)
+        -> Result<Timeout, TimerError>
+    {
+        self.eloop.timeout_ms(Timer::Spawn(m), delay)
+    }
     fn register<E: ?Sized>(&mut self, io: &E, interest: EventSet, opt: PollOpt)
         -> Result<(), Error>
         where E: Evented
     {
-        self.eloop.register_opt(io, self.token, interest, opt)
+        match self.eloop.register_opt(io, self.token, interest, opt) {
+            // The new machine took over the socket of the old one
+            Err(ref e) if self.replacing
+                && e.kind() == ErrorKind::AlreadyExists
+            => self.eloop.reregister(io, self.token, interest, opt),
+            result => result,
+        }
+    }
+    fn reregister<E: ?Sized>(&mut self, io: &E, interest: EventSet,
+        opt: PollOpt)
+        -> Result<(), Error>
+        where E: Evented
+    {
+        self.eloop.reregister(io, self.token, interest, opt)
+    }
+    fn deregister<E: ?Sized>(&mut self, io: &E) -> Result<(), Error>
+        where E: Evented
+    {
+        self.eloop.deregister(io)
+    }
+    fn token(&self) -> Token {
+        self.token
+    }
+    fn id(&self) -> MachineId {
+        self.state.id(self.token)
+    }
+    fn is_alive(&self, id: MachineId) -> bool {
+        self.state.is_current(id)
+    }
+    fn now(&self) -> Instant {
+        self.now
+    }
+    fn now_ms(&self) -> u64 {
+        millis(self.now.duration_since(self.state.epoch))
+    }
+    fn loop_stats(&self) -> Option<&Stats> {
+        self.state.stats.as_ref()
+    }
+    fn notifier(&self) -> Notifier {
+        Notifier {
+            id: self.id(),
+            channel: match self.state.waker {
+                Some((ref waker, _)) => Box::new(waker.clone()),
+                None => Box::new(self.state.channel.clone()),
+            },
+        }
+    }
+    fn replace_self(&mut self, m: M) {
+        self.replacement = Some(m);
+    }
+    fn migrate<T>(&mut self, token: Token, target: T) -> bool
+        where T: Target<M> + 'static
+    {
+        if token == self.token {
+            // The machine is out of the slab until the callback returns
+            self.migration = Some(Box::new(target));
+            return true;
+        }
+        let fsm = match self.state.slab.get_mut(token).and_then(|x| x.take()) {
+            Some(fsm) => fsm,
+            None => return false,
+        };
+        let old = self.switch_slot(token);
+        let fsm = self.send_away(fsm, &target);
+        self.switch_slot(old);
+        match fsm {
+            Some(fsm) => {
+                self.state.slab[token] = Some(fsm);
+                false
+            }
+            None => {
+                self.state.release_slot(token);
+                true
+            }
+        }
+    }
+    fn for_each_machine<F>(&self, mut f: F)
+        where F: FnMut(Token)
+    {
+        for &token in &self.state.occupied {
+            // The slot of the current machine is empty during the callback
+            match self.state.slab.get(token) {
+                Some(&Some(_)) => f(token),
+                Some(&None) if token == self.token => f(token),
+                _ => {}
+            }
+        }
+    }
+    fn wakeup(&mut self, token: Token) -> Result<(), NotifyError> {
+        let allocated = self.state.slab.contains(token);
+        self.state.history.check_wakeup(token, allocated);
+        if !allocated {
+            // The id of the free slot would match the next machine in it
+            debug!("Wakeup of the free slot {:?}", token);
+            return Ok(());
+        }
+        let id = self.state.id(token);
+        match self.state.waker {
+            Some((ref waker, _)) => Wakeup::wakeup(waker, id),
+            None => Wakeup::wakeup(&self.state.channel, id),
+        }
+    }
+    fn shutdown_loop(&mut self) {
+        self.state.shutdown_requested = true;
+    }
+    fn exit_loop(&mut self, status: i32) {
+        self.state.shutdown_requested = true;
+        self.state.exit_status = Some(status);
+    }
+    fn shutdown_self(&mut self) {
+        self.shutdown_self = true;
+    }
+    fn slot_data<T: Any + Default>(&mut self) -> &mut T {
+        self.state.slot_data.entry(self.token).or_insert_with(HashMap::new)
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(T::default()))
+            .downcast_mut().unwrap()
+    }
+    fn remove_slot_data<T: Any>(&mut self) -> Option<T> {
+        let (value, empty) = match self.state.slot_data.get_mut(&self.token) {
+            Some(data) => (data.remove(&TypeId::of::<T>()), data.len() == 0),
+            None => return None,
+        };
+        if empty {
+            self.state.slot_data.remove(&self.token);
+        }
+        value.map(|x| *x.downcast().unwrap())
+    }
+    fn request_tick(&mut self) {
+        self.state.ticks.push_back(self.token);
+    }
+    fn dump_machines(&self, out: &mut io::Write) -> io::Result<()> {
+        dump::<C, M>(&self.state, Some(self.token), out)
+    }
+    fn set_max_lifetime(&mut self, ms: u64) -> Result<(), TimerError> {
+        let deadline = self.now + Duration::from_millis(ms);
+        let id = self.id();
+        try!(self.eloop.timeout_ms(Timer::Lifetime(id), ms));
+        self.state.slot_data.entry(self.token).or_insert_with(HashMap::new)
+            .insert(TypeId::of::<Lifetime>(), Box::new(Lifetime(deadline)));
+        Ok(())
+    }
+    fn reserve_slot(&mut self) -> Option<Token> {
+        self.state.allocate_slot()
+    }
+    fn switch_slot(&mut self, token: Token) -> Token {
+        mem::replace(&mut self.token, token)
+    }
+    fn fill_slot(&mut self, token: Token, m: Option<M>) {
+        match m {
+            Some(m) => {
+                self.state.slab[token] = Some(m);
+                self.state.tracer.as_mut().map(|t| t.machine_created(token));
+            }
+            None => self.state.release_slot(token),
+        }
     }
 }
 
-impl<'a, M, Ctx> mio::Handler for Handler<Ctx, M>
-    where M: EventMachine<Ctx>
+impl<M, Ctx> mio::Handler for Handler<Ctx, M>
+    where M: EventMachine<Ctx> + 'static
 {
     type Message = Notify<M>;
-    type Timeout = M::Timeout;
+    type Timeout = Timer<M::Timeout, M>;
     fn ready<'x>(&mut self, eloop: &'x mut EventLoop<Self>,
         token: Token, events: EventSet)
     {
-        let ref mut ctx = self.context;
-        let ref mut scope = RootScope {
-            eloop: eloop,
-            channel: &self.channel,
-            token: token,
-        };
-        self.slab.replace_with(token, |fsm| {
-            fsm.ready(events, ctx, scope)
-        }).ok();  // Spurious events are ok in mio
+        if token == WAKER_TOKEN {
+            self.wakeup_all(eloop);
+            return;
+        }
+        if !self.priorities {
+            self.dispatch_ready(eloop, token, events);
+            return;
+        }
+        if !self.state.slab.contains(token) {
+            // Spurious event, nothing to defer
+            self.dispatch_ready(eloop, token, events);
+            return;
+        }
+        // The machine may be removed until the event is dispatched, and
+        // the event must not be delivered to the next machine in the slot
+        let id = self.state.id(token);
+        match self.priority(token) {
+            Priority::Control => self.dispatch_ready(eloop, token, events),
+            Priority::Interactive => self.deferred.push_back((id, events)),
+            Priority::Bulk => self.deferred_bulk.push_back((id, events)),
+        }
     }
 
     fn notify(&mut self, eloop: &mut EventLoop<Self>, msg: Self::Message) {
         use self::Notify::*;
-        let ref mut ctx = self.context;
-        match msg {
-            NewMachine(fsm) => {
-                // This is so complex because of limitations of Slab
-                match self.slab.insert(fsm) {
-                    Ok(tok) => {
-                        let ref mut scope = RootScope {
-                            eloop: eloop,
-                            channel: &self.channel,
-                            token: tok,
-                        };
-                        self.slab.replace_with(tok, |mut fsm| {
-                            match fsm.register(scope) {
-                                Ok(()) => Some(fsm),
-                                Err(_) => {
-                                    fsm.abort(Abort::RegisterFailed,
-                                        ctx, scope);
-                                    None
-                                }
-                            }
-                        }).unwrap();
+        if let Some(ref mut tracer) = self.state.tracer {
+            tracer.notify_received(match msg {
+                Wakeup(id) => Some(id.token),
+                NewMachine(_) | Broadcast | Shutdown | Drain | DumpStats
+                => None,
+            });
+        }
+        if let (Some(budget), &Wakeup(id)) = (self.notify_budget, &msg) {
+            if self.notified >= budget || self.deferred_notify.len() > 0 {
+                self.deferred_notify.push_back(id);
+                return;
+            }
+            self.notified += 1;
+        }
+        self.handle_notify(eloop, msg);
+    }
+
+    fn tick(&mut self, eloop: &mut EventLoop<Self>) {
+        let machines = self.state.slab.count();
+        let capacity = machines + self.state.slab.remaining();
+        self.state.stats.as_mut()
+            .map(|s| s.iteration_done(machines, capacity));
+        self.dispatch_deferred(eloop);
+        let budget = self.notify_budget.unwrap_or(usize::MAX);
+        while self.notified < budget {
+            match self.deferred_notify.pop_front() {
+                Some(id) => {
+                    self.notified += 1;
+                    self.handle_notify(eloop, Notify::Wakeup(id));
+                }
+                None => break,
+            }
+        }
+        self.notified = 0;
+        if let Some(ref mut hook) = self.hook {
+            hook.after_dispatch(&mut self.context);
+        }
+        // Ticks requested by these calls are run on the next iteration
+        for _ in 0..self.state.ticks.len() {
+            let token = self.state.ticks.pop_front().unwrap();
+            self.dispatch(eloop, token, |fsm, ctx, scope| {
+                fsm.tick(ctx, scope)
+            });
+        }
+        if let Some(ref mut hook) = self.hook {
+            hook.before_poll(&mut self.context);
+        }
+        if self.state.ticks.len() > 0 || self.deferred_bulk.len() > 0
+            || self.deferred_notify.len() > 0
+        {
+            // Don't block in poll while there is work to do
+            self.signal_waker();
+        }
+        // The clock is read again after the poll
+        self.clock = None;
+    }
+
+    fn timeout(&mut self, eloop: &mut EventLoop<Self>, timer: Self::Timeout)
+    {
+        match timer {
+            Timer::Machine(id, timeout, deadline) => {
+                if !self.state.is_current(id) {
+                    debug!("Timeout of removed machine {:?}", id);
+                    return;
+                }
+                let token = id.token;
+                if let Some(ref mut stats) = self.state.stats {
+                    let now = Instant::now();
+                    if now > deadline {
+                        stats.timer_lag.record(now - deadline);
+                    } else {
+                        stats.timer_lag.record(Duration::new(0, 0));
                     }
-                    Err(fsm) => {
-                        // TODO(tailhook) it should be global scope instead
-                        // of FSM-bound scope
-                        let ref mut scope = RootScope {
-                            eloop: eloop,
-                            channel: &self.channel,
-                            token: Token(usize::MAX),
-                        };
-                        fsm.abort(Abort::NoSlabSpace, ctx, scope);
+                }
+                self.state.tracer.as_mut().map(|t| t.timeout_fired(token));
+                self.dispatch(eloop, token, |fsm, ctx, scope| {
+                    fsm.timeout(timeout, ctx, scope)
+                });
+            }
+            Timer::Spawn(fsm) => {
+                if self.shutting_down {
+                    return;
+                }
+                self.insert(eloop, fsm);
+                self.add_pending(eloop);
+            }
+            Timer::ShutdownPoll => self.poll_draining(eloop),
+            Timer::ShutdownDeadline => {
+                warn!("Shutdown deadline expired with {} machines left",
+                    self.state.slab.count());
+                self.abandoned = Some(self.state.slab.count());
+                eloop.shutdown();
+            }
+            Timer::Idle => {
+                if !self.busy {
+                    if let Some(ref mut hook) = self.hook {
+                        hook.idle(&mut self.context);
                     }
                 }
+                self.busy = false;
+                self.schedule_idle(eloop);
+            }
+            Timer::Lifetime(id) => {
+                let token = id.token;
+                if self.state.is_current(id) && self.lifetime_expired(token) {
+                    info!("Machine {:?} reached maximum lifetime", token);
+                    self.start_draining(eloop, token);
+                }
+            }
+            Timer::Snapshot => {
+                if let Some(ref mut hook) = self.hook {
+                    hook.snapshot(&self.context);
+                }
+                self.schedule_snapshot(eloop);
+            }
+            Timer::Watchdog => {
+                if let Some(ms) = self.watchdog {
+                    self.check_stalled(eloop, ms);
+                }
+                self.schedule_watchdog(eloop);
             }
         }
     }
 }
 
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::usize;
+    use std::time::{Duration, Instant};
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    use std::os::unix::net::UnixStream;
+    use mio::{self, EventLoop, EventSet, Io, PollOpt, Token};
+    use {Scope, BaseMachine, Response};
+    use hook::LoopHook;
+    use super::{Handler, EventMachine, MachineId, Notify, Timer, Abort};
+    use super::WAKER_TOKEN;
+
+    /// The callbacks of the machines, in order
+    #[derive(Default)]
+    struct Log {
+        calls: Vec<(Token, &'static str)>,
+        ids: Vec<MachineId>,
+        alive: Vec<bool>,
+        hooks: Vec<&'static str>,
+        /// `Scope::now` in the timeouts
+        times: Vec<Instant>,
+    }
+
+    /// Records the calls of the hook in the context
+    struct Hook;
+
+    impl LoopHook<Log> for Hook {
+        fn after_dispatch(&mut self, ctx: &mut Log) {
+            ctx.hooks.push("after_dispatch");
+        }
+        fn before_poll(&mut self, ctx: &mut Log) {
+            ctx.hooks.push("before_poll");
+        }
+        fn idle(&mut self, ctx: &mut Log) {
+            ctx.hooks.push("idle");
+        }
+        fn drained(&mut self, ctx: &mut Log) {
+            ctx.hooks.push("drained");
+        }
+    }
+
+    /// A machine which records its callbacks, the variant is the scenario
+    enum Probe {
+        Plain,
+        /// Sets slot data, adds a child and another machine, then fails
+        /// to register
+        FailWithChild,
+        /// Shuts itself down in `register`
+        Quitter,
+        /// Migrates itself away in `register`
+        Migrant,
+        /// Logs whether the slot data is set
+        CheckData,
+        /// Keeps its id on wakeup and checks the ids kept before
+        Ids,
+        /// Calls `replace_self` and returns `Response::Replace` on wakeup
+        Swap,
+        /// Registers the socket, and passes it to the next machine of the
+        /// same kind on wakeup
+        Owner(Io),
+        /// Adds two machines on wakeup
+        Spawn,
+        /// Requests a tick on wakeup and on the first tick
+        Ticker(u32),
+        /// Finishes its work on wakeup after `drain`
+        Drainer(bool),
+    }
+
+    impl BaseMachine for Probe {
+        type Timeout = ();
+    }
+
+    impl EventMachine<Log> for Probe {
+        fn ready<S>(self, _events: EventSet, ctx: &mut Log, scope: &mut S)
+            -> Response<Self>
+            where S: Scope<Self>
+        {
+            ctx.calls.push((scope.token(), "ready"));
+            Response::Continue(self)
+        }
+        fn register<S>(&mut self, scope: &mut S) -> io::Result<()>
+            where S: Scope<Self>
+        {
+            match *self {
+                Probe::Plain | Probe::Ids | Probe::Swap | Probe::Spawn
+                | Probe::Ticker(_) | Probe::Drainer(_) => Ok(()),
+                Probe::Owner(ref io) => {
+                    scope.register(io, EventSet::readable(), PollOpt::level())
+                }
+                Probe::FailWithChild => {
+                    *scope.slot_data::<u32>() = 7;
+                    assert!(scope.add_child(Probe::Plain).is_ok());
+                    assert!(scope.async_add_machine(Probe::Plain).is_ok());
+                    Err(io::Error::new(io::ErrorKind::Other, "failed"))
+                }
+                Probe::Quitter => {
+                    scope.shutdown_self();
+                    Ok(())
+                }
+                Probe::Migrant => {
+                    let token = scope.token();
+                    assert!(scope.migrate(token, |_: Probe| Ok(())));
+                    Ok(())
+                }
+                Probe::CheckData => {
+                    assert_eq!(*scope.slot_data::<u32>(), 0);
+                    Ok(())
+                }
+            }
+        }
+        fn shutdown<S>(self, ctx: &mut Log, scope: &mut S) -> Response<Self>
+            where S: Scope<Self>
+        {
+            ctx.calls.push((scope.token(), "shutdown"));
+            Response::Remove
+        }
+        fn wakeup<S>(self, ctx: &mut Log, scope: &mut S) -> Response<Self>
+            where S: Scope<Self>
+        {
+            ctx.calls.push((scope.token(), "wakeup"));
+            match self {
+                Probe::Ids => {
+                    ctx.ids.push(scope.id());
+                    ctx.alive = ctx.ids.iter()
+                        .map(|&id| scope.is_alive(id)).collect();
+                }
+                Probe::Swap => {
+                    scope.replace_self(Probe::Plain);
+                    return Response::Replace(Probe::Ids);
+                }
+                Probe::Owner(io) => return Response::Replace(Probe::Owner(io)),
+                Probe::Ticker(_) => scope.request_tick(),
+                Probe::Drainer(true) => return Response::Remove,
+                Probe::Spawn => {
+                    for _ in 0..2 {
+                        let added = scope.async_add_machine(Probe::Plain)
+                            .is_ok();
+                        ctx.calls.push((scope.token(),
+                            if added { "added" } else { "full" }));
+                    }
+                }
+                _ => {}
+            }
+            Response::Continue(self)
+        }
+        fn tick<S>(self, ctx: &mut Log, scope: &mut S) -> Response<Self>
+            where S: Scope<Self>
+        {
+            ctx.calls.push((scope.token(), "tick"));
+            match self {
+                Probe::Ticker(0) => {
+                    scope.request_tick();
+                    Response::Continue(Probe::Ticker(1))
+                }
+                _ => Response::Continue(self),
+            }
+        }
+        fn drain<S>(self, ctx: &mut Log, scope: &mut S) -> Response<Self>
+            where S: Scope<Self>
+        {
+            ctx.calls.push((scope.token(), "drain"));
+            match self {
+                Probe::Drainer(_) => Response::Continue(Probe::Drainer(true)),
+                _ => Response::Continue(self),
+            }
+        }
+        fn is_draining(&self) -> bool {
+            match *self {
+                Probe::Drainer(draining) => draining,
+                _ => false,
+            }
+        }
+        fn abort<S>(self, _reason: Abort, ctx: &mut Log, scope: &mut S)
+            where S: Scope<Self>
+        {
+            ctx.calls.push((scope.token(), "abort"));
+        }
+        /// Every timeout removes the machine
+        fn timeout<S>(self, _timeout: (), ctx: &mut Log, scope: &mut S)
+            -> Response<Self>
+            where S: Scope<Self>
+        {
+            ctx.calls.push((scope.token(), "timeout"));
+            ctx.times.push(scope.now());
+            Response::Remove
+        }
+    }
+
+    fn handler() -> (Handler<Log, Probe>, EventLoop<Handler<Log, Probe>>) {
+        let mut eloop = EventLoop::new().unwrap();
+        let handler = Handler::new(Log::default(), &mut eloop);
+        (handler, eloop)
+    }
+
+    /// Wakes up the machine which is in the slot now
+    fn wakeup(handler: &mut Handler<Log, Probe>,
+        eloop: &mut EventLoop<Handler<Log, Probe>>, token: Token)
+    {
+        let id = handler.machine_id(token);
+        mio::Handler::notify(handler, eloop, Notify::Wakeup(id));
+    }
+
+    /// Delivers the timeout of the machine as if it was set by the machine
+    fn fire(handler: &mut Handler<Log, Probe>,
+        eloop: &mut EventLoop<Handler<Log, Probe>>, id: MachineId)
+    {
+        mio::Handler::timeout(handler, eloop,
+            Timer::Machine(id, (), Instant::now()));
+    }
+
+    #[test]
+    fn failed_registration_releases_slot() {
+        let (mut handler, mut eloop) = handler();
+        assert!(handler.add_machine(&mut eloop, Probe::FailWithChild)
+            .is_err());
+        // Machines added by the failed one are aborted
+        assert_eq!(handler.occupancy().0, 0);
+        assert_eq!(handler.context.calls,
+            vec![(Token(usize::MAX), "abort"), (Token(1), "abort")]);
+        // The slot is clean for the next machine
+        let tok = handler.add_machine(&mut eloop, Probe::CheckData).unwrap();
+        assert_eq!(tok, Token(0));
+        assert_eq!(handler.occupancy().0, 1);
+    }
+    #[test]
+    fn scope_requests_of_register() {
+        let (mut handler, mut eloop) = handler();
+        let tok = handler.add_machine(&mut eloop, Probe::Quitter).unwrap();
+        assert_eq!(handler.context.calls, vec![(tok, "shutdown")]);
+        assert_eq!(handler.occupancy().0, 0);
+        handler.add_machine(&mut eloop, Probe::Migrant).unwrap();
+        assert_eq!(handler.occupancy().0, 0);
+        let tok = handler.add_machine(&mut eloop, Probe::Plain).unwrap();
+        assert_eq!(handler.occupancy().0, 1);
+        wakeup(&mut handler, &mut eloop, tok);
+        assert_eq!(handler.context.calls,
+            vec![(tok, "shutdown"), (tok, "wakeup")]);
+    }
+    #[test]
+    fn ids_of_reused_slots() {
+        let (mut handler, mut eloop) = handler();
+        for _ in 0..2 {
+            let tok = handler.add_machine(&mut eloop, Probe::Ids).unwrap();
+            assert_eq!(tok, Token(0));
+            wakeup(&mut handler, &mut eloop, tok);
+            let id = *handler.context.ids.last().unwrap();
+            fire(&mut handler, &mut eloop, id);
+        }
+        let ids = &handler.context.ids;
+        assert_eq!(ids[0].token(), ids[1].token());
+        assert!(ids[0] != ids[1]);
+        assert_eq!(handler.context.alive, vec![false, true]);
+    }
+    #[test]
+    fn stale_wakeups() {
+        let (mut handler, mut eloop) = handler();
+        let tok = handler.add_machine(&mut eloop, Probe::Plain).unwrap();
+        let stale = handler.machine_id(tok);
+        fire(&mut handler, &mut eloop, stale);
+        // The wakeup is sent to the removed machine, and is delivered when
+        // its slot is reused
+        let tok = handler.add_machine(&mut eloop, Probe::Plain).unwrap();
+        assert_eq!(tok, stale.token());
+        mio::Handler::notify(&mut handler, &mut eloop, Notify::Wakeup(stale));
+        wakeup(&mut handler, &mut eloop, tok);
+        assert_eq!(handler.context.calls,
+            vec![(tok, "timeout"), (tok, "wakeup")]);
+    }
+    #[test]
+    fn stale_waker_wakeups() {
+        let (mut handler, mut eloop) = handler();
+        let tok = handler.add_machine(&mut eloop, Probe::Plain).unwrap();
+        let stale = handler.machine_id(tok);
+        fire(&mut handler, &mut eloop, stale);
+        let tok = handler.add_machine(&mut eloop, Probe::Plain).unwrap();
+        let waker = handler.state.waker.as_ref().unwrap().0.clone();
+        waker.wakeup(stale).unwrap();
+        waker.wakeup(handler.machine_id(tok)).unwrap();
+        mio::Handler::ready(&mut handler, &mut eloop,
+            WAKER_TOKEN, EventSet::readable());
+        assert_eq!(handler.context.calls,
+            vec![(tok, "timeout"), (tok, "wakeup")]);
+    }
+    #[test]
+    fn stale_timers() {
+        let (mut handler, mut eloop) = handler();
+        let tok = handler.add_machine(&mut eloop, Probe::Ids).unwrap();
+        wakeup(&mut handler, &mut eloop, tok);
+        let id = handler.context.ids[0];
+        fire(&mut handler, &mut eloop, id);
+        assert_eq!(handler.occupancy().0, 0);
+        // The timer of the removed machine is dropped
+        let tok = handler.add_machine(&mut eloop, Probe::Ids).unwrap();
+        assert_eq!(tok, id.token());
+        fire(&mut handler, &mut eloop, id);
+        assert_eq!(handler.occupancy().0, 1);
+        assert_eq!(handler.context.calls,
+            vec![(tok, "wakeup"), (tok, "timeout")]);
+    }
+    #[test]
+    fn deferred_events_of_removed_machines() {
+        let (mut handler, mut eloop) = handler();
+        handler.enable_priorities();
+        let tok = handler.add_machine(&mut eloop, Probe::Ids).unwrap();
+        wakeup(&mut handler, &mut eloop, tok);
+        let id = handler.context.ids[0];
+        mio::Handler::ready(&mut handler, &mut eloop, tok,
+            EventSet::readable());
+        // Removed and replaced before the deferred event is dispatched
+        fire(&mut handler, &mut eloop, id);
+        handler.add_machine(&mut eloop, Probe::Plain).unwrap();
+        mio::Handler::tick(&mut handler, &mut eloop);
+        mio::Handler::ready(&mut handler, &mut eloop, tok,
+            EventSet::readable());
+        mio::Handler::tick(&mut handler, &mut eloop);
+        assert_eq!(handler.context.calls,
+            vec![(tok, "wakeup"), (tok, "timeout"), (tok, "ready")]);
+    }
+    #[test]
+    fn shutdown_of_occupied_slots() {
+        let (mut handler, mut eloop) = handler();
+        for _ in 0..3 {
+            handler.add_machine(&mut eloop, Probe::Ids).unwrap();
+        }
+        wakeup(&mut handler, &mut eloop, Token(1));
+        let id = handler.context.ids[0];
+        fire(&mut handler, &mut eloop, id);
+        handler.shutdown(&mut eloop);
+        assert_eq!(handler.context.calls[2..].to_vec(),
+            vec![(Token(0), "shutdown"), (Token(2), "shutdown")]);
+        assert!(handler.state.draining.is_empty());
+        assert_eq!(handler.occupancy().0, 0);
+    }
+    #[test]
+    fn response_replace_wins() {
+        let (mut handler, mut eloop) = handler();
+        let tok = handler.add_machine(&mut eloop, Probe::Swap).unwrap();
+        for _ in 0..2 {
+            wakeup(&mut handler, &mut eloop, tok);
+        }
+        // The second wakeup is received by the `Ids` machine
+        assert_eq!(handler.context.ids.len(), 1);
+        assert_eq!(handler.context.alive, vec![true]);
+    }
+    #[test]
+    fn replace_with_same_socket() {
+        let (mut handler, mut eloop) = handler();
+        let (sock, _peer) = UnixStream::pair().unwrap();
+        let fd = sock.into_raw_fd();
+        let io = unsafe { <Io as FromRawFd>::from_raw_fd(fd) };
+        let tok = handler.add_machine(&mut eloop, Probe::Owner(io)).unwrap();
+        for _ in 0..2 {
+            wakeup(&mut handler, &mut eloop, tok);
+        }
+        assert_eq!(handler.context.calls,
+            vec![(tok, "wakeup"), (tok, "wakeup")]);
+        assert_eq!(handler.occupancy().0, 1);
+    }
+    #[test]
+    fn notify_budget_skips_control_messages() {
+        let (mut handler, mut eloop) = handler();
+        handler.set_notify_budget(1);
+        let tok = handler.add_machine(&mut eloop, Probe::Plain).unwrap();
+        for _ in 0..3 {
+            wakeup(&mut handler, &mut eloop, tok);
+        }
+        // Deferred wakeups are delivered one per iteration, starting from
+        // the next one
+        for _ in 0..2 {
+            mio::Handler::tick(&mut handler, &mut eloop);
+        }
+        mio::Handler::notify(&mut handler, &mut eloop, Notify::Shutdown);
+        assert_eq!(handler.context.calls,
+            vec![(tok, "wakeup"), (tok, "wakeup"), (tok, "shutdown")]);
+        // The one left is dropped, as its machine is removed
+        let tok = handler.add_machine(&mut eloop, Probe::Plain).unwrap();
+        for _ in 0..2 {
+            mio::Handler::tick(&mut handler, &mut eloop);
+        }
+        assert_eq!(handler.context.calls.len(), 3);
+        assert_eq!(handler.machine_id(tok).token(), tok);
+    }
+    #[test]
+    fn async_add_to_full_slab() {
+        let (mut handler, mut eloop) = handler();
+        let (_, slots) = handler.occupancy();
+        for _ in 0..slots - 2 {
+            handler.add_machine(&mut eloop, Probe::Plain).unwrap();
+        }
+        let tok = handler.add_machine(&mut eloop, Probe::Spawn).unwrap();
+        wakeup(&mut handler, &mut eloop, tok);
+        assert_eq!(handler.context.calls,
+            vec![(tok, "wakeup"), (tok, "added"), (tok, "full")]);
+        assert_eq!(handler.occupancy(), (slots, slots));
+    }
+    #[test]
+    fn ticks_after_notifications() {
+        let (mut handler, mut eloop) = handler();
+        let tok = handler.add_machine(&mut eloop, Probe::Ticker(0)).unwrap();
+        let other = handler.add_machine(&mut eloop, Probe::Plain).unwrap();
+        wakeup(&mut handler, &mut eloop, tok);
+        wakeup(&mut handler, &mut eloop, other);
+        mio::Handler::tick(&mut handler, &mut eloop);
+        assert_eq!(handler.context.calls,
+            vec![(tok, "wakeup"), (other, "wakeup"), (tok, "tick")]);
+        // The tick requested by the tick is run on the next iteration
+        mio::Handler::tick(&mut handler, &mut eloop);
+        mio::Handler::tick(&mut handler, &mut eloop);
+        assert_eq!(handler.context.calls[3..].to_vec(), vec![(tok, "tick")]);
+    }
+    #[test]
+    fn loop_hook() {
+        let (mut handler, mut eloop) = handler();
+        handler.set_loop_hook(Box::new(Hook));
+        handler.set_idle_timeout(&mut eloop, 1000);
+        let tok = handler.add_machine(&mut eloop, Probe::Ticker(1)).unwrap();
+        wakeup(&mut handler, &mut eloop, tok);
+        mio::Handler::tick(&mut handler, &mut eloop);
+        assert_eq!(handler.context.calls,
+            vec![(tok, "wakeup"), (tok, "tick")]);
+        assert_eq!(handler.context.hooks,
+            vec!["after_dispatch", "before_poll"]);
+        // The loop is idle if no machine is called between the timers
+        mio::Handler::timeout(&mut handler, &mut eloop, Timer::Idle);
+        mio::Handler::timeout(&mut handler, &mut eloop, Timer::Idle);
+        assert_eq!(handler.context.hooks[2..].to_vec(), vec!["idle"]);
+    }
+    #[test]
+    fn drain() {
+        let (mut handler, mut eloop) = handler();
+        handler.set_loop_hook(Box::new(Hook));
+        let plain = handler.add_machine(&mut eloop, Probe::Plain).unwrap();
+        let tok = handler.add_machine(&mut eloop, Probe::Drainer(false))
+            .unwrap();
+        handler.drain(&mut eloop);
+        assert_eq!(handler.context.calls,
+            vec![(plain, "drain"), (tok, "drain")]);
+        assert!(handler.context.hooks.is_empty());
+        // Drained when the last draining machine is removed
+        wakeup(&mut handler, &mut eloop, tok);
+        assert_eq!(handler.context.hooks, vec!["drained"]);
+        assert_eq!(handler.occupancy().0, 1);
+    }
+    #[test]
+    fn mocked_clock() {
+        let (mut handler, mut eloop) = handler();
+        let tok = handler.add_machine(&mut eloop, Probe::Plain).unwrap();
+        // An hour passes instantly
+        let later = Instant::now() + Duration::new(3600, 0);
+        handler.set_clock(later);
+        let id = handler.machine_id(tok);
+        mio::Handler::timeout(&mut handler, &mut eloop,
+            Timer::Machine(id, (), later));
+        assert_eq!(handler.context.calls, vec![(tok, "timeout")]);
+        assert_eq!(handler.context.times, vec![later]);
+    }
+}
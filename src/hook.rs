@@ -0,0 +1,22 @@
+//! Hooks into the iterations of the event loop
+//!
+//! Set a hook with `Handler::set_loop_hook` to do periodic maintenance of
+//! the context (expiring registry entries, flushing logs) without adding a
+//! dedicated machine. All methods have empty default implementations.
+pub trait LoopHook<C> {
+    /// All the I/O events, notifications and timeouts of the iteration are
+    /// dispatched
+    fn after_dispatch(&mut self, _context: &mut C) {}
+    /// The loop is going to wait for events, called after the ticks
+    /// requested by `Scope::request_tick` are run
+    fn before_poll(&mut self, _context: &mut C) {}
+    /// No machine was called for the idle timeout, see
+    /// `Handler::set_idle_timeout`
+    fn idle(&mut self, _context: &mut C) {}
+    /// Called periodically to export the state of the context (push
+    /// metrics, dump the state to disk), see
+    /// `Handler::set_snapshot_interval`
+    fn snapshot(&mut self, _context: &C) {}
+    /// All the machines have finished their work after `Handler::drain`
+    fn drained(&mut self, _context: &mut C) {}
+}
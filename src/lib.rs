@@ -4,6 +4,11 @@ extern crate netbuf;
 extern crate mio;
 #[macro_use] extern crate log;
 extern crate memchr;
+extern crate libc;
+#[cfg(feature="codec")] extern crate serde;
+#[cfg(feature="codec-json")] extern crate serde_json;
+#[cfg(feature="codec-bincode")] extern crate bincode;
+#[cfg(feature="codec-msgpack")] extern crate rmp_serde;
 
 pub mod transports;
 pub mod handler;
@@ -12,7 +17,29 @@ pub mod base;
 pub mod scope;
 pub mod compose;
 pub mod timeouts;
+pub mod listen;
+pub mod bind;
+pub mod context;
+pub mod machines;
+pub mod tracer;
+pub mod hook;
+pub mod waker;
+pub mod protocols;
+pub mod pool;
+pub mod request;
+pub mod limits;
+pub mod registry;
+pub mod stats;
+pub mod error;
+pub mod response;
+mod debug_tokens;
+mod fd_flags;
+#[cfg(feature="codec")] pub mod codec;
+#[cfg(feature="ffi")] pub mod ffi;
+#[cfg(any(test, feature="test-support"))] pub mod test_support;
 
 pub use base::Machine as BaseMachine;
 pub use handler::{EventMachine, Handler};
 pub use scope::{Scope};
+pub use response::Response;
+pub use error::Error;
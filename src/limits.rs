@@ -0,0 +1,439 @@
+//! Limiting outgoing connections and rates
+//!
+//! The rate limiters `TokenBucket` and `SlidingWindow` are plain values for
+//! a single loop, keep them in the context and pass `Scope::now` to them.
+//! The `KeyedBuckets` limits the rate per key, e.g. accepted connections
+//! per peer address in the `ServeWith` closure.
+//!
+//! Keep `Limits` in the context to limit connections per destination. The
+//! client machine asks for a slot before connecting, and reports the
+//! outcome. When there is no free slot, the machine is queued and woken up
+//! when the slot is reserved for it:
+//!
+//! ```ignore
+//! // before connecting, and again in wakeup
+//! if !ctx.limits.try_connect(scope, &addr) {
+//!     return Response::Continue(self);  // wait for wakeup
+//! }
+//! let sock = try!(TcpStream::connect(&addr));
+//! // when connected
+//! ctx.limits.connected(scope, &addr);
+//! // when the connection attempt failed, or the connection is closed
+//! ctx.limits.connect_failed(scope, &addr);
+//! ctx.limits.closed(scope, &addr);
+//! ```
+//!
+//! Only one waiting machine is woken up for every free slot, so a failing
+//! upstream doesn't get a storm of reconnects. Machines which stop waiting
+//! must call `cancel`, otherwise the slot reserved for them is lost.
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+use mio::Token;
+
+use {Scope, BaseMachine};
+
+
+#[derive(Default)]
+struct Destination {
+    /// Connection attempts, including the slots reserved for the machines
+    /// which are woken up
+    connecting: usize,
+    connected: usize,
+    waiting: VecDeque<Token>,
+    /// Machines which are woken up to use the reserved slot
+    granted: Vec<Token>,
+}
+
+/// Connection limits for the destinations of type `K`, e.g. `SocketAddr`
+pub struct Limits<K: Hash + Eq> {
+    max_connecting: usize,
+    max_connections: usize,
+    destinations: HashMap<K, Destination>,
+}
+
+impl Destination {
+    fn has_room(&self, max_connecting: usize, max_connections: usize)
+        -> bool
+    {
+        self.connecting < max_connecting &&
+            self.connecting + self.connected < max_connections
+    }
+    fn is_unused(&self) -> bool {
+        self.connecting == 0 && self.connected == 0 &&
+            self.waiting.is_empty()
+    }
+}
+
+impl<K: Hash + Eq + Clone> Limits<K> {
+    /// Allows `max_connecting` simultaneous connection attempts and
+    /// `max_connections` connections in total (including attempts) to
+    /// every destination
+    pub fn new(max_connecting: usize, max_connections: usize) -> Limits<K> {
+        Limits {
+            max_connecting: max_connecting,
+            max_connections: max_connections,
+            destinations: HashMap::new(),
+        }
+    }
+    /// Takes a slot for the connection attempt of the machine owning the
+    /// `scope`
+    ///
+    /// Returns false if there is no free slot, the machine is queued and
+    /// woken up when the slot is reserved for it, then it must call this
+    /// method again
+    pub fn try_connect<M, S>(&mut self, scope: &mut S, dest: &K) -> bool
+        where M: BaseMachine, S: Scope<M>
+    {
+        let token = scope.token();
+        let (max_connecting, max_total) =
+            (self.max_connecting, self.max_connections);
+        let d = self.destinations.entry(dest.clone())
+            .or_insert_with(Destination::default);
+        if let Some(pos) = d.granted.iter().position(|&t| t == token) {
+            d.granted.swap_remove(pos);
+            return true;
+        }
+        if d.waiting.is_empty() && d.has_room(max_connecting, max_total) {
+            d.connecting += 1;
+            return true;
+        }
+        if !d.waiting.contains(&token) {
+            d.waiting.push_back(token);
+        }
+        false
+    }
+    /// The connection attempt has succeeded
+    pub fn connected<M, S>(&mut self, scope: &mut S, dest: &K)
+        where M: BaseMachine, S: Scope<M>
+    {
+        if let Some(d) = self.destinations.get_mut(dest) {
+            d.connecting = d.connecting.saturating_sub(1);
+            d.connected += 1;
+        }
+        self.grant(scope, dest);
+    }
+    /// The connection attempt has failed
+    pub fn connect_failed<M, S>(&mut self, scope: &mut S, dest: &K)
+        where M: BaseMachine, S: Scope<M>
+    {
+        if let Some(d) = self.destinations.get_mut(dest) {
+            d.connecting = d.connecting.saturating_sub(1);
+        }
+        self.grant(scope, dest);
+    }
+    /// The established connection is closed
+    pub fn closed<M, S>(&mut self, scope: &mut S, dest: &K)
+        where M: BaseMachine, S: Scope<M>
+    {
+        if let Some(d) = self.destinations.get_mut(dest) {
+            d.connected = d.connected.saturating_sub(1);
+        }
+        self.grant(scope, dest);
+    }
+    /// The machine owning the `scope` doesn't wait for the slot anymore
+    ///
+    /// The slot reserved for it is passed to the next machine
+    pub fn cancel<M, S>(&mut self, scope: &mut S, dest: &K)
+        where M: BaseMachine, S: Scope<M>
+    {
+        let token = scope.token();
+        if let Some(d) = self.destinations.get_mut(dest) {
+            d.waiting.retain(|&t| t != token);
+            if let Some(pos) = d.granted.iter().position(|&t| t == token) {
+                d.granted.swap_remove(pos);
+                d.connecting -= 1;
+            }
+        }
+        self.grant(scope, dest);
+    }
+    /// Returns the number of connection attempts, connections and waiting
+    /// machines for the destination
+    pub fn usage(&self, dest: &K) -> (usize, usize, usize) {
+        self.destinations.get(dest)
+            .map(|d| (d.connecting, d.connected, d.waiting.len()))
+            .unwrap_or((0, 0, 0))
+    }
+    /// Reserves free slots for the waiting machines and wakes them up
+    fn grant<M, S>(&mut self, scope: &mut S, dest: &K)
+        where M: BaseMachine, S: Scope<M>
+    {
+        let unused = match self.destinations.get_mut(dest) {
+            Some(d) => {
+                while d.has_room(self.max_connecting, self.max_connections) {
+                    let token = match d.waiting.pop_front() {
+                        Some(token) => token,
+                        None => break,
+                    };
+                    match scope.wakeup(token) {
+                        Ok(()) => {
+                            d.connecting += 1;
+                            d.granted.push(token);
+                        }
+                        Err(e) => {
+                            debug!("Can't wake up {:?} waiting for \
+                                connection: {}", token, e);
+                        }
+                    }
+                }
+                d.is_unused()
+            }
+            None => false,
+        };
+        if unused {
+            self.destinations.remove(dest);
+        }
+    }
+}
+
+/// The token bucket: tokens are added at the constant rate, up to the burst
+pub struct TokenBucket {
+    /// Tokens per millisecond
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Counts events in the sliding window of time
+///
+/// The count of the previous fixed window is weighted by its overlap with
+/// the sliding window, so only two counters are stored
+pub struct SlidingWindow {
+    window: u64,
+    limit: u64,
+    /// The start of the current fixed window
+    start: Instant,
+    current: u64,
+    previous: u64,
+}
+
+/// Token buckets for every key, e.g. for the address of the peer
+pub struct KeyedBuckets<K: Hash + Eq> {
+    per_second: u32,
+    burst: u32,
+    buckets: HashMap<K, TokenBucket>,
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000. +
+        duration.subsec_nanos() as f64 / 1000000.
+}
+
+impl TokenBucket {
+    /// The bucket adding `per_second` tokens every second and holding at
+    /// most `burst` tokens (at least one), it's full when created
+    pub fn new(per_second: u32, burst: u32) -> TokenBucket {
+        let burst = if burst == 0 { 1 } else { burst } as f64;
+        TokenBucket {
+            rate: per_second as f64 / 1000.,
+            burst: burst,
+            tokens: burst,
+            updated: Instant::now(),
+        }
+    }
+    /// The `now` may be a bit older than the time the bucket is created at,
+    /// when it's the cached time of the loop
+    fn refill(&mut self, now: Instant) {
+        if now > self.updated {
+            let ms = millis(now.duration_since(self.updated));
+            self.tokens = (self.tokens + ms * self.rate).min(self.burst);
+            self.updated = now;
+        }
+    }
+    /// Returns milliseconds until the next token is available, if none is
+    pub fn delay(&mut self, now: Instant) -> Option<u64> {
+        self.refill(now);
+        if self.tokens >= 1. {
+            None
+        } else if self.rate <= 0. {
+            // Retry once a second when the rate is zero
+            Some(1000)
+        } else {
+            Some(((1. - self.tokens) / self.rate).ceil() as u64)
+        }
+    }
+    /// The rate of adding tokens per second
+    pub fn per_second(&self) -> f64 {
+        self.rate * 1000.
+    }
+    /// Takes a token, even if there is none
+    ///
+    /// Use it after `delay` returned `None`
+    pub fn take(&mut self) {
+        self.tokens -= 1.;
+    }
+    /// Takes `n` tokens if there are enough of them
+    pub fn try_take(&mut self, now: Instant, n: u32) -> bool {
+        self.refill(now);
+        if self.tokens >= n as f64 {
+            self.tokens -= n as f64;
+            true
+        } else {
+            false
+        }
+    }
+    /// Returns the number of whole tokens available
+    ///
+    /// With the bucket of bytes, it's the `Settings::read_budget` for a
+    /// new connection sharing the bandwidth
+    pub fn available(&mut self, now: Instant) -> u64 {
+        self.refill(now);
+        if self.tokens > 0. { self.tokens as u64 } else { 0 }
+    }
+    /// Returns true if the bucket is full at `now`
+    pub fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.burst
+    }
+}
+
+impl SlidingWindow {
+    /// Allows `limit` events per `window_ms` milliseconds
+    pub fn new(window_ms: u64, limit: u64) -> SlidingWindow {
+        SlidingWindow {
+            window: if window_ms == 0 { 1 } else { window_ms },
+            limit: limit,
+            start: Instant::now(),
+            current: 0,
+            previous: 0,
+        }
+    }
+    /// Returns milliseconds since the start of the current fixed window
+    fn roll(&mut self, now: Instant) -> u64 {
+        if now <= self.start {
+            return 0;
+        }
+        let elapsed = millis(now.duration_since(self.start)) as u64;
+        if elapsed >= 2 * self.window {
+            self.previous = 0;
+            self.current = 0;
+            self.start = now;
+            0
+        } else if elapsed >= self.window {
+            self.previous = self.current;
+            self.current = 0;
+            self.start += Duration::from_millis(self.window);
+            elapsed - self.window
+        } else {
+            elapsed
+        }
+    }
+    /// Returns the estimated number of events in the window ending `now`
+    pub fn count(&mut self, now: Instant) -> u64 {
+        let elapsed = self.roll(now);
+        self.current +
+            self.previous * (self.window - elapsed) / self.window
+    }
+    /// Records the event if it's within the limit
+    pub fn try_add(&mut self, now: Instant) -> bool {
+        if self.count(now) < self.limit {
+            self.current += 1;
+            true
+        } else {
+            false
+        }
+    }
+    /// Records the event even if the limit is reached
+    pub fn add(&mut self, now: Instant) {
+        self.roll(now);
+        self.current += 1;
+    }
+}
+
+impl<K: Hash + Eq> KeyedBuckets<K> {
+    /// Every key gets its own `TokenBucket::new(per_second, burst)`
+    pub fn new(per_second: u32, burst: u32) -> KeyedBuckets<K> {
+        KeyedBuckets {
+            per_second: per_second,
+            burst: burst,
+            buckets: HashMap::new(),
+        }
+    }
+    /// Takes a token from the bucket of the `key`
+    pub fn try_take(&mut self, key: K, now: Instant) -> bool {
+        let (per_second, burst) = (self.per_second, self.burst);
+        self.buckets.entry(key)
+            .or_insert_with(|| TokenBucket::new(per_second, burst))
+            .try_take(now, 1)
+    }
+    /// Forgets the keys whose buckets are full, call it periodically
+    pub fn expire(&mut self, now: Instant) {
+        self.buckets.retain(|_, bucket| !bucket.is_full(now));
+    }
+    /// Number of keys remembered
+    pub fn len(&self) -> usize {
+        self.buckets.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+    use super::{TokenBucket, SlidingWindow, KeyedBuckets};
+
+    #[test]
+    fn bucket() {
+        let start = Instant::now();
+        let mut b = TokenBucket::new(10, 2);
+        b.updated = start;
+        assert_eq!(b.delay(start), None);
+        b.take();
+        assert_eq!(b.delay(start), None);
+        b.take();
+        assert_eq!(b.delay(start), Some(100));
+        assert_eq!(b.delay(start + Duration::from_millis(60)), Some(40));
+        assert_eq!(b.delay(start + Duration::from_millis(100)), None);
+        // Tokens don't accumulate over the burst
+        assert_eq!(b.delay(start + Duration::from_secs(10)), None);
+        b.take();
+        b.take();
+        assert!(b.delay(start + Duration::from_secs(10)).is_some());
+    }
+
+    #[test]
+    fn bucket_budget() {
+        let start = Instant::now();
+        let mut b = TokenBucket::new(1000, 4096);
+        b.updated = start;
+        assert!(b.try_take(start, 4000));
+        assert!(!b.try_take(start, 100));
+        assert_eq!(b.available(start), 96);
+        assert_eq!(b.available(start + Duration::from_millis(4)), 100);
+        assert!(!b.is_full(start + Duration::from_millis(4)));
+        assert!(b.is_full(start + Duration::from_secs(4)));
+    }
+
+    #[test]
+    fn sliding_window() {
+        let start = Instant::now();
+        let mut w = SlidingWindow::new(1000, 10);
+        w.start = start;
+        for _ in 0..10 {
+            assert!(w.try_add(start));
+        }
+        assert!(!w.try_add(start + Duration::from_millis(999)));
+        // Half of the previous window is counted
+        assert_eq!(w.count(start + Duration::from_millis(1500)), 5);
+        assert!(w.try_add(start + Duration::from_millis(1500)));
+        assert_eq!(w.count(start + Duration::from_millis(1500)), 6);
+        assert_eq!(w.count(start + Duration::from_millis(3000)), 0);
+    }
+
+    #[test]
+    fn keyed() {
+        let start = Instant::now();
+        let mut k = KeyedBuckets::new(1, 2);
+        assert!(k.try_take("a", start));
+        assert!(k.try_take("a", start));
+        assert!(!k.try_take("a", start));
+        assert!(k.try_take("b", start));
+        assert_eq!(k.len(), 2);
+        k.expire(start + Duration::from_secs(10));
+        assert!(k.is_empty());
+    }
+}
@@ -0,0 +1,107 @@
+//! Helpers for obtaining listening sockets
+//!
+//! Sockets may be inherited from the parent process: either from systemd
+//! (socket activation) or from the previous instance of the server doing
+//! zero-downtime restart. Create `Serve` from the descriptors using
+//! `FromRawFd`.
+//!
+//! To scale accepting connections use `reuseport_serve`, which creates
+//! several listeners bound to the same address. See the `bind` module for
+//! other options of listening sockets.
+use std::io;
+use std::env;
+use std::net::SocketAddr;
+use std::process::Command;
+use std::os::unix::io::RawFd;
+
+use libc;
+use mio::tcp::{TcpListener, TcpStream};
+
+use EventMachine;
+use bind::Bind;
+use fd_flags::{set_flag, Flag};
+use transports::accept::{Serve, Init};
+
+/// Environment variable which is used to pass sockets by `pass_fds`
+pub const LISTEN_FDS_VAR: &'static str = "ROTOR_LISTEN_FDS";
+
+/// The first file descriptor passed by systemd
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+
+/// Returns listening sockets passed by `pass_fds` or by systemd
+///
+/// Descriptors are switched to non-blocking mode and are marked close-on-exec.
+/// Environment variables are removed so children of this process don't see
+/// them.
+pub fn listen_fds() -> io::Result<Vec<RawFd>> {
+    let fds = if let Some(val) = env::var_os(LISTEN_FDS_VAR) {
+        env::remove_var(LISTEN_FDS_VAR);
+        let val = try!(val.into_string().map_err(|_| invalid(LISTEN_FDS_VAR)));
+        try!(val.split(',').filter(|x| x.len() > 0)
+            .map(|x| x.parse().map_err(|_| invalid(LISTEN_FDS_VAR)))
+            .collect())
+    } else if let Some(num) = env::var_os("LISTEN_FDS") {
+        let pid = env::var_os("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_PID");
+        let mypid = unsafe { libc::getpid() }.to_string();
+        if pid.as_ref().and_then(|x| x.to_str()) != Some(&mypid[..]) {
+            // Descriptors are for another process
+            return Ok(Vec::new());
+        }
+        let num: RawFd = try!(num.to_str().and_then(|x| x.parse().ok())
+            .ok_or_else(|| invalid("LISTEN_FDS")));
+        (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START+num).collect()
+    } else {
+        return Ok(Vec::new());
+    };
+    for &fd in &fds {
+        try!(set_flag(fd, Flag::NonBlocking, true));
+        try!(set_flag(fd, Flag::CloseOnExec, true));
+    }
+    Ok(fds)
+}
+
+/// Makes file descriptors inheritable and lists them in the environment
+///
+/// The `cmd` should be a new instance of this server, which calls
+/// `listen_fds` to pick the sockets up. Note that any other process spawned
+/// after this call inherits the descriptors too.
+pub fn pass_fds(cmd: &mut Command, fds: &[RawFd]) -> io::Result<()> {
+    for &fd in fds {
+        try!(set_flag(fd, Flag::CloseOnExec, false));
+    }
+    let val = fds.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(",");
+    cmd.env(LISTEN_FDS_VAR, val);
+    Ok(())
+}
+
+/// Creates `n` listening sockets bound to `addr` with `SO_REUSEPORT`
+///
+/// The kernel distributes incoming connections between the sockets, so
+/// each of them may be served by a separate loop (or several of them by a
+/// single loop). Requires Linux 3.9 or BSD. Sockets are non-blocking and
+/// close-on-exec.
+pub fn reuseport_listeners(addr: &SocketAddr, n: usize)
+    -> io::Result<Vec<TcpListener>>
+{
+    let bind = Bind::new(*addr).reuse_port(true);
+    (0..n).map(|_| bind.listen()).collect()
+}
+
+/// Creates `n` machines accepting connections on `addr`
+///
+/// See `reuseport_listeners`
+pub fn reuseport_serve<M, C>(addr: &SocketAddr, n: usize)
+    -> io::Result<Vec<Serve<TcpListener, M, C>>>
+    where M: Init<TcpStream, C>, M: EventMachine<C>
+{
+    reuseport_listeners(addr, n)
+        .map(|v| v.into_iter().map(Serve::new).collect())
+}
+
+fn invalid(var: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput,
+        format!("Invalid value of {} environment variable", var))
+}
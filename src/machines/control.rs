@@ -0,0 +1,378 @@
+//! The admin interface of the server on a unix socket
+//!
+//! The control socket accepts text commands, one per line. The output of
+//! every command is finished by the `ok` or `error: <reason>` line:
+//!
+//! * `stats` -- loop statistics, see `Handler::enable_stats`
+//! * `dump` -- the list of machines, see `Handler::dump`
+//! * `pause-accept`, `resume-accept` -- see `Admin::set_accepting`
+//! * `shutdown` -- shuts down the loop
+//! * `help` -- the list of commands
+//!
+//! Other commands are passed to `Admin::command`. The context implements
+//! `Admin`, and the listener is composed with the other machines:
+//!
+//! ```ignore
+//! impl Admin for Context {
+//!     fn set_accepting(&mut self, accepting: bool) -> bool {
+//!         if accepting {
+//!             self.service.resume()
+//!         } else {
+//!             self.service.pause()
+//!         }
+//!     }
+//! }
+//! rotor_compose_state_machines!(Fsm<Context> {
+//!     Http(Serve<TcpListener, HttpConn, Context>),
+//!     Control(control::Listener<Context>),
+//! });
+//! try!(handler.add_machine(&mut eloop,
+//!     Fsm::Control(try!(control::bind("/run/server.sock")))));
+//! ```
+//!
+//! There is no authentication, so restrict the access to the socket with
+//! the permissions of the directory.
+use std::io::{self, Error, ErrorKind, Write};
+use std::marker::PhantomData;
+use std::path::Path;
+use std::str::from_utf8;
+
+use mio::{EventSet, PollOpt};
+use mio::unix::{UnixListener, UnixStream};
+use netbuf::Buf;
+
+use {BaseMachine, EventMachine, Scope, Response};
+use buffer_util::find_delimiter;
+use transports::accept::{Serve, Init};
+
+
+/// Maximum length of the command
+const MAX_LINE: usize = 4096;
+
+const HELP: &'static str = "\
+    stats           loop statistics\n\
+    dump            list of machines\n\
+    pause-accept    stop accepting connections\n\
+    resume-accept   start accepting connections again\n\
+    shutdown        shut down the loop\n";
+
+/// The machine listening on the control socket
+pub type Listener<C> = Serve<UnixListener, Session<C>, C>;
+
+/// The facilities of the application for the control socket, implement it
+/// for the context
+pub trait Admin {
+    /// Pauses (`false`) or resumes (`true`) accepting connections
+    ///
+    /// Usually it calls `bind::Service::pause` or `resume`. Returns false
+    /// on failure. Not supported by default.
+    fn set_accepting(&mut self, _accepting: bool) -> bool {
+        false
+    }
+    /// Runs the command of the application
+    ///
+    /// Write the output, without the final `ok` line, and return true. Return
+    /// false if the command is unknown.
+    fn command(&mut self, _command: &str, _output: &mut Buf) -> bool {
+        false
+    }
+}
+
+/// The connection to the control socket
+pub struct Session<C> {
+    sock: UnixStream,
+    input: Buf,
+    output: Buf,
+    /// The peer has closed the socket, the output is being flushed
+    eof: bool,
+    phantom: PhantomData<fn(&mut C)>,
+}
+
+/// Creates the listener of the control socket at `path`
+///
+/// The path must not exist
+pub fn bind<C, P: AsRef<Path>>(path: P) -> io::Result<Listener<C>>
+    where C: Admin
+{
+    UnixListener::bind(path.as_ref()).map(Serve::new)
+}
+
+impl<C: Admin> Session<C> {
+    fn execute<S>(&mut self, command: &str, ctx: &mut C, scope: &mut S)
+        where S: Scope<Self>
+    {
+        let out = &mut self.output;
+        let result = match command {
+            "stats" => match scope.loop_stats() {
+                Some(stats) => writeln!(out, "{:?}", stats)
+                    .map_err(|e| e.to_string()),
+                None => Err("statistics are not enabled".to_string()),
+            },
+            "dump" => scope.dump_machines(out).map_err(|e| e.to_string()),
+            "pause-accept" | "resume-accept" => {
+                if ctx.set_accepting(command == "resume-accept") {
+                    Ok(())
+                } else {
+                    Err("can't change accepting".to_string())
+                }
+            }
+            "shutdown" => {
+                info!("Shutdown requested on control socket");
+                scope.shutdown_loop();
+                Ok(())
+            }
+            "help" => {
+                out.extend(HELP.as_bytes());
+                Ok(())
+            }
+            _ if ctx.command(command, out) => Ok(()),
+            _ => Err(format!("unknown command {:?}", command)),
+        };
+        match result {
+            Ok(()) => out.extend(b"ok\n"),
+            Err(e) => {
+                writeln!(out, "error: {}", e).ok();
+            }
+        }
+    }
+    /// Reads the socket until it would block or more than `MAX_LINE` bytes
+    /// are buffered
+    ///
+    /// Returns `None` when the buffered lines must be executed before
+    /// reading more, `Some(false)` on eof
+    fn read(&mut self) -> io::Result<Option<bool>> {
+        while self.input.len() <= MAX_LINE {
+            match self.input.read_from(&mut self.sock) {
+                Ok(0) => return Ok(Some(false)),
+                Ok(_) => {}
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    return Ok(Some(true));
+                }
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(None)
+    }
+    /// Executes the complete lines of the input
+    fn execute_lines<S>(&mut self, context: &mut C, scope: &mut S)
+        where S: Scope<Self>
+    {
+        while let Some(end) = find_delimiter(&self.input, b"\n") {
+            let line = from_utf8(&self.input[..end])
+                .map(|x| x.trim().to_string());
+            self.input.consume(end + 1);
+            match line {
+                Ok(ref line) if line.len() == 0 => {}
+                Ok(line) => self.execute(&line, context, scope),
+                Err(_) => self.output.extend(b"error: invalid utf-8\n"),
+            }
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        while self.output.len() > 0 {
+            match self.output.write_to(&mut self.sock) {
+                Ok(0) => return Err(Error::new(ErrorKind::WriteZero,
+                    "control socket closed")),
+                Ok(_) => {}
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<C> BaseMachine for Session<C> {
+    type Timeout = ();
+}
+
+impl<C: Admin> Init<UnixStream, C> for Session<C> {
+    fn accept<S>(sock: UnixStream, _context: &mut C, _scope: &mut S)
+        -> Self
+        where S: Scope<Self>
+    {
+        Session {
+            sock: sock,
+            input: Buf::new(),
+            output: Buf::new(),
+            eof: false,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<C: Admin> EventMachine<C> for Session<C> {
+    fn ready<S>(mut self, events: EventSet, context: &mut C, scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        while events.is_readable() && !self.eof {
+            let done = match self.read() {
+                Ok(done) => done,
+                Err(e) => {
+                    debug!("Error reading control socket: {}", e);
+                    return Response::Remove;
+                }
+            };
+            self.execute_lines(context, scope);
+            if self.input.len() > MAX_LINE {
+                debug!("Command on control socket is too long");
+                return Response::Remove;
+            }
+            if let Some(open) = done {
+                self.eof = !open;
+                break;
+            }
+        }
+        if let Err(e) = self.flush() {
+            debug!("Error writing control socket: {}", e);
+            return Response::Remove;
+        }
+        if events.is_hup() || self.eof && self.output.len() == 0 {
+            return Response::Remove;
+        }
+        Response::Continue(self)
+    }
+    fn register<S>(&mut self, scope: &mut S) -> Result<(), Error>
+        where S: Scope<Self>
+    {
+        scope.register(&self.sock, EventSet::readable() | EventSet::writable(),
+            PollOpt::edge())
+    }
+    fn name(&self) -> &'static str {
+        "control"
+    }
+    fn deregister<S>(&mut self, scope: &mut S) -> Result<(), Error>
+        where S: Scope<Self>
+    {
+        scope.deregister(&self.sock)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::marker::PhantomData;
+    use std::net::Shutdown;
+    use std::os::unix::io::FromRawFd;
+    use std::os::unix::net;
+    use std::time::Duration;
+    use libc;
+    use mio::{self, EventLoop, EventSet, Token};
+    use mio::unix::UnixStream;
+    use netbuf::Buf;
+    use handler::Handler;
+    use super::{Admin, Session, MAX_LINE};
+
+    struct Context {
+        accepting: bool,
+    }
+
+    impl Admin for Context {
+        fn set_accepting(&mut self, accepting: bool) -> bool {
+            self.accepting = accepting;
+            true
+        }
+        fn command(&mut self, command: &str, output: &mut Buf) -> bool {
+            if command == "hello" {
+                output.extend(b"hi\n");
+                true
+            } else {
+                false
+            }
+        }
+    }
+
+    type Loop = EventLoop<Handler<Context, Session<Context>>>;
+
+    /// Adds the session on one end of the socket pair, returns the other
+    fn session() -> (Handler<Context, Session<Context>>, Loop, Token,
+        net::UnixStream)
+    {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe {
+            libc::socketpair(libc::AF_UNIX,
+                libc::SOCK_STREAM | libc::SOCK_NONBLOCK, 0, fds.as_mut_ptr())
+        }, 0);
+        let (sock, peer) = unsafe {
+            (UnixStream::from_raw_fd(fds[0]),
+             net::UnixStream::from_raw_fd(fds[1]))
+        };
+        peer.set_nonblocking(false).unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::new(Context { accepting: true },
+            &mut eloop);
+        let tok = handler.add_machine(&mut eloop, Session {
+            sock: sock,
+            input: Buf::new(),
+            output: Buf::new(),
+            eof: false,
+            phantom: PhantomData,
+        }).unwrap();
+        (handler, eloop, tok, peer)
+    }
+
+    fn ready(handler: &mut Handler<Context, Session<Context>>,
+        eloop: &mut Loop, tok: Token)
+    {
+        mio::Handler::ready(handler, eloop, tok,
+            EventSet::readable() | EventSet::writable());
+    }
+
+    /// Reads the output until the `lines` number of lines is received
+    fn read_lines(peer: &mut net::UnixStream, lines: usize) -> String {
+        let mut data = Vec::new();
+        let mut buf = [0u8; 4096];
+        while data.iter().filter(|&&b| b == b'\n').count() < lines {
+            let n = peer.read(&mut buf).unwrap();
+            assert!(n > 0, "unexpected eof after {:?}",
+                String::from_utf8_lossy(&data));
+            data.extend_from_slice(&buf[..n]);
+        }
+        String::from_utf8(data).unwrap()
+    }
+
+    #[test]
+    fn commands() {
+        let (mut handler, mut eloop, tok, mut peer) = session();
+        peer.write_all(b"pause-accept\n\nfrobnicate\n  hello  \n\xff\n")
+            .unwrap();
+        ready(&mut handler, &mut eloop, tok);
+        assert_eq!(read_lines(&mut peer, 5), "ok\n\
+            error: unknown command \"frobnicate\"\n\
+            hi\nok\n\
+            error: invalid utf-8\n");
+        assert!(!handler.context().accepting);
+        // The incomplete command is dropped on eof
+        peer.write_all(b"resume-accept").unwrap();
+        peer.shutdown(Shutdown::Write).unwrap();
+        ready(&mut handler, &mut eloop, tok);
+        assert_eq!(handler.occupancy().0, 0);
+        assert!(!handler.context().accepting);
+        assert_eq!(peer.read(&mut [0u8; 16]).unwrap(), 0);
+    }
+
+    #[test]
+    fn many_lines_fit() {
+        let (mut handler, mut eloop, tok, mut peer) = session();
+        let n = MAX_LINE / 6 * 4;
+        let input = b"hello\n".iter().cloned().cycle().take(n * 6)
+            .collect::<Vec<_>>();
+        peer.write_all(&input).unwrap();
+        ready(&mut handler, &mut eloop, tok);
+        assert_eq!(read_lines(&mut peer, n * 2), "hi\nok\n".repeat(n));
+        assert_eq!(handler.occupancy().0, 1);
+    }
+
+    #[test]
+    fn line_too_long() {
+        let (mut handler, mut eloop, tok, mut peer) = session();
+        peer.write_all(&vec![b'x'; MAX_LINE * 4]).unwrap();
+        ready(&mut handler, &mut eloop, tok);
+        assert_eq!(handler.occupancy().0, 0);
+        assert_eq!(peer.read(&mut [0u8; 16]).unwrap(), 0);
+    }
+}
@@ -0,0 +1,12 @@
+//! Generic state machines which are not bound to any transport
+
+pub mod ticker;
+pub mod raw_fd;
+pub mod reconnect;
+pub mod control;
+pub mod rpc;
+
+pub use self::ticker::{Ticker, Interval};
+pub use self::raw_fd::RawFd;
+pub use self::reconnect::Reconnect;
+pub use self::rpc::Rpc;
@@ -0,0 +1,144 @@
+//! A state machine which watches a file descriptor owned by someone else
+//!
+//! This is an escape hatch for components which do their own I/O, e.g. a
+//! C library which exposes its file descriptors and expects to be called
+//! when they are ready:
+//!
+//! ```ignore
+//! RawFd::new(lib.fd(), EventSet::readable(), |events, ctx, ctl| {
+//!     ctx.lib.process(events);
+//!     if ctx.lib.wants_write() {
+//!         ctl.set_interest(EventSet::readable() | EventSet::writable());
+//!     }
+//!     !ctx.lib.is_closed()
+//! })
+//! ```
+//!
+//! The descriptor is registered level-triggered, it's deregistered but not
+//! closed when the machine is removed.
+use std::io::{self, Error};
+use std::marker::PhantomData;
+use std::os::unix::io::RawFd as SysFd;
+
+use mio::{Evented, Selector, Token, EventSet, PollOpt};
+
+use {BaseMachine, EventMachine, Scope, Response};
+
+
+/// The file descriptor which is not closed on drop
+#[derive(Clone, Copy, Debug)]
+pub struct Fd(pub SysFd);
+
+/// Allows the callback of `RawFd` to change the interest
+pub struct Control {
+    interest: EventSet,
+    changed: bool,
+}
+
+/// The state machine which calls `F` when the descriptor is ready
+///
+/// The callback returns `false` to remove the machine
+pub struct RawFd<F, C> {
+    fd: Fd,
+    interest: EventSet,
+    callback: F,
+    phantom: PhantomData<fn(&mut C)>,
+}
+
+impl Evented for Fd {
+    fn register(&self, selector: &mut Selector, token: Token,
+        interest: EventSet, opts: PollOpt)
+        -> io::Result<()>
+    {
+        selector.register(self.0, token, interest, opts)
+    }
+    fn reregister(&self, selector: &mut Selector, token: Token,
+        interest: EventSet, opts: PollOpt)
+        -> io::Result<()>
+    {
+        selector.reregister(self.0, token, interest, opts)
+    }
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        selector.deregister(self.0)
+    }
+}
+
+impl Control {
+    pub fn interest(&self) -> EventSet {
+        self.interest
+    }
+    /// Changes the events the callback is called for
+    pub fn set_interest(&mut self, interest: EventSet) {
+        self.interest = interest;
+        self.changed = true;
+    }
+}
+
+impl<F, C> RawFd<F, C>
+    where F: FnMut(EventSet, &mut C, &mut Control) -> bool
+{
+    pub fn new(fd: SysFd, interest: EventSet, callback: F) -> RawFd<F, C> {
+        RawFd {
+            fd: Fd(fd),
+            interest: interest,
+            callback: callback,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<F, C> BaseMachine for RawFd<F, C> {
+    type Timeout = ();
+}
+
+impl<F, C> EventMachine<C> for RawFd<F, C>
+    where F: FnMut(EventSet, &mut C, &mut Control) -> bool
+{
+    fn ready<S>(mut self, events: EventSet, context: &mut C, scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        let mut ctl = Control {
+            interest: self.interest,
+            changed: false,
+        };
+        if !(self.callback)(events, context, &mut ctl) {
+            if let Err(e) = scope.deregister(&self.fd) {
+                warn!("Error when deregistering fd {}: {}", self.fd.0, e);
+            }
+            return Response::Remove;
+        }
+        if ctl.changed && ctl.interest != self.interest {
+            self.interest = ctl.interest;
+            if let Err(e) = scope.reregister(&self.fd, self.interest,
+                                             PollOpt::level())
+            {
+                scope.deregister(&self.fd).ok();
+                return Response::Error(e.into());
+            }
+        }
+        Response::Continue(self)
+    }
+    fn register<S>(&mut self, scope: &mut S) -> Result<(), Error>
+        where S: Scope<Self>
+    {
+        scope.register(&self.fd, self.interest, PollOpt::level())
+    }
+    fn name(&self) -> &'static str {
+        "raw_fd"
+    }
+    fn deregister<S>(&mut self, scope: &mut S) -> Result<(), Error>
+        where S: Scope<Self>
+    {
+        scope.deregister(&self.fd)
+    }
+    fn shutdown<S>(mut self, _context: &mut C, scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        if let Err(e) = self.deregister(scope) {
+            warn!("Error when deregistering fd {}: {}", self.fd.0, e);
+        }
+        Response::Remove
+    }
+}
@@ -0,0 +1,514 @@
+//! A wrapper which recreates a client machine when it fails
+//!
+//! The standard pattern for connections to an upstream: the factory
+//! connects the socket and creates the machine, and when the machine is
+//! removed with `Response::Error` (or can't be created or registered) the
+//! factory is called again after a delay:
+//!
+//! ```ignore
+//! Reconnect::new(move |ctx: &mut Context| {
+//!     let sock = try!(TcpStream::connect(&addr));
+//!     Ok(Client::new(sock))
+//! }).backoff(100, 30000).max_retries(10)
+//! ```
+//!
+//! The delay grows exponentially with a random jitter, and is reset when
+//! the machine handles an I/O event successfully. The machine which
+//! returns `Response::Remove` is not recreated. Machines added by the
+//! inner machine through the scope are wrapped with a copy of the
+//! factory, machines migrated to another loop are sent unwrapped.
+use std::any::Any;
+use std::fmt;
+use std::io::{self, Error};
+use std::marker::PhantomData;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use mio::{Token, Timeout, TimerError, EventSet, PollOpt, Evented};
+
+use {BaseMachine, EventMachine, Scope, Response};
+use error::Error as RotorError;
+use handler::{MachineId, Notifier, NotifyError, Target};
+use pool::Backoff;
+use stats::Stats;
+
+
+/// The timeout of the `Reconnect` machine
+pub enum Timer<T> {
+    /// The timeout of the inner machine
+    Inner(T),
+    /// Time to recreate the machine
+    Retry,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Settings {
+    min_ms: u64,
+    max_ms: u64,
+    max_retries: Option<u32>,
+}
+
+/// The state machine which owns the inner machine `M` and its factory `F`
+pub struct Reconnect<M, F, C> {
+    machine: Option<M>,
+    retry: Option<Timeout>,
+    factory: F,
+    settings: Settings,
+    backoff: Backoff,
+    failures: u32,
+    shutting_down: bool,
+    phantom: PhantomData<fn(&mut C)>,
+}
+
+struct ScopeProxy<'a, S: 'a, F: 'a, C>(&'a mut S, &'a F, Settings,
+                                       PhantomData<fn(&mut C)>);
+
+/// Returns a random delay between the half of `delay` and `delay`
+fn jitter(delay: u64) -> u64 {
+    let seed = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u64).unwrap_or(0);
+    delay - (seed % (delay / 2 + 1))
+}
+
+impl<M, F, C> Reconnect<M, F, C>
+    where M: EventMachine<C> + 'static,
+          F: FnMut(&mut C) -> io::Result<M> + Clone + 'static,
+          C: 'static,
+{
+    /// Creates the machine, the factory is called right after the machine
+    /// is added to the loop
+    ///
+    /// By default the delay is from 100 ms to 30 seconds, and retries are
+    /// not limited
+    pub fn new(factory: F) -> Reconnect<M, F, C> {
+        Reconnect::wrap(None, factory, Settings {
+            min_ms: 100,
+            max_ms: 30000,
+            max_retries: None,
+        })
+    }
+    /// Sets the first delay and the maximum delay in milliseconds
+    pub fn backoff(mut self, min_ms: u64, max_ms: u64) -> Self {
+        self.settings.min_ms = min_ms;
+        self.settings.max_ms = max_ms;
+        self.backoff = Backoff::new(min_ms, max_ms);
+        self
+    }
+    /// Gives up after `retries` consecutive failures
+    ///
+    /// The machine is removed with the last error then
+    pub fn max_retries(mut self, retries: u32) -> Self {
+        self.settings.max_retries = Some(retries);
+        self
+    }
+    /// Returns the inner machine, unless it's being recreated
+    pub fn get(&self) -> Option<&M> {
+        self.machine.as_ref()
+    }
+    fn wrap(machine: Option<M>, factory: F, settings: Settings)
+        -> Reconnect<M, F, C>
+    {
+        Reconnect {
+            machine: machine,
+            retry: None,
+            factory: factory,
+            settings: settings,
+            backoff: Backoff::new(settings.min_ms, settings.max_ms),
+            failures: 0,
+            shutting_down: false,
+            phantom: PhantomData,
+        }
+    }
+    /// Wraps the response of the inner machine
+    fn wrap_response<S>(mut self, response: Response<M>, scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        match response {
+            Response::Continue(m) => {
+                self.machine = Some(m);
+                Response::Continue(self)
+            }
+            Response::Replace(m) => {
+                self.machine = Some(m);
+                Response::Replace(self)
+            }
+            Response::Remove => Response::Remove,
+            Response::Error(e) => {
+                if self.shutting_down {
+                    Response::Error(e)
+                } else {
+                    self.schedule(e, scope)
+                }
+            }
+        }
+    }
+    /// Schedules recreation of the machine after the failure `e`
+    fn schedule<S>(mut self, e: RotorError, scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        self.failures += 1;
+        if let Some(max) = self.settings.max_retries {
+            if self.failures > max {
+                error!("Giving up after {} retries", max);
+                return Response::Error(e);
+            }
+        }
+        let delay = jitter(self.backoff.next());
+        warn!("Machine failed: {}, recreating in {} ms", e, delay);
+        match scope.add_timeout_ms(delay, Timer::Retry) {
+            Ok(timeout) => {
+                self.retry = Some(timeout);
+                Response::Continue(self)
+            }
+            Err(e) => Response::Error(e.into()),
+        }
+    }
+    fn recreate<S>(mut self, context: &mut C, scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        let result = (self.factory)(context).and_then(|mut m| {
+            try!(m.register(&mut ScopeProxy(scope, &self.factory,
+                                            self.settings, PhantomData)));
+            Ok(m)
+        });
+        match result {
+            Ok(m) => {
+                self.machine = Some(m);
+                Response::Continue(self)
+            }
+            Err(e) => self.schedule(e.into(), scope),
+        }
+    }
+}
+
+impl<M: BaseMachine, F, C> BaseMachine for Reconnect<M, F, C> {
+    type Timeout = Timer<M::Timeout>;
+}
+
+impl<M, F, C> EventMachine<C> for Reconnect<M, F, C>
+    where M: EventMachine<C> + 'static,
+          F: FnMut(&mut C) -> io::Result<M> + Clone + 'static,
+          C: 'static,
+{
+    fn ready<S>(mut self, events: EventSet, context: &mut C, scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        let m = match self.machine.take() {
+            Some(m) => m,
+            // Spurious event of the failed machine
+            None => return Response::Continue(self),
+        };
+        let response = m.ready(events, context,
+            &mut ScopeProxy(scope, &self.factory, self.settings, PhantomData));
+        if response.is_alive() {
+            self.backoff.reset();
+            self.failures = 0;
+        }
+        self.wrap_response(response, scope)
+    }
+    fn register<S>(&mut self, scope: &mut S) -> Result<(), Error>
+        where S: Scope<Self>
+    {
+        match self.machine {
+            Some(ref mut m) => m.register(&mut ScopeProxy(scope,
+                &self.factory, self.settings, PhantomData)),
+            None => {
+                let timeout = try!(scope.add_timeout_ms(0, Timer::Retry)
+                    .map_err(|e| Error::new(io::ErrorKind::Other,
+                        format!("Can't add timeout: {:?}", e))));
+                self.retry = Some(timeout);
+                Ok(())
+            }
+        }
+    }
+    fn wakeup<S>(mut self, context: &mut C, scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        match self.machine.take() {
+            Some(m) => {
+                let response = m.wakeup(context, &mut ScopeProxy(scope,
+                    &self.factory, self.settings, PhantomData));
+                self.wrap_response(response, scope)
+            }
+            None => Response::Continue(self),
+        }
+    }
+    fn tick<S>(mut self, context: &mut C, scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        match self.machine.take() {
+            Some(m) => {
+                let response = m.tick(context, &mut ScopeProxy(scope,
+                    &self.factory, self.settings, PhantomData));
+                self.wrap_response(response, scope)
+            }
+            None => Response::Continue(self),
+        }
+    }
+    fn stalled<S>(mut self, context: &mut C, scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        match self.machine.take() {
+            Some(m) => {
+                let response = m.stalled(context, &mut ScopeProxy(scope,
+                    &self.factory, self.settings, PhantomData));
+                self.wrap_response(response, scope)
+            }
+            // Waiting for the retry timer
+            None => Response::Continue(self),
+        }
+    }
+    fn child_terminated<S>(mut self, child: Token, context: &mut C,
+        scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        match self.machine.take() {
+            Some(m) => {
+                let response = m.child_terminated(child, context,
+                    &mut ScopeProxy(scope, &self.factory, self.settings,
+                                    PhantomData));
+                self.wrap_response(response, scope)
+            }
+            None => Response::Continue(self),
+        }
+    }
+    fn timeout<S>(mut self, timeout: Self::Timeout, context: &mut C,
+        scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        match (self.machine.take(), timeout) {
+            (Some(m), Timer::Inner(t)) => {
+                let response = m.timeout(t, context, &mut ScopeProxy(scope,
+                    &self.factory, self.settings, PhantomData));
+                self.wrap_response(response, scope)
+            }
+            (None, Timer::Retry) => {
+                self.retry = None;
+                self.recreate(context, scope)
+            }
+            // Timeout of the failed machine
+            (m, _) => {
+                self.machine = m;
+                Response::Continue(self)
+            }
+        }
+    }
+    fn drain<S>(mut self, context: &mut C, scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        match self.machine.take() {
+            Some(m) => {
+                let response = m.drain(context, &mut ScopeProxy(scope,
+                    &self.factory, self.settings, PhantomData));
+                self.wrap_response(response, scope)
+            }
+            None => Response::Continue(self),
+        }
+    }
+    fn is_draining(&self) -> bool {
+        match self.machine {
+            Some(ref m) => m.is_draining(),
+            None => false,
+        }
+    }
+    fn name(&self) -> &'static str {
+        match self.machine {
+            Some(ref m) => m.name(),
+            None => "reconnect",
+        }
+    }
+    fn debug(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.machine {
+            Some(ref m) => m.debug(f),
+            None => write!(f, "waiting for retry"),
+        }
+    }
+    fn deregister<S>(&mut self, scope: &mut S) -> Result<(), Error>
+        where S: Scope<Self>
+    {
+        self.retry.take().map(|t| scope.clear_timeout(t));
+        match self.machine {
+            Some(ref mut m) => m.deregister(&mut ScopeProxy(scope,
+                &self.factory, self.settings, PhantomData)),
+            None => Ok(()),
+        }
+    }
+    fn shutdown<S>(mut self, context: &mut C, scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        self.shutting_down = true;
+        match self.machine.take() {
+            Some(m) => {
+                let response = m.shutdown(context, &mut ScopeProxy(scope,
+                    &self.factory, self.settings, PhantomData));
+                self.wrap_response(response, scope)
+            }
+            None => {
+                self.retry.take().map(|t| scope.clear_timeout(t));
+                Response::Remove
+            }
+        }
+    }
+}
+
+impl<'a, M, S, F, C> ScopeProxy<'a, S, F, C>
+    where M: EventMachine<C> + 'static,
+          F: FnMut(&mut C) -> io::Result<M> + Clone + 'static,
+          C: 'static,
+{
+    fn wrap(&self, m: M) -> Reconnect<M, F, C> {
+        Reconnect::wrap(Some(m), self.1.clone(), self.2)
+    }
+}
+
+impl<'a, M, S, F, C> Scope<M> for ScopeProxy<'a, S, F, C>
+    where S: Scope<Reconnect<M, F, C>> + 'a,
+          M: EventMachine<C> + 'static,
+          F: FnMut(&mut C) -> io::Result<M> + Clone + 'static,
+          C: 'static,
+{
+    fn async_add_machine(&mut self, m: M) -> Result<(), M> {
+        let m = self.wrap(m);
+        self.0.async_add_machine(m)
+            .map_err(|x| x.machine.unwrap())
+    }
+    fn add_child(&mut self, m: M) -> Result<Token, M> {
+        let m = self.wrap(m);
+        self.0.add_child(m)
+            .map_err(|x| x.machine.unwrap())
+    }
+    fn add_timeout_ms(&mut self, delay: u64, t: M::Timeout)
+        -> Result<Timeout, TimerError>
+    {
+        self.0.add_timeout_ms(delay, Timer::Inner(t))
+    }
+    fn clear_timeout(&mut self, timeout: Timeout) -> bool {
+        self.0.clear_timeout(timeout)
+    }
+    fn spawn_after(&mut self, delay: u64, m: M)
+        -> Result<Timeout, TimerError>
+    {
+        let m = self.wrap(m);
+        self.0.spawn_after(delay, m)
+    }
+    fn register<E: ?Sized>(&mut self, io: &E, interest: EventSet, opt: PollOpt)
+        -> Result<(), Error>
+        where E: Evented
+    {
+        self.0.register(io, interest, opt)
+    }
+    fn reregister<E: ?Sized>(&mut self, io: &E, interest: EventSet,
+        opt: PollOpt)
+        -> Result<(), Error>
+        where E: Evented
+    {
+        self.0.reregister(io, interest, opt)
+    }
+    fn deregister<E: ?Sized>(&mut self, io: &E) -> Result<(), Error>
+        where E: Evented
+    {
+        self.0.deregister(io)
+    }
+    fn token(&self) -> Token {
+        self.0.token()
+    }
+    fn id(&self) -> MachineId {
+        self.0.id()
+    }
+    fn is_alive(&self, id: MachineId) -> bool {
+        self.0.is_alive(id)
+    }
+    fn now(&self) -> Instant {
+        self.0.now()
+    }
+    fn now_ms(&self) -> u64 {
+        self.0.now_ms()
+    }
+    fn loop_stats(&self) -> Option<&Stats> {
+        self.0.loop_stats()
+    }
+    fn notifier(&self) -> Notifier {
+        self.0.notifier()
+    }
+    fn replace_self(&mut self, m: M) {
+        let m = self.wrap(m);
+        self.0.replace_self(m)
+    }
+    fn for_each_machine<G>(&self, f: G)
+        where G: FnMut(Token)
+    {
+        self.0.for_each_machine(f)
+    }
+    fn wakeup(&mut self, token: Token) -> Result<(), NotifyError> {
+        self.0.wakeup(token)
+    }
+    fn shutdown_loop(&mut self) {
+        self.0.shutdown_loop()
+    }
+    fn exit_loop(&mut self, status: i32) {
+        self.0.exit_loop(status)
+    }
+    fn shutdown_self(&mut self) {
+        self.0.shutdown_self()
+    }
+    fn slot_data<T: Any + Default>(&mut self) -> &mut T {
+        self.0.slot_data()
+    }
+    fn remove_slot_data<T: Any>(&mut self) -> Option<T> {
+        self.0.remove_slot_data()
+    }
+    fn request_tick(&mut self) {
+        self.0.request_tick()
+    }
+    fn dump_machines(&self, out: &mut io::Write)
+        -> io::Result<()>
+    {
+        self.0.dump_machines(out)
+    }
+    fn set_max_lifetime(&mut self, ms: u64) -> Result<(), TimerError> {
+        self.0.set_max_lifetime(ms)
+    }
+    fn migrate<T>(&mut self, token: Token, target: T) -> bool
+        where T: Target<M> + 'static
+    {
+        self.0.migrate(token, move |mut m: Reconnect<M, F, C>| {
+            match m.machine.take() {
+                Some(c) => target.send_machine(c).map_err(|c| {
+                    m.machine = Some(c);
+                    m
+                }),
+                None => Err(m),
+            }
+        })
+    }
+    fn reserve_slot(&mut self) -> Option<Token> {
+        self.0.reserve_slot()
+    }
+    fn switch_slot(&mut self, token: Token) -> Token {
+        self.0.switch_slot(token)
+    }
+    fn fill_slot(&mut self, token: Token, m: Option<M>) {
+        let m = m.map(|m| self.wrap(m));
+        self.0.fill_slot(token, m)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::jitter;
+
+    #[test]
+    fn jitter_bounds() {
+        for &delay in &[0, 1, 100, 30000] {
+            let value = jitter(delay);
+            assert!(value >= delay / 2 && value <= delay);
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! Timeouts and retries of requests for client machines
+//!
+//! `Rpc` is kept in the client machine. It stores the requests which are
+//! not answered yet, and schedules a timeout for every request. The timeout
+//! of the machine must be convertible from `CallId`:
+//!
+//! ```ignore
+//! enum Timer { Call(CallId), Ping }
+//! impl From<CallId> for Timer { ... }
+//!
+//! // sending
+//! let id = try!(self.rpc.call(scope, query));
+//! write_query(&mut self.output, id, self.rpc.get(id).unwrap());
+//! // the response is received
+//! if let Some(query) = self.rpc.complete(scope, id) { ... }
+//! // in the timeout of the machine
+//! Timer::Call(id) => match self.rpc.timeout(scope, id) {
+//!     Some(Expired::Retry(id)) => write_query(&mut self.output, id,
+//!                                             self.rpc.get(id).unwrap()),
+//!     Some(Expired::Failed(query, err)) => reply_error(query, err),
+//!     None => {}  // already answered
+//! }
+//! ```
+//!
+//! The request is sent again with the same `CallId`, so the response to
+//! any of the attempts completes the call.
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+
+use mio::{Timeout, TimerError};
+
+use {BaseMachine, Scope};
+
+
+/// Identifier of the call, unique within `Rpc`
+///
+/// Use `value` to put it into requests of protocols which correlate
+/// responses by an id
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CallId(u64);
+
+/// The result of `Rpc::timeout`
+pub enum Expired<Q> {
+    /// Send the request again, the timeout is scheduled already
+    Retry(CallId),
+    /// No retries left, the request is removed
+    ///
+    /// The error has the `ErrorKind::TimedOut` kind
+    Failed(Q, Error),
+}
+
+struct Call<Q> {
+    request: Q,
+    attempts: u32,
+    timeout: Timeout,
+}
+
+/// Requests in flight, see module documentation
+pub struct Rpc<Q> {
+    next_id: u64,
+    timeout_ms: u64,
+    max_retries: u32,
+    calls: HashMap<CallId, Call<Q>>,
+}
+
+impl CallId {
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+}
+
+impl<Q> Rpc<Q> {
+    /// Creates an empty set of calls, each attempt times out after
+    /// `timeout_ms` milliseconds
+    ///
+    /// Requests are not retried by default
+    pub fn new(timeout_ms: u64) -> Rpc<Q> {
+        Rpc {
+            next_id: 0,
+            timeout_ms: timeout_ms,
+            max_retries: 0,
+            calls: HashMap::new(),
+        }
+    }
+    /// Sends every request at most `retries` more times
+    pub fn max_retries(mut self, retries: u32) -> Self {
+        self.max_retries = retries;
+        self
+    }
+    /// Stores the request and schedules its timeout
+    ///
+    /// The caller sends the request itself
+    pub fn call<M, S>(&mut self, scope: &mut S, request: Q)
+        -> Result<CallId, TimerError>
+        where M: BaseMachine, M::Timeout: From<CallId>, S: Scope<M>
+    {
+        let id = CallId(self.next_id);
+        let timeout = try!(scope.add_timeout_ms(self.timeout_ms, id.into()));
+        self.next_id += 1;
+        self.calls.insert(id, Call {
+            request: request,
+            attempts: 1,
+            timeout: timeout,
+        });
+        Ok(id)
+    }
+    /// Returns the request of the call which is in flight
+    pub fn get(&self, id: CallId) -> Option<&Q> {
+        self.calls.get(&id).map(|c| &c.request)
+    }
+    /// Number of attempts made for the call
+    pub fn attempts(&self, id: CallId) -> Option<u32> {
+        self.calls.get(&id).map(|c| c.attempts)
+    }
+    /// Marks the call as answered and returns its request
+    ///
+    /// Returns `None` for unknown calls, e.g. for a late response to the
+    /// call which has timed out
+    pub fn complete<M, S>(&mut self, scope: &mut S, id: CallId) -> Option<Q>
+        where M: BaseMachine, S: Scope<M>
+    {
+        self.calls.remove(&id).map(|call| {
+            scope.clear_timeout(call.timeout);
+            call.request
+        })
+    }
+    /// Handles the timeout of the call
+    ///
+    /// Returns `None` if the call is completed already
+    pub fn timeout<M, S>(&mut self, scope: &mut S, id: CallId)
+        -> Option<Expired<Q>>
+        where M: BaseMachine, M::Timeout: From<CallId>, S: Scope<M>
+    {
+        let retry = match self.calls.get(&id) {
+            Some(call) => call.attempts <= self.max_retries,
+            None => return None,
+        };
+        if retry {
+            if let Ok(timeout) = scope.add_timeout_ms(self.timeout_ms,
+                                                      id.into())
+            {
+                let call = self.calls.get_mut(&id).unwrap();
+                call.attempts += 1;
+                call.timeout = timeout;
+                return Some(Expired::Retry(id));
+            }
+            warn!("Can't schedule retry of the call {:?}", id);
+        }
+        self.calls.remove(&id).map(|call| {
+            let err = Error::new(ErrorKind::TimedOut, format!(
+                "request timed out after {} attempts", call.attempts));
+            Expired::Failed(call.request, err)
+        })
+    }
+    /// Removes all calls, e.g. when the connection is lost
+    ///
+    /// Requests are returned in the order they were made
+    pub fn cancel_all<M, S>(&mut self, scope: &mut S) -> Vec<(CallId, Q)>
+        where M: BaseMachine, S: Scope<M>
+    {
+        let mut calls = self.calls.drain().map(|(id, call)| {
+            scope.clear_timeout(call.timeout);
+            (id, call.request)
+        }).collect::<Vec<_>>();
+        calls.sort_by_key(|&(id, _)| id.0);
+        calls
+    }
+    /// Number of calls in flight
+    pub fn len(&self) -> usize {
+        self.calls.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.calls.is_empty()
+    }
+}
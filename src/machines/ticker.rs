@@ -0,0 +1,117 @@
+//! A state machine without a socket which runs a task periodically
+//!
+//! Useful for cache expiration, flushing metrics and similar jobs:
+//!
+//! ```ignore
+//! Interval::every(1000).start_after(100).run(|ctx: &mut Context| {
+//!     ctx.cache.expire();
+//!     true
+//! })
+//! ```
+use std::io::{Error, ErrorKind};
+use std::marker::PhantomData;
+
+use mio::{EventSet, Timeout};
+
+use {BaseMachine, EventMachine, Scope, Response};
+
+
+/// A task which is run periodically by the `Ticker`
+pub trait Task<C> {
+    /// Called on every tick. Return `false` to stop the ticker
+    fn tick(&mut self, context: &mut C) -> bool;
+}
+
+/// Builder of the `Ticker`
+#[derive(Clone, Copy, Debug)]
+pub struct Interval {
+    interval: u64,
+    first: u64,
+}
+
+/// The state machine which runs the task every `interval` milliseconds
+pub struct Ticker<T, C> {
+    task: T,
+    interval: Interval,
+    timeout: Option<Timeout>,
+    phantom: PhantomData<fn(&mut C)>,
+}
+
+impl<C, F> Task<C> for F
+    where F: FnMut(&mut C) -> bool
+{
+    fn tick(&mut self, context: &mut C) -> bool {
+        self(context)
+    }
+}
+
+impl Interval {
+    /// Starts building a ticker which runs every `ms` milliseconds
+    pub fn every(ms: u64) -> Interval {
+        Interval {
+            interval: ms,
+            first: ms,
+        }
+    }
+    /// Set the delay of the first tick, by default it's equal to the interval
+    pub fn start_after(mut self, ms: u64) -> Interval {
+        self.first = ms;
+        self
+    }
+    /// Creates a state machine which runs the `task`
+    pub fn run<T: Task<C>, C>(self, task: T) -> Ticker<T, C> {
+        Ticker {
+            task: task,
+            interval: self,
+            timeout: None,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T: Task<C>, C> BaseMachine for Ticker<T, C> {
+    type Timeout = ();
+}
+
+impl<T: Task<C>, C> EventMachine<C> for Ticker<T, C> {
+    fn ready<S>(self, _events: EventSet, _context: &mut C, _scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        // There is no socket, so the event is spurious
+        Response::Continue(self)
+    }
+    fn register<S>(&mut self, scope: &mut S) -> Result<(), Error>
+        where S: Scope<Self>
+    {
+        let timeout = try!(scope.add_timeout_ms(self.interval.first, ())
+            .map_err(|e| Error::new(ErrorKind::Other,
+                                    format!("Can't add timeout: {:?}", e))));
+        self.timeout = Some(timeout);
+        Ok(())
+    }
+    fn name(&self) -> &'static str {
+        "ticker"
+    }
+    fn deregister<S>(&mut self, scope: &mut S) -> Result<(), Error>
+        where S: Scope<Self>
+    {
+        self.timeout.take().map(|t| scope.clear_timeout(t));
+        Ok(())
+    }
+    fn timeout<S>(mut self, _timeout: (), context: &mut C, scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        if !self.task.tick(context) {
+            return Response::Remove;
+        }
+        match scope.add_timeout_ms(self.interval.interval, ()) {
+            Ok(timeout) => {
+                self.timeout = Some(timeout);
+                Response::Continue(self)
+            }
+            Err(e) => Response::Error(e.into()),
+        }
+    }
+}
@@ -0,0 +1,748 @@
+//! A pool of client connections to an upstream
+//!
+//! The `Pool` is kept in the context, which implements `AsMut<Pool>`.
+//! Every connection is a `Member` machine. It joins the pool when the
+//! inner machine becomes ready, and leaves it when the inner machine
+//! fails. Wrap it into `Reconnect` to recreate failed connections with the
+//! exponential backoff:
+//!
+//! ```ignore
+//! for _ in 0..4 {
+//!     let addrs = addrs.clone();
+//!     try!(handler.add_machine(&mut eloop, Reconnect::new(move |_| {
+//!         Ok(Member::new(Connect::new(addrs.clone(), Client::new())))
+//!     }).backoff(100, 30000)));
+//! }
+//! ```
+//!
+//! Machines which need a connection call `checkout`. When no connection is
+//! available the requester is queued, and it's woken up when a connection
+//! is reserved for it: its next `checkout` returns the connection. The
+//! connection is returned with `checkin` when the request is done (e.g.
+//! the request is sent to the connection with `request::Requests`, and the
+//! connection calls `checkin` when it responds).
+//!
+//! Connections and requesters are identified by `MachineId`, so the
+//! machines which are removed from the loop are skipped, even if their
+//! slots are reused. Idle connections are checked with
+//! `Connection::health_check` every `Config::idle_check_ms`.
+use std::any::Any;
+use std::cmp::min;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::{self, Error, ErrorKind};
+use std::time::{Duration, Instant};
+
+use mio::{Token, Timeout, TimerError, EventSet, PollOpt, Evented};
+
+use {BaseMachine, EventMachine, Scope, Response};
+use handler::{MachineId, Notifier, NotifyError, Target};
+use stats::Stats;
+
+
+/// Tunables of the pool
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Maximum number of requests checked out from a single connection
+    pub max_in_flight: usize,
+    /// Idle connections are checked after that many milliseconds
+    pub idle_check_ms: u64,
+}
+
+/// Exponential backoff of reconnects
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    min: u64,
+    max: u64,
+    current: u64,
+}
+
+pub struct Pool {
+    config: Config,
+    /// The connections which are ready
+    conns: HashMap<MachineId, Conn>,
+    waiters: VecDeque<MachineId>,
+    /// Connections reserved for waiters which are not woken up yet
+    reserved: HashMap<MachineId, MachineId>,
+}
+
+struct Conn {
+    in_flight: usize,
+    last_used: Instant,
+}
+
+/// A connection machine which may be kept in the `Pool`
+pub trait Connection<C>: EventMachine<C> {
+    /// Returns true when the connection may be checked out
+    fn is_ready(&self) -> bool;
+    /// The connection was idle for `Config::idle_check_ms`
+    ///
+    /// Send a ping or check the socket here, return `Response::Error` if
+    /// the connection is broken. Nothing is done by default
+    fn health_check<S>(self, _context: &mut C, _scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        Response::Continue(self)
+    }
+}
+
+/// The timeout of the `Member` machine
+pub enum Timer<T> {
+    /// The timeout of the inner machine
+    Inner(T),
+    /// Time to check whether the connection is idle
+    Check,
+}
+
+/// The machine which keeps connection `M` in the `Pool` of the context
+pub struct Member<M> {
+    machine: M,
+    state: State,
+}
+
+#[derive(Clone, Copy)]
+struct State {
+    ready: bool,
+    check: Option<Timeout>,
+    /// The loop is shutting down or draining, so closing is expected
+    closing: bool,
+}
+
+struct ScopeProxy<'a, S: 'a>(&'a mut S);
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            max_in_flight: 1,
+            idle_check_ms: 60000,
+        }
+    }
+}
+
+impl Backoff {
+    pub fn new(min_ms: u64, max_ms: u64) -> Backoff {
+        Backoff {
+            min: min_ms,
+            max: max_ms,
+            current: min_ms,
+        }
+    }
+    /// Returns the next delay and doubles it for the next time
+    pub fn next(&mut self) -> u64 {
+        let delay = self.current;
+        self.current = min(self.current.saturating_mul(2), self.max);
+        delay
+    }
+    /// Resets the delay after successful connection
+    pub fn reset(&mut self) {
+        self.current = self.min;
+    }
+}
+
+impl Pool {
+    pub fn new(config: Config) -> Pool {
+        Pool {
+            config: config,
+            conns: HashMap::new(),
+            waiters: VecDeque::new(),
+            reserved: HashMap::new(),
+        }
+    }
+    /// Adds the connection which is ready, and wakes up the requesters
+    /// it's reserved for
+    pub fn connected<M, S>(&mut self, scope: &mut S, conn: MachineId)
+        where M: BaseMachine, S: Scope<M>
+    {
+        self.conns.insert(conn, Conn {
+            in_flight: 0,
+            last_used: Instant::now(),
+        });
+        while self.conns[&conn].in_flight < self.config.max_in_flight {
+            if !self.hand_over(scope, conn) {
+                break;
+            }
+            self.conns.get_mut(&conn).unwrap().in_flight += 1;
+        }
+    }
+    /// Removes the connection which is closed or not ready any more
+    ///
+    /// Requests which were checked out are considered lost, requesters
+    /// should be notified by the connection machine itself.
+    pub fn remove(&mut self, conn: MachineId) {
+        self.conns.remove(&conn);
+        self.reserved.retain(|_, c| *c != conn);
+    }
+    /// Returns the least loaded connection for the machine of the `scope`
+    ///
+    /// If there is none, the machine is queued and `None` is returned
+    pub fn checkout<M, S>(&mut self, scope: &S) -> Option<MachineId>
+        where M: BaseMachine, S: Scope<M>
+    {
+        let requester = scope.id();
+        if let Some(conn) = self.reserved.remove(&requester) {
+            return Some(conn);
+        }
+        // Connections may be removed from the loop without a notice
+        self.conns.retain(|&id, _| scope.is_alive(id));
+        let limit = self.config.max_in_flight;
+        let best = self.conns.iter_mut()
+            .filter(|&(_, ref c)| c.in_flight < limit)
+            .min_by_key(|&(_, ref c)| c.in_flight);
+        match best {
+            Some((&conn, c)) => {
+                c.in_flight += 1;
+                c.last_used = Instant::now();
+                Some(conn)
+            }
+            None => {
+                if !self.waiters.contains(&requester) {
+                    self.waiters.push_back(requester);
+                }
+                None
+            }
+        }
+    }
+    /// Returns the connection to the pool
+    ///
+    /// The connection is handed over to the first waiting requester, if
+    /// any, and the requester is woken up
+    pub fn checkin<M, S>(&mut self, scope: &mut S, conn: MachineId)
+        where M: BaseMachine, S: Scope<M>
+    {
+        match self.conns.get_mut(&conn) {
+            Some(c) => c.last_used = Instant::now(),
+            None => return,
+        }
+        // When handed over, `in_flight` is unchanged
+        if !self.hand_over(scope, conn) {
+            let c = self.conns.get_mut(&conn).unwrap();
+            c.in_flight = c.in_flight.saturating_sub(1);
+        }
+    }
+    /// The machine of the `scope` is not interested in connection any more
+    pub fn cancel<M, S>(&mut self, scope: &mut S)
+        where M: BaseMachine, S: Scope<M>
+    {
+        let requester = scope.id();
+        self.waiters.retain(|&x| x != requester);
+        if let Some(conn) = self.reserved.remove(&requester) {
+            self.checkin(scope, conn);
+        }
+    }
+    /// Returns true if the connection is idle long enough to be checked
+    pub fn needs_check(&self, conn: MachineId) -> bool {
+        let limit = Duration::from_millis(self.config.idle_check_ms);
+        self.conns.get(&conn).map(|c| {
+            c.in_flight == 0 && c.last_used.elapsed() >= limit
+        }).unwrap_or(false)
+    }
+    /// Number of connections which are ready
+    pub fn ready_connections(&self) -> usize {
+        self.conns.len()
+    }
+    /// Number of requesters waiting for a connection
+    pub fn waiting(&self) -> usize {
+        self.waiters.len()
+    }
+    /// Reserves the connection for the first waiter which is still alive
+    ///
+    /// Returns false if there is no such waiter
+    fn hand_over<M, S>(&mut self, scope: &mut S, conn: MachineId) -> bool
+        where M: BaseMachine, S: Scope<M>
+    {
+        while let Some(waiter) = self.waiters.pop_front() {
+            if !scope.is_alive(waiter) {
+                continue;
+            }
+            self.reserved.insert(waiter, conn);
+            // The connection is kept reserved anyway, it's returned by the
+            // next `checkout` of the waiter
+            if let Err(e) = scope.wakeup(waiter.token()) {
+                warn!("Can't wake up the requester of connection: {}", e);
+            }
+            return true;
+        }
+        false
+    }
+}
+
+impl<M> Member<M> {
+    pub fn new(machine: M) -> Member<M> {
+        Member {
+            machine: machine,
+            state: State {
+                ready: false,
+                check: None,
+                closing: false,
+            },
+        }
+    }
+    /// Returns the connection machine
+    pub fn get(&self) -> &M {
+        &self.machine
+    }
+}
+
+impl State {
+    /// Reports changes of the connection to the pool
+    fn update<M, C, S>(mut self, response: Response<M>, context: &mut C,
+        scope: &mut S)
+        -> Response<Member<M>>
+        where M: Connection<C>, C: AsMut<Pool>, S: Scope<Member<M>>
+    {
+        let ready = match response {
+            Response::Continue(ref m) | Response::Replace(ref m) => {
+                m.is_ready()
+            }
+            Response::Remove | Response::Error(_) => false,
+        };
+        let id = scope.id();
+        let pool = context.as_mut();
+        if ready && !self.ready {
+            pool.connected(scope, id);
+        } else if !ready && self.ready {
+            pool.remove(id);
+        }
+        if ready && self.check.is_none() {
+            self.check = scope.add_timeout_ms(pool.config.idle_check_ms,
+                                              Timer::Check).ok();
+        } else if !ready {
+            self.check.take().map(|t| scope.clear_timeout(t));
+        }
+        self.ready = ready;
+        match response {
+            // Connections are kept until the loop is shut down, so the one
+            // closed by the peer is recreated by `Reconnect`
+            Response::Remove if !self.closing => {
+                Response::Error(Error::new(ErrorKind::ConnectionAborted,
+                    "Connection of the pool is closed").into())
+            }
+            response => response.map(|m| Member {
+                machine: m,
+                state: self,
+            }),
+        }
+    }
+}
+
+impl<M: BaseMachine> BaseMachine for Member<M> {
+    type Timeout = Timer<M::Timeout>;
+}
+
+impl<M, C> EventMachine<C> for Member<M>
+    where M: Connection<C> + 'static, C: AsMut<Pool>,
+{
+    fn ready<S>(self, events: EventSet, context: &mut C, scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        let response = self.machine.ready(events, context,
+            &mut ScopeProxy(scope));
+        self.state.update(response, context, scope)
+    }
+    fn register<S>(&mut self, scope: &mut S) -> io::Result<()>
+        where S: Scope<Self>
+    {
+        self.machine.register(&mut ScopeProxy(scope))
+    }
+    fn timeout<S>(mut self, timeout: Self::Timeout, context: &mut C,
+        scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        let response = match timeout {
+            Timer::Inner(t) => {
+                self.machine.timeout(t, context, &mut ScopeProxy(scope))
+            }
+            Timer::Check => {
+                self.state.check = None;
+                if context.as_mut().needs_check(scope.id()) {
+                    self.machine.health_check(context,
+                        &mut ScopeProxy(scope))
+                } else {
+                    Response::Continue(self.machine)
+                }
+            }
+        };
+        self.state.update(response, context, scope)
+    }
+    fn wakeup<S>(self, context: &mut C, scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        let response = self.machine.wakeup(context, &mut ScopeProxy(scope));
+        self.state.update(response, context, scope)
+    }
+    fn tick<S>(self, context: &mut C, scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        let response = self.machine.tick(context, &mut ScopeProxy(scope));
+        self.state.update(response, context, scope)
+    }
+    fn stalled<S>(self, context: &mut C, scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        let response = self.machine.stalled(context, &mut ScopeProxy(scope));
+        self.state.update(response, context, scope)
+    }
+    fn child_terminated<S>(self, child: Token, context: &mut C,
+        scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        let response = self.machine.child_terminated(child, context,
+            &mut ScopeProxy(scope));
+        self.state.update(response, context, scope)
+    }
+    fn shutdown<S>(mut self, context: &mut C, scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        self.state.closing = true;
+        let response = self.machine.shutdown(context,
+            &mut ScopeProxy(scope));
+        self.state.update(response, context, scope)
+    }
+    fn drain<S>(mut self, context: &mut C, scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        self.state.closing = true;
+        let response = self.machine.drain(context, &mut ScopeProxy(scope));
+        self.state.update(response, context, scope)
+    }
+    fn is_draining(&self) -> bool {
+        self.machine.is_draining()
+    }
+    fn name(&self) -> &'static str {
+        self.machine.name()
+    }
+    fn debug(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.machine.debug(f)
+    }
+    fn deregister<S>(&mut self, scope: &mut S) -> io::Result<()>
+        where S: Scope<Self>
+    {
+        self.state.check.take().map(|t| scope.clear_timeout(t));
+        self.machine.deregister(&mut ScopeProxy(scope))
+    }
+}
+
+impl<'a, M, S> Scope<M> for ScopeProxy<'a, S>
+    where S: Scope<Member<M>> + 'a,
+          M: BaseMachine,
+{
+    fn async_add_machine(&mut self, m: M) -> Result<(), M> {
+        self.0.async_add_machine(Member::new(m))
+            .map_err(|x| x.machine)
+    }
+    fn add_child(&mut self, m: M) -> Result<Token, M> {
+        self.0.add_child(Member::new(m))
+            .map_err(|x| x.machine)
+    }
+    fn add_timeout_ms(&mut self, delay: u64, t: M::Timeout)
+        -> Result<Timeout, TimerError>
+    {
+        self.0.add_timeout_ms(delay, Timer::Inner(t))
+    }
+    fn clear_timeout(&mut self, timeout: Timeout) -> bool {
+        self.0.clear_timeout(timeout)
+    }
+    fn spawn_after(&mut self, delay: u64, m: M)
+        -> Result<Timeout, TimerError>
+    {
+        self.0.spawn_after(delay, Member::new(m))
+    }
+    fn register<E: ?Sized>(&mut self, io: &E, interest: EventSet, opt: PollOpt)
+        -> Result<(), Error>
+        where E: Evented
+    {
+        self.0.register(io, interest, opt)
+    }
+    fn reregister<E: ?Sized>(&mut self, io: &E, interest: EventSet,
+        opt: PollOpt)
+        -> Result<(), Error>
+        where E: Evented
+    {
+        self.0.reregister(io, interest, opt)
+    }
+    fn deregister<E: ?Sized>(&mut self, io: &E) -> Result<(), Error>
+        where E: Evented
+    {
+        self.0.deregister(io)
+    }
+    fn token(&self) -> Token {
+        self.0.token()
+    }
+    fn id(&self) -> MachineId {
+        self.0.id()
+    }
+    fn is_alive(&self, id: MachineId) -> bool {
+        self.0.is_alive(id)
+    }
+    fn now(&self) -> Instant {
+        self.0.now()
+    }
+    fn now_ms(&self) -> u64 {
+        self.0.now_ms()
+    }
+    fn loop_stats(&self) -> Option<&Stats> {
+        self.0.loop_stats()
+    }
+    fn notifier(&self) -> Notifier {
+        self.0.notifier()
+    }
+    fn replace_self(&mut self, m: M) {
+        self.0.replace_self(Member::new(m))
+    }
+    fn for_each_machine<F>(&self, f: F)
+        where F: FnMut(Token)
+    {
+        self.0.for_each_machine(f)
+    }
+    fn wakeup(&mut self, token: Token) -> Result<(), NotifyError> {
+        self.0.wakeup(token)
+    }
+    fn shutdown_loop(&mut self) {
+        self.0.shutdown_loop()
+    }
+    fn exit_loop(&mut self, status: i32) {
+        self.0.exit_loop(status)
+    }
+    fn shutdown_self(&mut self) {
+        self.0.shutdown_self()
+    }
+    fn slot_data<T: Any + Default>(&mut self) -> &mut T {
+        self.0.slot_data()
+    }
+    fn remove_slot_data<T: Any>(&mut self) -> Option<T> {
+        self.0.remove_slot_data()
+    }
+    fn request_tick(&mut self) {
+        self.0.request_tick()
+    }
+    fn dump_machines(&self, out: &mut io::Write)
+        -> io::Result<()>
+    {
+        self.0.dump_machines(out)
+    }
+    fn set_max_lifetime(&mut self, ms: u64) -> Result<(), TimerError> {
+        self.0.set_max_lifetime(ms)
+    }
+    fn migrate<T>(&mut self, token: Token, target: T) -> bool
+        where T: Target<M> + 'static
+    {
+        // The migrated connection joins the pool of the other loop
+        self.0.migrate(token, move |m: Member<M>| {
+            let state = m.state;
+            target.send_machine(m.machine).map_err(|m| Member {
+                machine: m,
+                state: state,
+            })
+        })
+    }
+    fn reserve_slot(&mut self) -> Option<Token> {
+        self.0.reserve_slot()
+    }
+    fn switch_slot(&mut self, token: Token) -> Token {
+        self.0.switch_slot(token)
+    }
+    fn fill_slot(&mut self, token: Token, m: Option<M>) {
+        self.0.fill_slot(token, m.map(Member::new))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::time::Instant;
+    use mio::{self, EventLoop, EventSet, Token};
+    use {BaseMachine, EventMachine, Scope, Response};
+    use handler::{Handler, MachineId, Notify, Timer as LoopTimer};
+    use super::{Pool, Config, Backoff, Connection, Member, Timer};
+
+    struct Context {
+        pool: Pool,
+        /// Connections become ready on wakeup
+        up: bool,
+        /// Connections return the requests on wakeup
+        done: bool,
+        /// Requesters are removed on wakeup
+        quit: bool,
+        conn: Option<MachineId>,
+        log: Vec<String>,
+    }
+
+    /// A connection, or a requester which is never ready
+    enum Fake {
+        Conn(bool),
+        Requester,
+    }
+
+    type Loop = EventLoop<Handler<Context, Member<Fake>>>;
+
+    impl AsMut<Pool> for Context {
+        fn as_mut(&mut self) -> &mut Pool {
+            &mut self.pool
+        }
+    }
+
+    impl BaseMachine for Fake {
+        type Timeout = ();
+    }
+
+    impl EventMachine<Context> for Fake {
+        fn ready<S>(self, _events: EventSet, _ctx: &mut Context,
+            _scope: &mut S)
+            -> Response<Self>
+            where S: Scope<Self>
+        {
+            Response::Continue(self)
+        }
+        fn register<S>(&mut self, _scope: &mut S) -> io::Result<()>
+            where S: Scope<Self>
+        {
+            Ok(())
+        }
+        fn wakeup<S>(self, ctx: &mut Context, scope: &mut S)
+            -> Response<Self>
+            where S: Scope<Self>
+        {
+            match self {
+                Fake::Conn(_) => {
+                    ctx.conn = Some(scope.id());
+                    if ctx.done {
+                        let id = scope.id();
+                        ctx.pool.checkin(scope, id);
+                    }
+                    Response::Continue(Fake::Conn(ctx.up))
+                }
+                Fake::Requester if ctx.quit => Response::Remove,
+                Fake::Requester => {
+                    let msg = match ctx.pool.checkout(scope) {
+                        Some(conn) => format!("got {}", conn.token().0),
+                        None => "wait".to_string(),
+                    };
+                    ctx.log.push(msg);
+                    Response::Continue(self)
+                }
+            }
+        }
+    }
+
+    impl Connection<Context> for Fake {
+        fn is_ready(&self) -> bool {
+            match *self {
+                Fake::Conn(ready) => ready,
+                Fake::Requester => false,
+            }
+        }
+        fn health_check<S>(self, ctx: &mut Context, _scope: &mut S)
+            -> Response<Self>
+            where S: Scope<Self>
+        {
+            ctx.log.push("check".to_string());
+            Response::Continue(Fake::Conn(ctx.up))
+        }
+    }
+
+    fn handler(config: Config)
+        -> (Handler<Context, Member<Fake>>, Loop)
+    {
+        let mut eloop = EventLoop::new().unwrap();
+        let handler = Handler::new(Context {
+            pool: Pool::new(config),
+            up: false,
+            done: false,
+            quit: false,
+            conn: None,
+            log: Vec::new(),
+        }, &mut eloop);
+        (handler, eloop)
+    }
+
+    fn add(handler: &mut Handler<Context, Member<Fake>>, eloop: &mut Loop,
+        fake: Fake)
+        -> Token
+    {
+        handler.add_machine(eloop, Member::new(fake)).unwrap()
+    }
+
+    fn wakeup(handler: &mut Handler<Context, Member<Fake>>,
+        eloop: &mut Loop, token: Token)
+    {
+        let id = handler.machine_id(token);
+        mio::Handler::notify(handler, eloop, Notify::Wakeup(id));
+    }
+
+    #[test]
+    fn backoff() {
+        let mut b = Backoff::new(100, 350);
+        assert_eq!(b.next(), 100);
+        assert_eq!(b.next(), 200);
+        assert_eq!(b.next(), 350);
+        assert_eq!(b.next(), 350);
+        b.reset();
+        assert_eq!(b.next(), 100);
+    }
+
+    #[test]
+    fn checkout_waits_for_connection() {
+        let (mut handler, mut eloop) = handler(Config::default());
+        let conn = add(&mut handler, &mut eloop, Fake::Conn(false));
+        let first = add(&mut handler, &mut eloop, Fake::Requester);
+        let second = add(&mut handler, &mut eloop, Fake::Requester);
+        wakeup(&mut handler, &mut eloop, first);
+        handler.context().up = true;
+        wakeup(&mut handler, &mut eloop, conn);
+        assert_eq!(handler.context().pool.ready_connections(), 1);
+        wakeup(&mut handler, &mut eloop, first);
+        wakeup(&mut handler, &mut eloop, second);
+        handler.context().done = true;
+        wakeup(&mut handler, &mut eloop, conn);
+        wakeup(&mut handler, &mut eloop, second);
+        assert_eq!(handler.context().log,
+            vec!["wait", "got 0", "wait", "got 0"]);
+        assert_eq!(handler.context().pool.waiting(), 0);
+    }
+
+    #[test]
+    fn removed_requesters_are_skipped() {
+        let (mut handler, mut eloop) = handler(Config::default());
+        let conn = add(&mut handler, &mut eloop, Fake::Conn(false));
+        let gone = add(&mut handler, &mut eloop, Fake::Requester);
+        wakeup(&mut handler, &mut eloop, gone);
+        handler.context().quit = true;
+        wakeup(&mut handler, &mut eloop, gone);
+        handler.context().quit = false;
+        // The new machine in the same slot is a different requester
+        let requester = add(&mut handler, &mut eloop, Fake::Requester);
+        assert_eq!(requester, gone);
+        wakeup(&mut handler, &mut eloop, requester);
+        assert_eq!(handler.context().pool.waiting(), 2);
+        handler.context().up = true;
+        wakeup(&mut handler, &mut eloop, conn);
+        wakeup(&mut handler, &mut eloop, requester);
+        assert_eq!(handler.context().log, vec!["wait", "wait", "got 0"]);
+        assert_eq!(handler.context().pool.waiting(), 0);
+    }
+
+    #[test]
+    fn health_check() {
+        let (mut handler, mut eloop) = handler(Config {
+            idle_check_ms: 0,
+            ..Config::default()
+        });
+        let conn = add(&mut handler, &mut eloop, Fake::Conn(false));
+        handler.context().up = true;
+        wakeup(&mut handler, &mut eloop, conn);
+        let id = handler.context().conn.unwrap();
+        let check = || LoopTimer::Machine(id, Timer::Check, Instant::now());
+        mio::Handler::timeout(&mut handler, &mut eloop, check());
+        assert_eq!(handler.context().pool.ready_connections(), 1);
+        // The connection is broken
+        handler.context().up = false;
+        mio::Handler::timeout(&mut handler, &mut eloop, check());
+        assert_eq!(handler.context().log, vec!["check", "check"]);
+        assert_eq!(handler.context().pool.ready_connections(), 0);
+    }
+}
@@ -0,0 +1,762 @@
+//! DNS messages (RFC 1035) and a server over the `udp` transport
+//!
+//! The server answers queries using the `Resolver` implemented by the
+//! context:
+//!
+//! ```ignore
+//! impl Resolver for Context {
+//!     fn resolve(&mut self, query: &Message, response: &mut Message) {
+//!         for q in &query.questions {
+//!             if let Some(&ip) = self.hosts.get(&q.name) {
+//!                 response.answers.push(Record::new(&q.name, 60,
+//!                                                   RData::A(ip)));
+//!             }
+//!         }
+//!     }
+//! }
+//! let options = Options { pktinfo: true, ..Options::default() };
+//! let machine = Datagram::new(sock, DnsServer, options).unwrap();
+//! ```
+//!
+//! Responses which don't fit the UDP packet (512 bytes, or the size
+//! advertised in the EDNS `OPT` record) are sent without records and with
+//! the `TC` flag, so the client retries over TCP. Use `DnsTcp` to serve
+//! TCP queries from the same `Resolver`, responses which don't fit the
+//! 16-bit length prefix of TCP get `SERVFAIL`:
+//!
+//! ```ignore
+//! Stream::<TcpStream, Parsed<DnsTcp>, _>::new(sock, ctx)
+//! ```
+//!
+//! Names are dot-separated without the trailing dot, the root is an empty
+//! string. Names are compressed when encoding, except in the SRV target.
+use std::collections::HashMap;
+use std::io::{self, Error, ErrorKind};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::from_utf8;
+
+use BaseMachine;
+use transports::udp::{Protocol, Packet, Transport};
+use transports::greedy_stream;
+use transports::parser::{Parser, Parse};
+
+/// Size of the message header
+const HEADER: usize = 12;
+/// Maximum length of the encoded name
+const MAX_NAME: usize = 255;
+/// Maximum length of the label
+const MAX_LABEL: usize = 63;
+/// Compression pointers may only refer to the offsets below that
+const MAX_POINTER: usize = 0x3FFF;
+/// Maximum size of the UDP response without EDNS
+const UDP_SIZE: usize = 512;
+/// Maximum size of the UDP response we send with EDNS
+const MAX_UDP_SIZE: usize = 4096;
+/// Size of the length prefix of the messages over TCP
+const TCP_PREFIX: usize = 2;
+/// Maximum size of the message over TCP, limited by the length prefix
+const MAX_TCP_SIZE: usize = 0xFFFF;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_NS: u16 = 2;
+pub const TYPE_CNAME: u16 = 5;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_OPT: u16 = 41;
+/// Query type matching all records
+pub const TYPE_ANY: u16 = 255;
+
+pub const CLASS_IN: u16 = 1;
+
+pub const OPCODE_QUERY: u8 = 0;
+
+pub const RCODE_NOERROR: u8 = 0;
+pub const RCODE_FORMERR: u8 = 1;
+pub const RCODE_SERVFAIL: u8 = 2;
+pub const RCODE_NXDOMAIN: u8 = 3;
+pub const RCODE_NOTIMP: u8 = 4;
+pub const RCODE_REFUSED: u8 = 5;
+
+
+/// A DNS message, either query or response
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Message {
+    pub id: u16,
+    /// The message is a response
+    pub response: bool,
+    pub opcode: u8,
+    pub authoritative: bool,
+    pub truncated: bool,
+    pub recursion_desired: bool,
+    pub recursion_available: bool,
+    pub rcode: u8,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Record>,
+    pub authority: Vec<Record>,
+    pub additional: Vec<Record>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Question {
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+/// A resource record
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub name: String,
+    pub rtype: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub data: RData,
+}
+
+/// Data of the resource record
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    /// A name in the NS, CNAME or PTR record
+    Name(String),
+    Srv { priority: u16, weight: u16, port: u16, target: String },
+    Txt(Vec<Vec<u8>>),
+    /// Data of other types as is
+    Other(Vec<u8>),
+}
+
+/// Answers queries of the `DnsServer`, implement it for the context
+pub trait Resolver {
+    /// Fill in the `response`
+    ///
+    /// The `response` already has the id, flags and questions of the query
+    /// and the `NOERROR` code
+    fn resolve(&mut self, query: &Message, response: &mut Message);
+}
+
+/// A `udp::Protocol` which answers queries using the context
+pub struct DnsServer;
+
+/// A `Parser` which answers queries over TCP using the context
+pub struct DnsTcp;
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+fn read_u16(data: &[u8], pos: usize) -> io::Result<u16> {
+    if data.len() < pos + 2 {
+        return Err(invalid("Message is truncated"));
+    }
+    Ok(((data[pos] as u16) << 8) | data[pos+1] as u16)
+}
+
+fn read_u32(data: &[u8], pos: usize) -> io::Result<u32> {
+    let hi = try!(read_u16(data, pos)) as u32;
+    let lo = try!(read_u16(data, pos+2)) as u32;
+    Ok((hi << 16) | lo)
+}
+
+fn write_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.push((value >> 8) as u8);
+    buf.push(value as u8);
+}
+
+/// Reads a possibly compressed name, returns the position after it
+fn read_name(data: &[u8], mut pos: usize) -> io::Result<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    let mut encoded_len = 0;
+    loop {
+        let start = pos;
+        let len = match data.get(pos) {
+            Some(&len) => len as usize,
+            None => return Err(invalid("Message is truncated")),
+        };
+        pos += 1;
+        if len & 0xC0 == 0xC0 {
+            let target = try!(read_u16(data, start)) as usize & MAX_POINTER;
+            // Pointing only backwards prevents loops
+            if target >= start {
+                return Err(invalid("Invalid compression pointer"));
+            }
+            if end.is_none() {
+                end = Some(start + 2);
+            }
+            pos = target;
+            continue;
+        }
+        if len & 0xC0 != 0 {
+            return Err(invalid("Unsupported label type"));
+        }
+        encoded_len += len + 1;
+        if encoded_len > MAX_NAME {
+            return Err(invalid("Name is too long"));
+        }
+        if len == 0 {
+            break;
+        }
+        if data.len() < pos + len {
+            return Err(invalid("Message is truncated"));
+        }
+        let label = try!(from_utf8(&data[pos..pos+len])
+            .map_err(|_| invalid("Invalid characters in name")));
+        if label.contains('.') {
+            return Err(invalid("Invalid characters in name"));
+        }
+        if name.len() > 0 {
+            name.push('.');
+        }
+        name.push_str(label);
+        pos += len;
+    }
+    Ok((name, end.unwrap_or(pos)))
+}
+
+/// Writes a name, compressing it if `names` is given
+fn write_name(buf: &mut Vec<u8>, name: &str,
+    names: Option<&mut HashMap<String, usize>>)
+    -> io::Result<()>
+{
+    let labels = name.split('.').filter(|l| l.len() > 0).collect::<Vec<_>>();
+    if name.len() + 2 > MAX_NAME {
+        return Err(Error::new(ErrorKind::InvalidInput, "Name is too long"));
+    }
+    let mut names = names;
+    for i in 0..labels.len() {
+        if let Some(ref mut names) = names {
+            let suffix = labels[i..].join(".").to_lowercase();
+            if let Some(&offset) = names.get(&suffix) {
+                write_u16(buf, 0xC000 | offset as u16);
+                return Ok(());
+            }
+            if buf.len() <= MAX_POINTER {
+                names.insert(suffix, buf.len());
+            }
+        }
+        let label = labels[i].as_bytes();
+        if label.len() > MAX_LABEL {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                "Label is too long"));
+        }
+        buf.push(label.len() as u8);
+        buf.extend(label);
+    }
+    buf.push(0);
+    Ok(())
+}
+
+fn read_records(data: &[u8], pos: &mut usize, count: u16)
+    -> io::Result<Vec<Record>>
+{
+    let mut records = Vec::new();
+    for _ in 0..count {
+        let (name, next) = try!(read_name(data, *pos));
+        let rtype = try!(read_u16(data, next));
+        let class = try!(read_u16(data, next+2));
+        let ttl = try!(read_u32(data, next+4));
+        let len = try!(read_u16(data, next+8)) as usize;
+        let start = next + 10;
+        if data.len() < start + len {
+            return Err(invalid("Message is truncated"));
+        }
+        let rdata = &data[start..start+len];
+        let value = match rtype {
+            TYPE_A if len == 4 => {
+                RData::A(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]))
+            }
+            TYPE_AAAA if len == 16 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(rdata);
+                RData::Aaaa(Ipv6Addr::from(octets))
+            }
+            TYPE_A | TYPE_AAAA => {
+                return Err(invalid("Invalid length of the address"));
+            }
+            TYPE_NS | TYPE_CNAME | TYPE_PTR => {
+                // Name may point anywhere into the message
+                let (target, end) = try!(read_name(data, start));
+                if end != start + len {
+                    return Err(invalid("Invalid length of the record"));
+                }
+                RData::Name(target)
+            }
+            TYPE_SRV => {
+                let (target, end) = try!(read_name(data, start + 6));
+                if end != start + len {
+                    return Err(invalid("Invalid length of the record"));
+                }
+                RData::Srv {
+                    priority: try!(read_u16(data, start)),
+                    weight: try!(read_u16(data, start+2)),
+                    port: try!(read_u16(data, start+4)),
+                    target: target,
+                }
+            }
+            TYPE_TXT => {
+                let mut strings = Vec::new();
+                let mut i = 0;
+                while i < len {
+                    let n = rdata[i] as usize;
+                    if i + 1 + n > len {
+                        return Err(invalid("Invalid TXT record"));
+                    }
+                    strings.push(rdata[i+1..i+1+n].to_vec());
+                    i += 1 + n;
+                }
+                RData::Txt(strings)
+            }
+            _ => RData::Other(rdata.to_vec()),
+        };
+        records.push(Record {
+            name: name,
+            rtype: rtype,
+            class: class,
+            ttl: ttl,
+            data: value,
+        });
+        *pos = start + len;
+    }
+    Ok(records)
+}
+
+fn write_record(buf: &mut Vec<u8>, record: &Record,
+    names: &mut HashMap<String, usize>)
+    -> io::Result<()>
+{
+    try!(write_name(buf, &record.name, Some(names)));
+    write_u16(buf, record.rtype);
+    write_u16(buf, record.class);
+    write_u16(buf, (record.ttl >> 16) as u16);
+    write_u16(buf, record.ttl as u16);
+    let len_pos = buf.len();
+    write_u16(buf, 0);
+    match record.data {
+        RData::A(ip) => buf.extend(&ip.octets()),
+        RData::Aaaa(ip) => buf.extend(&ip.octets()),
+        RData::Name(ref name) => try!(write_name(buf, name, Some(names))),
+        RData::Srv { priority, weight, port, ref target } => {
+            write_u16(buf, priority);
+            write_u16(buf, weight);
+            write_u16(buf, port);
+            try!(write_name(buf, target, None));
+        }
+        RData::Txt(ref strings) => {
+            for s in strings {
+                if s.len() > 255 {
+                    return Err(Error::new(ErrorKind::InvalidInput,
+                        "TXT string is too long"));
+                }
+                buf.push(s.len() as u8);
+                buf.extend(s);
+            }
+        }
+        RData::Other(ref data) => buf.extend(data),
+    }
+    let len = buf.len() - len_pos - 2;
+    if len > 0xFFFF {
+        return Err(Error::new(ErrorKind::InvalidInput,
+            "Record is too long"));
+    }
+    buf[len_pos] = (len >> 8) as u8;
+    buf[len_pos+1] = len as u8;
+    Ok(())
+}
+
+impl Message {
+    pub fn decode(data: &[u8]) -> io::Result<Message> {
+        if data.len() < HEADER {
+            return Err(invalid("Message is truncated"));
+        }
+        let mut msg = Message {
+            id: try!(read_u16(data, 0)),
+            response: data[2] & 0x80 != 0,
+            opcode: (data[2] >> 3) & 0x0F,
+            authoritative: data[2] & 0x04 != 0,
+            truncated: data[2] & 0x02 != 0,
+            recursion_desired: data[2] & 0x01 != 0,
+            recursion_available: data[3] & 0x80 != 0,
+            rcode: data[3] & 0x0F,
+            .. Message::default()
+        };
+        let qdcount = try!(read_u16(data, 4));
+        let ancount = try!(read_u16(data, 6));
+        let nscount = try!(read_u16(data, 8));
+        let arcount = try!(read_u16(data, 10));
+        let mut pos = HEADER;
+        for _ in 0..qdcount {
+            let (name, next) = try!(read_name(data, pos));
+            msg.questions.push(Question {
+                name: name,
+                qtype: try!(read_u16(data, next)),
+                qclass: try!(read_u16(data, next+2)),
+            });
+            pos = next + 4;
+        }
+        msg.answers = try!(read_records(data, &mut pos, ancount));
+        msg.authority = try!(read_records(data, &mut pos, nscount));
+        msg.additional = try!(read_records(data, &mut pos, arcount));
+        Ok(msg)
+    }
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(512);
+        let mut names = HashMap::new();
+        write_u16(&mut buf, self.id);
+        buf.push((if self.response { 0x80 } else { 0 }) |
+                 ((self.opcode & 0x0F) << 3) |
+                 (if self.authoritative { 0x04 } else { 0 }) |
+                 (if self.truncated { 0x02 } else { 0 }) |
+                 (if self.recursion_desired { 0x01 } else { 0 }));
+        buf.push((if self.recursion_available { 0x80 } else { 0 }) |
+                 (self.rcode & 0x0F));
+        for &n in &[self.questions.len(), self.answers.len(),
+                    self.authority.len(), self.additional.len()]
+        {
+            if n > 0xFFFF {
+                return Err(Error::new(ErrorKind::InvalidInput,
+                    "Too many records"));
+            }
+            write_u16(&mut buf, n as u16);
+        }
+        for q in &self.questions {
+            try!(write_name(&mut buf, &q.name, Some(&mut names)));
+            write_u16(&mut buf, q.qtype);
+            write_u16(&mut buf, q.qclass);
+        }
+        for r in self.answers.iter()
+            .chain(self.authority.iter())
+            .chain(self.additional.iter())
+        {
+            try!(write_record(&mut buf, r, &mut names));
+        }
+        Ok(buf)
+    }
+    /// Returns an empty response to this query
+    pub fn response(&self) -> Message {
+        Message {
+            id: self.id,
+            response: true,
+            opcode: self.opcode,
+            recursion_desired: self.recursion_desired,
+            questions: self.questions.clone(),
+            .. Message::default()
+        }
+    }
+}
+
+impl Record {
+    /// A record of the class `IN` with the type derived from the `data`
+    ///
+    /// The type of `RData::Name` is `CNAME` and of `RData::Other` is zero,
+    /// set `rtype` explicitly for them
+    pub fn new(name: &str, ttl: u32, data: RData) -> Record {
+        let rtype = match data {
+            RData::A(..) => TYPE_A,
+            RData::Aaaa(..) => TYPE_AAAA,
+            RData::Name(..) => TYPE_CNAME,
+            RData::Srv { .. } => TYPE_SRV,
+            RData::Txt(..) => TYPE_TXT,
+            RData::Other(..) => 0,
+        };
+        Record {
+            name: name.to_string(),
+            rtype: rtype,
+            class: CLASS_IN,
+            ttl: ttl,
+            data: data,
+        }
+    }
+}
+
+/// Returns the encoded response to the `query` received over UDP, if any
+///
+/// Responses are not replied to. Malformed queries get `FORMERR` and
+/// unknown opcodes get `NOTIMP` without calling the `resolver`. Responses
+/// larger than allowed for UDP are truncated.
+pub fn respond_udp<R: Resolver>(query: &[u8], resolver: &mut R)
+    -> Option<Vec<u8>>
+{
+    respond(query, resolver, true)
+}
+
+/// Returns the encoded response to the `query` received over TCP, if any
+///
+/// Same as `respond_udp` but responses are never truncated, the ones
+/// larger than 65535 bytes are replaced by `SERVFAIL`
+pub fn respond_tcp<R: Resolver>(query: &[u8], resolver: &mut R)
+    -> Option<Vec<u8>>
+{
+    respond(query, resolver, false)
+}
+
+/// Maximum size of the UDP response to the `query`
+fn udp_limit(query: &Message) -> usize {
+    query.additional.iter().find(|r| r.rtype == TYPE_OPT)
+        // The class of the OPT record is the payload size of the client
+        .map(|r| r.class as usize)
+        .map(|n| if n < UDP_SIZE { UDP_SIZE } else { n })
+        .map(|n| if n > MAX_UDP_SIZE { MAX_UDP_SIZE } else { n })
+        .unwrap_or(UDP_SIZE)
+}
+
+fn respond<R: Resolver>(query: &[u8], resolver: &mut R, udp: bool)
+    -> Option<Vec<u8>>
+{
+    let query = match Message::decode(query) {
+        Ok(query) => query,
+        Err(e) => {
+            debug!("Invalid DNS query: {}", e);
+            if query.len() < HEADER || query[2] & 0x80 != 0 {
+                return None;
+            }
+            let response = Message {
+                id: ((query[0] as u16) << 8) | query[1] as u16,
+                response: true,
+                opcode: (query[2] >> 3) & 0x0F,
+                rcode: RCODE_FORMERR,
+                .. Message::default()
+            };
+            return response.encode().ok();
+        }
+    };
+    if query.response {
+        return None;
+    }
+    let mut response = query.response();
+    if query.opcode == OPCODE_QUERY {
+        resolver.resolve(&query, &mut response);
+    } else {
+        response.rcode = RCODE_NOTIMP;
+    }
+    match response.encode() {
+        Ok(ref data) if udp && data.len() > udp_limit(&query) => {
+            response.truncated = true;
+            response.answers.clear();
+            response.authority.clear();
+            response.additional.clear();
+            response.encode().ok()
+        }
+        Ok(ref data) if data.len() > MAX_TCP_SIZE => {
+            error!("DNS response is too large ({} bytes)", data.len());
+            server_failure(&query)
+        }
+        Ok(data) => Some(data),
+        Err(e) => {
+            error!("Can't encode DNS response: {}", e);
+            server_failure(&query)
+        }
+    }
+}
+
+/// Returns the encoded `SERVFAIL` response to the `query`
+fn server_failure(query: &Message) -> Option<Vec<u8>> {
+    let mut response = query.response();
+    response.rcode = RCODE_SERVFAIL;
+    response.encode().ok()
+}
+
+impl BaseMachine for DnsServer {
+    type Timeout = ();
+}
+
+impl<C: Resolver> Protocol<C> for DnsServer {
+    fn packet_received(self, packet: &Packet, transport: &mut Transport,
+        ctx: &mut C)
+        -> Option<Self>
+    {
+        if let Some(data) = respond_udp(packet.data, ctx) {
+            packet.queue_reply(transport, &data);
+        }
+        Some(self)
+    }
+}
+
+impl BaseMachine for DnsTcp {
+    type Timeout = ();
+}
+
+impl<C: Resolver> Parser<C> for DnsTcp {
+    type Output = Vec<u8>;
+    fn accepted(_ctx: &mut C) -> Self {
+        DnsTcp
+    }
+    fn parse(&mut self, data: &[u8]) -> Parse<Vec<u8>> {
+        if data.len() < TCP_PREFIX {
+            return Parse::NeedMore(TCP_PREFIX - data.len());
+        }
+        let len = ((data[0] as usize) << 8) | data[1] as usize;
+        if data.len() < TCP_PREFIX + len {
+            return Parse::NeedMore(TCP_PREFIX + len - data.len());
+        }
+        Parse::Done(data[TCP_PREFIX..TCP_PREFIX+len].to_vec(),
+                    TCP_PREFIX + len)
+    }
+    fn packet_received(self, query: Vec<u8>,
+        transport: &mut greedy_stream::Transport, ctx: &mut C)
+        -> Option<Self>
+    {
+        if let Some(data) = respond_tcp(&query, ctx) {
+            // Never larger than `MAX_TCP_SIZE`
+            let len = data.len();
+            transport.output().extend(&[(len >> 8) as u8, len as u8]);
+            transport.output().extend(&data);
+        }
+        Some(self)
+    }
+    fn max_buffer(&self) -> usize {
+        TCP_PREFIX + MAX_TCP_SIZE
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+    use transports::greedy_stream::{Protocol, fuzz_feed};
+    use transports::parser::Parsed;
+    use super::{Message, Question, Record, RData, Resolver, DnsTcp};
+    use super::{respond_udp, respond_tcp};
+    use super::{TYPE_A, TYPE_NS, TYPE_OPT, CLASS_IN};
+    use super::{RCODE_NXDOMAIN, RCODE_FORMERR, RCODE_SERVFAIL};
+
+    struct Hosts;
+
+    impl Resolver for Hosts {
+        fn resolve(&mut self, query: &Message, response: &mut Message) {
+            for q in &query.questions {
+                if q.name == "example.com" {
+                    response.answers.push(Record::new(&q.name, 60,
+                        RData::A(Ipv4Addr::new(127, 0, 0, 1))));
+                } else if q.name == "large.example.com" {
+                    for i in 0..100 {
+                        response.answers.push(Record::new(&q.name, 60,
+                            RData::A(Ipv4Addr::new(127, 0, 0, i))));
+                    }
+                } else if q.name == "huge.example.com" {
+                    for i in 0..5000 {
+                        response.answers.push(Record::new(&q.name, 60,
+                            RData::A(Ipv4Addr::from(0x7F000000 + i))));
+                    }
+                } else {
+                    response.rcode = RCODE_NXDOMAIN;
+                }
+            }
+        }
+    }
+
+    fn query(name: &str) -> Message {
+        Message {
+            id: 0x1234,
+            recursion_desired: true,
+            questions: vec![Question {
+                name: name.to_string(),
+                qtype: TYPE_A,
+                qclass: CLASS_IN,
+            }],
+            .. Message::default()
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut msg = query("example.com").response();
+        msg.answers.push(Record::new("www.example.com", 300,
+            RData::Name("example.com".to_string())));
+        msg.authority.push(Record {
+            rtype: TYPE_NS,
+            .. Record::new("example.com", 300,
+                           RData::Name("ns.example.com".to_string()))
+        });
+        msg.additional.push(Record::new("example.com", 300,
+            RData::Txt(vec![b"hello".to_vec(), vec![]])));
+        msg.additional.push(Record::new("_x._tcp.example.com", 300,
+            RData::Srv { priority: 1, weight: 2, port: 3,
+                         target: "example.com".to_string() }));
+        let data = msg.encode().unwrap();
+        // "example.com" is written once, the rest are pointers
+        assert_eq!(data.windows(7).filter(|w| w == b"example").count(), 2);
+        assert_eq!(Message::decode(&data).unwrap(), msg);
+    }
+
+    #[test]
+    fn pointer_loop() {
+        let mut data = query("a").encode().unwrap();
+        // Replace the name with the pointer to itself
+        data.truncate(12);
+        data.extend(&[0xC0, 12, 0, 1, 0, 1]);
+        assert!(Message::decode(&data).is_err());
+    }
+
+    #[test]
+    fn server() {
+        let data = respond_udp(&query("example.com").encode().unwrap(),
+                           &mut Hosts).unwrap();
+        let response = Message::decode(&data).unwrap();
+        assert_eq!(response.id, 0x1234);
+        assert!(response.response && response.recursion_desired);
+        assert_eq!(response.answers[0].data,
+                   RData::A(Ipv4Addr::new(127, 0, 0, 1)));
+
+        let data = respond_udp(&query("nx.com").encode().unwrap(),
+                           &mut Hosts).unwrap();
+        assert_eq!(Message::decode(&data).unwrap().rcode, RCODE_NXDOMAIN);
+
+        let data = respond_udp(&[0x12, 0x34, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 3],
+                           &mut Hosts).unwrap();
+        assert_eq!(Message::decode(&data).unwrap().rcode, RCODE_FORMERR);
+        assert!(respond_udp(&data, &mut Hosts).is_none());
+    }
+
+    #[test]
+    fn truncation() {
+        // 100 records take 1600 bytes
+        let mut msg = query("large.example.com");
+        let data = respond_udp(&msg.encode().unwrap(), &mut Hosts).unwrap();
+        let response = Message::decode(&data).unwrap();
+        assert!(response.truncated);
+        assert_eq!(response.answers.len(), 0);
+
+        let data = respond_tcp(&msg.encode().unwrap(), &mut Hosts).unwrap();
+        let response = Message::decode(&data).unwrap();
+        assert!(!response.truncated);
+        assert_eq!(response.answers.len(), 100);
+
+        msg.additional.push(Record {
+            name: String::new(),
+            rtype: TYPE_OPT,
+            class: 4096,
+            ttl: 0,
+            data: RData::Other(Vec::new()),
+        });
+        let data = respond_udp(&msg.encode().unwrap(), &mut Hosts).unwrap();
+        let response = Message::decode(&data).unwrap();
+        assert!(!response.truncated);
+        assert_eq!(response.answers.len(), 100);
+    }
+
+    #[test]
+    fn tcp_limit() {
+        // 5000 records take 80000 bytes
+        let msg = query("huge.example.com");
+        let data = respond_tcp(&msg.encode().unwrap(), &mut Hosts).unwrap();
+        let response = Message::decode(&data).unwrap();
+        assert_eq!(response.rcode, RCODE_SERVFAIL);
+        assert_eq!(response.answers.len(), 0);
+    }
+
+    #[test]
+    fn tcp_framing() {
+        let query = query("example.com").encode().unwrap();
+        let mut input = vec![15, (query.len() >> 8) as u8, query.len() as u8];
+        input.extend(&query);
+        let protocol = Parsed::<DnsTcp>::accepted(&mut Hosts);
+        let output = fuzz_feed(protocol, &mut Hosts, &input);
+        let len = ((output[0] as usize) << 8) | output[1] as usize;
+        assert_eq!(len, output.len() - 2);
+        let response = Message::decode(&output[2..]).unwrap();
+        assert_eq!(response.answers[0].data,
+                   RData::A(Ipv4Addr::new(127, 0, 0, 1)));
+    }
+}
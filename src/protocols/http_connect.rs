@@ -0,0 +1,168 @@
+//! Client side of the HTTP `CONNECT` proxy handshake
+//!
+//! ```ignore
+//! let proto = HttpConnect::new(Address::Domain("example.com".into(), 443),
+//!                              tls);
+//! Stream::with_protocol(sock, Upgrade::Handshake(proto), ctx)
+//! ```
+use std::io::{self, Error, ErrorKind};
+use std::str::from_utf8;
+
+use BaseMachine;
+use buffer_util::find_substr;
+use protocols::Address;
+use transports::greedy_stream::{Protocol, Handshake, Switch, Transport};
+
+/// Maximum size of the response headers of the proxy
+const MAX_HEADERS: usize = 16384;
+
+
+/// The handshake which connects to `target` and then switches to `P`
+pub struct HttpConnect<P> {
+    /// `None` if created for the accepted connection by mistake, such
+    /// connection is closed
+    target: Option<Address>,
+    headers: Vec<(String, String)>,
+    next: P,
+}
+
+impl<P> HttpConnect<P> {
+    pub fn new(target: Address, next: P) -> HttpConnect<P> {
+        HttpConnect {
+            target: Some(target),
+            headers: Vec::new(),
+            next: next,
+        }
+    }
+    /// Adds a header to the request, e.g. `Proxy-Authorization`
+    ///
+    /// Fails with `InvalidInput` if the name or the value contains a line
+    /// break, or the name contains a colon.
+    pub fn with_header(mut self, name: &str, value: &str)
+        -> io::Result<HttpConnect<P>>
+    {
+        let line_break = |s: &str| s.contains('\r') || s.contains('\n');
+        if line_break(name) || name.contains(':') || line_break(value) {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  "Invalid header of CONNECT request"));
+        }
+        self.headers.push((name.to_string(), value.to_string()));
+        Ok(self)
+    }
+}
+
+impl<P: BaseMachine> BaseMachine for HttpConnect<P> {
+    type Timeout = P::Timeout;
+}
+
+impl<P: Protocol<C>, C> Handshake<C> for HttpConnect<P> {
+    type Next = P;
+    fn accepted(ctx: &mut C) -> Self {
+        HttpConnect {
+            target: None,
+            headers: Vec::new(),
+            next: P::accepted(ctx),
+        }
+    }
+    fn connected(self, transport: &mut Transport, ctx: &mut C)
+        -> Switch<Self, P>
+    {
+        let mut req = match self.target {
+            Some(ref target) => {
+                format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target)
+            }
+            None => {
+                error!("HttpConnect is a client protocol, \
+                        use Stream::with_protocol");
+                return fail(self.next, "not an outgoing connection".into(),
+                            ctx);
+            }
+        };
+        for &(ref name, ref value) in &self.headers {
+            req.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        req.push_str("\r\n");
+        transport.output().extend(req.as_bytes());
+        Switch::Stay(self)
+    }
+    fn data_received(self, transport: &mut Transport, ctx: &mut C)
+        -> Switch<Self, P>
+    {
+        let inp = transport.input();
+        let end = match find_substr(&inp[..], "\r\n\r\n") {
+            Some(end) => end,
+            None if inp.len() > MAX_HEADERS => {
+                return fail(self.next, "response is too long".into(), ctx);
+            }
+            None => return Switch::Stay(self),
+        };
+        let status = {
+            let line = inp[..end].split(|&x| x == b'\n').next().unwrap();
+            let line = from_utf8(line).unwrap_or("").trim();
+            match parse_status(line) {
+                Some(code) if code >= 200 && code < 300 => None,
+                _ => Some(line.to_string()),
+            }
+        };
+        inp.consume(end + 4);
+        match status {
+            None => Switch::Upgrade(self.next),
+            Some(line) => {
+                fail(self.next, format!("proxy responded {:?}", line), ctx)
+            }
+        }
+    }
+    fn eof_received(self, ctx: &mut C) {
+        self.next.error_happened(Error::new(ErrorKind::UnexpectedEof,
+            "Proxy closed connection during handshake"), ctx)
+    }
+    fn error_happened(self, e: Error, ctx: &mut C) {
+        self.next.error_happened(e, ctx)
+    }
+}
+
+/// Returns status code of the `HTTP/1.x 200 OK` line
+fn parse_status(line: &str) -> Option<u16> {
+    let mut parts = line.splitn(3, ' ');
+    match parts.next() {
+        Some(proto) if proto.starts_with("HTTP/1.") => {}
+        _ => return None,
+    }
+    parts.next().and_then(|code| code.parse().ok())
+}
+
+/// Reports the error to the protocol, so it knows that connection failed
+fn fail<P, C, H>(next: P, msg: String, ctx: &mut C) -> Switch<H, P>
+    where P: Protocol<C>
+{
+    next.error_happened(Error::new(ErrorKind::Other,
+        format!("HTTP CONNECT failed: {}", msg)), ctx);
+    Switch::Close
+}
+
+#[cfg(test)]
+mod test {
+    use protocols::Address;
+    use super::{HttpConnect, parse_status};
+
+    #[test]
+    fn status() {
+        assert_eq!(parse_status("HTTP/1.1 200 Connection established"),
+                   Some(200));
+        assert_eq!(parse_status("HTTP/1.0 407 Proxy Auth Required"),
+                   Some(407));
+        assert_eq!(parse_status("SSH-2.0-OpenSSH"), None);
+    }
+
+    #[test]
+    fn header_injection() {
+        let proxy = HttpConnect::new(Address::Domain("a".into(), 80), ());
+        let proxy = proxy.with_header("Proxy-Authorization", "Basic eA==")
+            .unwrap();
+        assert!(proxy.with_header("X-Test", "a\r\nHost: b").is_err());
+        let proxy = HttpConnect::new(Address::Domain("a".into(), 80), ());
+        assert!(proxy.with_header("Host: b\r\nX-Test", "a").is_err());
+        let proxy = HttpConnect::new(Address::Domain("a".into(), 80), ());
+        assert!(proxy.with_header("Host:", "b").is_err());
+    }
+}
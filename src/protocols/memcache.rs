@@ -0,0 +1,380 @@
+//! Server side of the memcached text protocol
+//!
+//! Supports `get`/`gets` with multiple keys, `set` and `delete`, including
+//! `noreply`. Malformed commands are replied with `CLIENT_ERROR`. The data
+//! is kept by the context which implements `Storage`:
+//!
+//! ```ignore
+//! impl Storage for Context {
+//!     fn get(&mut self, key: &[u8]) -> Option<&Item> {
+//!         self.cache.get(key)
+//!     }
+//!     fn next_cas(&mut self) -> u64 {
+//!         self.cas += 1;
+//!         self.cas
+//!     }
+//!     // ...
+//! }
+//! let stream = Stream::<TcpStream, Parsed<Memcache>, _>::new(sock, ctx);
+//! ```
+//!
+//! The binary protocol is not supported.
+use std::io::{Error, ErrorKind, Write};
+use std::str::from_utf8;
+
+use netbuf::Buf;
+
+use BaseMachine;
+use buffer_util::find_substr;
+use transports::greedy_stream::Transport;
+use transports::parser::{Parser, Parse};
+
+/// Maximum length of the command line (without data)
+const MAX_LINE: usize = 2048;
+/// Maximum length of the key
+const MAX_KEY: usize = 250;
+/// Maximum size of the value (the default of the memcached)
+const MAX_VALUE: usize = 1 << 20;
+
+
+/// A value kept in the storage
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Item {
+    /// Opaque flags set by the client
+    pub flags: u32,
+    /// Expiration time as sent by the client, it's up to storage to obey
+    pub exptime: u32,
+    /// The unique value sent by `gets`, assigned on `set`
+    pub cas: u64,
+    pub data: Vec<u8>,
+}
+
+/// A storage of the items, implement it for the context
+pub trait Storage {
+    fn get(&mut self, key: &[u8]) -> Option<&Item>;
+    fn set(&mut self, key: Vec<u8>, item: Item);
+    /// Returns `false` if there was no such key
+    fn delete(&mut self, key: &[u8]) -> bool;
+    /// Returns the unique value for the item being stored, e.g. the next
+    /// value of a counter
+    fn next_cas(&mut self) -> u64;
+}
+
+/// A parsed command
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    Get(Vec<Vec<u8>>),
+    /// Same as `Get` but the unique values of the items are sent too
+    Gets(Vec<Vec<u8>>),
+    Set { key: Vec<u8>, item: Item, noreply: bool },
+    Delete { key: Vec<u8>, noreply: bool },
+    /// Unknown command, replied with `ERROR`
+    Unknown(String),
+    /// Malformed command, replied with `CLIENT_ERROR` and the message
+    Invalid(String),
+}
+
+/// A `Parser` which serves requests from the `Storage` in the context
+pub struct Memcache;
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+fn parse_num<T: ::std::str::FromStr>(word: Option<&[u8]>) -> Result<T, Error>
+{
+    word.and_then(|w| from_utf8(w).ok()).and_then(|s| s.parse().ok())
+        .ok_or_else(|| invalid("Invalid number in command"))
+}
+
+fn parse_key(word: Option<&[u8]>) -> Result<Vec<u8>, Error> {
+    match word {
+        Some(key) if key.len() <= MAX_KEY => Ok(key.to_vec()),
+        Some(_) => Err(invalid("Key is too long")),
+        None => Err(invalid("Key is expected")),
+    }
+}
+
+fn parse_keys(args: &[&[u8]]) -> Result<Vec<Vec<u8>>, Error> {
+    if args.len() == 0 {
+        return Err(invalid("Key is expected"));
+    }
+    args.iter().map(|&k| parse_key(Some(k))).collect()
+}
+
+fn parse_noreply(word: Option<&[u8]>) -> Result<bool, Error> {
+    match word {
+        Some(b"noreply") => Ok(true),
+        Some(_) => Err(invalid("Unexpected argument")),
+        None => Ok(false),
+    }
+}
+
+/// Parses a command at the start of the `data`
+///
+/// Malformed commands are parsed as `Command::Invalid`, only the command
+/// line which is too long and the value which is too large are errors.
+pub fn parse_command(data: &[u8]) -> Parse<Command> {
+    let end = match find_substr(data, b"\r\n") {
+        Some(end) if end <= MAX_LINE => end,
+        Some(_) => return Parse::Error(invalid("Command line is too long")),
+        None if data.len() > MAX_LINE => {
+            return Parse::Error(invalid("Command line is too long"));
+        }
+        None => return Parse::NeedMore(1),
+    };
+    let mut words = data[..end].split(|&x| x == b' ')
+        .filter(|w| w.len() > 0);
+    let command = words.next();
+    let args = words.collect::<Vec<_>>();
+    let result = match command {
+        Some(b"get") => parse_keys(&args).map(Command::Get),
+        Some(b"gets") => parse_keys(&args).map(Command::Gets),
+        Some(b"set") => return parse_set(&args, data, end),
+        Some(b"delete") => (|| {
+            let key = try!(parse_key(args.first().cloned()));
+            let noreply = try!(parse_noreply(args.get(1).cloned()));
+            Ok(Command::Delete { key: key, noreply: noreply })
+        })(),
+        Some(cmd) => {
+            Ok(Command::Unknown(String::from_utf8_lossy(cmd).into_owned()))
+        }
+        None => Ok(Command::Unknown(String::new())),
+    };
+    match result {
+        Ok(cmd) => Parse::Done(cmd, end + 2),
+        Err(e) => Parse::Done(Command::Invalid(e.to_string()), end + 2),
+    }
+}
+
+/// Parses the `set` command with the arguments `args`, the command line
+/// ends at `end`
+///
+/// The value is skipped if the command is malformed but its length is
+/// known, so it's not parsed as the next command.
+fn parse_set(args: &[&[u8]], data: &[u8], end: usize) -> Parse<Command> {
+    let start = end + 2;
+    let len: usize = match parse_num(args.get(3).cloned()) {
+        Ok(len) if len > MAX_VALUE => {
+            return Parse::Error(invalid("Value is too large"));
+        }
+        Ok(len) => len,
+        Err(e) => return Parse::Done(Command::Invalid(e.to_string()), start),
+    };
+    if data.len() < start + len + 2 {
+        return Parse::NeedMore(start + len + 2 - data.len());
+    }
+    let result = (|| {
+        let key = try!(parse_key(args.first().cloned()));
+        let flags = try!(parse_num(args.get(1).cloned()));
+        let exptime = try!(parse_num(args.get(2).cloned()));
+        let noreply = try!(parse_noreply(args.get(4).cloned()));
+        if &data[start+len..start+len+2] != b"\r\n" {
+            return Err(invalid("Value is not terminated"));
+        }
+        let item = Item {
+            flags: flags,
+            exptime: exptime,
+            cas: 0,
+            data: data[start..start+len].to_vec(),
+        };
+        Ok(Command::Set { key: key, item: item, noreply: noreply })
+    })();
+    match result {
+        Ok(cmd) => Parse::Done(cmd, start + len + 2),
+        Err(e) => {
+            Parse::Done(Command::Invalid(e.to_string()), start + len + 2)
+        }
+    }
+}
+
+/// Writes the values of the `keys` found in the storage, with the unique
+/// values if `cas` is true
+fn values<S: Storage>(keys: Vec<Vec<u8>>, cas: bool, storage: &mut S,
+    buf: &mut Buf)
+{
+    for key in keys {
+        if let Some(item) = storage.get(&key) {
+            buf.extend(b"VALUE ");
+            buf.extend(&key);
+            write!(buf, " {} {}", item.flags, item.data.len()).unwrap();
+            if cas {
+                write!(buf, " {}", item.cas).unwrap();
+            }
+            buf.extend(b"\r\n");
+            buf.extend(&item.data);
+            buf.extend(b"\r\n");
+        }
+    }
+    buf.extend(b"END\r\n");
+}
+
+/// Executes the command and writes reply into the buffer
+pub fn execute<S: Storage>(cmd: Command, storage: &mut S, buf: &mut Buf) {
+    match cmd {
+        Command::Get(keys) => values(keys, false, storage, buf),
+        Command::Gets(keys) => values(keys, true, storage, buf),
+        Command::Set { key, mut item, noreply } => {
+            item.cas = storage.next_cas();
+            storage.set(key, item);
+            if !noreply {
+                buf.extend(b"STORED\r\n");
+            }
+        }
+        Command::Delete { key, noreply } => {
+            let found = storage.delete(&key);
+            if !noreply {
+                if found {
+                    buf.extend(b"DELETED\r\n");
+                } else {
+                    buf.extend(b"NOT_FOUND\r\n");
+                }
+            }
+        }
+        Command::Unknown(cmd) => {
+            debug!("Unknown memcache command {:?}", cmd);
+            buf.extend(b"ERROR\r\n");
+        }
+        Command::Invalid(message) => {
+            debug!("Malformed memcache command: {}", message);
+            write!(buf, "CLIENT_ERROR {}\r\n", message).unwrap();
+        }
+    }
+}
+
+impl BaseMachine for Memcache {
+    type Timeout = ();
+}
+
+impl<C: Storage> Parser<C> for Memcache {
+    type Output = Command;
+    fn accepted(_ctx: &mut C) -> Self {
+        Memcache
+    }
+    fn parse(&mut self, data: &[u8]) -> Parse<Command> {
+        parse_command(data)
+    }
+    fn packet_received(self, cmd: Command, transport: &mut Transport,
+        ctx: &mut C)
+        -> Option<Self>
+    {
+        execute(cmd, ctx, transport.output());
+        Some(self)
+    }
+    fn max_buffer(&self) -> usize {
+        MAX_LINE + MAX_VALUE + 4
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use netbuf::Buf;
+    use transports::parser::Parse;
+    use super::{parse_command, execute, Storage, Item, Command};
+
+    #[derive(Default)]
+    struct Cache {
+        items: HashMap<Vec<u8>, Item>,
+        cas: u64,
+    }
+
+    impl Storage for Cache {
+        fn get(&mut self, key: &[u8]) -> Option<&Item> {
+            self.items.get(key)
+        }
+        fn set(&mut self, key: Vec<u8>, item: Item) {
+            self.items.insert(key, item);
+        }
+        fn delete(&mut self, key: &[u8]) -> bool {
+            self.items.remove(key).is_some()
+        }
+        fn next_cas(&mut self) -> u64 {
+            self.cas += 1;
+            self.cas
+        }
+    }
+
+    fn run(storage: &mut Cache, mut data: &[u8]) -> Buf {
+        let mut buf = Buf::new();
+        while data.len() > 0 {
+            match parse_command(data) {
+                Parse::Done(cmd, n) => {
+                    execute(cmd, storage, &mut buf);
+                    data = &data[n..];
+                }
+                Parse::NeedMore(_) => panic!("need more"),
+                Parse::Error(e) => panic!("error: {}", e),
+            }
+        }
+        buf
+    }
+
+    #[test]
+    fn set_get_delete() {
+        let mut storage = Cache::default();
+        let buf = run(&mut storage, b"set a 5 0 3\r\nabc\r\n\
+            set b 0 0 0 noreply\r\n\r\n\
+            get a b c\r\n\
+            delete a noreply\r\ndelete a\r\nget a\r\nflush_all\r\n");
+        assert_eq!(&buf[..], &b"STORED\r\n\
+            VALUE a 5 3\r\nabc\r\nVALUE b 0 0\r\n\r\nEND\r\n\
+            NOT_FOUND\r\nEND\r\nERROR\r\n"[..]);
+    }
+
+    #[test]
+    fn gets() {
+        let mut storage = Cache::default();
+        let buf = run(&mut storage, b"set a 0 0 1\r\nx\r\n\
+            set b 0 0 1 noreply\r\ny\r\nset a 0 0 1 noreply\r\nz\r\n\
+            gets a b\r\n");
+        assert_eq!(&buf[..], &b"STORED\r\n\
+            VALUE a 0 1 3\r\nz\r\nVALUE b 0 1 2\r\ny\r\nEND\r\n"[..]);
+    }
+
+    #[test]
+    fn partial() {
+        let data = b"set key 0 0 5\r\nhello\r\n";
+        for i in 0..data.len() {
+            match parse_command(&data[..i]) {
+                // The rest of the value is expected after the command line
+                Parse::NeedMore(n) if i >= 15 => assert_eq!(i + n, 22),
+                Parse::NeedMore(_) => {}
+                _ => panic!("partial command is parsed at {}", i),
+            }
+        }
+        match parse_command(&data[..]) {
+            Parse::Done(Command::Set { ref key, .. }, 22) => {
+                assert_eq!(key, b"key");
+            }
+            _ => panic!("command is not parsed"),
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        match parse_command(b"set key 0 0 2\r\nabc\r\n") {
+            Parse::Done(Command::Invalid(_), 19) => {}
+            _ => panic!("value of wrong length is parsed"),
+        }
+        match parse_command(b"get\r\n") {
+            Parse::Done(Command::Invalid(_), 5) => {}
+            _ => panic!("get without keys is parsed"),
+        }
+        match parse_command(b"set key 0 0 2000000\r\n") {
+            Parse::Error(_) => {}
+            _ => panic!("too large value is accepted"),
+        }
+    }
+
+    #[test]
+    fn client_errors() {
+        let mut storage = Cache::default();
+        // The value of the malformed `set` is skipped if its length is known
+        let buf = run(&mut storage, b"set a x 0 3\r\nabc\r\n\
+            set b 0 0 y\r\ndelete a b\r\nget a\r\n");
+        assert_eq!(&buf[..], &b"CLIENT_ERROR Invalid number in command\r\n\
+            CLIENT_ERROR Invalid number in command\r\n\
+            CLIENT_ERROR Unexpected argument\r\nEND\r\n"[..]);
+    }
+}
@@ -0,0 +1,44 @@
+//! Ready to use protocols on top of the transports
+//!
+//! The `socks5` and `http_connect` are client-side proxy handshakes. Wrap
+//! them into `greedy_stream::Upgrade` and create the stream with
+//! `Stream::with_protocol`, the socket is handed over to your protocol when
+//! the handshake is done.
+//!
+//! The `resp` is a parser and encoder of the Redis protocol, to be used in
+//! a `Parser` implementation. The `memcache` is a ready to use server of the
+//! memcached protocol on top of the user-supplied storage, and the `dns` is
+//! a DNS server over the `udp` transport with a TCP fallback. The `sntp`
+//! is a client which keeps track of the clock offset. The `sni` is a
+//! server-side handshake choosing the protocol of TLS connection by the
+//! server name, and the `sniff` tells TLS from plaintext connections on the
+//! same port.
+use std::fmt;
+use std::net::SocketAddr;
+
+pub mod socks5;
+pub mod http_connect;
+pub mod resp;
+pub mod memcache;
+pub mod dns;
+pub mod sntp;
+pub mod sni;
+pub mod sniff;
+
+
+/// The address to connect to through the proxy
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Address {
+    Ip(SocketAddr),
+    /// The name is resolved by the proxy
+    Domain(String, u16),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Address::Ip(ref addr) => write!(f, "{}", addr),
+            Address::Domain(ref name, port) => write!(f, "{}:{}", name, port),
+        }
+    }
+}
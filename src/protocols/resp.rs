@@ -0,0 +1,321 @@
+//! The Redis serialization protocol (RESP)
+//!
+//! `parse` and `parse_command` are meant to be called from
+//! `Parser::parse`, they consume nothing until the whole value is in the
+//! buffer, and return the number of missing bytes when it's known (e.g.
+//! for bulk strings). Lines, i.e. simple strings, errors and headers, are
+//! limited to `MAX_LINE` bytes. Replies are written into the output buffer
+//! with `encode` or the `write_*` helpers:
+//!
+//! ```ignore
+//! fn parse(&mut self, data: &[u8]) -> Parse<Vec<Vec<u8>>> {
+//!     resp::parse_command(data)
+//! }
+//! fn packet_received(self, cmd: Vec<Vec<u8>>, transport: &mut Transport,
+//!     ctx: &mut Context)
+//!     -> Option<Self>
+//! {
+//!     match &cmd[0][..] {
+//!         b"PING" => resp::write_simple(transport.output(), "PONG"),
+//!         _ => resp::write_error(transport.output(), "ERR unknown command"),
+//!     }
+//!     Some(self)
+//! }
+//! ```
+use std::cmp::min;
+use std::io::{Error, ErrorKind, Write};
+use std::str::from_utf8;
+
+use netbuf::Buf;
+
+use buffer_util::find_substr;
+use transports::parser::Parse;
+
+/// Maximum length of the bulk string (the limit of the Redis itself)
+const MAX_BULK: i64 = 512 << 20;
+/// Maximum number of elements of the array
+const MAX_ARRAY: i64 = 1 << 20;
+/// Maximum nesting of the arrays
+const MAX_DEPTH: usize = 32;
+/// Maximum length of the line without the CRLF
+pub const MAX_LINE: usize = 65536;
+
+
+/// A value of the protocol
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    /// Simple string, e.g. `+OK`
+    Simple(String),
+    /// Error, e.g. `-ERR unknown command`
+    Error(String),
+    Integer(i64),
+    /// Bulk string, `None` is the null bulk string
+    Bulk(Option<Vec<u8>>),
+    /// Array, `None` is the null array
+    Array(Option<Vec<Value>>),
+}
+
+/// Parses a single value at the start of the `data`
+pub fn parse(data: &[u8]) -> Parse<Value> {
+    parse_value(data, 0, 0)
+}
+
+/// Parses a command, i.e. an array of bulk strings
+pub fn parse_command(data: &[u8]) -> Parse<Vec<Vec<u8>>> {
+    match parse(data) {
+        Parse::Done(Value::Array(Some(items)), n) => {
+            let mut args = Vec::with_capacity(items.len());
+            for item in items {
+                match item {
+                    Value::Bulk(Some(arg)) => args.push(arg),
+                    _ => return Parse::Error(invalid(
+                        "Command arguments must be bulk strings")),
+                }
+            }
+            if args.len() == 0 {
+                return Parse::Error(invalid("Empty command"));
+            }
+            Parse::Done(args, n)
+        }
+        Parse::Done(..) => Parse::Error(invalid("Command must be an array")),
+        Parse::NeedMore(n) => Parse::NeedMore(n),
+        Parse::Error(e) => Parse::Error(e),
+    }
+}
+
+/// Writes the value into the buffer
+pub fn encode(buf: &mut Buf, value: &Value) {
+    match *value {
+        Value::Simple(ref s) => write_simple(buf, s),
+        Value::Error(ref s) => write_error(buf, s),
+        Value::Integer(n) => write_integer(buf, n),
+        Value::Bulk(ref data) => match *data {
+            Some(ref data) => write_bulk(buf, data),
+            None => buf.extend(b"$-1\r\n"),
+        },
+        Value::Array(ref items) => match *items {
+            Some(ref items) => {
+                write_array_header(buf, items.len());
+                for item in items {
+                    encode(buf, item);
+                }
+            }
+            None => buf.extend(b"*-1\r\n"),
+        },
+    }
+}
+
+/// Writes a simple string, it must not contain newlines
+pub fn write_simple(buf: &mut Buf, value: &str) {
+    debug_assert!(!value.contains('\r') && !value.contains('\n'));
+    write!(buf, "+{}\r\n", value).unwrap();
+}
+
+/// Writes an error, it must not contain newlines
+pub fn write_error(buf: &mut Buf, message: &str) {
+    debug_assert!(!message.contains('\r') && !message.contains('\n'));
+    write!(buf, "-{}\r\n", message).unwrap();
+}
+
+pub fn write_integer(buf: &mut Buf, value: i64) {
+    write!(buf, ":{}\r\n", value).unwrap();
+}
+
+pub fn write_bulk(buf: &mut Buf, data: &[u8]) {
+    write!(buf, "${}\r\n", data.len()).unwrap();
+    buf.extend(data);
+    buf.extend(b"\r\n");
+}
+
+/// Writes the header of the array, put `len` values after it
+pub fn write_array_header(buf: &mut Buf, len: usize) {
+    write!(buf, "*{}\r\n", len).unwrap();
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// Returns the line starting at `pos` and the position after the CRLF
+fn line(data: &[u8], pos: usize) -> Parse<&[u8]> {
+    let rest = &data[pos..min(data.len(), pos + MAX_LINE + 2)];
+    match find_substr(rest, b"\r\n") {
+        Some(n) => Parse::Done(&data[pos..pos+n], pos + n + 2),
+        None if rest.len() == MAX_LINE + 2 => {
+            Parse::Error(invalid("Line is too long"))
+        }
+        None if rest.ends_with(b"\r") => Parse::NeedMore(1),
+        None => Parse::NeedMore(2),
+    }
+}
+
+fn parse_int(line: &[u8]) -> Result<i64, Error> {
+    from_utf8(line).ok().and_then(|s| s.parse().ok())
+        .ok_or_else(|| invalid("Invalid integer"))
+}
+
+/// Parses the value at `pos`, the position after it is returned in
+/// `Parse::Done`
+fn parse_value(data: &[u8], pos: usize, depth: usize) -> Parse<Value> {
+    if pos >= data.len() {
+        // The type and the CRLF at least
+        return Parse::NeedMore(pos + 3 - data.len());
+    }
+    let (line, next) = match line(data, pos + 1) {
+        Parse::Done(line, next) => (line, next),
+        Parse::NeedMore(n) => return Parse::NeedMore(n),
+        Parse::Error(e) => return Parse::Error(e),
+    };
+    match data[pos] {
+        b'+' | b'-' => {
+            let text = match from_utf8(line) {
+                Ok(text) => text.to_string(),
+                Err(_) => return Parse::Error(invalid(
+                    "Invalid UTF-8 in simple string")),
+            };
+            if data[pos] == b'+' {
+                Parse::Done(Value::Simple(text), next)
+            } else {
+                Parse::Done(Value::Error(text), next)
+            }
+        }
+        b':' => match parse_int(line) {
+            Ok(n) => Parse::Done(Value::Integer(n), next),
+            Err(e) => Parse::Error(e),
+        },
+        b'$' => {
+            let len = match parse_int(line) {
+                Ok(len) => len,
+                Err(e) => return Parse::Error(e),
+            };
+            if len < 0 {
+                return Parse::Done(Value::Bulk(None), next);
+            }
+            if len > MAX_BULK {
+                return Parse::Error(invalid("Bulk string is too long"));
+            }
+            let end = next + len as usize;
+            if data.len() < end + 2 {
+                return Parse::NeedMore(end + 2 - data.len());
+            }
+            if &data[end..end+2] != b"\r\n" {
+                return Parse::Error(invalid(
+                    "Bulk string is not terminated"));
+            }
+            Parse::Done(Value::Bulk(Some(data[next..end].to_vec())), end + 2)
+        }
+        b'*' => {
+            let len = match parse_int(line) {
+                Ok(len) => len,
+                Err(e) => return Parse::Error(e),
+            };
+            if len < 0 {
+                return Parse::Done(Value::Array(None), next);
+            }
+            if len > MAX_ARRAY {
+                return Parse::Error(invalid("Array is too long"));
+            }
+            if depth >= MAX_DEPTH {
+                return Parse::Error(invalid("Arrays are nested too deep"));
+            }
+            let mut items = Vec::new();
+            let mut pos = next;
+            for _ in 0..len {
+                match parse_value(data, pos, depth + 1) {
+                    Parse::Done(item, next) => {
+                        items.push(item);
+                        pos = next;
+                    }
+                    Parse::NeedMore(n) => return Parse::NeedMore(n),
+                    Parse::Error(e) => return Parse::Error(e),
+                }
+            }
+            Parse::Done(Value::Array(Some(items)), pos)
+        }
+        _ => Parse::Error(invalid("Unknown type of value")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use netbuf::Buf;
+    use transports::parser::Parse;
+    use super::{parse, parse_command, encode, Value, MAX_LINE};
+
+    fn done<T>(result: Parse<T>) -> (T, usize) {
+        match result {
+            Parse::Done(value, n) => (value, n),
+            Parse::NeedMore(_) => panic!("need more"),
+            Parse::Error(e) => panic!("error: {}", e),
+        }
+    }
+
+    #[test]
+    fn command() {
+        let data = b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n*1\r\n";
+        assert_eq!(done(parse_command(data)),
+            (vec![b"GET".to_vec(), b"key".to_vec()], 22));
+        for i in 0..22 {
+            match parse_command(&data[..i]) {
+                // The hint never skips the end of the command
+                Parse::NeedMore(n) => assert!(i + n <= 22),
+                _ => panic!("partial command is parsed at {}", i),
+            }
+        }
+    }
+
+    #[test]
+    fn missing_bytes() {
+        match parse(b"$10\r\nabc") {
+            Parse::NeedMore(n) => assert_eq!(n, 9),
+            _ => panic!("partial bulk string is parsed"),
+        }
+        match parse(b"*2\r\n$1\r\na\r\n$10\r\nabc") {
+            Parse::NeedMore(n) => assert_eq!(n, 9),
+            _ => panic!("partial array is parsed"),
+        }
+    }
+
+    #[test]
+    fn long_line() {
+        let mut data = vec![b'+'; MAX_LINE + 1];
+        match parse(&data) {
+            Parse::NeedMore(n) => assert_eq!(n, 2),
+            _ => panic!("partial line is parsed"),
+        }
+        data.extend(b"\r\n");
+        assert_eq!(done(parse(&data)).1, data.len());
+        let data = vec![b':'; MAX_LINE + 3];
+        match parse(&data) {
+            Parse::Error(_) => {}
+            _ => panic!("too long line is accepted"),
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        match parse(b"$3\r\nabcd\r\n") {
+            Parse::Error(_) => {}
+            _ => panic!("unterminated bulk string is parsed"),
+        }
+        match parse_command(b":1\r\n") {
+            Parse::Error(_) => {}
+            _ => panic!("integer is parsed as a command"),
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let value = Value::Array(Some(vec![
+            Value::Simple("OK".to_string()),
+            Value::Error("ERR wrong".to_string()),
+            Value::Integer(-12),
+            Value::Bulk(Some(b"a\r\nb".to_vec())),
+            Value::Bulk(None),
+            Value::Array(None),
+        ]));
+        let mut buf = Buf::new();
+        encode(&mut buf, &value);
+        assert_eq!(done(parse(&buf[..])), (value, buf.len()));
+    }
+}
@@ -0,0 +1,223 @@
+//! Dispatching TLS connections by the server name (SNI)
+//!
+//! The `Sni` handshake waits for the `ClientHello` of the client, extracts
+//! the server name from it and asks the context, which implements `Route`,
+//! for the protocol of the connection:
+//!
+//! ```ignore
+//! impl Route<Backend> for Context {
+//!     fn route(&mut self, server_name: Option<&str>) -> Option<Backend> {
+//!         server_name.and_then(|name| self.tenants.get(name))
+//!             .map(|tenant| Backend::new(tenant))
+//!     }
+//! }
+//! type Conn = Stream<TcpStream, Upgrade<Sni<Backend>, Backend>, Context>;
+//! ```
+//!
+//! The `ClientHello` is not consumed, it's in the input buffer of the chosen
+//! protocol, so the protocol may pass the connection through to the backend
+//! or terminate TLS with the certificate of the tenant. Rotor has no TLS
+//! implementation itself.
+use std::io::{Error, ErrorKind};
+use std::marker::PhantomData;
+use std::str::from_utf8;
+
+use BaseMachine;
+use transports::greedy_stream::{Protocol, Handshake, Switch, Transport};
+
+/// The content type of the TLS handshake record
+const HANDSHAKE: u8 = 22;
+const CLIENT_HELLO: u8 = 1;
+const SERVER_NAME: u16 = 0;
+const HOST_NAME: u8 = 0;
+/// Maximum size of the TLS record
+const MAX_RECORD: usize = 16384;
+
+
+macro_rules! try_opt {
+    ($e:expr) => (match $e { Some(x) => x, None => return None })
+}
+
+/// The result of `parse_client_hello`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Hello {
+    /// The `ClientHello` is not received in full yet
+    NeedMore,
+    /// The server name, `None` if the client didn't send it
+    Done(Option<String>),
+    /// The data is not a TLS `ClientHello`
+    Invalid,
+}
+
+/// Chooses the protocol of the connection, implement it for the context
+pub trait Route<P> {
+    /// Returns `None` to close the connection
+    fn route(&mut self, server_name: Option<&str>) -> Option<P>;
+}
+
+/// The server-side handshake which switches to the protocol chosen by
+/// `Route`
+pub struct Sni<P>(PhantomData<P>);
+
+/// Extracts the server name from the `ClientHello` at the start of `data`
+///
+/// The `ClientHello` is expected to fit a single record, as it does with
+/// all the common clients
+pub fn parse_client_hello(data: &[u8]) -> Hello {
+    if data.len() < 5 {
+        return if data.len() > 0 && data[0] != HANDSHAKE {
+            Hello::Invalid
+        } else {
+            Hello::NeedMore
+        };
+    }
+    let len = read_u16(&data[3..]) as usize;
+    if data[0] != HANDSHAKE || len > MAX_RECORD {
+        return Hello::Invalid;
+    }
+    if data.len() < 5 + len {
+        return Hello::NeedMore;
+    }
+    match parse_handshake(&data[5..5+len]) {
+        Some(name) => Hello::Done(name),
+        None => Hello::Invalid,
+    }
+}
+
+fn read_u16(data: &[u8]) -> u16 {
+    (data[0] as u16) << 8 | data[1] as u16
+}
+
+/// Splits the field with the length prefix of `n` bytes from the `data`
+fn field(data: &[u8], n: usize) -> Option<(&[u8], &[u8])> {
+    if data.len() < n {
+        return None;
+    }
+    let len = data[..n].iter().fold(0, |len, &b| len << 8 | b as usize);
+    if data.len() < n + len {
+        return None;
+    }
+    Some((&data[n..n+len], &data[n+len..]))
+}
+
+/// Returns `None` if the message is invalid
+fn parse_handshake(data: &[u8]) -> Option<Option<String>> {
+    if data.len() < 4 || data[0] != CLIENT_HELLO {
+        return None;
+    }
+    let (body, _) = try_opt!(field(&data[1..], 3));
+    // Version and random
+    if body.len() < 34 {
+        return None;
+    }
+    let (_session_id, rest) = try_opt!(field(&body[34..], 1));
+    let (_ciphers, rest) = try_opt!(field(rest, 2));
+    let (_compression, rest) = try_opt!(field(rest, 1));
+    if rest.len() == 0 {
+        // No extensions
+        return Some(None);
+    }
+    let (mut exts, _) = try_opt!(field(rest, 2));
+    while exts.len() > 0 {
+        if exts.len() < 2 {
+            return None;
+        }
+        let kind = read_u16(exts);
+        let (ext, tail) = try_opt!(field(&exts[2..], 2));
+        exts = tail;
+        if kind != SERVER_NAME {
+            continue;
+        }
+        let (mut names, _) = try_opt!(field(ext, 2));
+        while names.len() > 0 {
+            let kind = names[0];
+            let (name, tail) = try_opt!(field(&names[1..], 2));
+            names = tail;
+            if kind == HOST_NAME {
+                return from_utf8(name).ok()
+                    .map(|name| Some(name.to_lowercase()));
+            }
+        }
+    }
+    Some(None)
+}
+
+impl<P: BaseMachine> BaseMachine for Sni<P> {
+    type Timeout = P::Timeout;
+}
+
+impl<P: Protocol<C>, C: Route<P>> Handshake<C> for Sni<P> {
+    type Next = P;
+    fn accepted(_ctx: &mut C) -> Self {
+        Sni(PhantomData)
+    }
+    fn data_received(self, transport: &mut Transport, ctx: &mut C)
+        -> Switch<Self, P>
+    {
+        match parse_client_hello(&transport.input()[..]) {
+            Hello::NeedMore => Switch::Stay(self),
+            Hello::Done(name) => {
+                match ctx.route(name.as_ref().map(|x| &x[..])) {
+                    Some(p) => Switch::Upgrade(p),
+                    None => {
+                        debug!("No route for the server name {:?}", name);
+                        Switch::Close
+                    }
+                }
+            }
+            Hello::Invalid => {
+                self.error_happened(Error::new(ErrorKind::InvalidData,
+                    "Invalid TLS ClientHello"), ctx);
+                Switch::Close
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_client_hello, Hello};
+
+    /// Returns the `ClientHello` record with the `extensions`
+    fn hello(extensions: &[u8]) -> Vec<u8> {
+        let mut body = vec![3, 3];
+        body.extend(&[0; 32]);  // random
+        body.extend(&[0]);  // session id
+        body.extend(&[0, 2, 0x13, 0x01]);  // cipher suites
+        body.extend(&[1, 0]);  // compression methods
+        body.extend(&[(extensions.len() >> 8) as u8, extensions.len() as u8]);
+        body.extend(extensions);
+        let mut msg = vec![1, 0, (body.len() >> 8) as u8, body.len() as u8];
+        msg.extend(body);
+        let mut record = vec![22, 3, 1,
+                              (msg.len() >> 8) as u8, msg.len() as u8];
+        record.extend(msg);
+        record
+    }
+
+    #[test]
+    fn server_name() {
+        let ext = b"\x00\x0b\x00\x02\x01\x00\
+                    \x00\x00\x00\x10\x00\x0e\x00\x00\x0bExample.com";
+        let data = hello(ext);
+        assert_eq!(parse_client_hello(&data),
+                   Hello::Done(Some("example.com".to_string())));
+        for n in 0..data.len() {
+            assert_eq!(parse_client_hello(&data[..n]), Hello::NeedMore);
+        }
+    }
+
+    #[test]
+    fn no_server_name() {
+        assert_eq!(parse_client_hello(&hello(b"\x00\x0b\x00\x02\x01\x00")),
+                   Hello::Done(None));
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(parse_client_hello(b"GET / HTTP/1.1\r\n"), Hello::Invalid);
+        let mut data = hello(b"\x00\x00\x00\x10");
+        data[5] = 2;
+        assert_eq!(parse_client_hello(&data), Hello::Invalid);
+    }
+}
@@ -0,0 +1,200 @@
+//! Serving TLS and plaintext connections on the same port
+//!
+//! The `Sniff` handshake waits for the first bytes of the connection and
+//! switches to the protocol `T` if they look like a TLS record, or to the
+//! protocol `P` otherwise:
+//!
+//! ```ignore
+//! type Conn = Stream<TcpStream,
+//!     Upgrade<Sniff<Https, Http>, Sniffed<Https, Http>>, Context>;
+//! ```
+//!
+//! The bytes are not consumed, they are in the input buffer of the chosen
+//! protocol. Rotor has no TLS implementation itself, the `T` protocol
+//! wraps one. Plaintext protocols in which the server speaks first (e.g.
+//! SMTP) can't be detected this way.
+use std::io::Error;
+use std::marker::PhantomData;
+
+use BaseMachine;
+use transports::greedy_stream::{Protocol, Handshake, Switch, Transport};
+use transports::greedy_stream::{Settings, Counters, Overflow, CloseReason};
+
+/// The content type of the TLS handshake record
+const HANDSHAKE: u8 = 22;
+/// The major version of SSL 3.0 and all TLS versions
+const MAJOR_VERSION: u8 = 3;
+
+
+/// The result of `detect`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// Not enough bytes to decide
+    NeedMore,
+    Tls,
+    Plain,
+}
+
+/// The server-side handshake which switches to the TLS protocol `T` or the
+/// plaintext protocol `P`
+pub struct Sniff<T, P>(PhantomData<(T, P)>);
+
+/// The protocol chosen by `Sniff`
+pub enum Sniffed<T, P> {
+    Tls(T),
+    Plain(P),
+}
+
+/// Checks whether `data` starts with a TLS handshake record
+///
+/// The SSL 2.0 compatible `ClientHello` is considered plaintext
+pub fn detect(data: &[u8]) -> Kind {
+    let prefix = [HANDSHAKE, MAJOR_VERSION];
+    let n = if data.len() < 2 { data.len() } else { 2 };
+    if data[..n] != prefix[..n] {
+        Kind::Plain
+    } else if data.len() < 3 {
+        Kind::NeedMore
+    } else if data[2] <= 4 {  // SSL 3.0 to TLS 1.3
+        Kind::Tls
+    } else {
+        Kind::Plain
+    }
+}
+
+impl<T: BaseMachine, P> BaseMachine for Sniff<T, P> {
+    type Timeout = T::Timeout;
+}
+
+impl<T, P, C> Handshake<C> for Sniff<T, P>
+    where T: Protocol<C>, P: Protocol<C, Timeout=T::Timeout>
+{
+    type Next = Sniffed<T, P>;
+    fn accepted(_ctx: &mut C) -> Self {
+        Sniff(PhantomData)
+    }
+    fn data_received(self, transport: &mut Transport, ctx: &mut C)
+        -> Switch<Self, Sniffed<T, P>>
+    {
+        match detect(&transport.input()[..]) {
+            Kind::NeedMore => Switch::Stay(self),
+            Kind::Tls => Switch::Upgrade(Sniffed::Tls(T::accepted(ctx))),
+            Kind::Plain => Switch::Upgrade(Sniffed::Plain(P::accepted(ctx))),
+        }
+    }
+}
+
+impl<T: BaseMachine, P> BaseMachine for Sniffed<T, P> {
+    type Timeout = T::Timeout;
+}
+
+impl<T, P, C> Protocol<C> for Sniffed<T, P>
+    where T: Protocol<C>, P: Protocol<C, Timeout=T::Timeout>
+{
+    /// Starts as plaintext, `Sniff` chooses the protocol instead
+    fn accepted(ctx: &mut C) -> Self {
+        Sniffed::Plain(P::accepted(ctx))
+    }
+    fn connected(self, transport: &mut Transport, ctx: &mut C)
+        -> Option<Self>
+    {
+        match self {
+            Sniffed::Tls(t) => t.connected(transport, ctx).map(Sniffed::Tls),
+            Sniffed::Plain(p) => p.connected(transport, ctx)
+                                  .map(Sniffed::Plain),
+        }
+    }
+    fn data_received(self, transport: &mut Transport, ctx: &mut C)
+        -> Option<Self>
+    {
+        match self {
+            Sniffed::Tls(t) => t.data_received(transport, ctx)
+                                .map(Sniffed::Tls),
+            Sniffed::Plain(p) => p.data_received(transport, ctx)
+                                  .map(Sniffed::Plain),
+        }
+    }
+    fn output_ready(self, transport: &mut Transport, ctx: &mut C)
+        -> Option<Self>
+    {
+        match self {
+            Sniffed::Tls(t) => t.output_ready(transport, ctx)
+                                .map(Sniffed::Tls),
+            Sniffed::Plain(p) => p.output_ready(transport, ctx)
+                                  .map(Sniffed::Plain),
+        }
+    }
+    fn eof_received(self, ctx: &mut C) {
+        match self {
+            Sniffed::Tls(t) => t.eof_received(ctx),
+            Sniffed::Plain(p) => p.eof_received(ctx),
+        }
+    }
+    fn error_happened(self, e: Error, ctx: &mut C) {
+        match self {
+            Sniffed::Tls(t) => t.error_happened(e, ctx),
+            Sniffed::Plain(p) => p.error_happened(e, ctx),
+        }
+    }
+    fn closed(self, reason: CloseReason, ctx: &mut C) {
+        match self {
+            Sniffed::Tls(t) => t.closed(reason, ctx),
+            Sniffed::Plain(p) => p.closed(reason, ctx),
+        }
+    }
+    /// Settings of the plaintext protocol are used for both, since they
+    /// are chosen before the first byte is received
+    fn settings(ctx: &mut C) -> Settings {
+        P::settings(ctx)
+    }
+    fn output_full(&mut self, counters: &Counters, ctx: &mut C) -> Overflow {
+        match *self {
+            Sniffed::Tls(ref mut t) => t.output_full(counters, ctx),
+            Sniffed::Plain(ref mut p) => p.output_full(counters, ctx),
+        }
+    }
+    fn input_overflow(self, transport: &mut Transport, ctx: &mut C)
+        -> Option<Self>
+    {
+        match self {
+            Sniffed::Tls(t) => t.input_overflow(transport, ctx)
+                                .map(Sniffed::Tls),
+            Sniffed::Plain(p) => p.input_overflow(transport, ctx)
+                                  .map(Sniffed::Plain),
+        }
+    }
+    fn drain_started(self, transport: &mut Transport, ctx: &mut C)
+        -> Option<Self>
+    {
+        match self {
+            Sniffed::Tls(t) => t.drain_started(transport, ctx)
+                                .map(Sniffed::Tls),
+            Sniffed::Plain(p) => p.drain_started(transport, ctx)
+                                  .map(Sniffed::Plain),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{detect, Kind};
+
+    #[test]
+    fn tls() {
+        assert_eq!(detect(b"\x16\x03\x01\x02\x00\x01"), Kind::Tls);
+        assert_eq!(detect(b"\x16\x03\x03"), Kind::Tls);
+        assert_eq!(detect(b""), Kind::NeedMore);
+        assert_eq!(detect(b"\x16"), Kind::NeedMore);
+        assert_eq!(detect(b"\x16\x03"), Kind::NeedMore);
+    }
+
+    #[test]
+    fn plain() {
+        assert_eq!(detect(b"GET / HTTP/1.1\r\n"), Kind::Plain);
+        assert_eq!(detect(b"G"), Kind::Plain);
+        assert_eq!(detect(b"\x16\x02"), Kind::Plain);
+        assert_eq!(detect(b"\x16\x03\x09"), Kind::Plain);
+        // SSL 2.0 compatible ClientHello
+        assert_eq!(detect(b"\x80\x2e\x01\x03\x01"), Kind::Plain);
+    }
+}
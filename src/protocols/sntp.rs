@@ -0,0 +1,266 @@
+//! SNTP (RFC 4330) client over the `udp` transport
+//!
+//! The client polls the server periodically and reports the clock offset
+//! to the context, which implements `Report`:
+//!
+//! ```ignore
+//! impl Report for Context {
+//!     fn time_sample(&mut self, sample: &Sample) {
+//!         self.clock_offset = sample.offset;
+//!     }
+//! }
+//! let sock = UdpSocket::bound(&"0.0.0.0:0".parse().unwrap()).unwrap();
+//! let machine = sntp::client(sock, Sntp::new(server)).unwrap();
+//! ```
+//!
+//! Only a single server is polled, so the samples should be filtered by
+//! the `Report` implementation if the clock is adjusted.
+use std::io::{self, Error, ErrorKind};
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use mio::udp::UdpSocket;
+
+use BaseMachine;
+use transports::udp::{Protocol, Packet, Transport, Datagram, Options};
+
+/// Size of the SNTP packet without extensions
+const PACKET: usize = 48;
+/// Seconds from 1900 (the NTP epoch) to 1970
+const UNIX_OFFSET: u64 = 2208988800;
+/// Leap indicator 0, version 4, mode 3 (client)
+const CLIENT_MODE: u8 = 0x23;
+const SERVER_MODE: u8 = 4;
+
+
+/// A result of the single poll of the server
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample {
+    /// Offset of the server clock relative to the local one, in seconds
+    pub offset: f64,
+    /// Round-trip delay, in seconds
+    pub delay: f64,
+    pub stratum: u8,
+    pub server: SocketAddr,
+}
+
+/// Receives the results of the `Sntp` client, implement it for the context
+pub trait Report {
+    fn time_sample(&mut self, sample: &Sample);
+    /// The server has not replied in time or replied with an error
+    ///
+    /// Default action is to log error on the info level
+    fn time_error(&mut self, e: &Error) {
+        info!("SNTP poll failed: {}", e);
+    }
+}
+
+/// A `udp::Protocol` which polls the SNTP server
+pub struct Sntp {
+    server: SocketAddr,
+    interval: u64,
+    response_timeout: u64,
+    pending: Option<Pending>,
+}
+
+/// The request waiting for the response
+struct Pending {
+    timestamp: u64,
+    sent: SystemTime,
+}
+
+/// Creates a state machine which polls the server right away
+pub fn client<C: Report>(sock: UdpSocket, sntp: Sntp)
+    -> io::Result<Datagram<Sntp, C>>
+{
+    let server = sntp.server;
+    let mut machine = try!(Datagram::new(sock, sntp, Options::default()));
+    machine.allow_peer(server);
+    machine.set_timeout(0);
+    Ok(machine)
+}
+
+impl Sntp {
+    /// Polls the `server` every 64 seconds, waits for response 1 second
+    pub fn new(server: SocketAddr) -> Sntp {
+        Sntp {
+            server: server,
+            interval: 64000,
+            response_timeout: 1000,
+            pending: None,
+        }
+    }
+    /// Sets the interval between polls in milliseconds
+    pub fn interval(mut self, ms: u64) -> Sntp {
+        self.interval = ms;
+        self
+    }
+    /// Sets how long to wait for the response in milliseconds
+    pub fn response_timeout(mut self, ms: u64) -> Sntp {
+        self.response_timeout = ms;
+        self
+    }
+}
+
+/// Converts the time to the 64-bit NTP timestamp
+fn to_ntp(time: SystemTime) -> io::Result<u64> {
+    let since = try!(time.duration_since(UNIX_EPOCH).map_err(|_| {
+        Error::new(ErrorKind::Other, "System time is before 1970")
+    }));
+    let frac = ((since.subsec_nanos() as u64) << 32) / 1000000000;
+    Ok(((since.as_secs() + UNIX_OFFSET) << 32) | frac)
+}
+
+/// Converts the NTP timestamp to seconds since the NTP epoch
+fn to_seconds(timestamp: u64) -> f64 {
+    (timestamp >> 32) as f64 + (timestamp & 0xFFFFFFFF) as f64 / 4294967296.0
+}
+
+fn read_timestamp(data: &[u8], pos: usize) -> u64 {
+    data[pos..pos+8].iter().fold(0, |acc, &b| (acc << 8) | b as u64)
+}
+
+fn encode_request(timestamp: u64) -> [u8; PACKET] {
+    let mut data = [0u8; PACKET];
+    data[0] = CLIENT_MODE;
+    for i in 0..8 {
+        data[40 + i] = (timestamp >> (56 - i*8)) as u8;
+    }
+    data
+}
+
+/// Computes the sample from the response to the `pending` request
+fn parse_response(data: &[u8], pending: &Pending, received: SystemTime,
+    server: SocketAddr)
+    -> io::Result<Sample>
+{
+    if data.len() < PACKET || data[0] & 0x07 != SERVER_MODE {
+        return Err(Error::new(ErrorKind::InvalidData,
+            "Invalid SNTP response"));
+    }
+    if read_timestamp(data, 24) != pending.timestamp {
+        return Err(Error::new(ErrorKind::InvalidData,
+            "SNTP response to unknown request"));
+    }
+    let stratum = data[1];
+    if stratum == 0 || data[0] >> 6 == 3 {
+        return Err(Error::new(ErrorKind::Other,
+            "SNTP server is not synchronized"));
+    }
+    let t1 = to_seconds(try!(to_ntp(pending.sent)));
+    let t2 = to_seconds(read_timestamp(data, 32));
+    let t3 = to_seconds(read_timestamp(data, 40));
+    let t4 = to_seconds(try!(to_ntp(received)));
+    Ok(Sample {
+        offset: ((t2 - t1) + (t3 - t4)) / 2.0,
+        delay: (t4 - t1) - (t3 - t2),
+        stratum: stratum,
+        server: server,
+    })
+}
+
+impl BaseMachine for Sntp {
+    type Timeout = ();
+}
+
+impl<C: Report> Protocol<C> for Sntp {
+    fn packet_received(mut self, packet: &Packet, transport: &mut Transport,
+        ctx: &mut C)
+        -> Option<Self>
+    {
+        let result = match self.pending {
+            Some(ref pending) => parse_response(packet.data, pending,
+                SystemTime::now(), self.server),
+            None => return Some(self),
+        };
+        match result {
+            Ok(sample) => ctx.time_sample(&sample),
+            // May be a late response to the previous request
+            Err(ref e) if e.kind() == ErrorKind::InvalidData => {
+                debug!("Ignoring SNTP packet: {}", e);
+                return Some(self);
+            }
+            Err(e) => ctx.time_error(&e),
+        }
+        self.pending = None;
+        transport.set_timeout(self.interval);
+        Some(self)
+    }
+    fn timeout(mut self, transport: &mut Transport, ctx: &mut C)
+        -> Option<Self>
+    {
+        if self.pending.take().is_some() {
+            ctx.time_error(&Error::new(ErrorKind::TimedOut,
+                "SNTP server has not responded in time"));
+            transport.set_timeout(self.interval);
+            return Some(self);
+        }
+        let sent = SystemTime::now();
+        let timestamp = match to_ntp(sent) {
+            Ok(timestamp) => timestamp,
+            Err(e) => {
+                ctx.time_error(&e);
+                transport.set_timeout(self.interval);
+                return Some(self);
+            }
+        };
+        match transport.send(&encode_request(timestamp), &self.server, None) {
+            Ok(_) => {
+                // Dropped packet is detected by the response timeout
+                self.pending = Some(Pending {
+                    timestamp: timestamp,
+                    sent: sent,
+                });
+                transport.set_timeout(self.response_timeout);
+            }
+            Err(e) => {
+                ctx.time_error(&e);
+                transport.set_timeout(self.interval);
+            }
+        }
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::ErrorKind;
+    use std::time::{Duration, UNIX_EPOCH};
+    use super::{Pending, encode_request, parse_response, to_ntp};
+
+    #[test]
+    fn offset_and_delay() {
+        let sent = UNIX_EPOCH + Duration::new(1500000000, 0);
+        let pending = Pending {
+            timestamp: to_ntp(sent).unwrap(),
+            sent: sent,
+        };
+        let mut response = encode_request(0);
+        response[0] = 0x24; // version 4, server
+        response[1] = 2;
+        response[24..32].copy_from_slice(
+            &encode_request(to_ntp(sent).unwrap())[40..]);
+        // The server is 10 seconds ahead, each way takes 0.25 seconds
+        let t2 = to_ntp(sent + Duration::new(10, 250000000)).unwrap();
+        let t3 = to_ntp(sent + Duration::new(10, 500000000)).unwrap();
+        response[32..40].copy_from_slice(&encode_request(t2)[40..]);
+        response[40..48].copy_from_slice(&encode_request(t3)[40..]);
+        let received = sent + Duration::new(0, 750000000);
+        let addr = "127.0.0.1:123".parse().unwrap();
+        let sample = parse_response(&response, &pending, received, addr)
+            .unwrap();
+        assert!((sample.offset - 10.0).abs() < 1e-6);
+        assert!((sample.delay - 0.5).abs() < 1e-6);
+        assert_eq!(sample.stratum, 2);
+
+        response[24] ^= 1;
+        assert!(parse_response(&response, &pending, received, addr)
+            .is_err());
+    }
+
+    #[test]
+    fn before_unix_epoch() {
+        let time = UNIX_EPOCH - Duration::new(1, 0);
+        assert_eq!(to_ntp(time).unwrap_err().kind(), ErrorKind::Other);
+    }
+}
@@ -0,0 +1,382 @@
+//! Client side of the SOCKS5 handshake (RFC 1928)
+//!
+//! ```ignore
+//! let proto = Socks5::new(Address::Domain("example.com".into(), 80), http);
+//! Stream::with_protocol(sock, Upgrade::Handshake(proto), ctx)
+//! ```
+use std::io::{Error, ErrorKind};
+
+use BaseMachine;
+use protocols::Address;
+use transports::greedy_stream::{Protocol, Handshake, Switch, Transport};
+
+const VERSION: u8 = 5;
+const METHOD_NONE: u8 = 0;
+const METHOD_PASSWORD: u8 = 2;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+
+/// The handshake which connects to `target` and then switches to `P`
+pub struct Socks5<P> {
+    state: State,
+    target: Address,
+    auth: Option<(String, String)>,
+    next: P,
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    /// Created for the accepted connection by mistake, it's closed
+    Accepted,
+    Greeting,
+    Auth,
+    Connect,
+}
+
+impl<P> Socks5<P> {
+    pub fn new(target: Address, next: P) -> Socks5<P> {
+        Socks5 {
+            state: State::Greeting,
+            target: target,
+            auth: None,
+            next: next,
+        }
+    }
+    /// Use username/password authentication (RFC 1929)
+    pub fn with_auth(mut self, user: &str, password: &str) -> Socks5<P> {
+        self.auth = Some((user.to_string(), password.to_string()));
+        self
+    }
+}
+
+impl<P: BaseMachine> BaseMachine for Socks5<P> {
+    type Timeout = P::Timeout;
+}
+
+impl<P: Protocol<C>, C> Handshake<C> for Socks5<P> {
+    type Next = P;
+    fn accepted(ctx: &mut C) -> Self {
+        Socks5 {
+            state: State::Accepted,
+            target: Address::Domain(String::new(), 0),
+            auth: None,
+            next: P::accepted(ctx),
+        }
+    }
+    fn connected(self, transport: &mut Transport, ctx: &mut C)
+        -> Switch<Self, P>
+    {
+        if let State::Accepted = self.state {
+            error!("Socks5 is a client protocol, use Stream::with_protocol");
+            return fail(self.next, "Not an outgoing connection", ctx);
+        }
+        let method = if self.auth.is_some() {
+            METHOD_PASSWORD
+        } else {
+            METHOD_NONE
+        };
+        transport.output().extend(&[VERSION, 1, method]);
+        Switch::Stay(self)
+    }
+    fn data_received(mut self, transport: &mut Transport, ctx: &mut C)
+        -> Switch<Self, P>
+    {
+        loop {
+            let inp = transport.input();
+            match self.state {
+                State::Accepted => {
+                    return fail(self.next, "Not an outgoing connection", ctx);
+                }
+                State::Greeting => {
+                    if inp.len() < 2 {
+                        return Switch::Stay(self);
+                    }
+                    let (version, method) = (inp[0], inp[1]);
+                    inp.consume(2);
+                    if version != VERSION {
+                        return fail(self.next, "Not a SOCKS5 proxy", ctx);
+                    }
+                    match (method, self.auth.is_some()) {
+                        (METHOD_NONE, false) => {
+                            self.state = State::Connect;
+                        }
+                        (METHOD_PASSWORD, true) => {
+                            self.state = State::Auth;
+                        }
+                        _ => {
+                            return fail(self.next,
+                                "No acceptable authentication method", ctx);
+                        }
+                    }
+                    let ok = match self.state {
+                        State::Auth => write_auth(&self.auth, transport),
+                        _ => write_connect(&self.target, transport),
+                    };
+                    if !ok {
+                        return fail(self.next,
+                            "Address or credentials are too long", ctx);
+                    }
+                }
+                State::Auth => {
+                    if inp.len() < 2 {
+                        return Switch::Stay(self);
+                    }
+                    let status = inp[1];
+                    inp.consume(2);
+                    if status != 0 {
+                        return fail(self.next, "Authentication failed", ctx);
+                    }
+                    self.state = State::Connect;
+                    if !write_connect(&self.target, transport) {
+                        return fail(self.next, "Address is too long", ctx);
+                    }
+                }
+                State::Connect => {
+                    if inp.len() < 5 {
+                        return Switch::Stay(self);
+                    }
+                    let len = match inp[3] {
+                        ATYP_IPV4 => 4 + 4 + 2,
+                        ATYP_DOMAIN => 4 + 1 + inp[4] as usize + 2,
+                        ATYP_IPV6 => 4 + 16 + 2,
+                        _ => {
+                            return fail(self.next,
+                                "Invalid address type in reply", ctx);
+                        }
+                    };
+                    if inp.len() < len {
+                        return Switch::Stay(self);
+                    }
+                    let reply = inp[1];
+                    inp.consume(len);
+                    if reply != 0 {
+                        return fail(self.next, reply_error(reply), ctx);
+                    }
+                    return Switch::Upgrade(self.next);
+                }
+            }
+        }
+    }
+    fn eof_received(self, ctx: &mut C) {
+        self.next.error_happened(Error::new(ErrorKind::UnexpectedEof,
+            "Proxy closed connection during handshake"), ctx)
+    }
+    fn error_happened(self, e: Error, ctx: &mut C) {
+        self.next.error_happened(e, ctx)
+    }
+}
+
+/// Reports the error to the protocol, so it knows that connection failed
+fn fail<P, C, H>(next: P, msg: &str, ctx: &mut C) -> Switch<H, P>
+    where P: Protocol<C>
+{
+    next.error_happened(Error::new(ErrorKind::Other,
+        format!("SOCKS5 handshake failed: {}", msg)), ctx);
+    Switch::Close
+}
+
+fn write_auth(auth: &Option<(String, String)>, transport: &mut Transport)
+    -> bool
+{
+    let &(ref user, ref password) = auth.as_ref().unwrap();
+    if user.len() > 255 || password.len() > 255 {
+        return false;
+    }
+    let out = transport.output();
+    out.extend(&[1, user.len() as u8]);
+    out.extend(user.as_bytes());
+    out.extend(&[password.len() as u8]);
+    out.extend(password.as_bytes());
+    true
+}
+
+fn write_connect(target: &Address, transport: &mut Transport) -> bool {
+    use std::net::SocketAddr::{V4, V6};
+    let out = transport.output();
+    let port = match *target {
+        Address::Ip(V4(ref addr)) => {
+            out.extend(&[VERSION, CMD_CONNECT, 0, ATYP_IPV4]);
+            out.extend(&addr.ip().octets());
+            addr.port()
+        }
+        Address::Ip(V6(ref addr)) => {
+            out.extend(&[VERSION, CMD_CONNECT, 0, ATYP_IPV6]);
+            out.extend(&addr.ip().octets());
+            addr.port()
+        }
+        Address::Domain(ref name, port) => {
+            if name.len() > 255 {
+                return false;
+            }
+            out.extend(&[VERSION, CMD_CONNECT, 0, ATYP_DOMAIN,
+                         name.len() as u8]);
+            out.extend(name.as_bytes());
+            port
+        }
+    };
+    out.extend(&[(port >> 8) as u8, port as u8]);
+    true
+}
+
+fn reply_error(code: u8) -> &'static str {
+    match code {
+        1 => "general SOCKS server failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Error;
+    use std::net::SocketAddr;
+    use BaseMachine;
+    use protocols::Address;
+    use transports::greedy_stream::{Protocol, Transport, Upgrade, fuzz_feed};
+    use super::Socks5;
+
+    #[derive(Default)]
+    struct Log {
+        data: Vec<u8>,
+        errors: Vec<String>,
+    }
+
+    /// Logs the data received after the handshake, and the errors
+    struct Tunnel;
+
+    impl BaseMachine for Tunnel {
+        type Timeout = ();
+    }
+
+    impl Protocol<Log> for Tunnel {
+        fn accepted(_ctx: &mut Log) -> Tunnel {
+            Tunnel
+        }
+        fn data_received(self, transport: &mut Transport, ctx: &mut Log)
+            -> Option<Tunnel>
+        {
+            let inp = transport.input();
+            let len = inp.len();
+            ctx.data.extend(&inp[..]);
+            inp.consume(len);
+            Some(Tunnel)
+        }
+        fn error_happened(self, e: Error, ctx: &mut Log) {
+            ctx.errors.push(e.to_string());
+        }
+    }
+
+    /// Runs the handshake with several sizes of the chunks, checks the
+    /// requests and returns the log
+    ///
+    /// Requests are not flushed when the handshake fails, so only their
+    /// start may be sent.
+    fn handshake(proto: fn() -> Socks5<Tunnel>, replies: &[u8],
+        requests: &[u8])
+        -> Log
+    {
+        let mut last = None;
+        for &chunk in &[0u8, 2, 63] {
+            let mut input = vec![chunk];
+            input.extend(replies);
+            let mut log = Log::default();
+            let output = fuzz_feed(Upgrade::Handshake(proto()), &mut log,
+                                   &input);
+            if log.errors.len() == 0 {
+                assert_eq!(output, requests);
+            } else {
+                assert!(requests.starts_with(&output));
+            }
+            last = Some(log);
+        }
+        last.unwrap()
+    }
+
+    fn domain() -> Socks5<Tunnel> {
+        Socks5::new(Address::Domain("example.com".into(), 80), Tunnel)
+    }
+
+    fn ipv4_auth() -> Socks5<Tunnel> {
+        let addr: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        Socks5::new(Address::Ip(addr), Tunnel).with_auth("user", "pass")
+    }
+
+    fn ipv6() -> Socks5<Tunnel> {
+        let addr: SocketAddr = "[::1]:443".parse().unwrap();
+        Socks5::new(Address::Ip(addr), Tunnel)
+    }
+
+    #[test]
+    fn connect_domain() {
+        let log = handshake(domain,
+            b"\x05\x00\x05\x00\x00\x03\x05proxy\x1f\x90hello",
+            b"\x05\x01\x00\x05\x01\x00\x03\x0bexample.com\x00\x50");
+        assert_eq!(log.data, b"hello");
+        assert_eq!(log.errors, Vec::<String>::new());
+    }
+
+    #[test]
+    fn connect_ipv4_with_auth() {
+        let log = handshake(ipv4_auth,
+            b"\x05\x02\x01\x00\x05\x00\x00\x01\x0a\x00\x00\x01\x1f\x90hi",
+            b"\x05\x01\x02\x01\x04user\x04pass\
+              \x05\x01\x00\x01\x7f\x00\x00\x01\x1f\x90");
+        assert_eq!(log.data, b"hi");
+        assert_eq!(log.errors, Vec::<String>::new());
+    }
+
+    #[test]
+    fn connect_ipv6() {
+        let mut replies = b"\x05\x00\x05\x00\x00\x04".to_vec();
+        replies.extend(&[0; 15]);
+        replies.extend(b"\x01\x01\xbbhi");
+        let mut requests = b"\x05\x01\x00\x05\x01\x00\x04".to_vec();
+        requests.extend(&[0; 15]);
+        requests.extend(b"\x01\x01\xbb");
+        let log = handshake(ipv6, &replies, &requests);
+        assert_eq!(log.data, b"hi");
+        assert_eq!(log.errors, Vec::<String>::new());
+    }
+
+    #[test]
+    fn rejections() {
+        let greeting = b"\x05\x01\x00";
+        let connect = b"\x05\x01\x00\x05\x01\x00\x03\x0bexample.com\x00\x50";
+        let cases: &[(fn() -> Socks5<Tunnel>, &[u8], &[u8], &str)] = &[
+            (domain, b"\x04\x00", greeting, "Not a SOCKS5 proxy"),
+            (domain, b"\x05\xff", greeting,
+             "No acceptable authentication method"),
+            (ipv4_auth, b"\x05\x02\x01\x01", b"\x05\x01\x02\x01\x04user\
+             \x04pass", "Authentication failed"),
+            (domain, b"\x05\x00\x05\x05\x00\x01\x00\x00\x00\x00\x00\x00",
+             connect, "connection refused"),
+            (domain, b"\x05\x00\x05\x00\x00\x07\x00", connect,
+             "Invalid address type in reply"),
+        ];
+        for &(proto, replies, requests, error) in cases {
+            let log = handshake(proto, replies, requests);
+            assert_eq!(log.data, b"");
+            assert_eq!(log.errors,
+                vec![format!("SOCKS5 handshake failed: {}", error)]);
+        }
+    }
+
+    #[test]
+    fn accepted_is_closed() {
+        let mut log = Log::default();
+        let proto = Upgrade::<Socks5<Tunnel>, Tunnel>::accepted(&mut log);
+        let output = fuzz_feed(proto, &mut log, b"\x00\x05\x01\x00");
+        assert_eq!(output, b"");
+        assert_eq!(log.errors,
+            vec!["SOCKS5 handshake failed: Not an outgoing connection"]);
+    }
+}
@@ -0,0 +1,327 @@
+//! Routing messages to machines by user-defined keys
+//!
+//! Keep `Registry` in the context. A machine registers itself under a key
+//! (session id, user id), other machines send messages by the key, which
+//! wakes up the receiver. The receiver takes messages in its `wakeup` with
+//! `take_message`.
+//!
+//! ```ignore
+//! // receiver, e.g. in `connected`
+//! ctx.sessions.register(scope, user_id);
+//! // sender
+//! ctx.sessions.send(scope, &user_id, Message::Kick).ok();
+//! // receiver, in wakeup
+//! while let Some(msg) = ctx.sessions.take_message(scope) { ... }
+//! ```
+//!
+//! Keys and pending messages are removed automatically when the machine
+//! is removed from the loop (registration is kept in `Scope::slot_data`).
+//! Machines are identified by `MachineId`, so messages are never delivered
+//! to the next machine in the same slot.
+//! Registrations are not moved with `Scope::migrate`. The registry is not
+//! `Send`, so create the context in the thread of the loop.
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::rc::{Rc, Weak};
+
+use {Scope, BaseMachine};
+use handler::{MachineId, NotifyError};
+
+
+pub struct Registry<K, T> {
+    inner: Rc<RefCell<Inner<K, T>>>,
+}
+
+/// The error of `Registry::send`, the message is returned back
+pub enum SendError<T> {
+    /// No machine is registered under the key
+    NotFound(T),
+    /// The machine can't be woken up
+    Wakeup(T, NotifyError),
+}
+
+struct Inner<K, T> {
+    machines: HashMap<K, MachineId>,
+    inbox: HashMap<MachineId, VecDeque<T>>,
+}
+
+/// Keys of the single machine, unregistered when the machine is removed
+struct Registration<K: Hash + Eq, T> {
+    registry: Option<Weak<RefCell<Inner<K, T>>>>,
+    id: Option<MachineId>,
+    keys: Vec<K>,
+}
+
+impl<K, T> Registry<K, T>
+    where K: Hash + Eq + Clone + 'static, T: 'static
+{
+    pub fn new() -> Registry<K, T> {
+        Registry {
+            inner: Rc::new(RefCell::new(Inner {
+                machines: HashMap::new(),
+                inbox: HashMap::new(),
+            })),
+        }
+    }
+    /// Registers the machine owning the `scope` under the `key`
+    ///
+    /// Returns the machine previously registered under the key, if any
+    pub fn register<M, S>(&mut self, scope: &mut S, key: K)
+        -> Option<MachineId>
+        where M: BaseMachine, S: Scope<M>
+    {
+        let id = scope.id();
+        let reg = scope.slot_data::<Registration<K, T>>();
+        reg.registry = Some(Rc::downgrade(&self.inner));
+        reg.id = Some(id);
+        reg.keys.push(key.clone());
+        self.inner.borrow_mut().machines.insert(key, id)
+    }
+    /// Removes the `key` if it belongs to the machine owning the `scope`
+    pub fn deregister<M, S>(&mut self, scope: &mut S, key: &K)
+        where M: BaseMachine, S: Scope<M>
+    {
+        let id = scope.id();
+        scope.slot_data::<Registration<K, T>>().keys.retain(|k| k != key);
+        let mut inner = self.inner.borrow_mut();
+        if inner.machines.get(key) == Some(&id) {
+            inner.machines.remove(key);
+        }
+    }
+    /// Returns the machine registered under the `key`
+    pub fn lookup(&self, key: &K) -> Option<MachineId> {
+        self.inner.borrow().machines.get(key).cloned()
+    }
+    /// Number of registered keys
+    pub fn len(&self) -> usize {
+        self.inner.borrow().machines.len()
+    }
+    /// Queues a message to the machine registered under the `key` and
+    /// wakes it up
+    pub fn send<M, S>(&mut self, scope: &mut S, key: &K, msg: T)
+        -> Result<(), SendError<T>>
+        where M: BaseMachine, S: Scope<M>
+    {
+        let id = match self.lookup(key) {
+            Some(id) if scope.is_alive(id) => id,
+            _ => return Err(SendError::NotFound(msg)),
+        };
+        if let Err(e) = scope.wakeup(id.token()) {
+            return Err(SendError::Wakeup(msg, e));
+        }
+        self.inner.borrow_mut().inbox.entry(id)
+            .or_insert_with(VecDeque::new)
+            .push_back(msg);
+        Ok(())
+    }
+    /// Returns next message to the machine owning the `scope`
+    pub fn take_message<M, S>(&mut self, scope: &S) -> Option<T>
+        where M: BaseMachine, S: Scope<M>
+    {
+        let me = scope.id();
+        let mut inner = self.inner.borrow_mut();
+        let (msg, empty) = match inner.inbox.get_mut(&me) {
+            Some(queue) => (queue.pop_front(), queue.len() == 0),
+            None => return None,
+        };
+        if empty {
+            inner.inbox.remove(&me);
+        }
+        msg
+    }
+}
+
+impl<K: Hash + Eq, T> Default for Registration<K, T> {
+    fn default() -> Registration<K, T> {
+        Registration {
+            registry: None,
+            id: None,
+            keys: Vec::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq, T> Drop for Registration<K, T> {
+    fn drop(&mut self) {
+        let inner = match self.registry.as_ref().and_then(|r| r.upgrade()) {
+            Some(inner) => inner,
+            None => return,
+        };
+        let id = match self.id {
+            Some(id) => id,
+            None => return,
+        };
+        let mut inner = inner.borrow_mut();
+        for key in &self.keys {
+            // The key may be taken over by another machine
+            if inner.machines.get(key) == Some(&id) {
+                inner.machines.remove(key);
+            }
+        }
+        inner.inbox.remove(&id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use mio::{self, EventLoop, EventSet, Token};
+    use {BaseMachine, EventMachine, Scope, Response};
+    use handler::{Handler, Notify};
+    use super::{Registry, SendError};
+
+    struct Context {
+        sessions: Registry<u32, &'static str>,
+        /// Machines are removed on wakeup
+        quit: bool,
+        log: Vec<String>,
+    }
+
+    /// The session registers under the key on the first wakeup and logs
+    /// messages afterwards, the sender sends `hello` to the key
+    enum Peer {
+        Session(u32, bool),
+        Sender(u32),
+    }
+
+    type Loop = EventLoop<Handler<Context, Peer>>;
+
+    impl BaseMachine for Peer {
+        type Timeout = ();
+    }
+
+    impl EventMachine<Context> for Peer {
+        fn ready<S>(self, _events: EventSet, _ctx: &mut Context,
+            _scope: &mut S)
+            -> Response<Self>
+            where S: Scope<Self>
+        {
+            Response::Continue(self)
+        }
+        fn register<S>(&mut self, _scope: &mut S) -> io::Result<()>
+            where S: Scope<Self>
+        {
+            Ok(())
+        }
+        fn wakeup<S>(self, ctx: &mut Context, scope: &mut S)
+            -> Response<Self>
+            where S: Scope<Self>
+        {
+            if ctx.quit {
+                return Response::Remove;
+            }
+            match self {
+                Peer::Session(key, false) => {
+                    if let Some(id) = ctx.sessions.register(scope, key) {
+                        ctx.log.push(format!("took over {:?}",
+                            id.token()));
+                    }
+                    Response::Continue(Peer::Session(key, true))
+                }
+                Peer::Session(key, true) => {
+                    while let Some(msg) = ctx.sessions.take_message(scope) {
+                        ctx.log.push(format!("{:?} got {}",
+                            scope.token(), msg));
+                    }
+                    Response::Continue(Peer::Session(key, true))
+                }
+                Peer::Sender(key) => {
+                    match ctx.sessions.send(scope, &key, "hello") {
+                        Ok(()) => {}
+                        Err(SendError::NotFound(_)) => {
+                            ctx.log.push(format!("{} not found", key));
+                        }
+                        Err(SendError::Wakeup(..)) => unreachable!(),
+                    }
+                    Response::Continue(self)
+                }
+            }
+        }
+    }
+
+    fn handler() -> (Handler<Context, Peer>, Loop) {
+        let mut eloop = EventLoop::new().unwrap();
+        let handler = Handler::new(Context {
+            sessions: Registry::new(),
+            quit: false,
+            log: Vec::new(),
+        }, &mut eloop);
+        (handler, eloop)
+    }
+
+    fn add(handler: &mut Handler<Context, Peer>, eloop: &mut Loop,
+        machine: Peer)
+        -> Token
+    {
+        let token = handler.add_machine(eloop, machine).unwrap();
+        wakeup(handler, eloop, token);
+        token
+    }
+
+    fn wakeup(handler: &mut Handler<Context, Peer>, eloop: &mut Loop,
+        token: Token)
+    {
+        let id = handler.machine_id(token);
+        mio::Handler::notify(handler, eloop, Notify::Wakeup(id));
+    }
+
+    /// Wakes up the machine with `quit` set
+    fn remove(handler: &mut Handler<Context, Peer>, eloop: &mut Loop,
+        token: Token)
+    {
+        handler.context().quit = true;
+        wakeup(handler, eloop, token);
+        handler.context().quit = false;
+    }
+
+    #[test]
+    fn send_and_take() {
+        let (mut handler, mut eloop) = handler();
+        let session = add(&mut handler, &mut eloop, Peer::Session(1, false));
+        assert_eq!(handler.context().sessions.lookup(&1),
+            Some(handler.machine_id(session)));
+        add(&mut handler, &mut eloop, Peer::Sender(1));
+        add(&mut handler, &mut eloop, Peer::Sender(2));
+        wakeup(&mut handler, &mut eloop, session);
+        assert_eq!(handler.context().log,
+            vec!["2 not found".to_string(),
+                 format!("{:?} got hello", session)]);
+        assert!(handler.context().sessions.inner.borrow().inbox.is_empty());
+    }
+
+    #[test]
+    fn key_takeover() {
+        let (mut handler, mut eloop) = handler();
+        let first = add(&mut handler, &mut eloop, Peer::Session(1, false));
+        let second = add(&mut handler, &mut eloop, Peer::Session(1, false));
+        assert_eq!(handler.context().log,
+            vec![format!("took over {:?}", first)]);
+        // The key stays with the new owner when the old one is removed
+        remove(&mut handler, &mut eloop, first);
+        assert_eq!(handler.context().sessions.lookup(&1),
+            Some(handler.machine_id(second)));
+        add(&mut handler, &mut eloop, Peer::Sender(1));
+        wakeup(&mut handler, &mut eloop, second);
+        assert_eq!(handler.context().log[1],
+            format!("{:?} got hello", second));
+    }
+
+    #[test]
+    fn removed_machine() {
+        let (mut handler, mut eloop) = handler();
+        let session = add(&mut handler, &mut eloop, Peer::Session(1, false));
+        add(&mut handler, &mut eloop, Peer::Sender(1));
+        assert_eq!(handler.context().sessions.inner.borrow().inbox.len(), 1);
+        remove(&mut handler, &mut eloop, session);
+        assert_eq!(handler.context().sessions.len(), 0);
+        assert!(handler.context().sessions.inner.borrow().inbox.is_empty());
+        // The next machine in the slot doesn't get the key
+        let next = handler.add_machine(&mut eloop, Peer::Session(2, true))
+            .unwrap();
+        assert_eq!(next, session);
+        add(&mut handler, &mut eloop, Peer::Sender(1));
+        wakeup(&mut handler, &mut eloop, next);
+        assert_eq!(handler.context().log, vec!["1 not found"]);
+    }
+}
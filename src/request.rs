@@ -0,0 +1,415 @@
+//! Request/response correlation between machines of the same loop
+//!
+//! Keep `Requests` in the context. The requesting machine calls `request`,
+//! which wakes up the responder. The responder takes requests in its
+//! `wakeup` with `take_request` and answers with `respond`, which wakes up
+//! the requester. The requester picks the answer up by `RequestId` with
+//! `response`.
+//!
+//! ```ignore
+//! // requester
+//! self.id = try!(ctx.requests.request(scope, resolver, name));
+//! // responder, in wakeup
+//! while let Some((id, name)) = ctx.requests.take_request(scope) {
+//!     ctx.requests.respond(scope, id, resolve(name)).ok();
+//! }
+//! // requester, in wakeup
+//! if let Some(addr) = ctx.requests.response(self.id) { ... }
+//! ```
+//!
+//! Machines are identified by `MachineId`, so requests to a machine which
+//! is removed are never delivered to the next machine in the same slot,
+//! and responses to a removed requester are dropped. Requests and
+//! responses of a machine are forgotten when it is removed from the loop
+//! (the machine is tracked in `Scope::slot_data`). `Requests` is not
+//! `Send`, so create the context in the thread of the loop.
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::{Rc, Weak};
+
+use {Scope, BaseMachine};
+use handler::{MachineId, NotifyError};
+
+
+/// Identifier of the request, unique within `Requests`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RequestId(u64);
+
+/// Error of `Requests::request`
+#[derive(Debug)]
+pub enum RequestError {
+    /// The responder is removed from the loop
+    NoResponder,
+    /// Can't wake up the responder, the request is not queued
+    Notify(NotifyError),
+}
+
+pub struct Requests<Q, R> {
+    inner: Rc<RefCell<Inner<Q, R>>>,
+}
+
+struct Inner<Q, R> {
+    next_id: u64,
+    inbox: HashMap<MachineId, VecDeque<(RequestId, Q)>>,
+    /// Requester of every request which is not answered yet
+    requesters: HashMap<RequestId, MachineId>,
+    /// Responses which are not picked up yet, with their requesters
+    responses: HashMap<RequestId, (MachineId, R)>,
+}
+
+/// Marks the machine which takes part in requests, everything kept for
+/// it is dropped when the machine is removed
+struct Endpoint<Q, R> {
+    requests: Option<Weak<RefCell<Inner<Q, R>>>>,
+    id: Option<MachineId>,
+}
+
+impl<Q: 'static, R: 'static> Requests<Q, R> {
+    pub fn new() -> Requests<Q, R> {
+        Requests {
+            inner: Rc::new(RefCell::new(Inner {
+                next_id: 0,
+                inbox: HashMap::new(),
+                requesters: HashMap::new(),
+                responses: HashMap::new(),
+            })),
+        }
+    }
+    /// Queues a request to the machine `to` and wakes it up
+    ///
+    /// The response is delivered to the machine owning the `scope`
+    pub fn request<M, S>(&mut self, scope: &mut S, to: MachineId, msg: Q)
+        -> Result<RequestId, RequestError>
+        where M: BaseMachine, S: Scope<M>
+    {
+        if !scope.is_alive(to) {
+            self.clear_inbox(to);
+            return Err(RequestError::NoResponder);
+        }
+        try!(scope.wakeup(to.token()).map_err(RequestError::Notify));
+        self.track(scope);
+        let mut inner = self.inner.borrow_mut();
+        if !inner.inbox.contains_key(&to) {
+            // Responders removed before taking any request are not
+            // tracked, so sweep their inboxes when a new one is created
+            let dead = inner.inbox.keys().cloned()
+                .filter(|&id| !scope.is_alive(id))
+                .collect::<Vec<_>>();
+            for id in dead {
+                inner.clear_inbox(id);
+            }
+        }
+        let id = RequestId(inner.next_id);
+        inner.next_id += 1;
+        inner.inbox.entry(to).or_insert_with(VecDeque::new)
+            .push_back((id, msg));
+        inner.requesters.insert(id, scope.id());
+        Ok(id)
+    }
+    /// Returns next request to the machine owning the `scope`
+    ///
+    /// Requests of the machines which are removed are skipped
+    pub fn take_request<M, S>(&mut self, scope: &mut S)
+        -> Option<(RequestId, Q)>
+        where M: BaseMachine, S: Scope<M>
+    {
+        let me = scope.id();
+        if !self.inner.borrow().inbox.contains_key(&me) {
+            return None;
+        }
+        self.track(scope);
+        let mut inner = self.inner.borrow_mut();
+        let inner = &mut *inner;
+        let mut result = None;
+        let empty = match inner.inbox.get_mut(&me) {
+            Some(queue) => {
+                while let Some((id, msg)) = queue.pop_front() {
+                    let alive = inner.requesters.get(&id)
+                        .map(|&r| scope.is_alive(r)).unwrap_or(false);
+                    if alive {
+                        result = Some((id, msg));
+                        break;
+                    }
+                    inner.requesters.remove(&id);
+                }
+                queue.len() == 0
+            }
+            None => return None,
+        };
+        if empty {
+            inner.inbox.remove(&me);
+        }
+        result
+    }
+    /// Stores the response and wakes up the requester
+    ///
+    /// The response is dropped if the request is cancelled or the requester
+    /// is removed
+    pub fn respond<M, S>(&mut self, scope: &mut S, id: RequestId, value: R)
+        -> Result<(), NotifyError>
+        where M: BaseMachine, S: Scope<M>
+    {
+        let mut inner = self.inner.borrow_mut();
+        match inner.requesters.remove(&id) {
+            Some(requester) if scope.is_alive(requester) => {
+                inner.responses.insert(id, (requester, value));
+                scope.wakeup(requester.token())
+            }
+            _ => Ok(()),
+        }
+    }
+    /// Returns the response to the request, if it's ready
+    pub fn response(&mut self, id: RequestId) -> Option<R> {
+        self.inner.borrow_mut().responses.remove(&id).map(|(_, value)| value)
+    }
+    /// Forgets the request, e.g. when the requester is shutting down
+    ///
+    /// The request is skipped if responder has not taken it yet, otherwise
+    /// the response is dropped
+    pub fn cancel(&mut self, id: RequestId) {
+        let mut inner = self.inner.borrow_mut();
+        inner.requesters.remove(&id);
+        inner.responses.remove(&id);
+    }
+    /// Drops requests to the machine, e.g. when it's shutting down
+    pub fn clear_inbox(&mut self, me: MachineId) {
+        self.inner.borrow_mut().clear_inbox(me);
+    }
+    /// Makes sure everything kept for the machine owning the `scope` is
+    /// dropped when it's removed
+    fn track<M, S>(&self, scope: &mut S)
+        where M: BaseMachine, S: Scope<M>
+    {
+        let id = scope.id();
+        let endpoint = scope.slot_data::<Endpoint<Q, R>>();
+        if endpoint.id != Some(id) {
+            endpoint.requests = Some(Rc::downgrade(&self.inner));
+            endpoint.id = Some(id);
+        }
+    }
+}
+
+impl<Q, R> Inner<Q, R> {
+    fn clear_inbox(&mut self, me: MachineId) {
+        if let Some(queue) = self.inbox.remove(&me) {
+            for (id, _) in queue {
+                self.requesters.remove(&id);
+            }
+        }
+    }
+}
+
+impl<Q, R> Default for Endpoint<Q, R> {
+    fn default() -> Endpoint<Q, R> {
+        Endpoint {
+            requests: None,
+            id: None,
+        }
+    }
+}
+
+impl<Q, R> Drop for Endpoint<Q, R> {
+    fn drop(&mut self) {
+        let inner = match self.requests.as_ref().and_then(|r| r.upgrade()) {
+            Some(inner) => inner,
+            None => return,
+        };
+        let id = match self.id {
+            Some(id) => id,
+            None => return,
+        };
+        let mut inner = inner.borrow_mut();
+        inner.clear_inbox(id);
+        inner.requesters.retain(|_, requester| *requester != id);
+        inner.responses.retain(|_, &mut (requester, _)| requester != id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use mio::{self, EventLoop, EventSet, Token};
+    use {BaseMachine, EventMachine, Scope, Response};
+    use handler::{Handler, MachineId, Notify};
+    use super::{Requests, RequestId};
+
+    struct Context {
+        requests: Requests<u32, u32>,
+        responder: Option<MachineId>,
+        /// Machines are removed on wakeup
+        quit: bool,
+        log: Vec<String>,
+    }
+
+    /// The responder doubles the numbers, the requester sends `21`
+    enum Peer {
+        Responder,
+        Requester(Option<RequestId>),
+    }
+
+    type Loop = EventLoop<Handler<Context, Peer>>;
+
+    impl BaseMachine for Peer {
+        type Timeout = ();
+    }
+
+    impl EventMachine<Context> for Peer {
+        fn ready<S>(self, _events: EventSet, _ctx: &mut Context,
+            _scope: &mut S)
+            -> Response<Self>
+            where S: Scope<Self>
+        {
+            Response::Continue(self)
+        }
+        fn register<S>(&mut self, _scope: &mut S) -> io::Result<()>
+            where S: Scope<Self>
+        {
+            Ok(())
+        }
+        fn wakeup<S>(self, ctx: &mut Context, scope: &mut S)
+            -> Response<Self>
+            where S: Scope<Self>
+        {
+            if ctx.quit {
+                return Response::Remove;
+            }
+            match self {
+                Peer::Responder => {
+                    ctx.responder = Some(scope.id());
+                    while let Some((id, n)) = ctx.requests.take_request(scope)
+                    {
+                        ctx.log.push(format!("request {}", n));
+                        ctx.requests.respond(scope, id, n * 2).unwrap();
+                    }
+                    Response::Continue(self)
+                }
+                Peer::Requester(None) => {
+                    let to = ctx.responder.unwrap();
+                    match ctx.requests.request(scope, to, 21) {
+                        Ok(id) => {
+                            Response::Continue(Peer::Requester(Some(id)))
+                        }
+                        Err(e) => {
+                            ctx.log.push(format!("{:?}", e));
+                            Response::Continue(self)
+                        }
+                    }
+                }
+                Peer::Requester(Some(id)) => {
+                    if let Some(n) = ctx.requests.response(id) {
+                        ctx.log.push(format!("response {}", n));
+                    }
+                    Response::Continue(self)
+                }
+            }
+        }
+    }
+
+    fn handler() -> (Handler<Context, Peer>, Loop) {
+        let mut eloop = EventLoop::new().unwrap();
+        let handler = Handler::new(Context {
+            requests: Requests::new(),
+            responder: None,
+            quit: false,
+            log: Vec::new(),
+        }, &mut eloop);
+        (handler, eloop)
+    }
+
+    fn wakeup(handler: &mut Handler<Context, Peer>, eloop: &mut Loop,
+        token: Token)
+    {
+        let id = handler.machine_id(token);
+        mio::Handler::notify(handler, eloop, Notify::Wakeup(id));
+    }
+
+    /// Wakes up the machine with `quit` set
+    fn remove(handler: &mut Handler<Context, Peer>, eloop: &mut Loop,
+        token: Token)
+    {
+        handler.context().quit = true;
+        wakeup(handler, eloop, token);
+        handler.context().quit = false;
+    }
+
+    #[test]
+    fn round_trip() {
+        let (mut handler, mut eloop) = handler();
+        let responder = handler.add_machine(&mut eloop, Peer::Responder)
+            .unwrap();
+        let requester = handler.add_machine(&mut eloop, Peer::Requester(None))
+            .unwrap();
+        for &token in &[responder, requester, responder, requester] {
+            wakeup(&mut handler, &mut eloop, token);
+        }
+        assert_eq!(handler.context().log, vec!["request 21", "response 42"]);
+    }
+
+    #[test]
+    fn removed_responder() {
+        let (mut handler, mut eloop) = handler();
+        let responder = handler.add_machine(&mut eloop, Peer::Responder)
+            .unwrap();
+        wakeup(&mut handler, &mut eloop, responder);
+        remove(&mut handler, &mut eloop, responder);
+        // The slot is reused by the machine which doesn't handle requests
+        let other = handler.add_machine(&mut eloop, Peer::Requester(None))
+            .unwrap();
+        assert_eq!(other, responder);
+        let requester = handler.add_machine(&mut eloop, Peer::Requester(None))
+            .unwrap();
+        wakeup(&mut handler, &mut eloop, requester);
+        assert_eq!(handler.context().log, vec!["NoResponder"]);
+    }
+
+    #[test]
+    fn removed_requester() {
+        let (mut handler, mut eloop) = handler();
+        let responder = handler.add_machine(&mut eloop, Peer::Responder)
+            .unwrap();
+        let requester = handler.add_machine(&mut eloop, Peer::Requester(None))
+            .unwrap();
+        wakeup(&mut handler, &mut eloop, responder);
+        wakeup(&mut handler, &mut eloop, requester);
+        remove(&mut handler, &mut eloop, requester);
+        let other = handler.add_machine(&mut eloop, Peer::Requester(None))
+            .unwrap();
+        assert_eq!(other, requester);
+        // The request of the removed machine is skipped
+        wakeup(&mut handler, &mut eloop, responder);
+        assert!(handler.context().log.is_empty());
+        assert!(handler.context().requests.inner.borrow().requesters
+            .is_empty());
+    }
+
+    #[test]
+    fn forgotten_on_removal() {
+        let (mut handler, mut eloop) = handler();
+        let responder = handler.add_machine(&mut eloop, Peer::Responder)
+            .unwrap();
+        let requester = handler.add_machine(&mut eloop, Peer::Requester(None))
+            .unwrap();
+        let waiting = handler.add_machine(&mut eloop, Peer::Requester(None))
+            .unwrap();
+        // The response to `requester` is not picked up and the request
+        // of `waiting` is not taken
+        for &token in &[responder, requester, responder, waiting] {
+            wakeup(&mut handler, &mut eloop, token);
+        }
+        {
+            let inner = handler.context().requests.inner.borrow();
+            assert_eq!(inner.responses.len(), 1);
+            assert_eq!(inner.inbox.len(), 1);
+            assert_eq!(inner.requesters.len(), 1);
+        }
+        remove(&mut handler, &mut eloop, requester);
+        assert!(handler.context().requests.inner.borrow().responses
+            .is_empty());
+        remove(&mut handler, &mut eloop, responder);
+        remove(&mut handler, &mut eloop, waiting);
+        let inner = handler.context().requests.inner.borrow();
+        assert!(inner.inbox.is_empty());
+        assert!(inner.requesters.is_empty());
+        assert!(inner.responses.is_empty());
+    }
+}
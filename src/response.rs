@@ -0,0 +1,56 @@
+//! The result of the machine callbacks
+use error::Error;
+
+
+/// What the loop should do with the machine after the callback
+pub enum Response<M> {
+    /// Keep the machine in the loop
+    Continue(M),
+    /// The machine is done, remove it from the loop
+    Remove,
+    /// The machine failed, the error is logged and the machine is removed
+    Error(Error),
+    /// Put another machine into the slot and call its `register`
+    ///
+    /// The sockets of the old machine must be dropped by this moment,
+    /// or passed to the new one, which may register them again.
+    /// Unlike `Scope::replace_self` it's useful to switch to a machine
+    /// which owns different sockets. It takes precedence over the machine
+    /// passed to `Scope::replace_self` in the same callback.
+    Replace(M),
+}
+
+impl<M> Response<M> {
+    /// Converts the machine inside the response
+    ///
+    /// Used to wrap responses of the child machines by compositions
+    pub fn map<N, F>(self, f: F) -> Response<N>
+        where F: FnOnce(M) -> N
+    {
+        use self::Response::*;
+        match self {
+            Continue(m) => Continue(f(m)),
+            Remove => Remove,
+            Error(e) => Error(e),
+            Replace(m) => Replace(f(m)),
+        }
+    }
+    /// Returns `true` unless the machine is going to be removed
+    pub fn is_alive(&self) -> bool {
+        use self::Response::*;
+        match *self {
+            Continue(_) | Replace(_) => true,
+            Remove | Error(_) => false,
+        }
+    }
+}
+
+/// `Some(m)` is `Continue(m)`, `None` is `Remove`
+impl<M> From<Option<M>> for Response<M> {
+    fn from(value: Option<M>) -> Response<M> {
+        match value {
+            Some(m) => Response::Continue(m),
+            None => Response::Remove,
+        }
+    }
+}
@@ -1,16 +1,225 @@
 use std::io;
+use std::any::Any;
+use std::time::Instant;
 
-use mio::{Timeout, TimerError, Evented, EventSet, PollOpt};
+use mio::{Token, Timeout, TimerError, Evented, EventSet, PollOpt};
 
 use BaseMachine;
+use handler::{MachineId, Notifier, NotifyError, Priority, SpawnError};
+use handler::Target;
+use stats::Stats;
 
 
 pub trait Scope<M:BaseMachine> {
+    /// Adds the machine to the loop after the current callback returns
+    ///
+    /// Fails if there is no free slot for the machine. The machine is
+    /// registered later, so errors of the registration are reported to
+    /// its `EventMachine::abort`.
     fn async_add_machine(&mut self, m: M) -> Result<(), M>;
+    /// Adds a child of the current machine to the loop
+    ///
+    /// Like with `async_add_machine`, the child is registered after the
+    /// callback returns, but the slot is reserved right away, so the token
+    /// of the child is returned. When the current machine is removed (or
+    /// migrated), `EventMachine::shutdown` is called for its children. When
+    /// a child is removed, the current machine gets
+    /// `EventMachine::child_terminated`. Fails if there is no free slot.
+    fn add_child(&mut self, m: M) -> Result<Token, M>;
     fn add_timeout_ms(&mut self, delay: u64, t: M::Timeout)
         -> Result<Timeout, TimerError>;
     fn clear_timeout(&mut self, timeout: Timeout) -> bool;
+    /// Sets the timeout at the `deadline`, the delay is counted from `now()`
+    ///
+    /// The timeout fires right away if the deadline is in the past
+    fn add_timeout_at(&mut self, deadline: Instant, t: M::Timeout)
+        -> Result<Timeout, TimerError>
+    {
+        let now = self.now();
+        let delay = if deadline > now {
+            let delay = deadline - now;
+            delay.as_secs() * 1000 + (delay.subsec_nanos() as u64 + 999999)
+                / 1000000
+        } else {
+            0
+        };
+        self.add_timeout_ms(delay, t)
+    }
+    /// Adds the machine to the loop after `delay` milliseconds
+    ///
+    /// The machine is not registered until then. Clearing the returned
+    /// timeout drops the machine. Machines are not spawned when the loop is
+    /// shutting down.
+    fn spawn_after(&mut self, delay: u64, m: M)
+        -> Result<Timeout, TimerError>;
     fn register<E: ?Sized>(&mut self, io: &E, interest: EventSet, opt: PollOpt)
         -> Result<(), io::Error>
         where E: Evented;
+    /// Changes the interest of the socket registered by `register`
+    fn reregister<E: ?Sized>(&mut self, io: &E, interest: EventSet,
+        opt: PollOpt)
+        -> Result<(), io::Error>
+        where E: Evented;
+    fn deregister<E: ?Sized>(&mut self, io: &E) -> Result<(), io::Error>
+        where E: Evented;
+    /// Returns the token of the current machine
+    ///
+    /// Tokens are `Hash` and `Ord`, so they may be used as keys of the
+    /// routing tables in the context. The token is the index of the slot in
+    /// the loop, it's reused after the machine is removed, so remove the
+    /// token from such tables in the last callback of the machine, or use
+    /// `id` instead.
+    fn token(&self) -> Token;
+    /// Returns the identity of the current machine
+    ///
+    /// The id is the token along with the generation of the slot, so the id
+    /// of a removed machine never matches the next machine in the slot.
+    /// Check the ids kept in the context with `is_alive`.
+    fn id(&self) -> MachineId;
+    /// Returns `true` if the machine with `id` is still in the loop
+    fn is_alive(&self, id: MachineId) -> bool;
+    /// Returns the time of the current iteration of the loop
+    ///
+    /// The clock is read once per iteration, so it's cheaper than
+    /// `Instant::now()` on hot paths, but it lags behind while the iteration
+    /// runs. Use `Instant::now()` to measure the duration of the work.
+    fn now(&self) -> Instant;
+    /// Returns `now()` in milliseconds since the handler is created
+    fn now_ms(&self) -> u64;
+    /// Returns loop statistics if they are enabled
+    fn loop_stats(&self) -> Option<&Stats>;
+    /// Returns a handle to wake up the machine from any thread
+    fn notifier(&self) -> Notifier;
+    /// Replaces the machine currently being processed by `m`
+    ///
+    /// The replacement takes place after the callback returns, so the value
+    /// returned by the callback is dropped. Token and registration in the
+    /// event loop are preserved, so the new machine should take ownership of
+    /// the socket of the old one. To switch to a machine of a different kind
+    /// compose both of them with `rotor_compose_state_machines!` and call
+    /// this method on the parent scope.
+    ///
+    /// Don't combine it with `Response::Replace`: when the callback returns
+    /// `Response::Replace`, that machine is used and `m` is dropped with an
+    /// error logged.
+    fn replace_self(&mut self, m: M);
+
+    /// Calls `f` with the token of every machine in the loop
+    ///
+    /// Machines may be added or removed while running callbacks, so use
+    /// `wakeup` (which is deferred) rather than keeping the tokens. The
+    /// current machine is included.
+    fn for_each_machine<F>(&self, f: F)
+        where F: FnMut(Token);
+    /// Schedules `EventMachine::wakeup` call for the machine with `token`
+    ///
+    /// Fails if notification queue of the event loop is full
+    fn wakeup(&mut self, token: Token) -> Result<(), NotifyError>;
+
+    /// Starts shutdown of the event loop after the callback returns
+    ///
+    /// See `Handler::shutdown` for the details
+    fn shutdown_loop(&mut self);
+    /// Shuts down the event loop like `shutdown_loop`, and makes
+    /// `Handler::run` return `Exit::Status(status)`
+    fn exit_loop(&mut self, status: i32);
+    /// Calls `EventMachine::shutdown` of this machine after the callback
+    ///
+    /// The machine may finish its work and remove itself. It's removed
+    /// anyway when the shutdown deadline expires.
+    fn shutdown_self(&mut self);
+
+    /// Returns a value of type `T` attached to the current machine
+    ///
+    /// The value is created with `Default` on first access. This lets
+    /// wrappers and cross-cutting layers (metrics, ACLs, deadlines) keep
+    /// per-connection state without changing the machines. The data is
+    /// dropped when the machine is removed, and it's not moved with
+    /// `migrate`.
+    fn slot_data<T: Any + Default>(&mut self) -> &mut T;
+    /// Detaches the value of type `T` from the current machine
+    fn remove_slot_data<T: Any>(&mut self) -> Option<T>;
+    /// Sets the class of the current machine for ordering I/O events
+    ///
+    /// The default is `Priority::Interactive`. Only has effect when
+    /// `Handler::enable_priorities` is called. Like other slot data, it's
+    /// not moved by `migrate`.
+    fn set_priority(&mut self, priority: Priority) {
+        *self.slot_data::<Priority>() = priority;
+    }
+
+    /// Schedules `EventMachine::tick` call for the current machine
+    ///
+    /// The tick is a low priority work: it's run after all the events,
+    /// timeouts and notifications of the current iteration of the loop are
+    /// processed. Multiple requests result in multiple calls.
+    fn request_tick(&mut self);
+    /// Writes the report of all the machines of the loop
+    ///
+    /// See `Handler::dump`, the current machine is written without the
+    /// details. Useful for the diagnostics over a control socket.
+    fn dump_machines(&self, out: &mut io::Write) -> io::Result<()>;
+
+    /// Calls `EventMachine::shutdown` of the current machine after `ms`
+    /// milliseconds, regardless of its activity
+    ///
+    /// Like `shutdown_self`, the machine is removed anyway when the shutdown
+    /// deadline expires. Calling it again replaces the previous limit. Call
+    /// it in `EventMachine::register` (or in `add_machine_with`) to limit
+    /// the lifetime from the start.
+    fn set_max_lifetime(&mut self, ms: u64) -> Result<(), TimerError>;
+
+    /// Moves the machine with `token` to another event loop
+    ///
+    /// `EventMachine::deregister` is called for the machine before it's sent
+    /// to the `target`, and `EventMachine::register` is called by the loop
+    /// which receives it. Notifiers of the machine are not valid after the
+    /// migration. Returns `false` if there is no such machine or it can't
+    /// be sent, the machine is registered back in the latter case.
+    ///
+    /// The current machine is moved after the callback returns, so `true`
+    /// is always returned for it, and failures are only logged.
+    fn migrate<T>(&mut self, token: Token, target: T) -> bool
+        where T: Target<M> + 'static;
+
+    /// Adds a machine created by `f` to the loop
+    ///
+    /// The slot for the new machine is reserved before calling `f`, and the
+    /// scope passed to `f` is bound to the new slot, so `f` may register
+    /// sockets of the new machine right away. If `f` returns an error the
+    /// slot is released and nothing is added. Returns the token of the new
+    /// machine.
+    ///
+    /// Note `f` must not call `replace_self`.
+    fn add_machine_with<F, E>(&mut self, f: F) -> Result<Token, SpawnError<E>>
+        where F: FnOnce(&mut Self) -> Result<M, E>, Self: Sized
+    {
+        let token = match self.reserve_slot() {
+            Some(token) => token,
+            None => return Err(SpawnError::NoSlabSpace),
+        };
+        let old = self.switch_slot(token);
+        let result = f(self);
+        self.switch_slot(old);
+        match result {
+            Ok(m) => {
+                self.fill_slot(token, Some(m));
+                Ok(token)
+            }
+            Err(e) => {
+                self.fill_slot(token, None);
+                Err(SpawnError::Failed(e))
+            }
+        }
+    }
+
+    /// Reserves an empty slot for `add_machine_with`
+    #[doc(hidden)]
+    fn reserve_slot(&mut self) -> Option<Token>;
+    /// Binds the scope to another slot, returns previous one
+    #[doc(hidden)]
+    fn switch_slot(&mut self, token: Token) -> Token;
+    /// Puts a machine into the reserved slot, or releases the slot
+    #[doc(hidden)]
+    fn fill_slot(&mut self, token: Token, m: Option<M>);
 }
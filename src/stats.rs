@@ -0,0 +1,172 @@
+//! Loop-level statistics for tuning servers
+//!
+//! Enable with `Handler::enable_stats`. Statistics are available to
+//! machines via `Scope::loop_stats` and are logged on `Notify::DumpStats`.
+use std::fmt;
+use std::time::Duration;
+
+
+/// Number of buckets, the last one is for values over 2^31 microseconds
+const BUCKETS: usize = 32;
+
+/// A histogram of durations with power of two buckets (in microseconds)
+#[derive(Clone)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    max: Duration,
+}
+
+/// Statistics of the event loop
+#[derive(Clone, Debug)]
+pub struct Stats {
+    /// Number of iterations of the loop
+    pub iterations: u64,
+    /// Number of machine callbacks in all iterations
+    pub events: u64,
+    /// Maximum number of machine callbacks in a single iteration
+    pub max_events_per_iteration: u64,
+    /// Time spent in the machine callbacks
+    pub dispatch_latency: Histogram,
+    /// How late timeouts are fired relative to scheduled time
+    pub timer_lag: Histogram,
+    /// Number of machines at the end of the last iteration
+    pub machines: usize,
+    /// Maximum number of machines at the end of an iteration
+    pub max_machines: usize,
+    /// Number of slots in the slab, the slab never shrinks
+    pub capacity: usize,
+    current_events: u64,
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            buckets: [0; BUCKETS],
+            count: 0,
+            max: Duration::new(0, 0),
+        }
+    }
+    pub fn record(&mut self, value: Duration) {
+        let micros = value.as_secs().saturating_mul(1000000)
+            .saturating_add(value.subsec_nanos() as u64 / 1000);
+        let bucket = (64 - micros.leading_zeros()) as usize;
+        self.buckets[if bucket < BUCKETS { bucket } else { BUCKETS-1 }] += 1;
+        self.count += 1;
+        if value > self.max {
+            self.max = value;
+        }
+    }
+    /// Number of recorded values
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+    /// Maximum recorded value
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+    /// Returns the upper bound of the `p` percentile (e.g. `0.99`)
+    ///
+    /// The result is precise up to the power of two
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.count == 0 {
+            return Duration::new(0, 0);
+        }
+        let rank = (p * self.count as f64).ceil() as u64;
+        let mut seen = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank && n > 0 {
+                if i == BUCKETS-1 {
+                    return self.max;
+                }
+                // Bucket `i` holds values below 2^i microseconds
+                return micros((1u64 << i) - 1);
+            }
+        }
+        self.max
+    }
+}
+
+fn micros(value: u64) -> Duration {
+    Duration::new(value / 1000000, (value % 1000000) as u32 * 1000)
+}
+
+impl fmt::Debug for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "n={} p50={:?} p99={:?} max={:?}", self.count,
+            self.percentile(0.5), self.percentile(0.99), self.max)
+    }
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            iterations: 0,
+            events: 0,
+            max_events_per_iteration: 0,
+            dispatch_latency: Histogram::new(),
+            timer_lag: Histogram::new(),
+            machines: 0,
+            max_machines: 0,
+            capacity: 0,
+            current_events: 0,
+        }
+    }
+    /// Records a machine callback
+    pub fn dispatched(&mut self, latency: Duration) {
+        self.events += 1;
+        self.current_events += 1;
+        self.dispatch_latency.record(latency);
+    }
+    /// Records the end of the loop iteration
+    pub fn iteration_done(&mut self, machines: usize, capacity: usize) {
+        self.iterations += 1;
+        self.machines = machines;
+        self.capacity = capacity;
+        if machines > self.max_machines {
+            self.max_machines = machines;
+        }
+        if self.current_events > self.max_events_per_iteration {
+            self.max_events_per_iteration = self.current_events;
+        }
+        self.current_events = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use super::{Histogram, Stats};
+
+    #[test]
+    fn percentiles() {
+        let mut h = Histogram::new();
+        for _ in 0..98 {
+            h.record(Duration::new(0, 5000)); // 5us
+        }
+        h.record(Duration::from_millis(3));
+        h.record(Duration::from_millis(10));
+        assert_eq!(h.count(), 100);
+        assert_eq!(h.percentile(0.5), Duration::new(0, 7000));
+        assert_eq!(h.percentile(0.99), Duration::new(0, 4095000));
+        assert_eq!(h.percentile(1.0), Duration::new(0, 16383000));
+        assert_eq!(h.max(), Duration::from_millis(10));
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(Histogram::new().percentile(0.5), Duration::new(0, 0));
+    }
+
+    #[test]
+    fn occupancy() {
+        let mut stats = Stats::new();
+        stats.iteration_done(10, 4096);
+        stats.iteration_done(300, 4096);
+        stats.iteration_done(2, 8192);
+        assert_eq!(stats.machines, 2);
+        assert_eq!(stats.max_machines, 300);
+        assert_eq!(stats.capacity, 8192);
+    }
+}
@@ -0,0 +1,93 @@
+//! Helpers for testing machines and their handles without an event loop
+//!
+//! Enabled by the `test-support` feature. The `Channel` replaces the
+//! notification queue of the loop, so the tests may fill it up, close it,
+//! and deliver the wakeups in any order:
+//!
+//! ```ignore
+//! let channel = Channel::new(1);
+//! let notifier = channel.notifier(Token(1));
+//! assert!(notifier.wakeup().is_ok());
+//! assert!(notifier.wakeup().is_err());  // the queue is full
+//! assert_eq!(channel.take(), vec![Token(1)]);
+//! ```
+//!
+//! Timeouts are scheduled on the timer of the mio loop, which runs on the
+//! real clock. To test timer-dependent machines (idle timeouts, heartbeats)
+//! without sleeping, set the time returned by `Scope::now` and deliver the
+//! timeout to the `Handler` directly:
+//!
+//! ```ignore
+//! let later = Instant::now() + Duration::new(60, 0);
+//! handler.set_clock(later);
+//! let id = handler.machine_id(token);
+//! mio::Handler::timeout(&mut handler, &mut eloop,
+//!     Timer::Machine(id, MyTimeout::Heartbeat, later));
+//! ```
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use mio::Token;
+
+use handler::{MachineId, Notifier, NotifyError, Wakeup};
+
+
+/// A model of the notification queue of the loop
+///
+/// Clones share the queue.
+#[derive(Clone)]
+pub struct Channel(Arc<Mutex<Queue>>);
+
+struct Queue {
+    wakeups: VecDeque<Token>,
+    capacity: usize,
+    closed: bool,
+}
+
+impl Channel {
+    /// Creates a queue which holds up to `capacity` wakeups
+    pub fn new(capacity: usize) -> Channel {
+        Channel(Arc::new(Mutex::new(Queue {
+            wakeups: VecDeque::new(),
+            capacity: capacity,
+            closed: false,
+        })))
+    }
+    /// Returns a notifier of the machine with `token` sending to the queue
+    pub fn notifier(&self, token: Token) -> Notifier {
+        Notifier::with_channel(MachineId::new(token, 0),
+                               Box::new(self.clone()))
+    }
+    /// Changes the capacity, wakeups which are over it are kept
+    pub fn set_capacity(&self, capacity: usize) {
+        self.0.lock().unwrap().capacity = capacity;
+    }
+    /// Makes further wakeups fail as if the loop is shut down
+    pub fn close(&self) {
+        self.0.lock().unwrap().closed = true;
+    }
+    /// Removes the queued wakeups, in the order they are sent
+    ///
+    /// Reorder the result to model delivery of the wakeups from several
+    /// threads.
+    pub fn take(&self) -> Vec<Token> {
+        self.0.lock().unwrap().wakeups.drain(..).collect()
+    }
+}
+
+impl Wakeup for Channel {
+    fn wakeup(&self, id: MachineId) -> Result<(), NotifyError> {
+        let mut queue = self.0.lock().unwrap();
+        if queue.closed {
+            Err(NotifyError::Closed)
+        } else if queue.wakeups.len() >= queue.capacity {
+            Err(NotifyError::Full)
+        } else {
+            queue.wakeups.push_back(id.token());
+            Ok(())
+        }
+    }
+    fn clone_box(&self) -> Box<Wakeup> {
+        Box::new(self.clone())
+    }
+}
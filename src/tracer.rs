@@ -0,0 +1,49 @@
+//! Hooks to watch the lifecycle of the state machines
+//!
+//! Set a tracer with `Handler::set_tracer` to find out why some connection
+//! is stuck without patching the library. All methods have empty default
+//! implementations, so implement only ones you're interested in.
+use std::time::Duration;
+
+use mio::{Token, EventSet};
+
+
+pub trait Tracer {
+    /// A machine is added to the loop (including migrated ones)
+    fn machine_created(&mut self, _token: Token) {}
+    /// A machine is removed from the loop (including migrated ones)
+    fn machine_removed(&mut self, _token: Token) {}
+    /// `EventMachine::ready` has been called, `duration` is the time spent
+    /// in the callback
+    fn event_dispatched(&mut self, _token: Token, _events: EventSet,
+        _duration: Duration)
+    {}
+    /// A timeout of the machine is going to be delivered
+    fn timeout_fired(&mut self, _token: Token) {}
+    /// A message is received from the notification queue, `token` is `None`
+    /// for messages which don't target a specific machine
+    fn notify_received(&mut self, _token: Option<Token>) {}
+}
+
+/// A tracer which writes all the events to the log at debug level
+pub struct LogTracer;
+
+impl Tracer for LogTracer {
+    fn machine_created(&mut self, token: Token) {
+        debug!("Machine {:?} created", token);
+    }
+    fn machine_removed(&mut self, token: Token) {
+        debug!("Machine {:?} removed", token);
+    }
+    fn event_dispatched(&mut self, token: Token, events: EventSet,
+        duration: Duration)
+    {
+        debug!("Machine {:?} got {:?} in {:?}", token, events, duration);
+    }
+    fn timeout_fired(&mut self, token: Token) {
+        debug!("Machine {:?} timeout", token);
+    }
+    fn notify_received(&mut self, token: Option<Token>) {
+        debug!("Notification for {:?}", token);
+    }
+}
@@ -1,27 +1,129 @@
-use std::io::Error;
+use std::any::Any;
+use std::fs::File;
+use std::fmt;
+use std::io::{self, Error};
+use std::cmp::max;
+use std::sync::{Arc, Mutex};
 use std::marker::PhantomData;
+use std::time::{Duration, Instant};
+use std::os::unix::io::{RawFd, AsRawFd, FromRawFd};
 
+use libc;
 use mio::TryAccept;
 use mio::{EventSet, Handler, PollOpt, Evented};
-use mio::{Timeout, TimerError};
+use mio::{Token, Timeout, TimerError};
 
-use {BaseMachine, EventMachine, Scope};
+use {BaseMachine, EventMachine, Scope, Response};
 use handler::Abort::MachineAddError;
+use handler::{MachineId, Notifier, NotifyError, Target};
+use stats::Stats;
+use limits::TokenBucket;
+use super::StreamSocket;
+use super::greedy_stream::{Stream, Protocol, StreamBuilder};
 
-pub enum Serve<S, M, Ctx>
+/// The machine which accepts connections and runs the connection machines
+///
+/// Connection machines are created by the factory `F`. By default it's
+/// `Init::accept` of the machine `M`, see `ServeWith` for the factory
+/// which is chosen at runtime.
+pub enum Serve<S, M, Ctx, F=PhantomData<fn(&mut Ctx)>>
     where
-        M: Init<S::Output, Ctx>, M: EventMachine<Ctx>, M: Send,
-        S: TryAccept+Send, S: Evented,
+        M: EventMachine<Ctx>, F: Factory<S::Output, Ctx, M>,
+        S: TryAccept, S: Evented,
 {
-    Accept(S, PhantomData<*const Ctx>),
+    Accept(S, Control, F, PhantomData<fn(&mut Ctx)>),
+    /// Listening socket is deregistered from the event loop
+    Paused(S, Control, F, PhantomData<fn(&mut Ctx)>),
+    /// Listening socket is deregistered until `Timer::Resume`, because of
+    /// the accept rate or the lack of file descriptors
+    Throttled(S, Control, F, PhantomData<fn(&mut Ctx)>),
     Connection(M),
 }
 
-unsafe impl<S:TryAccept+Send, M, Ctx> Send for Serve<S, M, Ctx>
-    where
-        M: Init<S::Output, Ctx>, M: EventMachine<Ctx>, M: Send,
-        S: TryAccept+Send, S: Evented,
-{}
+/// `Serve` which creates connection machines by the closure
+///
+/// The closure receives the accepted connection and returns the machine
+/// for it, or `None` to close the connection. To choose between several
+/// types of machines (e.g. by the address of the peer), compose them with
+/// `rotor_compose_state_machines!` (machines can't be boxed, because
+/// `EventMachine` isn't object safe):
+///
+/// ```ignore
+/// Serve::with(listener, |sock: TcpStream, ctx: &mut Context| {
+///     match sock.peer_addr() {
+///         Ok(ref addr) if ctx.is_admin(addr) => Some(Conn::Admin(
+///             Stream::new(sock, ctx))),
+///         Ok(_) => Some(Conn::Public(Stream::new(sock, ctx))),
+///         Err(_) => None,
+///     }
+/// })
+/// ```
+pub type ServeWith<S, M, Ctx, F> = Serve<S, M, Ctx, With<F>>;
+
+/// The factory of the `ServeWith`
+pub struct With<F>(F);
+
+/// The state of the listening socket requested by `Control`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Listen {
+    Accepting,
+    Paused,
+    Closed,
+}
+
+/// The timeout of `Serve`
+pub enum Timer<T> {
+    /// The timeout of the connection machine
+    Connection(T),
+    /// Accepting was throttled by `Control::set_accept_rate` or because
+    /// the process is out of file descriptors, resume it
+    Resume,
+}
+
+/// A handle to pause, resume or close the listening socket of `Serve`
+///
+/// The handle may be used from any thread. Connections which are already
+/// accepted are not affected by any of the operations.
+#[derive(Clone)]
+pub struct Control(Arc<Mutex<(Listen, Option<Notifier>, Limits)>>);
+
+/// What `Serve` does when the process is out of file descriptors, see
+/// `Control::reserve_fd`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FdExhausted {
+    /// Accept and close the pending connection with the spare descriptor
+    Reject,
+    /// Reject the connection and stop accepting for the number of
+    /// milliseconds, so descriptors can be freed by closed connections
+    RejectAndPause(u64),
+}
+
+/// Limits of accepting connections set through the `Control`
+struct Limits {
+    per_dispatch: usize,
+    /// The token bucket of `Control::set_accept_rate`
+    bucket: Option<TokenBucket>,
+    /// The spare descriptor of `Control::reserve_fd`, and the policy
+    reserve: Option<(Option<File>, FdExhausted)>,
+    slow_start: Option<SlowStart>,
+}
+
+/// Pacing of accepting after the start, see `Control::set_slow_start`
+struct SlowStart {
+    /// Connections per second at the start of the ramp
+    initial: f64,
+    ramp: u64,
+    /// The start of the ramp, `None` until the first connection
+    started: Option<Instant>,
+    /// The time the next connection may be accepted at
+    next: Option<Instant>,
+}
+
+/// The pause when the process is out of file descriptors and there is no
+/// spare one
+const FD_LIMIT_PAUSE_MS: u64 = 100;
+/// The growth of the rate over the slow start without an accept rate
+const SLOW_START_GROWTH: f64 = 1024.;
 
 pub trait Init<T, C>: EventMachine<C> {
     fn accept<S>(conn: T, context: &mut C, scope: &mut S)
@@ -29,12 +131,44 @@ pub trait Init<T, C>: EventMachine<C> {
         where S: Scope<Self>;
 }
 
-struct ScopeProxy<'a, S: 'a, A, C>(&'a mut S, PhantomData<*const (A, C)>);
+/// Creates the connection machine `M` for the accepted connection `T`
+pub trait Factory<T, C, M: BaseMachine> {
+    /// Returns `None` if the connection should be closed
+    fn create<S>(&mut self, conn: T, context: &mut C, scope: &mut S)
+        -> Option<M>
+        where S: Scope<M>;
+}
+
+/// The default factory, which calls `Init::accept`
+impl<T, C, M: Init<T, C>> Factory<T, C, M> for PhantomData<fn(&mut C)> {
+    fn create<S>(&mut self, conn: T, context: &mut C, scope: &mut S)
+        -> Option<M>
+        where S: Scope<M>
+    {
+        Some(M::accept(conn, context, scope))
+    }
+}
+
+impl<T, C, M, F> Factory<T, C, M> for With<F>
+    where M: BaseMachine,
+          F: FnMut(T, &mut C) -> Option<M>
+{
+    fn create<S>(&mut self, conn: T, context: &mut C, _scope: &mut S)
+        -> Option<M>
+        where S: Scope<M>
+    {
+        (self.0)(conn, context)
+    }
+}
 
-impl<'a, M, S, A, C> Scope<M> for ScopeProxy<'a, S, A, C>
-    where S: Scope<Serve<A, M, C>> + 'a,
-          A: TryAccept+Send, A: Evented,
-          M: Init<A::Output, C>,
+struct ScopeProxy<'a, S: 'a, A, C, F>(&'a mut S,
+                                      PhantomData<*const (A, C, F)>);
+
+impl<'a, M, S, A, C, F> Scope<M> for ScopeProxy<'a, S, A, C, F>
+    where S: Scope<Serve<A, M, C, F>> + 'a,
+          A: TryAccept, A: Evented,
+          M: EventMachine<C>,
+          F: Factory<A::Output, C, M>,
 {
     fn async_add_machine(&mut self, m: M) -> Result<(), M> {
         self.0.async_add_machine(Serve::Connection(m))
@@ -44,86 +178,890 @@ impl<'a, M, S, A, C> Scope<M> for ScopeProxy<'a, S, A, C>
             unreachable!();
         })
     }
+    fn add_child(&mut self, m: M) -> Result<Token, M> {
+        self.0.add_child(Serve::Connection(m))
+        .map_err(|x| if let Serve::Connection(c) = x {
+            c
+        } else {
+            unreachable!();
+        })
+    }
     fn add_timeout_ms(&mut self, delay: u64, t: M::Timeout)
         -> Result<Timeout, TimerError>
     {
-        self.0.add_timeout_ms(delay, t)
+        self.0.add_timeout_ms(delay, Timer::Connection(t))
     }
     fn clear_timeout(&mut self, timeout: Timeout) -> bool {
         self.0.clear_timeout(timeout)
     }
+    fn spawn_after(&mut self, delay: u64, m: M)
+        -> Result<Timeout, TimerError>
+    {
+        self.0.spawn_after(delay, Serve::Connection(m))
+    }
     fn register<E: ?Sized>(&mut self, io: &E, interest: EventSet, opt: PollOpt)
         -> Result<(), Error>
         where E: Evented
     {
         self.0.register(io, interest, opt)
     }
+    fn reregister<E: ?Sized>(&mut self, io: &E, interest: EventSet,
+        opt: PollOpt)
+        -> Result<(), Error>
+        where E: Evented
+    {
+        self.0.reregister(io, interest, opt)
+    }
+    fn deregister<E: ?Sized>(&mut self, io: &E) -> Result<(), Error>
+        where E: Evented
+    {
+        self.0.deregister(io)
+    }
+    fn token(&self) -> Token {
+        self.0.token()
+    }
+    fn id(&self) -> MachineId {
+        self.0.id()
+    }
+    fn is_alive(&self, id: MachineId) -> bool {
+        self.0.is_alive(id)
+    }
+    fn now(&self) -> Instant {
+        self.0.now()
+    }
+    fn now_ms(&self) -> u64 {
+        self.0.now_ms()
+    }
+    fn loop_stats(&self) -> Option<&Stats> {
+        self.0.loop_stats()
+    }
+    fn notifier(&self) -> Notifier {
+        self.0.notifier()
+    }
+    fn replace_self(&mut self, m: M) {
+        self.0.replace_self(Serve::Connection(m))
+    }
+    fn for_each_machine<G>(&self, f: G)
+        where G: FnMut(Token)
+    {
+        self.0.for_each_machine(f)
+    }
+    fn wakeup(&mut self, token: Token) -> Result<(), NotifyError> {
+        self.0.wakeup(token)
+    }
+    fn shutdown_loop(&mut self) {
+        self.0.shutdown_loop()
+    }
+    fn exit_loop(&mut self, status: i32) {
+        self.0.exit_loop(status)
+    }
+    fn shutdown_self(&mut self) {
+        self.0.shutdown_self()
+    }
+    fn slot_data<T: Any + Default>(&mut self) -> &mut T {
+        self.0.slot_data()
+    }
+    fn remove_slot_data<T: Any>(&mut self) -> Option<T> {
+        self.0.remove_slot_data()
+    }
+    fn request_tick(&mut self) {
+        self.0.request_tick()
+    }
+    fn dump_machines(&self, out: &mut io::Write)
+        -> io::Result<()>
+    {
+        self.0.dump_machines(out)
+    }
+    fn set_max_lifetime(&mut self, ms: u64) -> Result<(), TimerError> {
+        self.0.set_max_lifetime(ms)
+    }
+    fn migrate<T>(&mut self, token: Token, target: T) -> bool
+        where T: Target<M> + 'static
+    {
+        self.0.migrate(token, move |m| match m {
+            Serve::Connection(c) => {
+                target.send_machine(c).map_err(Serve::Connection)
+            }
+            m => Err(m),
+        })
+    }
+    fn reserve_slot(&mut self) -> Option<Token> {
+        self.0.reserve_slot()
+    }
+    fn switch_slot(&mut self, token: Token) -> Token {
+        self.0.switch_slot(token)
+    }
+    fn fill_slot(&mut self, token: Token, m: Option<M>) {
+        self.0.fill_slot(token, m.map(Serve::Connection))
+    }
 }
-impl<S, M, Ctx> BaseMachine for Serve<S, M, Ctx>
-    where M: Init<S::Output, Ctx>,
-          M: EventMachine<Ctx>,
+impl<S, M, Ctx, F> BaseMachine for Serve<S, M, Ctx, F>
+    where M: EventMachine<Ctx>,
+          F: Factory<S::Output, Ctx, M>,
           S: Evented,
-          S: TryAccept + Send,
+          S: TryAccept,
 {
-    type Timeout = M::Timeout;
+    type Timeout = Timer<M::Timeout>;
 }
 
-impl<S, M, Ctx> EventMachine<Ctx> for Serve<S, M, Ctx>
-    where M: Init<S::Output, Ctx>,
-          M: EventMachine<Ctx>,
+impl<S, M, Ctx, F> EventMachine<Ctx> for Serve<S, M, Ctx, F>
+    where M: EventMachine<Ctx>,
+          F: Factory<S::Output, Ctx, M>,
           S: Evented,
-          S: TryAccept + Send,
+          S: TryAccept,
 {
     fn ready<Sc>(self, evset: EventSet, context: &mut Ctx, scope: &mut Sc)
-        -> Option<Self>
+        -> Response<Self>
         where Sc: Scope<Self>
     {
         use self::Serve::*;
+        use response::Response::Continue;
         match self {
-            Accept(sock, _) => {
-                match sock.accept() {
-                    Ok(Some(child)) => {
-                        let conm: M = <M as Init<_, _>>::accept(child, context,
-                            &mut ScopeProxy(scope, PhantomData));
-                        let conn: Serve<S, M, Ctx> = Connection(conm);
-                        scope.async_add_machine(conn)
-                        .map_err(|child|
-                            child.abort(MachineAddError, context, scope))
-                        .ok();
+            Accept(sock, ctl, mut factory, _) => {
+                // The socket is level-triggered, so connections left in the
+                // backlog are accepted on the next iteration of the loop
+                for _ in 0..ctl.accepts_per_dispatch() {
+                    if let Some(delay) = ctl.throttle_delay(scope.now()) {
+                        return throttle(sock, ctl, factory, delay, scope);
                     }
-                    Ok(None) => {}
-                    Err(e) => {
-                        error!("Error on socket accept: {}", e);
+                    match sock.accept() {
+                        Ok(Some(child)) => {
+                            ctl.accepted(scope.now());
+                            let conm = match factory.create(child, context,
+                                &mut ScopeProxy(scope, PhantomData))
+                            {
+                                Some(conm) => conm,
+                                None => continue,
+                            };
+                            let conn: Self = Connection(conm);
+                            scope.async_add_machine(conn)
+                            .map_err(|child|
+                                child.abort(MachineAddError, context, scope))
+                            .ok();
+                        }
+                        Ok(None) => break,
+                        Err(ref e) if is_fd_limit(e) => {
+                            match ctl.fd_exhausted(&sock) {
+                                Some(pause) => return throttle(sock, ctl,
+                                    factory, pause, scope),
+                                None => break,
+                            }
+                        }
+                        Err(e) => {
+                            error!("Error on socket accept: {}", e);
+                            break;
+                        }
                     }
                 }
-                Some(Accept(sock, PhantomData))
+                Continue(Accept(sock, ctl, factory, PhantomData))
+            }
+            Paused(sock, ctl, f, _) => {
+                Continue(Paused(sock, ctl, f, PhantomData))
+            }
+            Throttled(sock, ctl, f, _) => {
+                Continue(Throttled(sock, ctl, f, PhantomData))
             }
             Connection(c) => c.ready(evset, context,
                 &mut ScopeProxy(scope, PhantomData))
                 .map(Connection),
         }
     }
+    fn timeout<Sc>(self, timeout: Self::Timeout, context: &mut Ctx,
+        scope: &mut Sc)
+        -> Response<Self>
+        where Sc: Scope<Self>
+    {
+        use self::Serve::*;
+        match (self, timeout) {
+            (Connection(c), Timer::Connection(t)) => c.timeout(t, context,
+                &mut ScopeProxy(scope, PhantomData))
+                .map(Connection),
+            (Throttled(sock, ctl, f, _), Timer::Resume) => match ctl.state() {
+                Listen::Accepting => resume(sock, ctl, f, scope),
+                Listen::Paused => {
+                    Response::Continue(Paused(sock, ctl, f, PhantomData))
+                }
+                Listen::Closed => Response::Remove,
+            },
+            (me, _) => Response::Continue(me),
+        }
+    }
+    fn wakeup<Sc>(self, context: &mut Ctx, scope: &mut Sc) -> Response<Self>
+        where Sc: Scope<Self>
+    {
+        use self::Serve::*;
+        use response::Response::{Continue, Remove};
+        match self {
+            Accept(sock, ctl, f, _) => match ctl.state() {
+                Listen::Accepting => {
+                    Continue(Accept(sock, ctl, f, PhantomData))
+                }
+                Listen::Paused => {
+                    scope.deregister(&sock).map_err(|e|
+                        error!("Error when pausing listener: {}", e)).ok();
+                    Continue(Paused(sock, ctl, f, PhantomData))
+                }
+                Listen::Closed => Remove,
+            },
+            Paused(sock, ctl, f, _) => match ctl.state() {
+                Listen::Accepting => resume(sock, ctl, f, scope),
+                Listen::Paused => Continue(Paused(sock, ctl, f, PhantomData)),
+                Listen::Closed => Remove,
+            },
+            // The state is applied by `Timer::Resume`
+            Throttled(sock, ctl, f, _) => match ctl.state() {
+                Listen::Closed => Remove,
+                _ => Continue(Throttled(sock, ctl, f, PhantomData)),
+            },
+            Connection(c) => c.wakeup(context,
+                &mut ScopeProxy(scope, PhantomData))
+                .map(Connection),
+        }
+    }
+    fn shutdown<Sc>(self, context: &mut Ctx, scope: &mut Sc)
+        -> Response<Self>
+        where Sc: Scope<Self>
+    {
+        match self {
+            Serve::Connection(c) => c.shutdown(context,
+                &mut ScopeProxy(scope, PhantomData))
+                .map(Serve::Connection),
+            // Stop accepting connections
+            _ => Response::Remove,
+        }
+    }
+    fn tick<Sc>(self, context: &mut Ctx, scope: &mut Sc) -> Response<Self>
+        where Sc: Scope<Self>
+    {
+        match self {
+            Serve::Connection(c) => c.tick(context,
+                &mut ScopeProxy(scope, PhantomData))
+                .map(Serve::Connection),
+            me => Response::Continue(me),
+        }
+    }
+    fn stalled<Sc>(self, context: &mut Ctx, scope: &mut Sc)
+        -> Response<Self>
+        where Sc: Scope<Self>
+    {
+        match self {
+            Serve::Connection(c) => c.stalled(context,
+                &mut ScopeProxy(scope, PhantomData))
+                .map(Serve::Connection),
+            // Listeners are idle until the next connection
+            me => Response::Continue(me),
+        }
+    }
+    fn child_terminated<Sc>(self, child: Token, context: &mut Ctx,
+        scope: &mut Sc)
+        -> Response<Self>
+        where Sc: Scope<Self>
+    {
+        match self {
+            Serve::Connection(c) => c.child_terminated(child, context,
+                &mut ScopeProxy(scope, PhantomData))
+                .map(Serve::Connection),
+            me => Response::Continue(me),
+        }
+    }
     fn register<Sc>(&mut self, scope: &mut Sc)
         -> Result<(), Error>
         where Sc: Scope<Self>
     {
         use self::Serve::*;
         match self {
-            &mut Accept(ref mut s, _)
-            => scope.register(s, EventSet::readable(), PollOpt::level()),
+            &mut Accept(ref mut s, ref ctl, _, _) => {
+                try!(scope.register(s, EventSet::readable(),
+                                    PollOpt::level()));
+                ctl.set_notifier(scope.notifier());
+                Ok(())
+            }
+            &mut Paused(_, ref ctl, _, _) | &mut Throttled(_, ref ctl, _, _)
+            => {
+                ctl.set_notifier(scope.notifier());
+                Ok(())
+            }
             &mut Connection(ref mut c)
             => c.register(&mut ScopeProxy(scope, PhantomData)),
         }
     }
+    fn drain<Sc>(self, context: &mut Ctx, scope: &mut Sc)
+        -> Response<Self>
+        where Sc: Scope<Self>
+    {
+        match self {
+            Serve::Connection(c) => c.drain(context,
+                &mut ScopeProxy(scope, PhantomData))
+                .map(Serve::Connection),
+            // Listeners are paused by the application, see `bind::Service`
+            me => Response::Continue(me),
+        }
+    }
+    fn is_draining(&self) -> bool {
+        match self {
+            &Serve::Connection(ref c) => c.is_draining(),
+            _ => false,
+        }
+    }
+    fn name(&self) -> &'static str {
+        match self {
+            &Serve::Connection(ref c) => c.name(),
+            _ => "listener",
+        }
+    }
+    fn debug(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Serve::Connection(ref c) => c.debug(f),
+            &Serve::Paused(..) => write!(f, "paused"),
+            &Serve::Throttled(..) => write!(f, "throttled"),
+            _ => Ok(()),
+        }
+    }
+    fn deregister<Sc>(&mut self, scope: &mut Sc)
+        -> Result<(), Error>
+        where Sc: Scope<Self>
+    {
+        use self::Serve::*;
+        match self {
+            &mut Accept(ref mut s, _, _, _) => scope.deregister(s),
+            &mut Paused(..) | &mut Throttled(..) => Ok(()),
+            &mut Connection(ref mut c)
+            => c.deregister(&mut ScopeProxy(scope, PhantomData)),
+        }
+    }
+}
+
+/// Returns true if the process or the system is out of file descriptors
+fn is_fd_limit(e: &io::Error) -> bool {
+    match e.raw_os_error() {
+        Some(libc::EMFILE) | Some(libc::ENFILE) => true,
+        _ => false,
+    }
+}
+
+/// Registers the listening socket back
+fn resume<S, M, Ctx, F, Sc>(sock: S, ctl: Control, f: F, scope: &mut Sc)
+    -> Response<Serve<S, M, Ctx, F>>
+    where M: EventMachine<Ctx>, F: Factory<S::Output, Ctx, M>,
+          S: TryAccept, S: Evented,
+          Sc: Scope<Serve<S, M, Ctx, F>>,
+{
+    match scope.register(&sock, EventSet::readable(), PollOpt::level()) {
+        Ok(()) => Response::Continue(Serve::Accept(sock, ctl, f, PhantomData)),
+        Err(e) => {
+            error!("Error when resuming listener: {}", e);
+            Response::Continue(Serve::Paused(sock, ctl, f, PhantomData))
+        }
+    }
+}
+
+/// Deregisters the listening socket for `delay` milliseconds
+fn throttle<S, M, Ctx, F, Sc>(sock: S, ctl: Control, f: F, delay: u64,
+    scope: &mut Sc)
+    -> Response<Serve<S, M, Ctx, F>>
+    where M: EventMachine<Ctx>, F: Factory<S::Output, Ctx, M>,
+          S: TryAccept, S: Evented,
+          Sc: Scope<Serve<S, M, Ctx, F>>,
+{
+    if let Err(e) = scope.add_timeout_ms(delay, Timer::Resume) {
+        // Keep accepting rather than stop forever
+        error!("Can't throttle listener: {:?}", e);
+        return Response::Continue(Serve::Accept(sock, ctl, f, PhantomData));
+    }
+    debug!("Pausing listener for {} ms", delay);
+    scope.deregister(&sock).map_err(|e|
+        error!("Error when throttling listener: {}", e)).ok();
+    Response::Continue(Serve::Throttled(sock, ctl, f, PhantomData))
 }
 
 impl<S, T, M, Ctx> Serve<S, M, Ctx>
     where M: Init<T, Ctx>,
           M: EventMachine<Ctx>,
           S: Evented,
-          S: TryAccept<Output=T>+Send,
+          S: TryAccept<Output=T>,
 {
     pub fn new(sock: S) -> Self {
-        Serve::Accept(sock, PhantomData)
+        Serve::Accept(sock, Control::new(), PhantomData, PhantomData)
+    }
+}
+
+impl<S, T, M, Ctx, F> Serve<S, M, Ctx, With<F>>
+    where M: EventMachine<Ctx>,
+          F: FnMut(T, &mut Ctx) -> Option<M>,
+          S: Evented,
+          S: TryAccept<Output=T>,
+{
+    /// Creates the listener which calls `factory` for every connection,
+    /// see `ServeWith`
+    pub fn with(sock: S, factory: F) -> Self {
+        Serve::Accept(sock, Control::new(), With(factory), PhantomData)
+    }
+}
+
+impl<S, T, P, Ctx> Serve<S, Stream<T, P, Ctx>, Ctx, StreamBuilder>
+    where T: StreamSocket,
+          P: Protocol<Ctx>,
+          S: Evented,
+          S: TryAccept<Output=T>,
+{
+    /// Creates the listener of streams configured by the `builder`
+    pub fn new_with(sock: S, builder: StreamBuilder) -> Self {
+        Serve::Accept(sock, Control::new(), builder, PhantomData)
+    }
+}
+
+impl<S, T, M, Ctx, F> Serve<S, M, Ctx, F>
+    where M: EventMachine<Ctx>,
+          F: Factory<T, Ctx, M>,
+          S: Evented,
+          S: TryAccept<Output=T>,
+{
+    /// Limits the rate of accepting connections, see
+    /// `Control::set_accept_rate`
+    pub fn accept_rate(self, per_second: u32, burst: u32) -> Self {
+        self.control().map(|c| c.set_accept_rate(per_second, burst));
+        self
+    }
+    /// Ramps up the rate of accepting connections, see
+    /// `Control::set_slow_start`
+    pub fn slow_start(self, initial_per_second: u32, ramp_ms: u64) -> Self {
+        self.control().map(|c| c.set_slow_start(initial_per_second, ramp_ms));
+        self
+    }
+    /// Sets how many connections are accepted on a single event, see
+    /// `Control::set_accepts_per_dispatch`
+    pub fn accepts_per_dispatch(self, n: usize) -> Self {
+        self.control().map(|c| c.set_accepts_per_dispatch(n));
+        self
+    }
+    /// Keeps a spare file descriptor, see `Control::reserve_fd`
+    pub fn reserve_fd(self, policy: FdExhausted) -> io::Result<Self> {
+        if let Some(c) = self.control() {
+            try!(c.reserve_fd(policy));
+        }
+        Ok(self)
+    }
+    /// Returns file descriptor of the listening socket
+    ///
+    /// Use `listen::pass_fds` to pass it to a child process
+    pub fn listener_fd(&self) -> Option<RawFd>
+        where S: AsRawFd
+    {
+        use self::Serve::*;
+        match *self {
+            Accept(ref s, ..) | Paused(ref s, ..) | Throttled(ref s, ..)
+            => Some(s.as_raw_fd()),
+            Connection(_) => None,
+        }
+    }
+    /// Returns a handle to control the listening socket
+    ///
+    /// Returns `None` for connection machines
+    pub fn control(&self) -> Option<Control> {
+        use self::Serve::*;
+        match *self {
+            Accept(_, ref ctl, ..) | Paused(_, ref ctl, ..)
+            | Throttled(_, ref ctl, ..) => Some(ctl.clone()),
+            Connection(_) => None,
+        }
+    }
+}
+
+/// Creates a listener from the inherited socket, see `listen::listen_fds`
+impl<S, T, M, Ctx> FromRawFd for Serve<S, M, Ctx>
+    where M: Init<T, Ctx>,
+          M: EventMachine<Ctx>,
+          S: Evented + FromRawFd,
+          S: TryAccept<Output=T>,
+{
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        Serve::new(S::from_raw_fd(fd))
+    }
+}
+
+impl Control {
+    fn new() -> Control {
+        Control(Arc::new(Mutex::new((Listen::Accepting, None, Limits {
+            per_dispatch: 1,
+            bucket: None,
+            reserve: None,
+            slow_start: None,
+        }))))
+    }
+    fn accepts_per_dispatch(&self) -> usize {
+        self.0.lock().unwrap().2.per_dispatch
+    }
+    /// Returns the delay if the accept rate limit is reached
+    fn throttle_delay(&self, now: Instant) -> Option<u64> {
+        let mut guard = self.0.lock().unwrap();
+        let limits = &mut guard.2;
+        let target = limits.bucket.as_ref().map(|b| b.per_second());
+        let pace = limits.slow_start.as_mut()
+            .and_then(|s| s.delay(now, target));
+        let rate = limits.bucket.as_mut().and_then(|b| b.delay(now));
+        max(pace, rate)
+    }
+    fn accepted(&self, now: Instant) {
+        let mut guard = self.0.lock().unwrap();
+        let limits = &mut guard.2;
+        let target = limits.bucket.as_ref().map(|b| b.per_second());
+        limits.slow_start.as_mut().map(|s| s.accepted(now, target));
+        limits.bucket.as_mut().map(|b| b.take());
+    }
+    /// Rejects the pending connection with the spare descriptor, if any
+    ///
+    /// Returns the delay to pause accepting for
+    fn fd_exhausted<S: TryAccept>(&self, sock: &S) -> Option<u64> {
+        let mut guard = self.0.lock().unwrap();
+        let (spare, policy) = match guard.2.reserve {
+            Some((ref mut spare, policy)) => (spare, policy),
+            None => {
+                warn!("Out of file descriptors, pausing listener");
+                return Some(FD_LIMIT_PAUSE_MS);
+            }
+        };
+        if spare.take().is_none() {
+            *spare = File::open("/dev/null").ok();
+            warn!("Out of file descriptors, no spare one, pausing listener");
+            return Some(FD_LIMIT_PAUSE_MS);
+        }
+        match sock.accept() {
+            Ok(Some(conn)) => {
+                drop(conn);
+                warn!("Out of file descriptors, connection rejected");
+            }
+            Ok(None) => {}
+            Err(e) => error!("Error on socket accept: {}", e),
+        }
+        *spare = File::open("/dev/null").ok();
+        match policy {
+            FdExhausted::Reject => None,
+            FdExhausted::RejectAndPause(ms) => Some(ms),
+        }
+    }
+    fn set_notifier(&self, notifier: Notifier) {
+        let mut guard = self.0.lock().unwrap();
+        let wakeup = guard.0 != Listen::Accepting;
+        guard.1 = Some(notifier);
+        if wakeup {
+            // State was changed before the machine has been added to the loop
+            guard.1.as_ref().map(|n| n.wakeup().map_err(|e|
+                error!("Can't wake up listener: {}", e)).ok());
+        }
+    }
+    fn set(&self, state: Listen) -> bool {
+        let mut guard = self.0.lock().unwrap();
+        if guard.0 == Listen::Closed {
+            return true;
+        }
+        guard.0 = state;
+        guard.1.as_ref().map(|n| n.wakeup().is_ok()).unwrap_or(true)
+    }
+    /// Returns the requested state of the listening socket
+    pub fn state(&self) -> Listen {
+        self.0.lock().unwrap().0
+    }
+    /// Stop accepting connections by deregistering the listening socket
+    ///
+    /// Returns `false` if the request can't be delivered because the
+    /// notification queue of the loop is full (the request is applied
+    /// on next wakeup in this case), or the loop is shut down.
+    pub fn pause(&self) -> bool {
+        self.set(Listen::Paused)
+    }
+    /// Continue accepting connections after `pause()`
+    ///
+    /// The slow start, if any, begins again
+    pub fn resume(&self) -> bool {
+        {
+            let mut guard = self.0.lock().unwrap();
+            if guard.0 == Listen::Paused {
+                guard.2.slow_start.as_mut().map(|s| s.restart());
+            }
+        }
+        self.set(Listen::Accepting)
+    }
+    /// Limits accepting to `per_second` connections on average, with bursts
+    /// of up to `burst` connections
+    ///
+    /// When the limit is reached the listening socket is deregistered until
+    /// the next connection may be accepted, new connections wait in the
+    /// backlog of the socket. Protects latency of existing connections
+    /// under connection storms.
+    pub fn set_accept_rate(&self, per_second: u32, burst: u32) {
+        let bucket = TokenBucket::new(per_second, burst);
+        self.0.lock().unwrap().2.bucket = Some(bucket);
+    }
+    /// Ramps up the rate of accepting connections, e.g. while the caches
+    /// behind the server are cold
+    ///
+    /// The ramp starts with the first connection after the listener is
+    /// added to the loop or resumed. The rate grows exponentially from
+    /// `initial_per_second` to the rate of `set_accept_rate` over
+    /// `ramp_ms` milliseconds, or 1024 times without the accept rate.
+    /// Connections are paced evenly, no bursts are allowed. After the ramp
+    /// only the accept rate applies.
+    pub fn set_slow_start(&self, initial_per_second: u32, ramp_ms: u64) {
+        self.0.lock().unwrap().2.slow_start = Some(SlowStart {
+            initial: max(initial_per_second, 1) as f64,
+            ramp: ramp_ms,
+            started: None,
+            next: None,
+        });
+    }
+    /// Removes the limit set by `set_accept_rate`
+    pub fn clear_accept_rate(&self) {
+        self.0.lock().unwrap().2.bucket = None;
+    }
+    /// Accept up to `n` connections on a single readiness event
+    ///
+    /// The default is 1, so the listener can't delay events of the other
+    /// machines much
+    pub fn set_accepts_per_dispatch(&self, n: usize) {
+        self.0.lock().unwrap().2.per_dispatch = max(n, 1);
+    }
+    /// Keeps a spare file descriptor to handle running out of descriptors
+    ///
+    /// When accept fails with `EMFILE` or `ENFILE` the connection stays in
+    /// the backlog, and the level-triggered listener would be polled again
+    /// and again. With the spare descriptor the listener closes it, accepts
+    /// and closes the connection, so the client gets an error instead of a
+    /// hang, and opens the spare descriptor again. Without it the listener
+    /// is paused for 100 ms.
+    pub fn reserve_fd(&self, policy: FdExhausted) -> io::Result<()> {
+        let spare = try!(File::open("/dev/null"));
+        self.0.lock().unwrap().2.reserve = Some((Some(spare), policy));
+        Ok(())
+    }
+    /// Close the listening socket, so another process can bind the address
+    ///
+    /// The state machine of the listener is removed from the loop. This
+    /// operation can't be undone.
+    pub fn close(&self) -> bool {
+        self.set(Listen::Closed)
+    }
+}
+
+impl SlowStart {
+    /// Returns connections per second, or `None` after the ramp
+    fn rate(&mut self, now: Instant, target: Option<f64>) -> Option<f64> {
+        let started = *self.started.get_or_insert(now);
+        let elapsed = if now > started {
+            let time = now.duration_since(started);
+            time.as_secs() as f64 * 1000. +
+                time.subsec_nanos() as f64 / 1000000.
+        } else {
+            0.
+        };
+        if elapsed >= self.ramp as f64 {
+            return None;
+        }
+        let target = target.unwrap_or(self.initial * SLOW_START_GROWTH);
+        if target <= self.initial {
+            // The accept rate is lower anyway
+            return None;
+        }
+        let progress = elapsed / self.ramp as f64;
+        Some(self.initial * (target / self.initial).powf(progress))
+    }
+    /// Returns milliseconds until the next connection may be accepted
+    fn delay(&mut self, now: Instant, target: Option<f64>) -> Option<u64> {
+        match (self.rate(now, target), self.next) {
+            (Some(_), Some(next)) if next > now => {
+                let wait = next.duration_since(now);
+                Some(wait.as_secs() * 1000 +
+                    (wait.subsec_nanos() as u64 + 999999) / 1000000)
+            }
+            _ => None,
+        }
+    }
+    fn accepted(&mut self, now: Instant, target: Option<f64>) {
+        if let Some(rate) = self.rate(now, target) {
+            let nanos = (1e9 / rate) as u64;
+            self.next = Some(now + Duration::new(nanos / 1000000000,
+                                                 (nanos % 1000000000) as u32));
+        }
+    }
+    fn restart(&mut self) {
+        self.started = None;
+        self.next = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mio::Token;
+    use test_support::Channel;
+    use std::cell::Cell;
+    use std::io;
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+    use libc;
+    use mio::{self, TryAccept, Evented, Selector, EventLoop, EventSet};
+    use mio::PollOpt;
+    use {BaseMachine, EventMachine, Scope, Response, Handler};
+    use handler::{Notify, Timer as LoopTimer};
+    use super::{Serve, With, Timer, Control, Listen, FdExhausted};
+
+    /// A listener with `n` pending connections
+    struct Backlog(Cell<usize>);
+
+    impl TryAccept for Backlog {
+        type Output = ();
+        fn accept(&self) -> io::Result<Option<()>> {
+            match self.0.get() {
+                0 => Ok(None),
+                n => {
+                    self.0.set(n - 1);
+                    Ok(Some(()))
+                }
+            }
+        }
+    }
+
+    /// A listener which is out of file descriptors, and tracks whether it's
+    /// registered in the loop
+    struct Exhausted(Rc<Cell<bool>>);
+
+    impl TryAccept for Exhausted {
+        type Output = ();
+        fn accept(&self) -> io::Result<Option<()>> {
+            Err(io::Error::from_raw_os_error(libc::EMFILE))
+        }
+    }
+
+    impl Evented for Exhausted {
+        fn register(&self, _: &mut Selector, _: Token, _: EventSet,
+            _: PollOpt) -> io::Result<()>
+        {
+            self.0.set(true);
+            Ok(())
+        }
+        fn reregister(&self, _: &mut Selector, _: Token, _: EventSet,
+            _: PollOpt) -> io::Result<()> { Ok(()) }
+        fn deregister(&self, _: &mut Selector) -> io::Result<()> {
+            self.0.set(false);
+            Ok(())
+        }
+    }
+
+    /// The connection machine, never created
+    struct Conn;
+
+    impl BaseMachine for Conn {
+        type Timeout = ();
+    }
+
+    impl EventMachine<()> for Conn {
+        fn ready<S>(self, _: EventSet, _: &mut (), _: &mut S)
+            -> Response<Self>
+            where S: Scope<Self>
+        {
+            Response::Continue(self)
+        }
+        fn register<S>(&mut self, _: &mut S) -> Result<(), io::Error>
+            where S: Scope<Self>
+        {
+            Ok(())
+        }
+    }
+
+    type Listener = Serve<Exhausted, Conn, (),
+        With<fn((), &mut ()) -> Option<Conn>>>;
+
+    fn no_conn(_: (), _: &mut ()) -> Option<Conn> { None }
+
+    #[test]
+    fn fd_limit_pause_outlives_wakeups() {
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::<(), Listener>::new((), &mut eloop);
+        let registered = Rc::new(Cell::new(false));
+        let listener = Serve::with(Exhausted(registered.clone()),
+            no_conn as fn((), &mut ()) -> Option<Conn>);
+        let ctl = listener.control().unwrap();
+        let tok = handler.add_machine(&mut eloop, listener).unwrap();
+        assert!(registered.get());
+        mio::Handler::ready(&mut handler, &mut eloop, tok,
+            EventSet::readable());
+        assert!(!registered.get());
+        // Wakeups don't resume the listener before the timer
+        mio::Handler::notify(&mut handler, &mut eloop, Notify::Broadcast);
+        assert!(!registered.get());
+        let id = handler.machine_id(tok);
+        mio::Handler::timeout(&mut handler, &mut eloop,
+            LoopTimer::Machine(id, Timer::Resume, Instant::now()));
+        assert!(registered.get());
+        // The pause requested by the application is applied on wakeup
+        ctl.pause();
+        mio::Handler::notify(&mut handler, &mut eloop, Notify::Wakeup(id));
+        assert!(!registered.get());
+        ctl.resume();
+        mio::Handler::notify(&mut handler, &mut eloop, Notify::Wakeup(id));
+        assert!(registered.get());
+    }
+
+    #[test]
+    fn control_notifications() {
+        let channel = Channel::new(1);
+        let ctl = Control::new();
+        // Requests before the listener is added are applied on register
+        assert!(ctl.pause());
+        ctl.set_notifier(channel.notifier(Token(3)));
+        assert_eq!(channel.take(), vec![Token(3)]);
+        // The state is applied on the next wakeup if the queue is full
+        channel.set_capacity(0);
+        assert!(!ctl.resume());
+        assert_eq!(ctl.state(), Listen::Accepting);
+        channel.set_capacity(1);
+        assert!(ctl.close());
+        assert_eq!(channel.take(), vec![Token(3)]);
+        // Closing is final, even when the loop is gone
+        channel.close();
+        assert!(ctl.resume());
+        assert_eq!(ctl.state(), Listen::Closed);
+    }
+
+    #[test]
+    fn fd_reserve() {
+        let backlog = Backlog(Cell::new(2));
+        let ctl = Control::new();
+        // Paused when there is no spare descriptor
+        assert_eq!(ctl.fd_exhausted(&backlog), Some(100));
+        assert_eq!(backlog.0.get(), 2);
+        ctl.reserve_fd(FdExhausted::Reject).unwrap();
+        assert_eq!(ctl.fd_exhausted(&backlog), None);
+        assert_eq!(backlog.0.get(), 1);
+        ctl.reserve_fd(FdExhausted::RejectAndPause(500)).unwrap();
+        assert_eq!(ctl.fd_exhausted(&backlog), Some(500));
+        assert_eq!(backlog.0.get(), 0);
+        // The spare descriptor is open again
+        assert!(ctl.0.lock().unwrap().2.reserve.as_ref().unwrap().0.is_some());
+    }
+
+    #[test]
+    fn slow_start() {
+        let ctl = Control::new();
+        ctl.set_slow_start(10, 1000);
+        let start = Instant::now();
+        assert_eq!(ctl.throttle_delay(start), None);
+        ctl.accepted(start);
+        assert_eq!(ctl.throttle_delay(start), Some(100));
+        // Faster in the middle of the ramp
+        let middle = start + Duration::from_millis(500);
+        assert_eq!(ctl.throttle_delay(middle), None);
+        ctl.accepted(middle);
+        assert_eq!(ctl.throttle_delay(middle), Some(4));
+        // No pacing after the ramp
+        let end = start + Duration::from_millis(1000);
+        ctl.accepted(end);
+        assert_eq!(ctl.throttle_delay(end), None);
+        // Up to the accept rate
+        ctl.set_accept_rate(1000, 1);
+        ctl.0.lock().unwrap().2.slow_start.as_mut().unwrap().restart();
+        ctl.accepted(end);
+        let middle = end + Duration::from_millis(500);
+        assert_eq!(ctl.throttle_delay(middle), None);
+        ctl.accepted(middle);
+        assert_eq!(ctl.throttle_delay(middle), Some(10));
     }
 }
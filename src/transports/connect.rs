@@ -0,0 +1,723 @@
+//! Connecting to a host with several addresses (Happy Eyeballs)
+//!
+//! The `Connect` machine implements the connection racing of RFC 8305:
+//! addresses are tried in turn, alternating IPv6 and IPv4 ones, and the
+//! next attempt is started when the previous one has failed or hasn't
+//! succeeded in the `attempt_delay` (250 ms by default). The first
+//! established connection is handed over to the protocol and the others
+//! are closed:
+//!
+//! ```ignore
+//! let addrs = try!(("example.com", 80).to_socket_addrs()).collect();
+//! let machine = Connect::new(addrs, Client::new(request));
+//! ```
+//!
+//! When all the attempts fail the last error is reported to
+//! `Protocol::closed`, and the machine is removed with `Response::Error`,
+//! so `Reconnect` recreates it.
+use std::any::Any;
+use std::fmt;
+use std::collections::VecDeque;
+use std::io::{self, Error, ErrorKind};
+use std::mem;
+use std::time::Instant;
+use std::net::SocketAddr;
+
+use mio::tcp::TcpStream;
+use mio::{Token, Timeout, TimerError, EventSet, PollOpt, Evented};
+
+use {BaseMachine, EventMachine, Scope, Response};
+use handler::{MachineId, Notifier, NotifyError, Target};
+use pool::Connection;
+use stats::Stats;
+use super::StreamSocket;
+use super::greedy_stream::{Stream, Protocol, CloseReason};
+
+
+/// The timeout of the `Connect` machine
+pub enum Timer<T> {
+    /// The timeout of the stream
+    Stream(T),
+    /// Time to start the next connection attempt
+    Attempt,
+}
+
+/// The machine which connects and then runs the protocol `P`
+pub struct Connect<P: Protocol<C>, C>(State<P, C>);
+
+enum State<P: Protocol<C>, C> {
+    Connecting(Attempts<P>),
+    Connected(Stream<TcpStream, P, C>),
+}
+
+struct Attempts<P> {
+    protocol: P,
+    addrs: VecDeque<SocketAddr>,
+    pending: Vec<TcpStream>,
+    timer: Option<Timeout>,
+    attempt_delay: u64,
+    error: Option<Error>,
+}
+
+struct ScopeProxy<'a, S: 'a>(&'a mut S);
+
+/// Orders addresses for connection attempts
+///
+/// Address families are interleaved, starting with the family of the first
+/// address, as recommended by RFC 8305. Order within the family is kept.
+pub fn interleave(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().map(|a| a.is_ipv6()).unwrap_or(true);
+    let (mut first, mut second): (VecDeque<_>, VecDeque<_>) = addrs.iter()
+        .cloned()
+        .partition(|a| a.is_ipv6() == first_v6);
+    let mut result = Vec::with_capacity(addrs.len());
+    loop {
+        match (first.pop_front(), second.pop_front()) {
+            (None, None) => break,
+            (a, b) => {
+                result.extend(a);
+                result.extend(b);
+            }
+        }
+    }
+    result
+}
+
+impl<P: Protocol<C>, C> Connect<P, C> {
+    /// Creates the machine connecting to the `addrs`
+    ///
+    /// Addresses are reordered by `interleave`
+    pub fn new(addrs: Vec<SocketAddr>, protocol: P) -> Connect<P, C> {
+        Connect(State::Connecting(Attempts {
+            protocol: protocol,
+            addrs: interleave(&addrs).into_iter().collect(),
+            pending: Vec::new(),
+            timer: None,
+            attempt_delay: 250,
+            error: None,
+        }))
+    }
+    /// Sets the delay before starting the next attempt in milliseconds
+    pub fn attempt_delay(mut self, ms: u64) -> Self {
+        if let State::Connecting(ref mut a) = self.0 {
+            a.attempt_delay = ms;
+        }
+        self
+    }
+    /// Returns true when the connection is established
+    pub fn is_connected(&self) -> bool {
+        match self.0 {
+            State::Connecting(_) => false,
+            State::Connected(_) => true,
+        }
+    }
+}
+
+impl<P> Attempts<P> {
+    /// Starts the connection to the next address which may be connected
+    fn start<C, S>(&mut self, scope: &mut S)
+        where P: Protocol<C>, S: Scope<Connect<P, C>>
+    {
+        while let Some(addr) = self.addrs.pop_front() {
+            let result = TcpStream::connect(&addr).and_then(|sock| {
+                try!(scope.register(&sock,
+                    EventSet::writable() | EventSet::hup() | EventSet::error(),
+                    PollOpt::edge()));
+                Ok(sock)
+            });
+            match result {
+                Ok(sock) => {
+                    debug!("Connecting to {}", addr);
+                    self.pending.push(sock);
+                    break;
+                }
+                Err(e) => {
+                    debug!("Can't connect to {}: {}", addr, e);
+                    self.error = Some(e);
+                }
+            }
+        }
+        if self.addrs.len() > 0 {
+            match scope.add_timeout_ms(self.attempt_delay, Timer::Attempt) {
+                Ok(timeout) => self.timer = Some(timeout),
+                Err(e) => error!("Can't add connection timeout: {:?}", e),
+            }
+        }
+    }
+    /// Returns the established connection, dropping the failed ones
+    fn check<C, S>(&mut self, scope: &mut S) -> Option<TcpStream>
+        where P: Protocol<C>, S: Scope<Connect<P, C>>
+    {
+        let mut connected = None;
+        for sock in mem::replace(&mut self.pending, Vec::new()) {
+            let state = sock.take_socket_error()
+                .and_then(|()| sock.peer_addr().map(|_| true))
+                .or_else(|e| if e.kind() == ErrorKind::NotConnected {
+                    Ok(false)
+                } else {
+                    Err(e)
+                });
+            match state {
+                Ok(true) if connected.is_none() => connected = Some(sock),
+                Ok(false) => self.pending.push(sock),
+                Ok(true) => {
+                    scope.deregister(&sock).ok();
+                }
+                Err(e) => {
+                    debug!("Connection attempt failed: {}", e);
+                    scope.deregister(&sock).ok();
+                    self.error = Some(e);
+                }
+            }
+        }
+        connected
+    }
+    /// Closes the attempts in progress
+    fn abort<C, S>(&mut self, scope: &mut S)
+        where P: Protocol<C>, S: Scope<Connect<P, C>>
+    {
+        self.timer.take().map(|t| scope.clear_timeout(t));
+        for sock in self.pending.drain(..) {
+            scope.deregister(&sock).ok();
+        }
+    }
+    /// Hands the connection over to the protocol, or reports the failure
+    /// if there are no attempts left
+    fn finish<C, S>(mut self, connected: Option<TcpStream>, context: &mut C,
+        scope: &mut S)
+        -> Response<Connect<P, C>>
+        where P: Protocol<C>, S: Scope<Connect<P, C>>
+    {
+        if let Some(sock) = connected {
+            self.abort(scope);
+            // The socket is registered again by the stream
+            scope.deregister(&sock).ok();
+            let stream = Stream::with_protocol(sock, self.protocol, context);
+            return Response::Replace(Connect(State::Connected(stream)));
+        }
+        if self.pending.len() == 0 && self.addrs.len() > 0 {
+            // Don't wait for the timer when all the attempts failed
+            self.timer.take().map(|t| scope.clear_timeout(t));
+            self.start(scope);
+        }
+        if self.pending.len() > 0 {
+            return Response::Continue(Connect(State::Connecting(self)));
+        }
+        self.abort(scope);
+        let error = self.error.unwrap_or_else(|| Error::new(
+            ErrorKind::InvalidInput, "No addresses to connect to"));
+        let failure = Error::new(error.kind(),
+            format!("Can't connect: {}", error));
+        self.protocol.closed(CloseReason::Error(error), context);
+        Response::Error(failure.into())
+    }
+}
+
+impl<P: Protocol<C>, C> BaseMachine for Connect<P, C> {
+    type Timeout = Timer<P::Timeout>;
+}
+
+impl<P: Protocol<C>, C> EventMachine<C> for Connect<P, C> {
+    fn ready<S>(self, events: EventSet, context: &mut C, scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        match self.0 {
+            State::Connecting(mut a) => {
+                let connected = a.check(scope);
+                a.finish(connected, context, scope)
+            }
+            State::Connected(s) => s.ready(events, context,
+                &mut ScopeProxy(scope))
+                .map(State::Connected).map(Connect),
+        }
+    }
+    fn register<S>(&mut self, scope: &mut S) -> io::Result<()>
+        where S: Scope<Self>
+    {
+        match self.0 {
+            State::Connecting(ref mut a) => {
+                let timeout = try!(scope.add_timeout_ms(0, Timer::Attempt)
+                    .map_err(|e| Error::new(ErrorKind::Other,
+                        format!("Can't add timeout: {:?}", e))));
+                a.timer = Some(timeout);
+                Ok(())
+            }
+            State::Connected(ref mut s) => s.register(&mut ScopeProxy(scope)),
+        }
+    }
+    fn timeout<S>(self, timeout: Self::Timeout, context: &mut C,
+        scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        match (self.0, timeout) {
+            (State::Connecting(mut a), Timer::Attempt) => {
+                a.timer = None;
+                a.start(scope);
+                let connected = a.check(scope);
+                a.finish(connected, context, scope)
+            }
+            (State::Connected(s), Timer::Stream(t)) => s.timeout(t, context,
+                &mut ScopeProxy(scope))
+                .map(State::Connected).map(Connect),
+            (state, _) => Response::Continue(Connect(state)),
+        }
+    }
+    fn wakeup<S>(self, context: &mut C, scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        match self.0 {
+            State::Connected(s) => s.wakeup(context, &mut ScopeProxy(scope))
+                .map(State::Connected).map(Connect),
+            state => Response::Continue(Connect(state)),
+        }
+    }
+    fn tick<S>(self, context: &mut C, scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        match self.0 {
+            State::Connected(s) => s.tick(context, &mut ScopeProxy(scope))
+                .map(State::Connected).map(Connect),
+            state => Response::Continue(Connect(state)),
+        }
+    }
+    fn stalled<S>(self, context: &mut C, scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        match self.0 {
+            State::Connected(s) => s.stalled(context, &mut ScopeProxy(scope))
+                .map(State::Connected).map(Connect),
+            // The attempt timers are running
+            state => Response::Continue(Connect(state)),
+        }
+    }
+    fn child_terminated<S>(self, child: Token, context: &mut C,
+        scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        match self.0 {
+            State::Connected(s) => s.child_terminated(child, context,
+                    &mut ScopeProxy(scope))
+                .map(State::Connected).map(Connect),
+            state => Response::Continue(Connect(state)),
+        }
+    }
+    fn shutdown<S>(self, context: &mut C, scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        match self.0 {
+            State::Connected(s) => s.shutdown(context, &mut ScopeProxy(scope))
+                .map(State::Connected).map(Connect),
+            State::Connecting(mut a) => {
+                a.abort(scope);
+                Response::Remove
+            }
+        }
+    }
+    fn drain<S>(self, context: &mut C, scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        match self.0 {
+            State::Connected(s) => s.drain(context, &mut ScopeProxy(scope))
+                .map(State::Connected).map(Connect),
+            state => Response::Continue(Connect(state)),
+        }
+    }
+    fn is_draining(&self) -> bool {
+        match self.0 {
+            State::Connected(ref s) => s.is_draining(),
+            State::Connecting(_) => false,
+        }
+    }
+    fn name(&self) -> &'static str {
+        match self.0 {
+            State::Connecting(_) => "connect",
+            State::Connected(ref s) => s.name(),
+        }
+    }
+    fn debug(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            State::Connecting(ref a) => write!(f, "pending={} left={}",
+                a.pending.len(), a.addrs.len()),
+            State::Connected(ref s) => s.debug(f),
+        }
+    }
+    fn deregister<S>(&mut self, scope: &mut S) -> io::Result<()>
+        where S: Scope<Self>
+    {
+        match self.0 {
+            State::Connecting(ref mut a) => {
+                a.abort(scope);
+                Ok(())
+            }
+            State::Connected(ref mut s) => {
+                s.deregister(&mut ScopeProxy(scope))
+            }
+        }
+    }
+}
+
+impl<P: Protocol<C>, C> Connection<C> for Connect<P, C> {
+    fn is_ready(&self) -> bool {
+        self.is_connected()
+    }
+    /// Checks the socket as if the error event is received
+    ///
+    /// The protocol is closed if there is a pending error, or the peer has
+    /// closed the connection
+    fn health_check<S>(self, context: &mut C, scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        if self.is_connected() {
+            self.ready(EventSet::error(), context, scope)
+        } else {
+            Response::Continue(self)
+        }
+    }
+}
+
+impl<'a, S, P, C> Scope<Stream<TcpStream, P, C>> for ScopeProxy<'a, S>
+    where S: Scope<Connect<P, C>> + 'a,
+          P: Protocol<C>,
+{
+    fn async_add_machine(&mut self, m: Stream<TcpStream, P, C>)
+        -> Result<(), Stream<TcpStream, P, C>>
+    {
+        self.0.async_add_machine(Connect(State::Connected(m)))
+            .map_err(unwrap)
+    }
+    fn add_child(&mut self, m: Stream<TcpStream, P, C>)
+        -> Result<Token, Stream<TcpStream, P, C>>
+    {
+        self.0.add_child(Connect(State::Connected(m)))
+            .map_err(unwrap)
+    }
+    fn add_timeout_ms(&mut self, delay: u64, t: P::Timeout)
+        -> Result<Timeout, TimerError>
+    {
+        self.0.add_timeout_ms(delay, Timer::Stream(t))
+    }
+    fn clear_timeout(&mut self, timeout: Timeout) -> bool {
+        self.0.clear_timeout(timeout)
+    }
+    fn spawn_after(&mut self, delay: u64, m: Stream<TcpStream, P, C>)
+        -> Result<Timeout, TimerError>
+    {
+        self.0.spawn_after(delay, Connect(State::Connected(m)))
+    }
+    fn register<E: ?Sized>(&mut self, io: &E, interest: EventSet, opt: PollOpt)
+        -> Result<(), Error>
+        where E: Evented
+    {
+        self.0.register(io, interest, opt)
+    }
+    fn reregister<E: ?Sized>(&mut self, io: &E, interest: EventSet,
+        opt: PollOpt)
+        -> Result<(), Error>
+        where E: Evented
+    {
+        self.0.reregister(io, interest, opt)
+    }
+    fn deregister<E: ?Sized>(&mut self, io: &E) -> Result<(), Error>
+        where E: Evented
+    {
+        self.0.deregister(io)
+    }
+    fn token(&self) -> Token {
+        self.0.token()
+    }
+    fn id(&self) -> MachineId {
+        self.0.id()
+    }
+    fn is_alive(&self, id: MachineId) -> bool {
+        self.0.is_alive(id)
+    }
+    fn now(&self) -> Instant {
+        self.0.now()
+    }
+    fn now_ms(&self) -> u64 {
+        self.0.now_ms()
+    }
+    fn loop_stats(&self) -> Option<&Stats> {
+        self.0.loop_stats()
+    }
+    fn notifier(&self) -> Notifier {
+        self.0.notifier()
+    }
+    fn replace_self(&mut self, m: Stream<TcpStream, P, C>) {
+        self.0.replace_self(Connect(State::Connected(m)))
+    }
+    fn for_each_machine<F>(&self, f: F)
+        where F: FnMut(Token)
+    {
+        self.0.for_each_machine(f)
+    }
+    fn wakeup(&mut self, token: Token) -> Result<(), NotifyError> {
+        self.0.wakeup(token)
+    }
+    fn shutdown_loop(&mut self) {
+        self.0.shutdown_loop()
+    }
+    fn exit_loop(&mut self, status: i32) {
+        self.0.exit_loop(status)
+    }
+    fn shutdown_self(&mut self) {
+        self.0.shutdown_self()
+    }
+    fn slot_data<T: Any + Default>(&mut self) -> &mut T {
+        self.0.slot_data()
+    }
+    fn remove_slot_data<T: Any>(&mut self) -> Option<T> {
+        self.0.remove_slot_data()
+    }
+    fn request_tick(&mut self) {
+        self.0.request_tick()
+    }
+    fn dump_machines(&self, out: &mut io::Write)
+        -> io::Result<()>
+    {
+        self.0.dump_machines(out)
+    }
+    fn set_max_lifetime(&mut self, ms: u64) -> Result<(), TimerError> {
+        self.0.set_max_lifetime(ms)
+    }
+    fn migrate<T>(&mut self, token: Token, target: T) -> bool
+        where T: Target<Stream<TcpStream, P, C>> + 'static
+    {
+        self.0.migrate(token, move |m: Connect<P, C>| match m.0 {
+            State::Connected(s) => {
+                target.send_machine(s)
+                    .map_err(|s| Connect(State::Connected(s)))
+            }
+            state => Err(Connect(state)),
+        })
+    }
+    fn reserve_slot(&mut self) -> Option<Token> {
+        self.0.reserve_slot()
+    }
+    fn switch_slot(&mut self, token: Token) -> Token {
+        self.0.switch_slot(token)
+    }
+    fn fill_slot(&mut self, token: Token,
+        m: Option<Stream<TcpStream, P, C>>)
+    {
+        self.0.fill_slot(token, m.map(|m| Connect(State::Connected(m))))
+    }
+}
+
+/// Returns the stream of the machine, which wasn't added to the loop
+fn unwrap<P: Protocol<C>, C>(m: Connect<P, C>) -> Stream<TcpStream, P, C> {
+    match m.0 {
+        State::Connected(s) => s,
+        State::Connecting(_) => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read};
+    use std::mem;
+    use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+    use std::os::unix::io::FromRawFd;
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+    use libc;
+    use mio::{self, EventLoop, EventSet, Token};
+    use BaseMachine;
+    use handler::{Handler, Timer as LoopTimer};
+    use machines::reconnect::{Reconnect, Timer as RetryTimer};
+    use transports::greedy_stream::{Protocol, Transport, CloseReason};
+    use super::{Connect, Timer, interleave};
+
+    struct Context {
+        addrs: Vec<SocketAddr>,
+        log: Vec<String>,
+    }
+
+    /// Writes `hello` when connected and logs the close reason
+    struct Probe;
+
+    impl BaseMachine for Probe {
+        type Timeout = ();
+    }
+
+    impl Protocol<Context> for Probe {
+        fn accepted(_ctx: &mut Context) -> Probe {
+            unreachable!();
+        }
+        fn connected(self, transport: &mut Transport, ctx: &mut Context)
+            -> Option<Probe>
+        {
+            ctx.log.push("connected".to_string());
+            transport.output().extend(b"hello");
+            Some(self)
+        }
+        fn data_received(self, _transport: &mut Transport,
+            _ctx: &mut Context)
+            -> Option<Probe>
+        {
+            Some(self)
+        }
+        fn closed(self, reason: CloseReason, ctx: &mut Context) {
+            ctx.log.push(match reason {
+                CloseReason::Error(e) => format!("error {:?}", e.kind()),
+                _ => "closed".to_string(),
+            });
+        }
+    }
+
+    type Client = Connect<Probe, Context>;
+
+    fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|x| x.parse().unwrap()).collect()
+    }
+
+    /// Returns the listener with the `backlog`
+    fn listener(backlog: i32) -> TcpListener {
+        unsafe {
+            let fd = libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0);
+            assert!(fd >= 0);
+            let listener = TcpListener::from_raw_fd(fd);
+            let mut addr: libc::sockaddr_in = mem::zeroed();
+            addr.sin_family = libc::AF_INET as libc::sa_family_t;
+            addr.sin_addr.s_addr = u32::from(Ipv4Addr::new(127, 0, 0, 1))
+                .to_be();
+            assert_eq!(libc::bind(fd, &addr as *const _ as *const _,
+                mem::size_of_val(&addr) as libc::socklen_t), 0);
+            assert_eq!(libc::listen(fd, backlog), 0);
+            listener
+        }
+    }
+
+    /// Returns the address nobody listens on
+    fn refused() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    fn handler(addrs: Vec<SocketAddr>)
+        -> (Handler<Context, Client>, EventLoop<Handler<Context, Client>>)
+    {
+        let mut eloop = EventLoop::new().unwrap();
+        let handler = Handler::new(Context {
+            addrs: addrs,
+            log: Vec::new(),
+        }, &mut eloop);
+        (handler, eloop)
+    }
+
+    /// Fires the attempt timer, as if the `attempt_delay` has passed
+    fn attempt(handler: &mut Handler<Context, Client>,
+        eloop: &mut EventLoop<Handler<Context, Client>>, token: Token)
+    {
+        let id = handler.machine_id(token);
+        mio::Handler::timeout(handler, eloop,
+            LoopTimer::Machine(id, Timer::Attempt, Instant::now()));
+    }
+
+    /// Waits for the loopback connections to be established or refused
+    /// and delivers the event
+    fn ready(handler: &mut Handler<Context, Client>,
+        eloop: &mut EventLoop<Handler<Context, Client>>, token: Token)
+    {
+        sleep(Duration::from_millis(50));
+        mio::Handler::ready(handler, eloop, token,
+            EventSet::readable() | EventSet::writable());
+    }
+
+    #[test]
+    fn interleaved() {
+        assert_eq!(interleave(&addrs(&["[::1]:80", "[::2]:80", "[::3]:80",
+                                       "1.1.1.1:80", "2.2.2.2:80"])),
+                   addrs(&["[::1]:80", "1.1.1.1:80", "[::2]:80",
+                           "2.2.2.2:80", "[::3]:80"]));
+        assert_eq!(interleave(&addrs(&["1.1.1.1:80", "[::1]:80",
+                                       "2.2.2.2:80"])),
+                   addrs(&["1.1.1.1:80", "[::1]:80", "2.2.2.2:80"]));
+        assert_eq!(interleave(&[]), vec![]);
+    }
+
+    #[test]
+    fn race_to_the_next_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = vec![refused(), listener.local_addr().unwrap()];
+        let (mut handler, mut eloop) = handler(target.clone());
+        let tok = handler.add_machine(&mut eloop,
+            Connect::new(target, Probe).attempt_delay(10000)).unwrap();
+        // The first attempt, and the second one after the delay
+        attempt(&mut handler, &mut eloop, tok);
+        attempt(&mut handler, &mut eloop, tok);
+        ready(&mut handler, &mut eloop, tok);
+        // The established connection is handed over to the protocol
+        assert_eq!(handler.context().log, vec!["connected"]);
+        ready(&mut handler, &mut eloop, tok);
+        let (mut sock, _) = listener.accept().unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let mut buf = [0u8; 5];
+        sock.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        assert_eq!(handler.occupancy().0, 1);
+    }
+
+    #[test]
+    fn loser_is_closed() {
+        // The accept queue of the listener with zero backlog is full after
+        // a single connection, so the next one is stuck in SYN_SENT
+        let stalled = listener(0);
+        let filler = TcpStream::connect(stalled.local_addr().unwrap())
+            .unwrap();
+        let accepting = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = vec![stalled.local_addr().unwrap(),
+                          accepting.local_addr().unwrap()];
+        let (mut handler, mut eloop) = handler(target.clone());
+        let tok = handler.add_machine(&mut eloop, Connect::new(target, Probe))
+            .unwrap();
+        attempt(&mut handler, &mut eloop, tok);
+        assert!(handler.context().log.is_empty());
+        attempt(&mut handler, &mut eloop, tok);
+        ready(&mut handler, &mut eloop, tok);
+        assert_eq!(handler.context().log, vec!["connected"]);
+        // Had the stalled attempt been kept, the retransmitted SYN would be
+        // accepted when there is room in the queue
+        let (first, _) = stalled.accept().unwrap();
+        drop((first, filler));
+        sleep(Duration::from_millis(1500));
+        stalled.set_nonblocking(true).unwrap();
+        assert_eq!(stalled.accept().unwrap_err().kind(),
+            io::ErrorKind::WouldBlock);
+    }
+
+    type Retry = Reconnect<Client,
+        fn(&mut Context) -> io::Result<Client>, Context>;
+
+    fn client(ctx: &mut Context) -> io::Result<Client> {
+        Ok(Connect::new(ctx.addrs.clone(), Probe))
+    }
+
+    #[test]
+    fn failure_is_an_error() {
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::<Context, Retry>::new(Context {
+            addrs: vec![refused()],
+            log: Vec::new(),
+        }, &mut eloop);
+        let factory = client as fn(&mut Context) -> io::Result<Client>;
+        let tok = handler.add_machine(&mut eloop, Reconnect::new(factory))
+            .unwrap();
+        let id = handler.machine_id(tok);
+        mio::Handler::timeout(&mut handler, &mut eloop,
+            LoopTimer::Machine(id, RetryTimer::Retry, Instant::now()));
+        mio::Handler::timeout(&mut handler, &mut eloop,
+            LoopTimer::Machine(id, RetryTimer::Inner(Timer::Attempt),
+                Instant::now()));
+        sleep(Duration::from_millis(50));
+        mio::Handler::ready(&mut handler, &mut eloop, tok,
+            EventSet::writable() | EventSet::error());
+        assert_eq!(handler.context().log, vec!["error ConnectionRefused"]);
+        // The machine is kept by `Reconnect`, which is only the case for
+        // `Response::Error`
+        assert_eq!(handler.occupancy().0, 1);
+    }
+}
@@ -0,0 +1,593 @@
+//! A stream which reads into the buffers owned by the protocol
+//!
+//! The `greedy_stream` reads into its own input buffer, so the protocol
+//! copies the data once more. Protocols which parse in place or forward
+//! the bytes verbatim (e.g. proxies) supply the destination of every read
+//! instead:
+//!
+//! ```ignore
+//! fn alloc_read_buf(&mut self, _ctx: &mut C) -> &mut [u8] {
+//!     &mut self.frame[self.filled..]
+//! }
+//! fn bytes_read(mut self, n: usize, output: &mut Buf, ctx: &mut C)
+//!     -> Option<Self>
+//! {
+//!     self.filled += n;
+//!     ...
+//! }
+//! ```
+//!
+//! Returning an empty buffer pauses reading, it's resumed on the next
+//! event or wakeup of the machine. The output is buffered as in the
+//! `greedy_stream`, and so is the lifecycle: the connection is closed on
+//! `Handler::drain` when it's idle, on shutdown and after
+//! `Settings::idle_timeout`, and the reason is reported by
+//! `Protocol::closed`.
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::marker::PhantomData;
+use std::time::{Duration, Instant};
+use std::io::ErrorKind::{WouldBlock, Interrupted};
+
+use mio::{EventSet, PollOpt};
+use netbuf::Buf;
+
+use super::StreamSocket as Socket;
+use super::accept::Init;
+use super::greedy_stream::{Settings, CloseReason};
+use {Scope, BaseMachine, EventMachine, Response};
+use error::Error as RotorError;
+
+/// Maximum number of `Protocol::output_ready` calls in a single dispatch,
+/// the rest is continued on the next loop iteration
+const OUTPUT_CALLS: usize = 64;
+
+
+/// The protocol which owns the input buffers, see module documentation
+pub trait Protocol<C>: BaseMachine + Sized {
+    /// Returns new state machine in a state for new accepted connection
+    fn accepted(ctx: &mut C) -> Self;
+    /// Returns the buffer to read the next chunk of data into
+    ///
+    /// Return an empty slice to stop reading for now
+    fn alloc_read_buf(&mut self, ctx: &mut C) -> &mut [u8];
+    /// The first `n` bytes of the buffer returned by `alloc_read_buf` are
+    /// filled with the data
+    fn bytes_read(self, n: usize, output: &mut Buf, ctx: &mut C)
+        -> Option<Self>;
+    /// Output buffer is flushed and the socket is writable
+    fn output_ready(self, _output: &mut Buf, _ctx: &mut C) -> Option<Self> {
+        Some(self)
+    }
+    /// Eof received. State machine will shutdown unconditionally
+    fn eof_received(self, _ctx: &mut C) {}
+    /// Fatal error on connection happened
+    ///
+    /// Default action is to log error on the info level
+    fn error_happened(self, e: Error, _ctx: &mut C) {
+        info!("Error when handling connection: {}", e);
+    }
+    /// The connection is closed by the stream, see
+    /// `greedy_stream::Protocol::closed`
+    fn closed(self, reason: CloseReason, ctx: &mut C) {
+        match reason {
+            CloseReason::Eof => self.eof_received(ctx),
+            CloseReason::Error(e) => self.error_happened(e, ctx),
+            CloseReason::IdleTimeout => self.error_happened(
+                Error::new(ErrorKind::TimedOut, "Connection is idle"), ctx),
+            CloseReason::OutputOverflow | CloseReason::Shutdown |
+            CloseReason::Drained => {}
+        }
+    }
+    /// Returns settings for the new connection
+    ///
+    /// The `read_budget`, `level_triggered`, `shrink_buffers` and
+    /// `idle_timeout` apply, the limits of the buffers don't
+    fn settings(_ctx: &mut C) -> Settings {
+        Settings::default()
+    }
+    /// The loop is draining, see `greedy_stream::Protocol::drain_started`
+    ///
+    /// The connection is closed as soon as the output is flushed and
+    /// `is_idle` returns true
+    fn drain_started(self, _output: &mut Buf, _ctx: &mut C)
+        -> Option<Self>
+    {
+        Some(self)
+    }
+    /// Returns false while a partial request is in the buffers of the
+    /// protocol, so the draining loop doesn't close the connection
+    fn is_idle(&self) -> bool {
+        true
+    }
+}
+
+struct Inner<S: Socket> {
+    sock: S,
+    outbuf: Buf,
+    readable: bool,
+    writable: bool,
+    /// The protocol has returned an empty read buffer
+    paused: bool,
+    /// The loop is draining, the stream is closed when it's idle
+    draining: bool,
+    /// A budget of the dispatch is exhausted, continued on the tick
+    exhausted: bool,
+    settings: Settings,
+    /// Number of bytes read and written
+    transferred: u64,
+    /// The time of the last read or write, see `Settings::idle_timeout`
+    active: Instant,
+    /// Registered interest, used in level-triggered mode only
+    interest: EventSet,
+}
+
+pub struct Stream<S: Socket, P: Protocol<C>, C>(
+    Inner<S>, P, PhantomData<fn(&mut C)>);
+
+impl<T, P, C> Init<T, C> for Stream<T, P, C>
+    where T: Socket, P: Protocol<C>
+{
+    fn accept<S>(conn: T, context: &mut C, _scope: &mut S)
+        -> Self
+        where S: Scope<Self>
+    {
+        Stream::new(conn, context)
+    }
+}
+
+impl<S: Socket> Inner<S> {
+    /// Writes output buffer until it's empty or socket would block
+    ///
+    /// Returns `Ok(false)` if connection is closed
+    fn flush(&mut self) -> Result<bool, Error> {
+        while self.writable && self.outbuf.len() > 0 {
+            match self.outbuf.write_to(&mut self.sock) {
+                Ok(0) => return Ok(false),
+                Ok(n) => {
+                    self.transferred += n as u64;
+                }
+                Err(ref e) if e.kind() == WouldBlock => {
+                    self.writable = false;
+                }
+                Err(ref e) if e.kind() == Interrupted =>  { continue; }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+    /// The events to poll for in level-triggered mode
+    fn desired_interest(&self) -> EventSet {
+        let mut interest = EventSet::hup() | EventSet::error();
+        if !self.paused {
+            interest = interest | EventSet::readable();
+        }
+        if self.outbuf.len() > 0 {
+            interest = interest | EventSet::writable();
+        }
+        interest
+    }
+}
+
+impl<T, P, C> Stream<T, P, C>
+    where T: Socket, P: Protocol<C>
+{
+    /// Creates a state machine for a connected socket
+    pub fn new(sock: T, context: &mut C) -> Self {
+        let settings = P::settings(context);
+        Stream(Inner {
+            sock: sock,
+            outbuf: Buf::new(),
+            readable: false,
+            writable: true,   // Accepted socket is immediately writable
+            paused: false,
+            draining: false,
+            exhausted: false,
+            settings: settings,
+            transferred: 0,
+            active: Instant::now(),
+            interest: EventSet::none(),
+        }, P::accepted(context), PhantomData)
+    }
+    /// Returns true if there is no request in progress
+    fn is_idle(&self) -> bool {
+        self.0.outbuf.len() == 0 && self.1.is_idle()
+    }
+    /// Handles the events of the socket
+    ///
+    /// As in the `greedy_stream`, the data which is already in the socket
+    /// is delivered before the end of stream or an error is reported
+    fn process(self, evset: EventSet, context: &mut C) -> Option<Self> {
+        let Stream(mut stream, mut fsm, _) = self;
+        if evset.is_writable() {
+            stream.writable = true;
+        }
+        if evset.is_readable() || evset.is_hup() || evset.is_error() {
+            stream.readable = true;
+        }
+        let mut closed = None;
+        if evset.is_error() {
+            if let Err(e) = stream.sock.take_socket_error() {
+                closed = Some(Some(e));
+            }
+        }
+        stream.exhausted = false;
+        let mut budget = stream.settings.read_budget;
+        let mut calls = OUTPUT_CALLS;
+        loop {
+            if closed.is_none() {
+                match stream.flush() {
+                    Ok(true) => {}
+                    Ok(false) => closed = Some(None),
+                    Err(e) => closed = Some(Some(e)),
+                }
+            }
+            if closed.is_none() && stream.writable &&
+                stream.outbuf.len() == 0
+            {
+                if calls == 0 {
+                    stream.exhausted = true;
+                } else {
+                    calls -= 1;
+                    fsm = match fsm.output_ready(&mut stream.outbuf,
+                                                 context)
+                    {
+                        Some(fsm) => fsm,
+                        None => return None,
+                    };
+                    if stream.outbuf.len() > 0 {
+                        continue;
+                    }
+                }
+            }
+            if !stream.readable {
+                break;
+            }
+            if budget == 0 {
+                // The socket is still readable, see `ready`
+                stream.exhausted = true;
+                break;
+            }
+            let result = {
+                let buf = fsm.alloc_read_buf(context);
+                stream.paused = buf.len() == 0;
+                if stream.paused {
+                    break;
+                }
+                stream.sock.read(buf)
+            };
+            match result {
+                Ok(0) => {
+                    let reason = match closed {
+                        Some(Some(e)) => CloseReason::Error(e),
+                        _ => CloseReason::Eof,
+                    };
+                    fsm.closed(reason, context);
+                    return None;
+                }
+                Ok(n) => {
+                    stream.transferred += n as u64;
+                    budget = budget.saturating_sub(n);
+                    fsm = match fsm.bytes_read(n, &mut stream.outbuf,
+                                               context)
+                    {
+                        Some(fsm) => fsm,
+                        None => return None,
+                    };
+                }
+                Err(ref e) if e.kind() == WouldBlock => {
+                    stream.readable = false;
+                }
+                Err(ref e) if e.kind() == Interrupted =>  {}
+                Err(e) => {
+                    fsm.closed(CloseReason::Error(e), context);
+                    return None;
+                }
+            }
+        }
+        match closed {
+            Some(None) => {
+                fsm.closed(CloseReason::Eof, context);
+                None
+            }
+            Some(Some(e)) => {
+                fsm.closed(CloseReason::Error(e), context);
+                None
+            }
+            None => {
+                if stream.settings.shrink_buffers &&
+                    stream.outbuf.len() == 0
+                {
+                    stream.outbuf = Buf::new();
+                }
+                Some(Stream(stream, fsm, PhantomData))
+            }
+        }
+    }
+}
+
+impl<T, P, Ctx> BaseMachine for Stream<T, P, Ctx>
+    where T: Socket, P: Protocol<Ctx>
+{
+    type Timeout = P::Timeout;
+}
+
+impl<T, P, Ctx> EventMachine<Ctx> for Stream<T, P, Ctx>
+    where T: Socket, P: Protocol<Ctx>
+{
+    fn ready<S>(self, evset: EventSet, context: &mut Ctx, scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        let transferred = self.0.transferred;
+        match self.process(evset, context) {
+            Some(stream) if stream.0.draining && stream.is_idle() => {
+                debug!("Closing the drained connection");
+                stream.1.closed(CloseReason::Drained, context);
+                Response::Remove
+            }
+            Some(mut stream) => {
+                if stream.0.transferred != transferred {
+                    stream.0.active = scope.now();
+                }
+                if stream.0.exhausted {
+                    scope.request_tick();
+                }
+                if stream.0.settings.level_triggered {
+                    let interest = stream.0.desired_interest();
+                    if interest != stream.0.interest {
+                        if let Err(e) = scope.reregister(&stream.0.sock,
+                            interest, PollOpt::level())
+                        {
+                            return Response::Error(RotorError::Register(e));
+                        }
+                        stream.0.interest = interest;
+                    }
+                }
+                Response::Continue(stream)
+            }
+            None => Response::Remove,
+        }
+    }
+
+    fn tick<S>(self, context: &mut Ctx, scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        self.ready(EventSet::none(), context, scope)
+    }
+
+    /// Resumes reading paused by the protocol
+    fn wakeup<S>(self, context: &mut Ctx, scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        self.ready(EventSet::none(), context, scope)
+    }
+
+    fn register<S>(&mut self, scope: &mut S)
+        -> Result<(), Error>
+        where S: Scope<Self>
+    {
+        if self.0.settings.level_triggered {
+            let interest = self.0.desired_interest();
+            try!(scope.register(&self.0.sock, interest, PollOpt::level()));
+            self.0.interest = interest;
+            Ok(())
+        } else {
+            scope.register(&self.0.sock, EventSet::all(), PollOpt::edge())
+        }
+    }
+
+    /// Calls `Protocol::drain_started` and closes the connection when
+    /// it's idle
+    fn drain<S>(self, context: &mut Ctx, scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        let Stream(mut stream, fsm, _) = self;
+        stream.draining = true;
+        match fsm.drain_started(&mut stream.outbuf, context) {
+            Some(fsm) => Stream(stream, fsm, PhantomData)
+                .ready(EventSet::none(), context, scope),
+            None => Response::Remove,
+        }
+    }
+    fn is_draining(&self) -> bool {
+        self.0.draining
+    }
+    fn shutdown<S>(self, context: &mut Ctx, _scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        self.1.closed(CloseReason::Shutdown, context);
+        Response::Remove
+    }
+    /// Closes the connection after `Settings::idle_timeout`
+    fn stalled<S>(self, context: &mut Ctx, scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        let timeout = match self.0.settings.idle_timeout {
+            Some(ms) => Duration::from_millis(ms),
+            None => {
+                warn!("Stream {:?} is stalled", scope.token());
+                return Response::Continue(self);
+            }
+        };
+        if scope.now() >= self.0.active + timeout {
+            self.1.closed(CloseReason::IdleTimeout, context);
+            Response::Remove
+        } else {
+            Response::Continue(self)
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "direct_stream"
+    }
+    fn debug(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "out={}", self.0.outbuf.len()));
+        if self.0.paused {
+            try!(write!(f, " paused"));
+        }
+        Ok(())
+    }
+
+    fn deregister<S>(&mut self, scope: &mut S)
+        -> Result<(), Error>
+        where S: Scope<Self>
+    {
+        scope.deregister(&self.0.sock)
+    }
+}
+
+#[cfg(all(test, target_os="linux"))]
+mod test {
+    use std::io::{Read, Write};
+    use mio::EventSet;
+    use netbuf::Buf;
+
+    use BaseMachine;
+    use transports::greedy_stream::{Settings, CloseReason};
+    use transports::loopback::{self, End};
+    use super::{Stream, Protocol, OUTPUT_CALLS};
+
+    /// Replies with the number of bytes in every chunk read
+    struct Counter {
+        buf: [u8; 4],
+    }
+    /// Same as `Counter` but reads at most 4 bytes per event
+    struct Budget(Counter);
+    /// Writes a byte whenever there is room in the output
+    struct Endless;
+
+    impl BaseMachine for Counter {
+        type Timeout = ();
+    }
+
+    impl Protocol<Vec<String>> for Counter {
+        fn accepted(_ctx: &mut Vec<String>) -> Counter {
+            Counter { buf: [0; 4] }
+        }
+        fn alloc_read_buf(&mut self, _ctx: &mut Vec<String>) -> &mut [u8] {
+            &mut self.buf
+        }
+        fn bytes_read(self, n: usize, output: &mut Buf,
+            ctx: &mut Vec<String>)
+            -> Option<Counter>
+        {
+            ctx.push(String::from_utf8_lossy(&self.buf[..n]).into_owned());
+            output.extend(n.to_string().as_bytes());
+            Some(self)
+        }
+        fn closed(self, reason: CloseReason, ctx: &mut Vec<String>) {
+            ctx.push(format!("{:?}", reason));
+        }
+    }
+
+    impl BaseMachine for Budget {
+        type Timeout = ();
+    }
+
+    impl Protocol<Vec<String>> for Budget {
+        fn accepted(ctx: &mut Vec<String>) -> Budget {
+            Budget(Counter::accepted(ctx))
+        }
+        fn alloc_read_buf(&mut self, ctx: &mut Vec<String>) -> &mut [u8] {
+            self.0.alloc_read_buf(ctx)
+        }
+        fn bytes_read(self, n: usize, output: &mut Buf,
+            ctx: &mut Vec<String>)
+            -> Option<Budget>
+        {
+            self.0.bytes_read(n, output, ctx).map(Budget)
+        }
+        fn settings(_ctx: &mut Vec<String>) -> Settings {
+            Settings {
+                read_budget: 4,
+                ..Settings::default()
+            }
+        }
+    }
+
+    impl BaseMachine for Endless {
+        type Timeout = ();
+    }
+
+    impl Protocol<Vec<String>> for Endless {
+        fn accepted(_ctx: &mut Vec<String>) -> Endless {
+            Endless
+        }
+        fn alloc_read_buf(&mut self, _ctx: &mut Vec<String>) -> &mut [u8] {
+            &mut []
+        }
+        fn bytes_read(self, _n: usize, _output: &mut Buf,
+            _ctx: &mut Vec<String>)
+            -> Option<Endless>
+        {
+            Some(self)
+        }
+        fn output_ready(self, output: &mut Buf, _ctx: &mut Vec<String>)
+            -> Option<Endless>
+        {
+            output.extend(b"x");
+            Some(self)
+        }
+    }
+
+    /// Returns the stream and the peer end of the connection
+    fn connect<P: Protocol<Vec<String>>>(log: &mut Vec<String>)
+        -> (Stream<End, P, Vec<String>>, End)
+    {
+        let (sock, peer) = loopback::pair().unwrap();
+        (Stream::new(sock, log), peer)
+    }
+
+    fn read_all(peer: &mut End) -> Vec<u8> {
+        let mut buf = vec![0; peer.pending()];
+        peer.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn read_into_protocol() {
+        let mut log = Vec::new();
+        let (stream, mut peer) = connect::<Counter>(&mut log);
+        peer.write_all(b"hello world").unwrap();
+        assert!(stream.process(EventSet::readable(), &mut log).is_some());
+        assert_eq!(log, vec!["hell", "o wo", "rld"]);
+        assert_eq!(read_all(&mut peer), b"443");
+    }
+
+    #[test]
+    fn closed_by_peer() {
+        let mut log = Vec::new();
+        let (stream, peer) = connect::<Counter>(&mut log);
+        drop(peer);
+        assert!(stream.process(EventSet::readable(), &mut log).is_none());
+        assert_eq!(log, vec!["Eof"]);
+    }
+
+    #[test]
+    fn read_budget() {
+        let mut log = Vec::new();
+        let (stream, mut peer) = connect::<Budget>(&mut log);
+        peer.write_all(b"hello world").unwrap();
+        let stream = stream.process(EventSet::readable(), &mut log)
+            .unwrap();
+        assert_eq!(log, vec!["hell"]);
+        // The rest is read on the ticks
+        let stream = stream.process(EventSet::none(), &mut log).unwrap();
+        stream.process(EventSet::none(), &mut log).unwrap();
+        assert_eq!(log, vec!["hell", "o wo", "rld"]);
+        assert_eq!(read_all(&mut peer), b"443");
+    }
+
+    #[test]
+    fn output_calls() {
+        let mut log = Vec::new();
+        let (stream, mut peer) = connect::<Endless>(&mut log);
+        let stream = stream.process(EventSet::writable(), &mut log)
+            .unwrap();
+        assert_eq!(read_all(&mut peer).len(), OUTPUT_CALLS);
+        stream.process(EventSet::none(), &mut log).unwrap();
+        assert_eq!(read_all(&mut peer).len(), OUTPUT_CALLS);
+    }
+}
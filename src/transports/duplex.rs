@@ -0,0 +1,358 @@
+//! An adapter of the `greedy_stream` for full-duplex protocols
+//!
+//! Some protocols read and write independently, e.g. a client which
+//! streams telemetry while receiving commands. Implement `Reader` and
+//! `Writer` and wrap them into `Duplex` to get a `Protocol`:
+//!
+//! * the reader gets the input, the writer is asked for the output when
+//!   there is room in the output buffer (see `Protocol::output_ready`);
+//!   when the writer has new data later, wake up the machine
+//! * either half may finish by returning `None`, the input is discarded
+//!   after the reader is finished, nothing is written after the writer is
+//!   finished
+//! * the connection is closed when both halves are finished and the output
+//!   is flushed, or when the peer closes the connection
+//!
+//! The halves don't see each other, use the context to pass data between
+//! them.
+use std::io::{Error, ErrorKind};
+
+use netbuf::Buf;
+
+use BaseMachine;
+use super::greedy_stream::{Protocol, Transport, Settings, CloseReason};
+
+
+/// The reading half of the duplex protocol
+pub trait Reader<C>: BaseMachine + Sized {
+    /// The writing half of the protocol
+    type Writer: Writer<C>;
+    /// Returns both halves for a new connection
+    fn accepted(ctx: &mut C) -> (Self, Self::Writer);
+    /// Called on the first event of the connection, see
+    /// `Protocol::connected`
+    ///
+    /// The writer is asked for the output right after this call
+    fn connected(self, _ctx: &mut C) -> Option<Self> {
+        Some(self)
+    }
+    /// Some chunk of data has been received and placed into the buffer
+    ///
+    /// Return `None` to stop reading, the connection is not closed until
+    /// the writer is finished too
+    fn data_received(self, input: &mut Buf, ctx: &mut C) -> Option<Self>;
+    /// Eof received. Both halves are shut down unconditionally
+    fn eof_received(self, _ctx: &mut C) {}
+    /// Fatal error on connection happened
+    ///
+    /// Default action is to log error on the info level
+    fn error_happened(self, e: Error, _ctx: &mut C) {
+        info!("Error when handling connection: {}", e);
+    }
+    /// The connection is closed by the stream, see `Protocol::closed`
+    fn closed(self, reason: CloseReason, ctx: &mut C) {
+        match reason {
+            CloseReason::Eof => self.eof_received(ctx),
+            CloseReason::Error(e) => self.error_happened(e, ctx),
+            CloseReason::IdleTimeout => self.error_happened(
+                Error::new(ErrorKind::TimedOut, "Connection is idle"), ctx),
+            CloseReason::OutputOverflow | CloseReason::Shutdown |
+            CloseReason::Drained => {}
+        }
+    }
+    /// Returns settings for the new connection
+    fn settings(_ctx: &mut C) -> Settings {
+        Settings::default()
+    }
+    /// Input buffer is larger than `Settings::max_input_buffer`, see
+    /// `Protocol::input_overflow`
+    ///
+    /// Return `None` to close the connection, which is the default
+    fn input_overflow(self, input: &mut Buf, ctx: &mut C) -> Option<Self> {
+        self.error_happened(Error::new(ErrorKind::Other,
+            format!("Input buffer overflow ({} bytes)", input.len())),
+            ctx);
+        None
+    }
+    /// The loop is draining, see `Protocol::drain_started`
+    ///
+    /// Return `None` to stop reading, the connection is closed when the
+    /// writer is finished too
+    fn drain_started(self, _ctx: &mut C) -> Option<Self> {
+        Some(self)
+    }
+}
+
+/// The writing half of the duplex protocol
+pub trait Writer<C>: Sized {
+    /// There is room in the output buffer
+    ///
+    /// Put the data which is ready into the buffer, or nothing. Return
+    /// `None` when there is nothing more to write, the data already in the
+    /// buffer is still sent
+    fn output_ready(self, output: &mut Buf, ctx: &mut C) -> Option<Self>;
+    /// The connection is closed before the writer is finished
+    fn closed(self, _ctx: &mut C) {}
+}
+
+/// A `Protocol` which runs the reader `R` and the writer `W` independently
+pub struct Duplex<R, W> {
+    reader: Option<R>,
+    writer: Option<W>,
+}
+
+impl<R, W> Duplex<R, W> {
+    /// Returns false when both halves are finished
+    fn is_alive(&self) -> bool {
+        self.reader.is_some() || self.writer.is_some()
+    }
+}
+
+impl<R: BaseMachine, W> BaseMachine for Duplex<R, W> {
+    type Timeout = R::Timeout;
+}
+
+impl<R, W, C> Protocol<C> for Duplex<R, W>
+    where R: Reader<C, Writer=W>, W: Writer<C>
+{
+    fn accepted(ctx: &mut C) -> Self {
+        let (reader, writer) = R::accepted(ctx);
+        Duplex {
+            reader: Some(reader),
+            writer: Some(writer),
+        }
+    }
+    fn connected(mut self, _transport: &mut Transport, ctx: &mut C)
+        -> Option<Self>
+    {
+        if let Some(reader) = self.reader.take() {
+            self.reader = reader.connected(ctx);
+        }
+        if self.is_alive() {
+            Some(self)
+        } else {
+            None
+        }
+    }
+    fn data_received(mut self, transport: &mut Transport, ctx: &mut C)
+        -> Option<Self>
+    {
+        match self.reader.take() {
+            Some(reader) => {
+                self.reader = reader.data_received(transport.input(), ctx);
+            }
+            None => {
+                let n = transport.input().len();
+                transport.input().consume(n);
+            }
+        }
+        if self.is_alive() || transport.output().len() > 0 {
+            Some(self)
+        } else {
+            None
+        }
+    }
+    fn output_ready(mut self, transport: &mut Transport, ctx: &mut C)
+        -> Option<Self>
+    {
+        if let Some(writer) = self.writer.take() {
+            self.writer = writer.output_ready(transport.output(), ctx);
+        }
+        if self.is_alive() || transport.output().len() > 0 {
+            Some(self)
+        } else {
+            None
+        }
+    }
+    fn eof_received(self, ctx: &mut C) {
+        if let Some(reader) = self.reader {
+            reader.eof_received(ctx);
+        }
+        if let Some(writer) = self.writer {
+            writer.closed(ctx);
+        }
+    }
+    fn error_happened(self, e: Error, ctx: &mut C) {
+        match self.reader {
+            Some(reader) => reader.error_happened(e, ctx),
+            None => info!("Error when handling connection: {}", e),
+        }
+        if let Some(writer) = self.writer {
+            writer.closed(ctx);
+        }
+    }
+    fn closed(self, reason: CloseReason, ctx: &mut C) {
+        match (self.reader, reason) {
+            (Some(reader), reason) => reader.closed(reason, ctx),
+            (None, CloseReason::Error(e)) => {
+                info!("Error when handling connection: {}", e);
+            }
+            (None, _) => {}
+        }
+        if let Some(writer) = self.writer {
+            writer.closed(ctx);
+        }
+    }
+    fn settings(ctx: &mut C) -> Settings {
+        R::settings(ctx)
+    }
+    fn drain_started(mut self, transport: &mut Transport, ctx: &mut C)
+        -> Option<Self>
+    {
+        if let Some(reader) = self.reader.take() {
+            self.reader = reader.drain_started(ctx);
+        }
+        if self.is_alive() || transport.output().len() > 0 {
+            Some(self)
+        } else {
+            None
+        }
+    }
+    fn input_overflow(mut self, transport: &mut Transport, ctx: &mut C)
+        -> Option<Self>
+    {
+        // The input is discarded after the reader is finished, so there is
+        // always a reader here
+        let reader = match self.reader.take() {
+            Some(reader) => reader,
+            None => return Some(self),
+        };
+        match reader.input_overflow(transport.input(), ctx) {
+            Some(reader) => {
+                self.reader = Some(reader);
+                Some(self)
+            }
+            None => {
+                if let Some(writer) = self.writer {
+                    writer.closed(ctx);
+                }
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Error;
+    use netbuf::Buf;
+    use BaseMachine;
+    use transports::greedy_stream::{Protocol, Settings, CloseReason};
+    use transports::greedy_stream::fuzz_feed;
+    use super::{Duplex, Reader, Writer};
+
+    /// Logs the input until `quit` is received
+    struct Commands;
+    /// Writes `tick` the number of times
+    struct Ticks(u32);
+    /// Keeps the input, which is limited to 4 bytes, and logs the events
+    struct Short;
+    /// Writes nothing, logs when the connection is closed
+    struct Idle;
+
+    impl BaseMachine for Commands {
+        type Timeout = ();
+    }
+
+    impl BaseMachine for Short {
+        type Timeout = ();
+    }
+
+    impl Reader<Vec<String>> for Commands {
+        type Writer = Ticks;
+        fn accepted(_ctx: &mut Vec<String>) -> (Commands, Ticks) {
+            (Commands, Ticks(2))
+        }
+        fn data_received(self, input: &mut Buf, ctx: &mut Vec<String>)
+            -> Option<Commands>
+        {
+            let len = input.len();
+            let quit = &input[..] == b"quit";
+            ctx.push(format!("command {}",
+                String::from_utf8_lossy(&input[..])));
+            input.consume(len);
+            if quit { None } else { Some(Commands) }
+        }
+    }
+
+    impl Writer<Vec<String>> for Ticks {
+        fn output_ready(self, output: &mut Buf, ctx: &mut Vec<String>)
+            -> Option<Ticks>
+        {
+            if self.0 == 0 {
+                ctx.push("ticks done".to_string());
+                return None;
+            }
+            output.extend(b"tick");
+            ctx.push("tick".to_string());
+            Some(Ticks(self.0 - 1))
+        }
+    }
+
+    impl Reader<Vec<String>> for Short {
+        type Writer = Idle;
+        fn accepted(_ctx: &mut Vec<String>) -> (Short, Idle) {
+            (Short, Idle)
+        }
+        fn connected(self, ctx: &mut Vec<String>) -> Option<Short> {
+            ctx.push("connected".to_string());
+            Some(self)
+        }
+        fn data_received(self, _input: &mut Buf, _ctx: &mut Vec<String>)
+            -> Option<Short>
+        {
+            Some(self)
+        }
+        fn error_happened(self, e: Error, ctx: &mut Vec<String>) {
+            ctx.push(format!("error {}", e));
+        }
+        fn closed(self, reason: CloseReason, ctx: &mut Vec<String>) {
+            ctx.push(match reason {
+                CloseReason::Eof => "eof".to_string(),
+                _ => "closed".to_string(),
+            });
+        }
+        fn settings(_ctx: &mut Vec<String>) -> Settings {
+            Settings { max_input_buffer: 4, ..Settings::default() }
+        }
+    }
+
+    impl Writer<Vec<String>> for Idle {
+        fn output_ready(self, _output: &mut Buf, _ctx: &mut Vec<String>)
+            -> Option<Idle>
+        {
+            Some(self)
+        }
+        fn closed(self, ctx: &mut Vec<String>) {
+            ctx.push("writer closed".to_string());
+        }
+    }
+
+    #[test]
+    fn closed_by_peer() {
+        let mut log = Vec::new();
+        let protocol = Duplex::<Short, Idle>::accepted(&mut log);
+        fuzz_feed(protocol, &mut log, b"\x04hi");
+        assert_eq!(log, vec!["connected", "eof", "writer closed"]);
+    }
+
+    #[test]
+    fn input_overflow() {
+        let mut log = Vec::new();
+        let protocol = Duplex::<Short, Idle>::accepted(&mut log);
+        fuzz_feed(protocol, &mut log, b"\x04hello");
+        assert_eq!(log, vec!["connected",
+            "error Input buffer overflow (5 bytes)", "writer closed"]);
+    }
+
+    #[test]
+    fn reader_outlives_writer() {
+        let mut log = Vec::new();
+        let protocol = Duplex::<Commands, Ticks>::accepted(&mut log);
+        // Read in chunks of 5 bytes
+        let output = fuzz_feed(protocol, &mut log, b"\x04helloquit");
+        assert_eq!(output, b"ticktick");
+        assert_eq!(log, vec!["tick", "tick", "ticks done",
+                             "command hello", "command quit"]);
+    }
+}
@@ -12,8 +12,11 @@
 //! This is tradeoff to have super simple protocol and semantics. More
 //! elaborate protocols will be implemented in the future.
 //!
-use std::io::{Read, Write, Error};
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::usize;
 use std::marker::PhantomData;
+use std::time::{Duration, Instant};
 use std::io::ErrorKind::{WouldBlock, Interrupted};
 
 use mio::{EventSet, PollOpt, Evented};
@@ -21,37 +24,293 @@ use netbuf::Buf;
 
 use super::StreamSocket as Socket;
 use super::super::handler::EventMachine;
-use super::accept::Init;
+use super::accept::{Init, Factory};
 
-use {Scope, BaseMachine};
+use {Scope, BaseMachine, Response};
+use error::Error as RotorError;
 
-impl<T> Socket for T where T: Read, T: Write, T: Evented {}
 
-
-struct Inner<S: Socket+Send> {
+struct Inner<S: Socket> {
     sock: S,
     inbuf: Buf,
     outbuf: Buf,
     writable: bool,
     readable: bool,
+    paused: bool,
+    connected: bool,
+    /// The loop is draining, the stream is closed when it's idle
+    draining: bool,
+    producer: Option<Box<OutputProducer>>,
+    settings: Settings,
+    counters: Counters,
+    /// The time the stream is created
+    created: Instant,
+    /// The time of the last read or write, see `Settings::idle_timeout`
+    active: Instant,
+    /// Registered interest, used in level-triggered mode only
+    interest: EventSet,
+}
+
+/// Tunables of the stream, see `Protocol::settings`
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// When output buffer grows larger than this value after
+    /// `data_received`, the `Protocol::output_full` is called. Unlimited by
+    /// default
+    pub output_high_watermark: usize,
+    /// When reading is paused because of the output buffer overflow, it's
+    /// resumed when output buffer shrinks to this size
+    pub output_low_watermark: usize,
+    /// When input buffer grows larger than this value after
+    /// `data_received`, the `Protocol::input_overflow` is called. Unlimited
+    /// by default
+    pub max_input_buffer: usize,
+    /// The `OutputProducer` is asked for more data when output buffer is
+    /// smaller than this value
+    pub producer_threshold: usize,
+    /// Maximum number of bytes read in a single dispatch. When exceeded,
+    /// reading is continued on the next loop iteration, so a fast peer
+    /// can't starve other connections. Unlimited by default
+    pub read_budget: usize,
+    /// Use level-triggered polling instead of edge-triggered
+    ///
+    /// The interest is updated after every dispatch: reading is not
+    /// polled while the stream is paused or input buffer is over the
+    /// `max_input_buffer`, writing is polled only when there is output
+    pub level_triggered: bool,
+    /// Free the memory of the buffers whenever they are empty after the
+    /// dispatch, e.g. when request is processed and the response is sent
+    ///
+    /// Otherwise buffers keep the capacity they have grown to, which adds
+    /// up for servers holding many mostly idle connections. The cost is
+    /// reallocation of buffers for every request.
+    pub shrink_buffers: bool,
+    /// Close the connection when nothing is read or written for this
+    /// number of milliseconds
+    ///
+    /// It's checked by the watchdog, so `Handler::set_watchdog` must be
+    /// enabled with a period not longer than the timeout. Disabled by
+    /// default
+    pub idle_timeout: Option<u64>,
+}
+
+/// Per-listener configuration of the `Stream` machines, see
+/// `Serve::new_with`
+///
+/// The values which are set override the ones of `Protocol::settings`:
+///
+/// ```ignore
+/// let builder = StreamBuilder::new()
+///     .output_watermarks(1 << 20, 65536)
+///     .idle_timeout(120000)
+///     .nodelay(true);
+/// Serve::<_, Stream<_, Http, _>, _, _>::new_with(listener, builder)
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct StreamBuilder {
+    output_watermarks: Option<(usize, usize)>,
+    max_input_buffer: Option<usize>,
+    producer_threshold: Option<usize>,
+    read_budget: Option<usize>,
+    level_triggered: Option<bool>,
+    shrink_buffers: Option<bool>,
+    idle_timeout: Option<u64>,
+    nodelay: Option<bool>,
+    keepalive: Option<u32>,
+}
+
+/// Per-connection counters passed to the protocol callbacks
+#[derive(Clone, Debug, Default)]
+pub struct Counters {
+    /// Number of times output buffer was over the high watermark
+    pub output_overflows: u64,
+    /// Number of bytes dropped from output buffer by `Overflow::Shed`
+    pub bytes_shed: u64,
+    /// Number of times input buffer was over the `max_input_buffer`
+    pub input_overflows: u64,
+    /// Number of bytes read from the socket
+    pub bytes_read: u64,
+    /// Number of bytes written to the socket
+    pub bytes_written: u64,
+}
+
+/// The decision of `Protocol::output_full`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    /// Close the connection
+    Close,
+    /// Stop reading (and calling `data_received`) until output buffer drains
+    /// to the low watermark
+    Pause,
+    /// Drop everything that is in the output buffer
+    Shed,
+}
+
+/// Why the connection is closed, see `Protocol::closed`
+#[derive(Debug)]
+pub enum CloseReason {
+    /// The peer has closed the connection
+    Eof,
+    /// Reading or writing has failed, or the socket has reported an error
+    Error(Error),
+    /// Nothing was read or written for `Settings::idle_timeout`
+    IdleTimeout,
+    /// `Protocol::output_full` returned `Overflow::Close`
+    OutputOverflow,
+    /// The loop is shutting down
+    Shutdown,
+    /// The loop is draining and the connection became idle
+    Drained,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            output_high_watermark: usize::MAX,
+            output_low_watermark: 0,
+            max_input_buffer: usize::MAX,
+            producer_threshold: 65536,
+            read_budget: usize::MAX,
+            level_triggered: false,
+            shrink_buffers: false,
+            idle_timeout: None,
+        }
+    }
+}
+
+impl StreamBuilder {
+    pub fn new() -> StreamBuilder {
+        StreamBuilder::default()
+    }
+    /// See `Settings::output_high_watermark` and `output_low_watermark`
+    pub fn output_watermarks(mut self, high: usize, low: usize) -> Self {
+        self.output_watermarks = Some((high, low));
+        self
+    }
+    /// See `Settings::max_input_buffer`
+    pub fn max_input_buffer(mut self, bytes: usize) -> Self {
+        self.max_input_buffer = Some(bytes);
+        self
+    }
+    /// See `Settings::producer_threshold`
+    pub fn producer_threshold(mut self, bytes: usize) -> Self {
+        self.producer_threshold = Some(bytes);
+        self
+    }
+    /// See `Settings::read_budget`
+    pub fn read_budget(mut self, bytes: usize) -> Self {
+        self.read_budget = Some(bytes);
+        self
+    }
+    /// See `Settings::level_triggered`
+    pub fn level_triggered(mut self, enable: bool) -> Self {
+        self.level_triggered = Some(enable);
+        self
+    }
+    /// See `Settings::shrink_buffers`
+    pub fn shrink_buffers(mut self, enable: bool) -> Self {
+        self.shrink_buffers = Some(enable);
+        self
+    }
+    /// See `Settings::idle_timeout`
+    pub fn idle_timeout(mut self, ms: u64) -> Self {
+        self.idle_timeout = Some(ms);
+        self
+    }
+    /// Sets `TCP_NODELAY` on accepted sockets
+    pub fn nodelay(mut self, enable: bool) -> Self {
+        self.nodelay = Some(enable);
+        self
+    }
+    /// Enables TCP keepalive on accepted sockets
+    pub fn keepalive(mut self, seconds: u32) -> Self {
+        self.keepalive = Some(seconds);
+        self
+    }
+    /// Overrides the values which are set in the builder
+    pub fn apply(&self, settings: &mut Settings) {
+        if let Some((high, low)) = self.output_watermarks {
+            settings.output_high_watermark = high;
+            settings.output_low_watermark = low;
+        }
+        self.max_input_buffer.map(|x| settings.max_input_buffer = x);
+        self.producer_threshold.map(|x| settings.producer_threshold = x);
+        self.read_budget.map(|x| settings.read_budget = x);
+        self.level_triggered.map(|x| settings.level_triggered = x);
+        self.shrink_buffers.map(|x| settings.shrink_buffers = x);
+        if self.idle_timeout.is_some() {
+            settings.idle_timeout = self.idle_timeout;
+        }
+    }
+    fn configure<S: Socket>(&self, sock: &S) -> Result<(), Error> {
+        if let Some(nodelay) = self.nodelay {
+            try!(sock.set_nodelay(nodelay));
+        }
+        if let Some(seconds) = self.keepalive {
+            try!(sock.set_keepalive(Some(seconds)));
+        }
+        Ok(())
+    }
+}
+
+impl<T, P, C> Factory<T, C, Stream<T, P, C>> for StreamBuilder
+    where T: Socket, P: Protocol<C>
+{
+    fn create<S>(&mut self, conn: T, context: &mut C, _scope: &mut S)
+        -> Option<Stream<T, P, C>>
+        where S: Scope<Stream<T, P, C>>
+    {
+        if let Err(e) = self.configure(&conn) {
+            // The connection is usable anyway
+            debug!("Can't set socket options: {}", e);
+        }
+        let mut settings = P::settings(context);
+        self.apply(&mut settings);
+        let protocol = P::accepted(context);
+        Some(Stream::configured(conn, protocol, settings))
+    }
 }
 
 pub struct Transport<'a> {
+    sock: &'a Socket,
     inbuf: &'a mut Buf,
     outbuf: &'a mut Buf,
+    producer: &'a mut Option<Box<OutputProducer>>,
+    counters: &'a Counters,
+    created: Instant,
 }
 
-pub struct Stream<S: Socket+Send, P: Protocol<C>, C>(
-    Inner<S>, P, PhantomData<*const C>);
+/// A source of output data which is pulled when the socket is writable
+///
+/// Useful for large responses, which shouldn't be buffered at once. Set
+/// it by `Transport::set_producer`.
+pub trait OutputProducer {
+    /// Put at most `max` bytes (it's just a hint) into the buffer
+    ///
+    /// Return `false` when there is no more data, the producer is dropped
+    /// then
+    fn pull(&mut self, buf: &mut Buf, max: usize) -> bool;
+}
 
-unsafe impl<S: Socket+Send, P: Protocol<C>+Send, C> Send for Stream<S, P, C> {}
+pub struct Stream<S: Socket, P: Protocol<C>, C>(
+    Inner<S>, P, PhantomData<fn(&mut C)>);
 
 /// This trait you should implement to handle the protocol. Only data_received
 /// handler is required, everything else may be left as is.
-pub trait Protocol<C>: BaseMachine + Send + Sized {
+pub trait Protocol<C>: BaseMachine + Sized {
     /// Returns new state machine in a state for new accepted connection
     // TODO(tailhook) should socket address be passed here?
     fn accepted(ctx: &mut C) -> Self;
+    /// Called on the first event of the connection, before any data is read
+    ///
+    /// Client protocols may write the request here. For outgoing
+    /// connections it means the connection is established (or failed, in
+    /// which case the error is reported right after this call)
+    fn connected(self, _transport: &mut Transport, _ctx: &mut C)
+        -> Option<Self>
+    {
+        Some(self)
+    }
     /// Some chunk of data has been received and placed into the buffer
     ///
     /// It's edge-triggered so be sure to read everything useful. But you
@@ -59,6 +318,18 @@ pub trait Protocol<C>: BaseMachine + Send + Sized {
     fn data_received(self, transport: &mut Transport, ctx: &mut C)
         -> Option<Self>;
 
+    /// Output buffer is flushed and the socket is writable
+    ///
+    /// Called on every dispatch when there is room for more output (and
+    /// there is no `OutputProducer`), and again as long as the call adds
+    /// data to the buffer. Protocols which produce output on their own
+    /// schedule should wake up the machine when new data is ready.
+    fn output_ready(self, _transport: &mut Transport, _ctx: &mut C)
+        -> Option<Self>
+    {
+        Some(self)
+    }
+
     /// Eof received. State machine will shutdown unconditionally
     fn eof_received(self, _ctx: &mut C) {}
 
@@ -69,75 +340,406 @@ pub trait Protocol<C>: BaseMachine + Send + Sized {
     fn error_happened(self, e: Error, _ctx: &mut C) {
         info!("Error when handling connection: {}", e);
     }
+
+    /// The connection is closed by the stream, the final callback
+    ///
+    /// Not called when the protocol closes the connection itself by
+    /// returning `None`. The default calls `eof_received` on `Eof`, and
+    /// `error_happened` on errors and on `IdleTimeout` (with the
+    /// `TimedOut` kind), the protocol is dropped silently otherwise
+    fn closed(self, reason: CloseReason, ctx: &mut C) {
+        match reason {
+            CloseReason::Eof => self.eof_received(ctx),
+            CloseReason::Error(e) => self.error_happened(e, ctx),
+            CloseReason::IdleTimeout => self.error_happened(
+                Error::new(ErrorKind::TimedOut, "Connection is idle"), ctx),
+            CloseReason::OutputOverflow | CloseReason::Shutdown |
+            CloseReason::Drained => {}
+        }
+    }
+
+    /// Returns settings for the new connection
+    fn settings(_ctx: &mut C) -> Settings {
+        Settings::default()
+    }
+
+    /// Output buffer is larger than `Settings::output_high_watermark`
+    ///
+    /// Default action is to log the event and close the connection
+    fn output_full(&mut self, counters: &Counters, _ctx: &mut C) -> Overflow {
+        warn!("Output buffer overflow ({} times), closing connection",
+            counters.output_overflows);
+        Overflow::Close
+    }
+
+    /// Input buffer is larger than `Settings::max_input_buffer`
+    ///
+    /// The protocol may consume or drop some input and continue. Default
+    /// action is to close the connection with an error
+    fn input_overflow(self, transport: &mut Transport, ctx: &mut C)
+        -> Option<Self>
+    {
+        self.error_happened(Error::new(ErrorKind::Other,
+            format!("Input buffer overflow ({} bytes)",
+                    transport.input().len())),
+            ctx);
+        None
+    }
+    /// The loop is draining, see `Handler::drain`
+    ///
+    /// Stop taking new requests, e.g. reply with `Connection: close`. The
+    /// connection is closed as soon as both buffers are empty, so the
+    /// request in progress is read and answered. Return `None` to close
+    /// the connection right away. Default does nothing.
+    fn drain_started(self, _transport: &mut Transport, _ctx: &mut C)
+        -> Option<Self>
+    {
+        Some(self)
+    }
+}
+
+/// The result of `Handshake::data_received`
+pub enum Switch<H, P> {
+    /// Continue the handshake
+    Stay(H),
+    /// Replace the handshake by the protocol `P`. Bytes left in the input
+    /// buffer are passed to the new protocol immediately
+    Upgrade(P),
+    /// Close the connection
+    Close,
+}
+
+/// A protocol which replaces itself with another one at some point
+///
+/// This is useful for STARTTLS, HTTP Upgrade, SOCKS and similar handshakes.
+/// Wrap the handshake into `Upgrade` to get a `Protocol`. Both input and
+/// output buffers are carried over to the new protocol.
+pub trait Handshake<C>: BaseMachine + Sized {
+    /// The protocol which takes over the connection after the handshake
+    type Next: Protocol<C, Timeout=Self::Timeout>;
+    /// Returns new state machine in a state for new accepted connection
+    fn accepted(ctx: &mut C) -> Self;
+    /// Called on the first event of the connection, see
+    /// `Protocol::connected`
+    fn connected(self, _transport: &mut Transport, _ctx: &mut C)
+        -> Switch<Self, Self::Next>
+    {
+        Switch::Stay(self)
+    }
+    /// Some chunk of data has been received and placed into the buffer
+    ///
+    /// Same as `Protocol::data_received` except that the protocol may be
+    /// switched
+    fn data_received(self, transport: &mut Transport, ctx: &mut C)
+        -> Switch<Self, Self::Next>;
+
+    /// Eof received. State machine will shutdown unconditionally
+    fn eof_received(self, _ctx: &mut C) {}
+
+    /// Fatal error on connection happened, you may process error somehow, but
+    /// statemachine will be destroyed anyway (note you receive self)
+    ///
+    /// Default action is to log error on the info level
+    fn error_happened(self, e: Error, _ctx: &mut C) {
+        info!("Error when handling connection: {}", e);
+    }
+}
+
+/// A protocol which runs the handshake `H` and then switches to `P`
+pub enum Upgrade<H, P> {
+    Handshake(H),
+    Upgraded(P),
+}
+
+impl<H, P> BaseMachine for Upgrade<H, P>
+    where H: BaseMachine
+{
+    type Timeout = H::Timeout;
+}
+
+impl<H, P, C> Protocol<C> for Upgrade<H, P>
+    where H: Handshake<C, Next=P>, P: Protocol<C, Timeout=H::Timeout>
+{
+    fn accepted(ctx: &mut C) -> Self {
+        Upgrade::Handshake(H::accepted(ctx))
+    }
+    fn connected(self, transport: &mut Transport, ctx: &mut C)
+        -> Option<Self>
+    {
+        use self::Upgrade::*;
+        match self {
+            Handshake(h) => match h.connected(transport, ctx) {
+                Switch::Stay(h) => Some(Handshake(h)),
+                Switch::Upgrade(p) => p.connected(transport, ctx)
+                                       .map(Upgraded),
+                Switch::Close => None,
+            },
+            Upgraded(p) => p.connected(transport, ctx).map(Upgraded),
+        }
+    }
+    fn data_received(self, transport: &mut Transport, ctx: &mut C)
+        -> Option<Self>
+    {
+        use self::Upgrade::*;
+        match self {
+            Handshake(h) => match h.data_received(transport, ctx) {
+                Switch::Stay(h) => Some(Handshake(h)),
+                Switch::Upgrade(p) => {
+                    if transport.input().len() > 0 {
+                        p.data_received(transport, ctx).map(Upgraded)
+                    } else {
+                        Some(Upgraded(p))
+                    }
+                }
+                Switch::Close => None,
+            },
+            Upgraded(p) => p.data_received(transport, ctx).map(Upgraded),
+        }
+    }
+    fn output_ready(self, transport: &mut Transport, ctx: &mut C)
+        -> Option<Self>
+    {
+        match self {
+            Upgrade::Upgraded(p) => p.output_ready(transport, ctx)
+                                     .map(Upgrade::Upgraded),
+            handshake => Some(handshake),
+        }
+    }
+    fn eof_received(self, ctx: &mut C) {
+        match self {
+            Upgrade::Handshake(h) => h.eof_received(ctx),
+            Upgrade::Upgraded(p) => p.eof_received(ctx),
+        }
+    }
+    fn drain_started(self, transport: &mut Transport, ctx: &mut C)
+        -> Option<Self>
+    {
+        match self {
+            Upgrade::Upgraded(p) => p.drain_started(transport, ctx)
+                                     .map(Upgrade::Upgraded),
+            handshake => Some(handshake),
+        }
+    }
+    fn error_happened(self, e: Error, ctx: &mut C) {
+        match self {
+            Upgrade::Handshake(h) => h.error_happened(e, ctx),
+            Upgrade::Upgraded(p) => p.error_happened(e, ctx),
+        }
+    }
+    fn closed(self, reason: CloseReason, ctx: &mut C) {
+        match (self, reason) {
+            (Upgrade::Upgraded(p), reason) => p.closed(reason, ctx),
+            (Upgrade::Handshake(h), CloseReason::Eof) => h.eof_received(ctx),
+            (Upgrade::Handshake(h), CloseReason::Error(e)) => {
+                h.error_happened(e, ctx)
+            }
+            (Upgrade::Handshake(h), CloseReason::IdleTimeout) => {
+                h.error_happened(Error::new(ErrorKind::TimedOut,
+                    "Connection is idle"), ctx)
+            }
+            (Upgrade::Handshake(_), _) => {}
+        }
+    }
+    fn settings(ctx: &mut C) -> Settings {
+        P::settings(ctx)
+    }
+    fn output_full(&mut self, counters: &Counters, ctx: &mut C) -> Overflow {
+        match *self {
+            Upgrade::Handshake(_) => {
+                warn!("Output buffer overflow during handshake, \
+                    closing connection");
+                Overflow::Close
+            }
+            Upgrade::Upgraded(ref mut p) => p.output_full(counters, ctx),
+        }
+    }
+    fn input_overflow(self, transport: &mut Transport, ctx: &mut C)
+        -> Option<Self>
+    {
+        match self {
+            Upgrade::Upgraded(p) => p.input_overflow(transport, ctx)
+                                     .map(Upgrade::Upgraded),
+            Upgrade::Handshake(h) => {
+                h.error_happened(Error::new(ErrorKind::Other,
+                    format!("Input buffer overflow during handshake \
+                             ({} bytes)", transport.input().len())),
+                    ctx);
+                None
+            }
+        }
+    }
 }
 
 impl<T, P, C> Init<T, C> for Stream<T, P, C>
-    where T: Socket+Send, P: Protocol<C>
+    where T: Socket, P: Protocol<C>
 {
     fn accept<S>(conn: T, context: &mut C, _scope: &mut S)
         -> Self
         where S: Scope<Self>
     {
+        Stream::new(conn, context)
+    }
+}
+
+impl<T, P, C> Stream<T, P, C>
+    where T: Socket, P: Protocol<C>
+{
+    /// Creates a state machine for a connected socket or a `pipe::Pipe`
+    ///
+    /// Streams for accepted connections are created by `Serve`
+    pub fn new(sock: T, context: &mut C) -> Self {
+        let protocol = P::accepted(context);
+        Stream::with_protocol(sock, protocol, context)
+    }
+    /// Creates a state machine with the protocol already constructed
+    ///
+    /// This is useful for outgoing connections, where the protocol needs
+    /// some data (i.e. the request) beyond the context
+    pub fn with_protocol(sock: T, protocol: P, context: &mut C) -> Self {
+        let settings = P::settings(context);
+        Stream::configured(sock, protocol, settings)
+    }
+    fn configured(sock: T, protocol: P, settings: Settings) -> Self {
         Stream(Inner {
-            sock: conn,
+            sock: sock,
             inbuf: Buf::new(),
             outbuf: Buf::new(),
             readable: false,
             writable: true,   // Accepted socket is immediately writable
-        }, Protocol::accepted(context), PhantomData)
+            paused: false,
+            connected: false,
+            draining: false,
+            producer: None,
+            settings: settings,
+            counters: Counters::default(),
+            created: Instant::now(),
+            active: Instant::now(),
+            interest: EventSet::none(),
+        }, protocol, PhantomData)
     }
 }
-impl<T, P, Ctx> BaseMachine for Stream<T, P, Ctx>
-    where T: Socket+Send, P: Protocol<Ctx>
-{
-    type Timeout = P::Timeout;
-}
-
-impl<T, P, Ctx> EventMachine<Ctx> for Stream<T, P, Ctx>
-    where T: Socket+Send, P: Protocol<Ctx>
+impl<T, P, C> Stream<T, P, C>
+    where T: Socket, P: Protocol<C>
 {
-    fn ready<S>(self, evset: EventSet, context: &mut Ctx, _scope: &mut S)
-        -> Option<Stream<T, P, Ctx>>
-        where S: Scope<Self>
-    {
+    /// Handles the events of the socket
+    ///
+    /// Data which is already in the socket is always delivered to the
+    /// protocol before the end of stream or an error is reported, whatever
+    /// combination of events is received
+    fn process(self, evset: EventSet, context: &mut C) -> Option<Self> {
         let Stream(mut stream, mut fsm, _) = self;
-        if evset.is_writable() && stream.outbuf.len() > 0 {
+        if evset.is_writable() {
             stream.writable = true;
-            while stream.outbuf.len() > 0 {
-                match stream.outbuf.write_to(&mut stream.sock) {
-                    Ok(0) => { // Connection closed
-                        fsm.eof_received(context);
-                        return None;
-                    }
-                    Ok(_) => {}  // May notify application
-                    Err(ref e) if e.kind() == WouldBlock => {
-                        stream.writable = false;
-                        break;
-                    }
-                    Err(ref e) if e.kind() == Interrupted =>  { continue; }
-                    Err(e) => {
-                        fsm.error_happened(e, context);
-                        return None;
+        }
+        // On hangup or error the rest of the data is still readable, then
+        // read returns end of stream or the error
+        if evset.is_readable() || evset.is_hup() || evset.is_error() {
+            stream.readable = true;
+        }
+        if !stream.connected {
+            stream.connected = true;
+            fsm = match fsm.connected(&mut stream.transport(), context) {
+                Some(fsm) => fsm,
+                None => return None,
+            };
+        }
+        // Failure of the socket or the writing side is reported after
+        // reading the input
+        let mut closed = None;
+        if evset.is_error() {
+            if let Err(e) = stream.sock.take_socket_error() {
+                closed = Some(Some(e));
+            }
+        }
+        let mut budget = stream.settings.read_budget;
+        'events: loop {
+            if closed.is_none() {
+                match stream.flush() {
+                    Ok(true) => {}
+                    Ok(false) => closed = Some(None),
+                    Err(e) => closed = Some(Some(e)),
+                }
+                if closed.is_none() && stream.fill() {
+                    continue;
+                }
+                if closed.is_none() && stream.has_room() {
+                    let old_len = stream.outbuf.len();
+                    fsm = match fsm.output_ready(
+                        &mut stream.transport(), context)
+                    {
+                        Some(fsm) => fsm,
+                        None => return None,
+                    };
+                    if stream.outbuf.len() > old_len {
+                        continue;
                     }
                 }
             }
-        }
-        if evset.is_readable() {
-            stream.readable = true;
+            if stream.paused &&
+                stream.outbuf.len() <= stream.settings.output_low_watermark
+            {
+                stream.paused = false;
+            }
+            // Output is not sent anyway when connection is broken
+            let paused = stream.paused && closed.is_none();
+            if !stream.readable || paused {
+                break;
+            }
             loop {
                 match stream.inbuf.read_from(&mut stream.sock) {
                     Ok(0) => { // Connection closed
-                        fsm.eof_received(context);
+                        let reason = match closed {
+                            Some(Some(e)) => CloseReason::Error(e),
+                            _ => CloseReason::Eof,
+                        };
+                        fsm.closed(reason, context);
                         return None;
                     }
-                    Ok(_) => {
-                        fsm = match fsm.data_received(&mut Transport {
-                            inbuf: &mut stream.inbuf,
-                            outbuf: &mut stream.outbuf,
-                        }, context) {
+                    Ok(n) => {
+                        stream.counters.bytes_read += n as u64;
+                        budget = budget.saturating_sub(n);
+                        fsm = match fsm.data_received(
+                            &mut stream.transport(), context)
+                        {
                             Some(fsm) => fsm,
                             None => return None,
                         };
+                        if stream.inbuf.len() >
+                            stream.settings.max_input_buffer
+                        {
+                            stream.counters.input_overflows += 1;
+                            fsm = match fsm.input_overflow(
+                                &mut stream.transport(), context)
+                            {
+                                Some(fsm) => fsm,
+                                None => return None,
+                            };
+                        }
+                        if stream.outbuf.len() >
+                            stream.settings.output_high_watermark
+                        {
+                            stream.counters.output_overflows += 1;
+                            match fsm.output_full(&stream.counters, context) {
+                                Overflow::Close => {
+                                    fsm.closed(CloseReason::OutputOverflow,
+                                               context);
+                                    return None;
+                                }
+                                Overflow::Pause => {
+                                    stream.paused = true;
+                                    break;
+                                }
+                                Overflow::Shed => {
+                                    let n = stream.outbuf.len();
+                                    stream.counters.bytes_shed += n as u64;
+                                    stream.outbuf.consume(n);
+                                }
+                            }
+                        }
+                        if budget == 0 {
+                            // The socket is still readable, see `ready`
+                            break 'events;
+                        }
                     }
                     Err(ref e) if e.kind() == WouldBlock => {
                         stream.readable = false;
@@ -145,40 +747,249 @@ impl<T, P, Ctx> EventMachine<Ctx> for Stream<T, P, Ctx>
                     }
                     Err(ref e) if e.kind() == Interrupted =>  { continue; }
                     Err(e) => {
-                        fsm.error_happened(e, context);
+                        fsm.closed(CloseReason::Error(e), context);
                         return None;
                     }
                 }
             }
         }
-        if stream.writable && stream.outbuf.len() > 0 {
-            while stream.outbuf.len() > 0 {
-                match stream.outbuf.write_to(&mut stream.sock) {
-                    Ok(0) => { // Connection closed
-                        fsm.eof_received(context);
-                        return None;
-                    }
-                    Ok(_) => {}  // May notify application
-                    Err(ref e) if e.kind() == WouldBlock => {
-                        stream.writable = false;
-                        break;
-                    }
-                    Err(ref e) if e.kind() == Interrupted =>  { continue; }
-                    Err(e) => {
-                        fsm.error_happened(e, context);
-                        return None;
+        match closed {
+            Some(None) => {
+                fsm.closed(CloseReason::Eof, context);
+                None
+            }
+            Some(Some(e)) => {
+                fsm.closed(CloseReason::Error(e), context);
+                None
+            }
+            None => {
+                if stream.settings.shrink_buffers {
+                    stream.shrink();
+                }
+                Some(Stream(stream, fsm, PhantomData))
+            }
+        }
+    }
+}
+
+impl<T, P, Ctx> BaseMachine for Stream<T, P, Ctx>
+    where T: Socket, P: Protocol<Ctx>
+{
+    type Timeout = P::Timeout;
+}
+
+impl<T, P, Ctx> EventMachine<Ctx> for Stream<T, P, Ctx>
+    where T: Socket, P: Protocol<Ctx>
+{
+    fn ready<S>(self, evset: EventSet, context: &mut Ctx, scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        let transferred = self.0.transferred();
+        match self.process(evset, context) {
+            Some(stream) if stream.0.draining && stream.0.is_idle() => {
+                debug!("Closing the drained connection");
+                stream.1.closed(CloseReason::Drained, context);
+                Response::Remove
+            }
+            Some(mut stream) => {
+                if stream.0.transferred() != transferred {
+                    stream.0.active = scope.now();
+                }
+                if stream.0.readable && !stream.0.paused {
+                    // Read budget is exhausted, continue after other machines
+                    scope.request_tick();
+                }
+                if stream.0.settings.level_triggered {
+                    let interest = stream.0.desired_interest();
+                    if interest != stream.0.interest {
+                        if let Err(e) = scope.reregister(&stream.0.sock,
+                            interest, PollOpt::level())
+                        {
+                            return Response::Error(RotorError::Register(e));
+                        }
+                        stream.0.interest = interest;
                     }
                 }
+                Response::Continue(stream)
             }
+            None => Response::Remove,
         }
-        Some(Stream(stream, fsm, PhantomData))
+    }
+
+    fn tick<S>(self, context: &mut Ctx, scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        self.ready(EventSet::none(), context, scope)
+    }
+
+    /// Flushes the output and calls `Protocol::output_ready`
+    fn wakeup<S>(self, context: &mut Ctx, scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        self.ready(EventSet::none(), context, scope)
     }
 
     fn register<S>(&mut self, scope: &mut S)
         -> Result<(), Error>
         where S: Scope<Self>
     {
-        scope.register(&self.0.sock, EventSet::all(), PollOpt::edge())
+        if self.0.settings.level_triggered {
+            let interest = self.0.desired_interest();
+            try!(scope.register(&self.0.sock, interest, PollOpt::level()));
+            self.0.interest = interest;
+            Ok(())
+        } else {
+            scope.register(&self.0.sock, EventSet::all(), PollOpt::edge())
+        }
+    }
+
+    /// Calls `Protocol::drain_started` and closes the connection when
+    /// it's idle
+    fn drain<S>(self, context: &mut Ctx, scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        let Stream(mut stream, fsm, _) = self;
+        stream.draining = true;
+        match fsm.drain_started(&mut stream.transport(), context) {
+            Some(fsm) => Stream(stream, fsm, PhantomData)
+                .ready(EventSet::none(), context, scope),
+            None => Response::Remove,
+        }
+    }
+    fn is_draining(&self) -> bool {
+        self.0.draining
+    }
+    /// Closes the connection right away, use `Handler::drain` before the
+    /// shutdown to finish the requests in progress
+    fn shutdown<S>(self, context: &mut Ctx, _scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        self.1.closed(CloseReason::Shutdown, context);
+        Response::Remove
+    }
+    /// Closes the connection after `Settings::idle_timeout`
+    fn stalled<S>(self, context: &mut Ctx, scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        let timeout = match self.0.settings.idle_timeout {
+            Some(ms) => Duration::from_millis(ms),
+            None => {
+                warn!("Stream {:?} is stalled", scope.token());
+                return Response::Continue(self);
+            }
+        };
+        if scope.now() >= self.0.active + timeout {
+            self.1.closed(CloseReason::IdleTimeout, context);
+            Response::Remove
+        } else {
+            Response::Continue(self)
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "stream"
+    }
+    fn debug(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "in={} out={}",
+            self.0.inbuf.len(), self.0.outbuf.len()));
+        if self.0.paused {
+            try!(write!(f, " paused"));
+        }
+        Ok(())
+    }
+
+    fn deregister<S>(&mut self, scope: &mut S)
+        -> Result<(), Error>
+        where S: Scope<Self>
+    {
+        scope.deregister(&self.0.sock)
+    }
+}
+
+impl<S: Socket> Inner<S> {
+    /// The events to poll for in level-triggered mode
+    fn desired_interest(&self) -> EventSet {
+        let mut interest = EventSet::hup() | EventSet::error();
+        if !self.paused && self.inbuf.len() <= self.settings.max_input_buffer
+        {
+            interest = interest | EventSet::readable();
+        }
+        if !self.connected || self.outbuf.len() > 0 ||
+            self.producer.is_some()
+        {
+            interest = interest | EventSet::writable();
+        }
+        interest
+    }
+    fn transport(&mut self) -> Transport {
+        Transport {
+            sock: &self.sock,
+            inbuf: &mut self.inbuf,
+            outbuf: &mut self.outbuf,
+            producer: &mut self.producer,
+            counters: &self.counters,
+            created: self.created,
+        }
+    }
+    /// Total number of bytes read and written
+    fn transferred(&self) -> u64 {
+        self.counters.bytes_read + self.counters.bytes_written
+    }
+    /// Returns true if there is no request in progress
+    fn is_idle(&self) -> bool {
+        self.inbuf.len() == 0 && self.outbuf.len() == 0 &&
+            self.producer.is_none()
+    }
+    /// Returns true if the protocol may be asked for more output
+    fn has_room(&self) -> bool {
+        self.writable && self.producer.is_none() &&
+            self.outbuf.len() < self.settings.producer_threshold
+    }
+    /// Pulls data from the producer, returns true if there is new data
+    fn fill(&mut self) -> bool {
+        let threshold = self.settings.producer_threshold;
+        if !self.writable || self.outbuf.len() >= threshold {
+            return false;
+        }
+        let old_len = self.outbuf.len();
+        let more = match self.producer {
+            Some(ref mut p) => p.pull(&mut self.outbuf, threshold - old_len),
+            None => return false,
+        };
+        if !more {
+            self.producer = None;
+        }
+        self.outbuf.len() > old_len
+    }
+    /// Writes output buffer until it's empty or socket would block
+    ///
+    /// Returns `Ok(false)` if connection is closed
+    fn flush(&mut self) -> Result<bool, Error> {
+        while self.writable && self.outbuf.len() > 0 {
+            match self.outbuf.write_to(&mut self.sock) {
+                Ok(0) => return Ok(false),
+                Ok(n) => {  // May notify application
+                    self.counters.bytes_written += n as u64;
+                }
+                Err(ref e) if e.kind() == WouldBlock => {
+                    self.writable = false;
+                }
+                Err(ref e) if e.kind() == Interrupted =>  { continue; }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+    /// Replaces empty buffers with the new ones to free the memory
+    fn shrink(&mut self) {
+        if self.inbuf.len() == 0 {
+            self.inbuf = Buf::new();
+        }
+        if self.outbuf.len() == 0 {
+            self.outbuf = Buf::new();
+        }
     }
 }
 
@@ -189,4 +1000,601 @@ impl<'a> Transport<'a> {
     pub fn output<'x>(&'x mut self) -> &'x mut Buf {
         self.outbuf
     }
+    /// Returns the socket, e.g. to enable `TCP_NODELAY` after the handshake
+    ///
+    /// Reading and writing is only possible through the buffers
+    pub fn socket(&self) -> &Socket {
+        self.sock
+    }
+    /// Sets the producer of the output, replacing the previous one
+    ///
+    /// Data is pulled after the data already in the output buffer
+    pub fn set_producer(&mut self, producer: Box<OutputProducer>) {
+        *self.producer = Some(producer);
+    }
+    /// Returns true if the output producer has not finished yet
+    pub fn has_producer(&self) -> bool {
+        self.producer.is_some()
+    }
+    /// Returns the counters of the connection
+    pub fn counters(&self) -> &Counters {
+        self.counters
+    }
+    /// Total number of bytes read from the socket
+    pub fn bytes_read_total(&self) -> u64 {
+        self.counters.bytes_read
+    }
+    /// Total number of bytes written to the socket
+    ///
+    /// The data in the output buffer is not counted until it's written
+    pub fn bytes_written_total(&self) -> u64 {
+        self.counters.bytes_written
+    }
+    /// Time since the stream is created (i.e. accepted or started
+    /// connecting)
+    pub fn connected_duration(&self) -> Duration {
+        self.created.elapsed()
+    }
+}
+
+/// Runs the `protocol` on the `data` as if it's received from the socket
+///
+/// The entry point for fuzzing of the protocols (including `Parsed` ones)
+/// and of the buffer management of the stream. The first byte of the data
+/// is the size of the chunks the rest of the data is read in, and of the
+/// writes the socket accepts, so partial reads and writes are covered. The
+/// input is limited to 1 MiB. Returns the output of the protocol.
+///
+/// Panics if the counters of the stream disagree with the data passed
+/// through the socket.
+#[cfg(any(test, feature="fuzzing"))]
+pub fn fuzz_feed<P: Protocol<C>, C>(protocol: P, ctx: &mut C, data: &[u8])
+    -> Vec<u8>
+{
+    use self::fuzz::FuzzSocket;
+    let data = &data[..::std::cmp::min(data.len(), fuzz::MAX_INPUT)];
+    let (chunk, input) = match data.split_first() {
+        Some((&chunk, input)) => ((chunk & 63) as usize + 1, input),
+        None => return Vec::new(),
+    };
+    let (sock, state) = FuzzSocket::new(input.to_vec(), chunk);
+    let mut stream = Stream::with_protocol(sock, protocol, ctx);
+    // Every call makes some progress, unless the protocol has stalled
+    for _ in 0..2 * input.len() / chunk + 16 {
+        let events = EventSet::readable() | EventSet::writable();
+        stream = match stream.process(events, ctx) {
+            Some(stream) => stream,
+            None => break,
+        };
+        let state = state.borrow();
+        let counters = &stream.0.counters;
+        assert_eq!(counters.bytes_read, state.pos as u64);
+        assert_eq!(counters.bytes_written, state.output.len() as u64);
+        assert!(stream.0.inbuf.len() as u64 <= counters.bytes_read);
+    }
+    let state = state.borrow();
+    state.output.clone()
+}
+
+#[cfg(any(test, feature="fuzzing"))]
+mod fuzz {
+    use std::cell::RefCell;
+    use std::cmp::min;
+    use std::io::{self, Read, Write};
+    use std::rc::Rc;
+    use mio::{EventSet, Evented, Selector, Token, PollOpt};
+    use transports::StreamSocket;
+
+    pub const MAX_INPUT: usize = 1 << 20;
+
+    pub struct State {
+        input: Vec<u8>,
+        pub pos: usize,
+        pub output: Vec<u8>,
+        chunk: usize,
+        /// The socket would block on the next operation
+        blocked: bool,
+    }
+
+    /// The socket which alternates chunks of the data and `WouldBlock`
+    pub struct FuzzSocket(Rc<RefCell<State>>);
+
+    impl FuzzSocket {
+        pub fn new(input: Vec<u8>, chunk: usize)
+            -> (FuzzSocket, Rc<RefCell<State>>)
+        {
+            let state = Rc::new(RefCell::new(State {
+                input: input,
+                pos: 0,
+                output: Vec::new(),
+                chunk: chunk,
+                blocked: false,
+            }));
+            (FuzzSocket(state.clone()), state)
+        }
+    }
+
+    impl Read for FuzzSocket {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut state = self.0.borrow_mut();
+            if state.blocked {
+                state.blocked = false;
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            state.blocked = true;
+            let start = state.pos;
+            let n = min(min(state.chunk, buf.len()),
+                        state.input.len() - start);
+            buf[..n].copy_from_slice(&state.input[start..start+n]);
+            state.pos += n;
+            Ok(n)
+        }
+    }
+
+    impl Write for FuzzSocket {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut state = self.0.borrow_mut();
+            let n = min(state.chunk, buf.len());
+            state.output.extend(&buf[..n]);
+            Ok(n)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Evented for FuzzSocket {
+        fn register(&self, _: &mut Selector, _: Token, _: EventSet,
+            _: PollOpt) -> io::Result<()> { Ok(()) }
+        fn reregister(&self, _: &mut Selector, _: Token, _: EventSet,
+            _: PollOpt) -> io::Result<()> { Ok(()) }
+        fn deregister(&self, _: &mut Selector) -> io::Result<()> { Ok(()) }
+    }
+
+    impl StreamSocket for FuzzSocket {}
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Write};
+    use std::collections::VecDeque;
+    use mio::{EventSet, Evented, Selector, Token, PollOpt};
+    use BaseMachine;
+    use transports::StreamSocket;
+    use super::{Stream, Protocol, Transport, Settings, StreamBuilder};
+    use super::{CloseReason, Counters, Overflow};
+    use super::{Handshake, Switch, Upgrade};
+
+    /// A socket which returns prepared chunks, then `WouldBlock`
+    struct Mock {
+        input: VecDeque<io::Result<Vec<u8>>>,
+        write_error: Option<io::ErrorKind>,
+        error: Option<io::ErrorKind>,
+    }
+
+    struct Log;
+    /// Writes a long reply and logs the close reason
+    struct Reasons;
+    /// Logs the counters of the transport
+    struct Counting;
+    /// Same as `Log` but reads at most 5 bytes per event
+    struct Budget;
+    /// Pauses reading when there is any output
+    struct Pausing;
+    /// Upgrades to `Dropping` when the input starts with `hi`
+    struct Greeting;
+    /// Keeps the input until the buffer is full, then drops it
+    struct Dropping;
+
+    impl Read for Mock {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.input.pop_front() {
+                Some(Ok(chunk)) => {
+                    buf[..chunk.len()].copy_from_slice(&chunk);
+                    Ok(chunk.len())
+                }
+                Some(Err(e)) => Err(e),
+                None => Err(io::ErrorKind::WouldBlock.into()),
+            }
+        }
+    }
+
+    impl Write for Mock {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            match self.write_error {
+                Some(kind) => Err(kind.into()),
+                None => Ok(buf.len()),
+            }
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Evented for Mock {
+        fn register(&self, _: &mut Selector, _: Token, _: EventSet,
+            _: PollOpt) -> io::Result<()> { Ok(()) }
+        fn reregister(&self, _: &mut Selector, _: Token, _: EventSet,
+            _: PollOpt) -> io::Result<()> { Ok(()) }
+        fn deregister(&self, _: &mut Selector) -> io::Result<()> { Ok(()) }
+    }
+
+    impl StreamSocket for Mock {
+        fn take_socket_error(&self) -> io::Result<()> {
+            match self.error {
+                Some(kind) => Err(kind.into()),
+                None => Ok(()),
+            }
+        }
+    }
+
+    impl BaseMachine for Log {
+        type Timeout = ();
+    }
+
+    impl Protocol<Vec<String>> for Log {
+        fn accepted(_ctx: &mut Vec<String>) -> Log {
+            Log
+        }
+        fn data_received(self, transport: &mut Transport,
+            ctx: &mut Vec<String>)
+            -> Option<Log>
+        {
+            let len = transport.input().len();
+            ctx.push(format!("data {}",
+                String::from_utf8_lossy(&transport.input()[..])));
+            transport.input().consume(len);
+            transport.output().extend(b"reply");
+            Some(Log)
+        }
+        fn eof_received(self, ctx: &mut Vec<String>) {
+            ctx.push("eof".to_string());
+        }
+        fn error_happened(self, e: io::Error, ctx: &mut Vec<String>) {
+            ctx.push(format!("error {:?}", e.kind()));
+        }
+    }
+
+    impl BaseMachine for Reasons {
+        type Timeout = ();
+    }
+
+    impl Protocol<Vec<String>> for Reasons {
+        fn accepted(_ctx: &mut Vec<String>) -> Reasons {
+            Reasons
+        }
+        fn data_received(self, transport: &mut Transport,
+            _ctx: &mut Vec<String>)
+            -> Option<Reasons>
+        {
+            let len = transport.input().len();
+            transport.input().consume(len);
+            transport.output().extend(b"long reply");
+            Some(Reasons)
+        }
+        fn settings(_ctx: &mut Vec<String>) -> Settings {
+            Settings {
+                output_high_watermark: 4,
+                ..Settings::default()
+            }
+        }
+        fn closed(self, reason: CloseReason, ctx: &mut Vec<String>) {
+            ctx.push(format!("{:?}", reason));
+        }
+    }
+
+    impl BaseMachine for Counting {
+        type Timeout = ();
+    }
+
+    impl Protocol<Vec<String>> for Counting {
+        fn accepted(_ctx: &mut Vec<String>) -> Counting {
+            Counting
+        }
+        fn data_received(self, transport: &mut Transport,
+            ctx: &mut Vec<String>)
+            -> Option<Counting>
+        {
+            ctx.push(format!("read {} written {}",
+                transport.bytes_read_total(),
+                transport.counters().bytes_written));
+            let len = transport.input().len();
+            transport.input().consume(len);
+            transport.output().extend(b"reply");
+            Some(Counting)
+        }
+    }
+
+    impl BaseMachine for Budget {
+        type Timeout = ();
+    }
+
+    impl Protocol<Vec<String>> for Budget {
+        fn accepted(_ctx: &mut Vec<String>) -> Budget {
+            Budget
+        }
+        fn data_received(self, transport: &mut Transport,
+            ctx: &mut Vec<String>)
+            -> Option<Budget>
+        {
+            Log.data_received(transport, ctx).map(|_| Budget)
+        }
+        fn settings(_ctx: &mut Vec<String>) -> Settings {
+            Settings {
+                read_budget: 5,
+                ..Settings::default()
+            }
+        }
+    }
+
+    impl BaseMachine for Pausing {
+        type Timeout = ();
+    }
+
+    impl Protocol<Vec<String>> for Pausing {
+        fn accepted(_ctx: &mut Vec<String>) -> Pausing {
+            Pausing
+        }
+        fn data_received(self, transport: &mut Transport,
+            ctx: &mut Vec<String>)
+            -> Option<Pausing>
+        {
+            Log.data_received(transport, ctx).map(|_| Pausing)
+        }
+        fn settings(_ctx: &mut Vec<String>) -> Settings {
+            Settings {
+                output_high_watermark: 0,
+                level_triggered: true,
+                ..Settings::default()
+            }
+        }
+        fn output_full(&mut self, _counters: &Counters,
+            ctx: &mut Vec<String>)
+            -> Overflow
+        {
+            ctx.push("pause".to_string());
+            Overflow::Pause
+        }
+    }
+
+    impl BaseMachine for Greeting {
+        type Timeout = ();
+    }
+
+    impl Handshake<Vec<String>> for Greeting {
+        type Next = Dropping;
+        fn accepted(_ctx: &mut Vec<String>) -> Greeting {
+            Greeting
+        }
+        fn data_received(self, transport: &mut Transport,
+            ctx: &mut Vec<String>)
+            -> Switch<Greeting, Dropping>
+        {
+            if transport.input()[..].starts_with(b"hi") {
+                transport.input().consume(2);
+                ctx.push("upgrade".to_string());
+                Switch::Upgrade(Dropping)
+            } else {
+                Switch::Stay(self)
+            }
+        }
+        fn error_happened(self, e: io::Error, ctx: &mut Vec<String>) {
+            ctx.push(format!("handshake error {:?}", e.kind()));
+        }
+    }
+
+    impl BaseMachine for Dropping {
+        type Timeout = ();
+    }
+
+    impl Protocol<Vec<String>> for Dropping {
+        fn accepted(_ctx: &mut Vec<String>) -> Dropping {
+            Dropping
+        }
+        fn data_received(self, transport: &mut Transport,
+            ctx: &mut Vec<String>)
+            -> Option<Dropping>
+        {
+            ctx.push(format!("data {}",
+                String::from_utf8_lossy(&transport.input()[..])));
+            Some(Dropping)
+        }
+        fn settings(_ctx: &mut Vec<String>) -> Settings {
+            Settings {
+                max_input_buffer: 4,
+                ..Settings::default()
+            }
+        }
+        fn input_overflow(self, transport: &mut Transport,
+            ctx: &mut Vec<String>)
+            -> Option<Dropping>
+        {
+            let len = transport.input().len();
+            ctx.push(format!("overflow {}", len));
+            transport.input().consume(len);
+            Some(Dropping)
+        }
+    }
+
+    /// A socket which returns the chunks, then `WouldBlock`
+    fn mock(input: Vec<io::Result<Vec<u8>>>) -> Mock {
+        Mock {
+            input: input.into_iter().collect(),
+            write_error: None,
+            error: None,
+        }
+    }
+
+    /// Runs the protocol `P` on the socket, `process` is called for each
+    /// of the `events`
+    ///
+    /// Returns whether the stream is still alive, and the log
+    fn run<P>(sock: Mock, events: &[EventSet]) -> (bool, Vec<String>)
+        where P: Protocol<Vec<String>>
+    {
+        let mut log = Vec::new();
+        let mut stream = Stream::<_, P, _>::new(sock, &mut log);
+        for &evset in events {
+            stream = match stream.process(evset, &mut log) {
+                Some(stream) => stream,
+                None => return (false, log),
+            };
+        }
+        (true, log)
+    }
+
+    #[test]
+    fn close_reasons() {
+        let (alive, log) = run::<Reasons>(mock(vec![Ok(vec![])]),
+            &[EventSet::readable()]);
+        assert!(!alive);
+        assert_eq!(log, vec!["Eof"]);
+        let (alive, log) = run::<Reasons>(mock(vec![Ok(b"hello".to_vec())]),
+            &[EventSet::readable()]);
+        assert!(!alive);
+        assert_eq!(log, vec!["OutputOverflow"]);
+    }
+
+    #[test]
+    fn data_before_hup() {
+        let (alive, log) = run::<Log>(
+            mock(vec![Ok(b"hello".to_vec()), Ok(vec![])]),
+            &[EventSet::hup()]);
+        assert!(!alive);
+        assert_eq!(log, vec!["data hello", "eof"]);
+    }
+
+    #[test]
+    fn data_before_eof_with_all_events() {
+        let (alive, log) = run::<Log>(
+            mock(vec![Ok(b"hello".to_vec()), Ok(vec![])]),
+            &[EventSet::all()]);
+        assert!(!alive);
+        assert_eq!(log, vec!["data hello", "eof"]);
+    }
+
+    #[test]
+    fn data_before_read_error() {
+        let (alive, log) = run::<Log>(
+            mock(vec![Ok(b"hello".to_vec()),
+                      Err(io::ErrorKind::ConnectionReset.into())]),
+            &[EventSet::readable() | EventSet::error()]);
+        assert!(!alive);
+        assert_eq!(log, vec!["data hello", "error ConnectionReset"]);
+    }
+
+    #[test]
+    fn data_before_write_error() {
+        let sock = Mock {
+            write_error: Some(io::ErrorKind::BrokenPipe),
+            ..mock(vec![Ok(b"hello".to_vec())])
+        };
+        let (alive, log) = run::<Log>(sock,
+            &[EventSet::readable() | EventSet::writable()]);
+        assert!(!alive);
+        assert_eq!(log, vec!["data hello", "error BrokenPipe"]);
+    }
+
+    #[test]
+    fn counters() {
+        let sock = mock(vec![Ok(b"hello".to_vec()),
+            Err(io::ErrorKind::WouldBlock.into()), Ok(b"world!".to_vec())]);
+        let (alive, log) = run::<Counting>(sock,
+            &[EventSet::readable() | EventSet::writable(),
+              EventSet::readable()]);
+        assert!(alive);
+        assert_eq!(log, vec!["read 5 written 0", "read 11 written 5"]);
+    }
+
+    #[test]
+    fn read_budget() {
+        let input = || mock(vec![Ok(b"hello".to_vec()),
+                                 Ok(b"world".to_vec())]);
+        let (alive, log) = run::<Budget>(input(), &[EventSet::readable()]);
+        assert!(alive);
+        assert_eq!(log, vec!["data hello"]);
+        // The socket is still readable, the rest is read on the tick
+        let (alive, log) = run::<Budget>(input(),
+            &[EventSet::readable(), EventSet::none()]);
+        assert!(alive);
+        assert_eq!(log, vec!["data hello", "data world"]);
+    }
+
+    #[test]
+    fn level_triggered_interest() {
+        let mut log = Vec::new();
+        let base = EventSet::hup() | EventSet::error();
+        let sock = Mock {
+            write_error: Some(io::ErrorKind::WouldBlock),
+            ..mock(vec![Ok(b"hello".to_vec())])
+        };
+        let stream = Stream::<_, Pausing, _>::new(sock, &mut log);
+        // Connection is checked by the first writable event
+        assert_eq!(stream.0.desired_interest(),
+            base | EventSet::readable() | EventSet::writable());
+        let stream = stream.process(EventSet::readable(), &mut log)
+            .unwrap();
+        assert_eq!(log, vec!["data hello", "pause"]);
+        assert_eq!(stream.0.desired_interest(), base | EventSet::writable());
+        let stream = Stream::<_, Log, _>::new(
+            mock(vec![Ok(b"hello".to_vec())]), &mut log);
+        let stream = stream.process(EventSet::readable(), &mut log)
+            .unwrap();
+        assert_eq!(stream.0.desired_interest(), base | EventSet::readable());
+    }
+
+    #[test]
+    fn socket_error_after_data() {
+        let sock = Mock {
+            error: Some(io::ErrorKind::TimedOut),
+            ..mock(vec![Ok(b"hello".to_vec()), Ok(vec![])])
+        };
+        let (alive, log) = run::<Log>(sock,
+            &[EventSet::readable() | EventSet::error()]);
+        assert!(!alive);
+        assert_eq!(log, vec!["data hello", "error TimedOut"]);
+    }
+
+    #[test]
+    fn readable() {
+        let (alive, log) = run::<Log>(mock(vec![Ok(b"hello".to_vec())]),
+            &[EventSet::readable()]);
+        assert!(alive);
+        assert_eq!(log, vec!["data hello"]);
+    }
+
+    #[test]
+    fn upgraded_input_overflow() {
+        let (alive, log) = run::<Upgrade<Greeting, Dropping>>(
+            mock(vec![Ok(b"hi12345678".to_vec()), Ok(b"9".to_vec())]),
+            &[EventSet::readable()]);
+        assert!(alive);
+        assert_eq!(log, vec!["upgrade", "data 12345678", "overflow 8",
+                             "data 9"]);
+        // The policy of the protocol doesn't apply to the handshake
+        let (alive, log) = run::<Upgrade<Greeting, Dropping>>(
+            mock(vec![Ok(b"hello".to_vec())]),
+            &[EventSet::readable()]);
+        assert!(!alive);
+        assert_eq!(log, vec!["handshake error Other"]);
+    }
+
+    #[test]
+    fn builder_overrides() {
+        let mut settings = Settings::default();
+        settings.read_budget = 4096;
+        settings.shrink_buffers = true;
+        StreamBuilder::new()
+            .output_watermarks(1000, 100)
+            .read_budget(65536)
+            .idle_timeout(5000)
+            .apply(&mut settings);
+        assert_eq!(settings.output_high_watermark, 1000);
+        assert_eq!(settings.output_low_watermark, 100);
+        assert_eq!(settings.read_budget, 65536);
+        assert_eq!(settings.idle_timeout, Some(5000));
+        // Not set in the builder
+        assert!(settings.shrink_buffers);
+        assert!(!settings.level_triggered);
+    }
 }
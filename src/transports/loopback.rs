@@ -0,0 +1,267 @@
+//! In-process connected pair of byte streams
+//!
+//! `pair` returns two connected ends, which are driven by the
+//! `greedy_stream` like sockets, so machines of the same process (or of
+//! different loops) may be joined by a pipeline without the overhead of
+//! real sockets:
+//!
+//! ```ignore
+//! let (left, right) = try!(loopback::pair());
+//! scope.async_add_machine(Stream::<_, Frontend, _>::new(left, ctx));
+//! scope.async_add_machine(Stream::<_, Backend, _>::new(right, ctx));
+//! ```
+//!
+//! The data is kept in a bounded ring buffer for each direction, the
+//! writer gets `WouldBlock` when the buffer is full. Every end has an
+//! eventfd, which the peer signals when it writes data, frees room in the
+//! buffer or is closed. Register the ends edge-triggered (the default of
+//! the `Stream`), the eventfd is always writable, so the level-triggered
+//! mode would spin.
+use std::cmp::min;
+use std::mem;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::io::ErrorKind::{WouldBlock, Interrupted, BrokenPipe};
+use std::os::unix::io::{RawFd, FromRawFd};
+use std::sync::{Arc, Mutex};
+
+use libc;
+use mio::{Io, Evented, Selector, Token, EventSet, PollOpt};
+
+use super::StreamSocket;
+
+/// Size of the buffer of each direction used by `pair`
+pub const DEFAULT_CAPACITY: usize = 65536;
+
+
+/// An end of the in-process connection, see module documentation
+pub struct End {
+    shared: Arc<Mutex<Shared>>,
+    side: usize,
+    /// Eventfd of this end
+    io: Io,
+    /// A copy of the eventfd of the peer
+    peer: RawFd,
+}
+
+struct Shared {
+    capacity: usize,
+    /// The data to be read by each side
+    buffers: [VecDeque<u8>; 2],
+    closed: [bool; 2],
+}
+
+/// Creates a connected pair with `DEFAULT_CAPACITY` buffers
+pub fn pair() -> io::Result<(End, End)> {
+    pair_with_capacity(DEFAULT_CAPACITY)
+}
+
+/// Creates a connected pair, each direction buffers at most `capacity`
+/// bytes
+pub fn pair_with_capacity(capacity: usize) -> io::Result<(End, End)> {
+    assert!(capacity > 0);
+    let (left, left_copy) = try!(create_eventfd());
+    let (right, right_copy) = match create_eventfd() {
+        Ok(fds) => fds,
+        Err(e) => {
+            unsafe { libc::close(left_copy); }
+            return Err(e);
+        }
+    };
+    let shared = Arc::new(Mutex::new(Shared {
+        capacity: capacity,
+        buffers: [VecDeque::new(), VecDeque::new()],
+        closed: [false, false],
+    }));
+    Ok((End { shared: shared.clone(), side: 0, io: left, peer: right_copy },
+        End { shared: shared, side: 1, io: right, peer: left_copy }))
+}
+
+/// Returns the eventfd and its copy, to be owned by the peer
+fn create_eventfd() -> io::Result<(Io, RawFd)> {
+    unsafe {
+        let fd = libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let copy = libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0);
+        if copy < 0 {
+            let err = io::Error::last_os_error();
+            libc::close(fd);
+            return Err(err);
+        }
+        Ok((<Io as FromRawFd>::from_raw_fd(fd), copy))
+    }
+}
+
+impl End {
+    /// Number of bytes which are written by the peer and not read yet
+    pub fn pending(&self) -> usize {
+        self.shared.lock().unwrap().buffers[self.side].len()
+    }
+    /// Wakes up the peer
+    fn signal(&self) {
+        let value: u64 = 1;
+        let res = unsafe {
+            libc::write(self.peer,
+                        &value as *const u64 as *const libc::c_void,
+                        mem::size_of::<u64>())
+        };
+        if res < 0 {
+            let err = io::Error::last_os_error();
+            // Overflown counter means the peer is signalled anyway
+            if err.kind() != WouldBlock {
+                error!("Error signalling loopback peer: {}", err);
+            }
+        }
+    }
+    /// Clears the signal of this end
+    ///
+    /// Only done before reading, the caller reads until `WouldBlock`, so
+    /// no data is left unnoticed
+    fn reset(&mut self) {
+        let mut buf = [0u8; 8];
+        loop {
+            match self.io.read(&mut buf) {
+                Err(ref e) if e.kind() == Interrupted => continue,
+                Ok(_) | Err(_) => break,
+            }
+        }
+    }
+}
+
+impl Read for End {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reset();
+        let mut shared = self.shared.lock().unwrap();
+        let was_full;
+        let n;
+        {
+            let Shared { capacity, ref mut buffers, ref closed } = *shared;
+            let data = &mut buffers[self.side];
+            if data.len() == 0 {
+                if closed[1 - self.side] {
+                    return Ok(0);
+                }
+                return Err(WouldBlock.into());
+            }
+            was_full = data.len() >= capacity;
+            n = {
+                let (head, tail) = data.as_slices();
+                let first = min(head.len(), buf.len());
+                buf[..first].copy_from_slice(&head[..first]);
+                let second = min(tail.len(), buf.len() - first);
+                buf[first..first+second].copy_from_slice(&tail[..second]);
+                first + second
+            };
+            data.drain(..n);
+        }
+        drop(shared);
+        if was_full {
+            self.signal();
+        }
+        Ok(n)
+    }
+}
+
+impl Write for End {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut shared = self.shared.lock().unwrap();
+        if shared.closed[1 - self.side] {
+            return Err(io::Error::new(BrokenPipe,
+                                      "Loopback peer is closed"));
+        }
+        let capacity = shared.capacity;
+        let data = &mut shared.buffers[1 - self.side];
+        if buf.len() == 0 {
+            return Ok(0);
+        }
+        let n = min(buf.len(), capacity.saturating_sub(data.len()));
+        if n == 0 {
+            return Err(WouldBlock.into());
+        }
+        let was_empty = data.len() == 0;
+        data.extend(&buf[..n]);
+        drop(shared);
+        if was_empty {
+            self.signal();
+        }
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Evented for End {
+    fn register(&self, selector: &mut Selector, token: Token,
+        interest: EventSet, opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.io.register(selector, token, interest, opts)
+    }
+    fn reregister(&self, selector: &mut Selector, token: Token,
+        interest: EventSet, opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.io.reregister(selector, token, interest, opts)
+    }
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.io.deregister(selector)
+    }
+}
+
+impl StreamSocket for End {}
+
+impl Drop for End {
+    fn drop(&mut self) {
+        self.shared.lock().unwrap().closed[self.side] = true;
+        self.signal();
+        unsafe { libc::close(self.peer); }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::io::ErrorKind::{WouldBlock, BrokenPipe};
+    use super::{pair, pair_with_capacity};
+
+    #[test]
+    fn transfer() {
+        let (mut left, mut right) = pair().unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(right.read(&mut buf).unwrap_err().kind(), WouldBlock);
+        assert_eq!(left.write(b"hello").unwrap(), 5);
+        assert_eq!(right.write(b"world").unwrap(), 5);
+        assert_eq!(right.pending(), 5);
+        assert_eq!(right.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(left.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"world");
+    }
+
+    #[test]
+    fn backpressure() {
+        let (mut left, mut right) = pair_with_capacity(4).unwrap();
+        let mut buf = [0u8; 3];
+        assert_eq!(left.write(b"abcdef").unwrap(), 4);
+        assert_eq!(left.write(b"ef").unwrap_err().kind(), WouldBlock);
+        assert_eq!(right.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf, b"abc");
+        assert_eq!(left.write(b"ef").unwrap(), 2);
+        assert_eq!(right.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf, b"def");
+    }
+
+    #[test]
+    fn close() {
+        let (mut left, mut right) = pair().unwrap();
+        left.write_all(b"bye").unwrap();
+        drop(left);
+        let mut data = Vec::new();
+        right.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"bye");
+        assert_eq!(right.write(b"x").unwrap_err().kind(), BrokenPipe);
+    }
+}
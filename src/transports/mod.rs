@@ -1,9 +1,90 @@
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::{RawFd, AsRawFd};
 
+use libc;
 use mio::Evented;
+use mio::tcp::TcpStream;
+use mio::unix::UnixStream;
 
 pub mod greedy_stream;
 pub mod accept;
+pub mod parser;
+pub mod duplex;
+pub mod direct;
+#[cfg(target_os="linux")] pub mod loopback;
+pub mod connect;
+pub mod pipe;
+pub mod seqpacket;
+pub mod udp;
+pub mod tunnel;
 
-pub trait StreamSocket: Read + Write + Evented {}
+pub trait StreamSocket: Read + Write + Evented {
+    /// Returns and clears the pending error of the socket (`SO_ERROR`)
+    ///
+    /// Called when the error event is received. The default is for objects
+    /// which are not sockets (e.g. pipes)
+    fn take_socket_error(&self) -> io::Result<()> {
+        Ok(())
+    }
+    /// Sets `TCP_NODELAY`, does nothing for sockets which are not TCP
+    fn set_nodelay(&self, _nodelay: bool) -> io::Result<()> {
+        Ok(())
+    }
+    /// Enables TCP keepalive with the interval in seconds (`None` disables
+    /// it), does nothing for sockets which are not TCP
+    fn set_keepalive(&self, _seconds: Option<u32>) -> io::Result<()> {
+        Ok(())
+    }
+    /// Returns the descriptor of the socket, for the options and queries
+    /// which are not covered here (e.g. `TCP_INFO`, `MSG_PEEK`)
+    ///
+    /// Returns `None` for objects which are not a single socket. Don't
+    /// read, write or close the descriptor, the stream owns it
+    fn socket_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+impl StreamSocket for TcpStream {
+    fn take_socket_error(&self) -> io::Result<()> {
+        socket_error(self.as_raw_fd())
+    }
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        TcpStream::set_nodelay(self, nodelay)
+    }
+    fn set_keepalive(&self, seconds: Option<u32>) -> io::Result<()> {
+        TcpStream::set_keepalive(self, seconds)
+    }
+    fn socket_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+impl StreamSocket for UnixStream {
+    fn take_socket_error(&self) -> io::Result<()> {
+        socket_error(self.as_raw_fd())
+    }
+    fn socket_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+impl StreamSocket for pipe::Pipe {}
+
+fn socket_error(fd: RawFd) -> io::Result<()> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_ERROR,
+            &mut value as *mut _ as *mut libc::c_void, &mut len)
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if value != 0 {
+        return Err(io::Error::from_raw_os_error(value));
+    }
+    Ok(())
+}
 
@@ -0,0 +1,392 @@
+//! An adapter of the `greedy_stream` for incremental parsers
+//!
+//! Implement `Parser` and wrap it into `Parsed` to get a `Protocol`. The
+//! adapter handles partial packets in the buffer: it calls `parse` only when
+//! enough bytes are received, consumes parsed bytes, and closes connection
+//! when the packet doesn't fit the limit.
+use std::cmp::max;
+use std::io::{Error, ErrorKind};
+use std::usize;
+
+use BaseMachine;
+use super::greedy_stream::{Protocol, Transport, Settings, Counters, Overflow};
+use super::greedy_stream::CloseReason;
+
+
+/// The result of `Parser::parse`
+pub enum Parse<T> {
+    /// At least `n` more bytes are needed to parse the packet
+    ///
+    /// It's just a hint, use `1` if it's unknown (`0` means the same)
+    NeedMore(usize),
+    /// The packet is parsed, and `usize` bytes of the input are consumed
+    ///
+    /// At least one byte must be consumed, otherwise the connection is
+    /// closed with the `InvalidData` error
+    Done(T, usize),
+    /// The data is invalid, connection is closed
+    Error(Error),
+}
+
+/// A protocol which consumes the input as a sequence of packets
+pub trait Parser<C>: BaseMachine + Sized {
+    /// The parsed packet
+    type Output;
+    /// Returns new state machine in a state for new accepted connection
+    fn accepted(ctx: &mut C) -> Self;
+    /// Called on the first event of the connection, see
+    /// `Protocol::connected`
+    fn connected(self, _transport: &mut Transport, _ctx: &mut C)
+        -> Option<Self>
+    {
+        Some(self)
+    }
+    /// Parses a packet at the start of the `data`
+    fn parse(&mut self, data: &[u8]) -> Parse<Self::Output>;
+    /// A packet has been parsed, use `transport.output()` to respond
+    fn packet_received(self, packet: Self::Output, transport: &mut Transport,
+        ctx: &mut C)
+        -> Option<Self>;
+
+    /// There is room for more output, see `Protocol::output_ready`
+    fn output_ready(self, _transport: &mut Transport, _ctx: &mut C)
+        -> Option<Self>
+    {
+        Some(self)
+    }
+    /// Maximum size of the input buffer, i.e. of the single packet
+    ///
+    /// Unlimited by default
+    fn max_buffer(&self) -> usize {
+        usize::MAX
+    }
+    /// Eof received. State machine will shutdown unconditionally
+    fn eof_received(self, _ctx: &mut C) {}
+    /// Fatal error on connection happened, including parse errors
+    ///
+    /// Default action is to log error on the info level
+    fn error_happened(self, e: Error, _ctx: &mut C) {
+        info!("Error when handling connection: {}", e);
+    }
+    /// The connection is closed by the stream, see `Protocol::closed`
+    fn closed(self, reason: CloseReason, ctx: &mut C) {
+        match reason {
+            CloseReason::Eof => self.eof_received(ctx),
+            CloseReason::Error(e) => self.error_happened(e, ctx),
+            CloseReason::IdleTimeout => self.error_happened(
+                Error::new(ErrorKind::TimedOut, "Connection is idle"), ctx),
+            CloseReason::OutputOverflow | CloseReason::Shutdown |
+            CloseReason::Drained => {}
+        }
+    }
+    /// Returns settings for the new connection
+    fn settings(_ctx: &mut C) -> Settings {
+        Settings::default()
+    }
+    /// See `Protocol::output_full`
+    fn output_full(&mut self, counters: &Counters, _ctx: &mut C) -> Overflow {
+        warn!("Output buffer overflow ({} times), closing connection",
+            counters.output_overflows);
+        Overflow::Close
+    }
+    /// Input buffer is larger than `Settings::max_input_buffer`, see
+    /// `Protocol::input_overflow`
+    ///
+    /// Parsing starts from scratch if the parser continues
+    fn input_overflow(self, transport: &mut Transport, ctx: &mut C)
+        -> Option<Self>
+    {
+        self.error_happened(Error::new(ErrorKind::Other,
+            format!("Input buffer overflow ({} bytes)",
+                    transport.input().len())),
+            ctx);
+        None
+    }
+    /// The loop is draining, see `Protocol::drain_started`
+    ///
+    /// The packet in progress is still parsed and answered
+    fn drain_started(self, _transport: &mut Transport, _ctx: &mut C)
+        -> Option<Self>
+    {
+        Some(self)
+    }
+}
+
+/// A `Protocol` which feeds input to the parser `P`
+pub struct Parsed<P> {
+    parser: P,
+    /// Don't call parser until there are that many bytes in the buffer
+    need: usize,
+}
+
+impl<P: BaseMachine> BaseMachine for Parsed<P> {
+    type Timeout = P::Timeout;
+}
+
+impl<P: Parser<C>, C> Protocol<C> for Parsed<P> {
+    fn accepted(ctx: &mut C) -> Self {
+        Parsed {
+            parser: P::accepted(ctx),
+            need: 1,
+        }
+    }
+    fn connected(self, transport: &mut Transport, ctx: &mut C)
+        -> Option<Self>
+    {
+        let Parsed { parser, need } = self;
+        parser.connected(transport, ctx)
+            .map(|parser| Parsed { parser: parser, need: need })
+    }
+    fn data_received(self, transport: &mut Transport, ctx: &mut C)
+        -> Option<Self>
+    {
+        let Parsed { mut parser, mut need } = self;
+        while transport.input().len() >= need {
+            let result = parser.parse(&transport.input()[..]);
+            match result {
+                Parse::NeedMore(n) => {
+                    // The parser is never called twice with the same data
+                    need = transport.input().len() + max(n, 1);
+                    if need > parser.max_buffer() {
+                        parser.error_happened(Error::new(
+                            ErrorKind::InvalidData, "Packet is too large"),
+                            ctx);
+                        return None;
+                    }
+                }
+                Parse::Done(_, 0) => {
+                    parser.error_happened(Error::new(
+                        ErrorKind::InvalidData,
+                        "Parser has consumed no bytes"), ctx);
+                    return None;
+                }
+                Parse::Done(packet, consumed) => {
+                    transport.input().consume(consumed);
+                    need = 1;
+                    parser = match parser.packet_received(packet, transport,
+                                                          ctx)
+                    {
+                        Some(parser) => parser,
+                        None => return None,
+                    };
+                }
+                Parse::Error(e) => {
+                    parser.error_happened(e, ctx);
+                    return None;
+                }
+            }
+        }
+        Some(Parsed { parser: parser, need: need })
+    }
+    fn output_ready(self, transport: &mut Transport, ctx: &mut C)
+        -> Option<Self>
+    {
+        let Parsed { parser, need } = self;
+        parser.output_ready(transport, ctx)
+            .map(|parser| Parsed { parser: parser, need: need })
+    }
+    fn eof_received(self, ctx: &mut C) {
+        self.parser.eof_received(ctx)
+    }
+    fn error_happened(self, e: Error, ctx: &mut C) {
+        self.parser.error_happened(e, ctx)
+    }
+    fn closed(self, reason: CloseReason, ctx: &mut C) {
+        self.parser.closed(reason, ctx)
+    }
+    fn settings(ctx: &mut C) -> Settings {
+        P::settings(ctx)
+    }
+    fn output_full(&mut self, counters: &Counters, ctx: &mut C) -> Overflow {
+        self.parser.output_full(counters, ctx)
+    }
+    fn input_overflow(self, transport: &mut Transport, ctx: &mut C)
+        -> Option<Self>
+    {
+        // The input may be consumed, so the hint is stale
+        self.parser.input_overflow(transport, ctx)
+            .map(|parser| Parsed { parser: parser, need: 1 })
+    }
+    fn drain_started(self, transport: &mut Transport, ctx: &mut C)
+        -> Option<Self>
+    {
+        let Parsed { parser, need } = self;
+        parser.drain_started(transport, ctx)
+            .map(|parser| Parsed { parser: parser, need: need })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Error, ErrorKind, Read, Write};
+    use mio::{self, EventLoop, EventSet};
+    use {BaseMachine, Handler};
+    use transports::loopback::{self, End};
+    use transports::greedy_stream::{Protocol, Transport, Settings};
+    use transports::greedy_stream::{Stream, CloseReason, fuzz_feed};
+    use super::{Parser, Parse, Parsed};
+
+    #[derive(Default)]
+    struct Log {
+        /// Return `NeedMore(0)` instead of the number of missing bytes
+        zero_hints: bool,
+        /// Write `hi` when the output is ready for the first time
+        greeting: bool,
+        max_input: Option<usize>,
+        packets: Vec<Vec<u8>>,
+        errors: Vec<ErrorKind>,
+        events: Vec<&'static str>,
+    }
+
+    /// Packets prefixed by the length byte, `0xFF` is a broken parser
+    /// which consumes nothing
+    struct Frames(bool);
+
+    impl BaseMachine for Frames {
+        type Timeout = ();
+    }
+
+    impl Parser<Log> for Frames {
+        type Output = Vec<u8>;
+        fn accepted(ctx: &mut Log) -> Frames {
+            Frames(ctx.zero_hints)
+        }
+        fn connected(self, _transport: &mut Transport, ctx: &mut Log)
+            -> Option<Frames>
+        {
+            ctx.events.push("connected");
+            Some(self)
+        }
+        fn output_ready(self, transport: &mut Transport, ctx: &mut Log)
+            -> Option<Frames>
+        {
+            if ctx.greeting {
+                ctx.greeting = false;
+                transport.output().extend(b"hi");
+            }
+            Some(self)
+        }
+        fn parse(&mut self, data: &[u8]) -> Parse<Vec<u8>> {
+            let len = data[0] as usize;
+            if len == 0xFF {
+                Parse::Done(Vec::new(), 0)
+            } else if data.len() < len + 1 {
+                Parse::NeedMore(if self.0 { 0 } else { len + 1 - data.len() })
+            } else {
+                Parse::Done(data[1..len+1].to_vec(), len + 1)
+            }
+        }
+        fn packet_received(self, packet: Vec<u8>, transport: &mut Transport,
+            ctx: &mut Log)
+            -> Option<Frames>
+        {
+            transport.output().extend(b"ok");
+            ctx.packets.push(packet);
+            Some(self)
+        }
+        fn error_happened(self, e: Error, ctx: &mut Log) {
+            ctx.errors.push(e.kind());
+        }
+        fn closed(self, reason: CloseReason, ctx: &mut Log) {
+            ctx.events.push(match reason {
+                CloseReason::Eof => "eof",
+                _ => "closed",
+            });
+        }
+        fn settings(ctx: &mut Log) -> Settings {
+            let mut settings = Settings::default();
+            if let Some(max) = ctx.max_input {
+                settings.max_input_buffer = max;
+            }
+            settings
+        }
+        fn drain_started(self, _transport: &mut Transport, ctx: &mut Log)
+            -> Option<Frames>
+        {
+            ctx.events.push("drain");
+            Some(self)
+        }
+        /// Drops the input
+        fn input_overflow(self, transport: &mut Transport, ctx: &mut Log)
+            -> Option<Frames>
+        {
+            let len = transport.input().len();
+            transport.input().consume(len);
+            ctx.events.push("overflow");
+            Some(self)
+        }
+    }
+
+    /// Feeds the data in chunks of `chunk` bytes
+    fn feed(log: &mut Log, chunk: u8, data: &[u8]) -> Vec<u8> {
+        let mut input = vec![chunk - 1];
+        input.extend(data);
+        let protocol = Parsed::<Frames>::accepted(log);
+        fuzz_feed(protocol, log, &input)
+    }
+
+    #[test]
+    fn split_frames() {
+        let mut log = Log::default();
+        let output = feed(&mut log, 2, b"\x03abc\x00\x01d");
+        assert_eq!(log.packets,
+            vec![b"abc".to_vec(), b"".to_vec(), b"d".to_vec()]);
+        assert_eq!(output, b"okokok");
+        assert!(log.errors.is_empty());
+    }
+
+    #[test]
+    fn zero_hint() {
+        let mut log = Log { zero_hints: true, ..Log::default() };
+        feed(&mut log, 1, b"\x03abc\x01d");
+        assert_eq!(log.packets, vec![b"abc".to_vec(), b"d".to_vec()]);
+    }
+
+    #[test]
+    fn nothing_consumed() {
+        let mut log = Log::default();
+        feed(&mut log, 8, b"\x01a\xFFbcd");
+        assert_eq!(log.packets, vec![b"a".to_vec()]);
+        assert_eq!(log.errors, vec![ErrorKind::InvalidData]);
+    }
+
+    #[test]
+    fn forwarded_hooks() {
+        let mut log = Log {
+            greeting: true,
+            max_input: Some(4),
+            ..Log::default()
+        };
+        // The start of the packet which doesn't fit is dropped, and the
+        // parser starts from scratch
+        let output = feed(&mut log, 8, b"\x09abcdefg\x01z");
+        assert_eq!(output, b"hiok");
+        assert_eq!(log.packets, vec![b"z".to_vec()]);
+        assert_eq!(log.events, vec!["connected", "overflow", "eof"]);
+    }
+
+    #[test]
+    fn drain_finishes_packet() {
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::<Log, Stream<End, Parsed<Frames>, Log>>
+            ::new(Log::default(), &mut eloop);
+        let (left, mut right) = loopback::pair().unwrap();
+        let stream = Stream::new(left, handler.context());
+        let tok = handler.add_machine(&mut eloop, stream).unwrap();
+        let events = EventSet::readable() | EventSet::writable();
+        right.write_all(b"\x03ab").unwrap();
+        mio::Handler::ready(&mut handler, &mut eloop, tok, events);
+        // The connection is kept until the packet in progress is answered
+        handler.drain(&mut eloop);
+        assert_eq!(handler.occupancy().0, 1);
+        right.write_all(b"c").unwrap();
+        mio::Handler::ready(&mut handler, &mut eloop, tok, events);
+        assert_eq!(handler.occupancy().0, 0);
+        assert_eq!(handler.context().packets, vec![b"abc".to_vec()]);
+        assert_eq!(handler.context().events,
+            vec!["connected", "drain", "closed"]);
+        let mut output = Vec::new();
+        right.read_to_end(&mut output).unwrap();
+        assert_eq!(output, b"ok");
+    }
+}
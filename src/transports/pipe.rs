@@ -0,0 +1,132 @@
+//! Pipes and stdio of child processes for the `greedy_stream`
+//!
+//! `Pipe` joins a reading and a writing end into a single socket-like
+//! object, so any stream `Protocol` may drive a subprocess:
+//!
+//! ```ignore
+//! let (child, pipe) = try!(pipe::spawn(&mut Command::new("resolver")));
+//! scope.async_add_machine(Stream::new(pipe, ctx));
+//! ```
+use std::io::{self, Read, Write};
+use std::process::{Command, Child, Stdio};
+use std::os::unix::io::{IntoRawFd, FromRawFd};
+
+use mio::{Io, Evented, Selector, Token, EventSet, PollOpt};
+use mio::unix::{PipeReader, PipeWriter};
+
+use fd_flags::{set_flag, Flag};
+
+
+/// A reading and a writing end of pipes registered under a single token
+///
+/// Either end is optional. Reading from the missing end returns end of
+/// file, writing to the missing end fails with `BrokenPipe`.
+pub struct Pipe {
+    reader: Option<PipeReader>,
+    writer: Option<PipeWriter>,
+}
+
+impl Pipe {
+    pub fn new(reader: Option<PipeReader>, writer: Option<PipeWriter>)
+        -> Pipe
+    {
+        Pipe {
+            reader: reader,
+            writer: writer,
+        }
+    }
+}
+
+/// Spawns the command with stdin and stdout connected to the `Pipe`
+///
+/// Stderr is left as configured in the `cmd`. The descriptors are switched
+/// to non-blocking mode. Use `Stream::new` to create a state machine for
+/// the pipe. Note it's still the caller's duty to wait for the child.
+pub fn spawn(cmd: &mut Command) -> io::Result<(Child, Pipe)> {
+    let mut child = try!(cmd.stdin(Stdio::piped())
+                            .stdout(Stdio::piped())
+                            .spawn());
+    let stdin = child.stdin.take().unwrap().into_raw_fd();
+    let stdout = child.stdout.take().unwrap().into_raw_fd();
+    let (reader, writer) = unsafe {
+        (<Io as FromRawFd>::from_raw_fd(stdout),
+         <Io as FromRawFd>::from_raw_fd(stdin))
+    };
+    let pipe = Pipe::new(Some(PipeReader::from(reader)),
+                         Some(PipeWriter::from(writer)));
+    try!(set_flag(stdin, Flag::NonBlocking, true));
+    try!(set_flag(stdout, Flag::NonBlocking, true));
+    Ok((child, pipe))
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.reader {
+            Some(ref mut r) => r.read(buf),
+            None => Ok(0),
+        }
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.writer {
+            Some(ref mut w) => w.write(buf),
+            None => Err(io::Error::new(io::ErrorKind::BrokenPipe,
+                                       "Pipe has no writing end")),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self.writer {
+            Some(ref mut w) => w.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Evented for Pipe {
+    fn register(&self, selector: &mut Selector, token: Token,
+        interest: EventSet, opts: PollOpt)
+        -> io::Result<()>
+    {
+        if let Some(ref r) = self.reader {
+            if interest.is_readable() {
+                try!(r.register(selector, token,
+                    EventSet::readable() | EventSet::hup(), opts));
+            }
+        }
+        if let Some(ref w) = self.writer {
+            if interest.is_writable() {
+                try!(w.register(selector, token, EventSet::writable(), opts));
+            }
+        }
+        Ok(())
+    }
+    fn reregister(&self, selector: &mut Selector, token: Token,
+        interest: EventSet, opts: PollOpt)
+        -> io::Result<()>
+    {
+        if let Some(ref r) = self.reader {
+            if interest.is_readable() {
+                try!(r.reregister(selector, token,
+                    EventSet::readable() | EventSet::hup(), opts));
+            }
+        }
+        if let Some(ref w) = self.writer {
+            if interest.is_writable() {
+                try!(w.reregister(selector, token,
+                    EventSet::writable(), opts));
+            }
+        }
+        Ok(())
+    }
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        if let Some(ref r) = self.reader {
+            try!(r.deregister(selector));
+        }
+        if let Some(ref w) = self.writer {
+            try!(w.deregister(selector));
+        }
+        Ok(())
+    }
+}
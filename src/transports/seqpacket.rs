@@ -0,0 +1,472 @@
+//! Connection-oriented Unix sockets which preserve message boundaries
+//!
+//! `SOCK_SEQPACKET` sockets are convenient for local IPC (control planes,
+//! supervisor interfaces): every `send` is received as a single message,
+//! so no framing is needed. Accept connections with `Serve`:
+//!
+//! ```ignore
+//! let listener = try!(SeqPacketListener::bind("/run/app/control.sock"));
+//! let serve = Serve::<_, Channel<Control, _>, _>::new(listener);
+//! ```
+use std::io::{self, Error};
+use std::mem;
+use std::ptr;
+use std::path::Path;
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::io::ErrorKind::{WouldBlock, Interrupted};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{RawFd, AsRawFd, FromRawFd};
+
+use libc;
+use mio::{Io, Evented, Selector, Token, EventSet, PollOpt, TryAccept};
+
+use {BaseMachine, EventMachine, Scope, Response};
+use fd_flags::{set_flag, Flag};
+use super::accept::Init;
+
+/// Messages larger than that are reported as an error
+const MAX_MESSAGE: usize = 65536;
+const LISTEN_BACKLOG: libc::c_int = 128;
+
+
+/// A listening `SOCK_SEQPACKET` Unix socket
+pub struct SeqPacketListener(Io);
+
+/// A connected `SOCK_SEQPACKET` Unix socket
+pub struct SeqPacket(Io);
+
+/// A handle to send messages from the `Protocol` callbacks
+pub struct Transport<'a> {
+    queue: &'a mut VecDeque<Vec<u8>>,
+}
+
+/// This trait you should implement to handle the message protocol
+pub trait Protocol<C>: BaseMachine + Sized {
+    /// Returns new state machine for new accepted connection
+    fn accepted(ctx: &mut C) -> Self;
+    /// Called on the first event of the connection
+    ///
+    /// Client protocols may send the request here
+    fn connected(self, _transport: &mut Transport, _ctx: &mut C)
+        -> Option<Self>
+    {
+        Some(self)
+    }
+    /// A message has been received
+    fn message_received(self, data: &[u8], transport: &mut Transport,
+        ctx: &mut C)
+        -> Option<Self>;
+    /// Eof received. State machine will shutdown unconditionally
+    fn eof_received(self, _ctx: &mut C) {}
+    /// Fatal error on connection happened, state machine will be destroyed
+    ///
+    /// Default action is to log error on the info level
+    fn error_happened(self, e: Error, _ctx: &mut C) {
+        info!("Error when handling seqpacket connection: {}", e);
+    }
+}
+
+/// A state machine which exchanges messages over the `SeqPacket` socket
+pub struct Channel<P, C> {
+    sock: SeqPacket,
+    protocol: P,
+    buf: Vec<u8>,
+    queue: VecDeque<Vec<u8>>,
+    writable: bool,
+    connected: bool,
+    phantom: PhantomData<fn(&mut C)>,
+}
+
+impl SeqPacketListener {
+    /// Binds the socket to the filesystem `path` and starts listening
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<SeqPacketListener> {
+        let io = try!(socket());
+        let (addr, len) = try!(unix_addr(path.as_ref()));
+        unsafe {
+            if libc::bind(io.as_raw_fd(),
+                &addr as *const _ as *const libc::sockaddr, len) < 0 ||
+               libc::listen(io.as_raw_fd(), LISTEN_BACKLOG) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(SeqPacketListener(io))
+    }
+}
+
+impl SeqPacket {
+    /// Starts connecting to the socket at `path`
+    ///
+    /// Connecting to a Unix socket usually completes immediately
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<SeqPacket> {
+        let io = try!(socket());
+        let (addr, len) = try!(unix_addr(path.as_ref()));
+        let res = unsafe {
+            libc::connect(io.as_raw_fd(),
+                &addr as *const _ as *const libc::sockaddr, len)
+        };
+        if res < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EINPROGRESS) {
+                return Err(err);
+            }
+        }
+        Ok(SeqPacket(io))
+    }
+    /// Sends a single message
+    pub fn send(&self, data: &[u8]) -> io::Result<()> {
+        let res = unsafe {
+            libc::send(self.0.as_raw_fd(),
+                data.as_ptr() as *const libc::c_void, data.len(), 0)
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+    /// Receives a single message, returns zero on end of stream
+    ///
+    /// Messages which don't fit the buffer are reported as `InvalidData`
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        unsafe {
+            let mut iov = libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            };
+            let mut msg: libc::msghdr = mem::zeroed();
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            let n = libc::recvmsg(self.0.as_raw_fd(), &mut msg, 0);
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if msg.msg_flags & libc::MSG_TRUNC != 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    "Message is larger than the buffer"));
+            }
+            Ok(n as usize)
+        }
+    }
+}
+
+impl TryAccept for SeqPacketListener {
+    type Output = SeqPacket;
+    fn accept(&self) -> io::Result<Option<SeqPacket>> {
+        let fd = unsafe {
+            libc::accept(self.0.as_raw_fd(), ptr::null_mut(), ptr::null_mut())
+        };
+        if fd < 0 {
+            let err = io::Error::last_os_error();
+            return match err.kind() {
+                WouldBlock | Interrupted => Ok(None),
+                _ => Err(err),
+            };
+        }
+        let io = unsafe { <Io as FromRawFd>::from_raw_fd(fd) };
+        try!(set_flag(fd, Flag::NonBlocking, true));
+        try!(set_flag(fd, Flag::CloseOnExec, true));
+        Ok(Some(SeqPacket(io)))
+    }
+}
+
+fn socket() -> io::Result<Io> {
+    let fd = unsafe {
+        libc::socket(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0)
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let io = unsafe { <Io as FromRawFd>::from_raw_fd(fd) };
+    try!(set_flag(fd, Flag::NonBlocking, true));
+    try!(set_flag(fd, Flag::CloseOnExec, true));
+    Ok(io)
+}
+
+fn unix_addr(path: &Path)
+    -> io::Result<(libc::sockaddr_un, libc::socklen_t)>
+{
+    let bytes = path.as_os_str().as_bytes();
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    // Leave room for the terminating zero
+    if bytes.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "Unix socket path is too long"));
+    }
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    for (dst, &src) in addr.sun_path.iter_mut().zip(bytes) {
+        *dst = src as libc::c_char;
+    }
+    let base = &addr.sun_path as *const _ as usize -
+               &addr as *const _ as usize;
+    Ok((addr, (base + bytes.len() + 1) as libc::socklen_t))
+}
+
+impl<'a> Transport<'a> {
+    /// Queues the message, it's sent when the socket is writable
+    pub fn send(&mut self, data: &[u8]) {
+        self.queue.push_back(data.to_vec());
+    }
+    /// Number of messages waiting to be sent
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+impl<P: Protocol<C>, C> Channel<P, C> {
+    /// Creates a state machine for a connected socket
+    ///
+    /// Machines for accepted connections are created by `Serve`
+    pub fn new(sock: SeqPacket, context: &mut C) -> Self {
+        let protocol = P::accepted(context);
+        Channel::with_protocol(sock, protocol)
+    }
+    /// Creates a state machine with the protocol already constructed
+    pub fn with_protocol(sock: SeqPacket, protocol: P) -> Self {
+        Channel {
+            sock: sock,
+            protocol: protocol,
+            buf: vec![0; MAX_MESSAGE],
+            queue: VecDeque::new(),
+            writable: false,
+            connected: false,
+            phantom: PhantomData,
+        }
+    }
+    /// Sends queued messages while the socket is writable
+    fn flush(&mut self) -> io::Result<()> {
+        while self.writable {
+            match self.queue.front() {
+                Some(msg) => match self.sock.send(msg) {
+                    Ok(()) => {}
+                    Err(ref e) if e.kind() == WouldBlock => {
+                        self.writable = false;
+                        break;
+                    }
+                    Err(ref e) if e.kind() == Interrupted => continue,
+                    Err(e) => return Err(e),
+                },
+                None => break,
+            }
+            self.queue.pop_front();
+        }
+        Ok(())
+    }
+}
+
+impl<P: Protocol<C>, C> BaseMachine for Channel<P, C> {
+    type Timeout = P::Timeout;
+}
+
+impl<P: Protocol<C>, C> EventMachine<C> for Channel<P, C> {
+    fn ready<S>(mut self, evset: EventSet, context: &mut C, _scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        if evset.is_writable() {
+            self.writable = true;
+        }
+        let mut protocol = self.protocol;
+        if !self.connected {
+            self.connected = true;
+            protocol = match protocol.connected(
+                &mut Transport { queue: &mut self.queue }, context)
+            {
+                Some(p) => p,
+                None => return Response::Remove,
+            };
+        }
+        loop {
+            match self.sock.recv(&mut self.buf) {
+                Ok(0) => {
+                    protocol.eof_received(context);
+                    return Response::Remove;
+                }
+                Ok(n) => {
+                    protocol = match protocol.message_received(&self.buf[..n],
+                        &mut Transport { queue: &mut self.queue }, context)
+                    {
+                        Some(p) => p,
+                        None => return Response::Remove,
+                    };
+                }
+                Err(ref e) if e.kind() == WouldBlock => break,
+                Err(ref e) if e.kind() == Interrupted => continue,
+                Err(e) => {
+                    protocol.error_happened(e, context);
+                    return Response::Remove;
+                }
+            }
+        }
+        self.protocol = protocol;
+        if let Err(e) = self.flush() {
+            self.protocol.error_happened(e, context);
+            return Response::Remove;
+        }
+        Response::Continue(self)
+    }
+    fn register<S>(&mut self, scope: &mut S) -> Result<(), Error>
+        where S: Scope<Self>
+    {
+        scope.register(&self.sock, EventSet::all(), PollOpt::edge())
+    }
+    fn name(&self) -> &'static str {
+        "seqpacket"
+    }
+    fn deregister<S>(&mut self, scope: &mut S) -> Result<(), Error>
+        where S: Scope<Self>
+    {
+        scope.deregister(&self.sock)
+    }
+}
+
+impl<P: Protocol<C>, C> Init<SeqPacket, C> for Channel<P, C> {
+    fn accept<S>(conn: SeqPacket, context: &mut C, _scope: &mut S)
+        -> Self
+        where S: Scope<Self>
+    {
+        Channel::new(conn, context)
+    }
+}
+
+macro_rules! delegate_evented {
+    ($t:ident) => {
+        impl Evented for $t {
+            fn register(&self, selector: &mut Selector, token: Token,
+                interest: EventSet, opts: PollOpt)
+                -> io::Result<()>
+            {
+                self.0.register(selector, token, interest, opts)
+            }
+            fn reregister(&self, selector: &mut Selector, token: Token,
+                interest: EventSet, opts: PollOpt)
+                -> io::Result<()>
+            {
+                self.0.reregister(selector, token, interest, opts)
+            }
+            fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+                self.0.deregister(selector)
+            }
+        }
+        impl AsRawFd for $t {
+            fn as_raw_fd(&self) -> RawFd {
+                self.0.as_raw_fd()
+            }
+        }
+        impl FromRawFd for $t {
+            unsafe fn from_raw_fd(fd: RawFd) -> $t {
+                $t(<Io as FromRawFd>::from_raw_fd(fd))
+            }
+        }
+    }
+}
+
+delegate_evented!(SeqPacketListener);
+delegate_evented!(SeqPacket);
+
+#[cfg(test)]
+mod test {
+    use std::io::ErrorKind;
+    use std::os::unix::io::FromRawFd;
+    use libc;
+    use mio::{self, EventLoop, EventSet};
+    use BaseMachine;
+    use handler::Handler;
+    use super::{SeqPacket, Channel, Protocol, Transport};
+
+    fn pair() -> (SeqPacket, SeqPacket) {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe {
+            libc::socketpair(libc::AF_UNIX,
+                libc::SOCK_SEQPACKET | libc::SOCK_NONBLOCK, 0,
+                fds.as_mut_ptr())
+        }, 0);
+        unsafe {
+            (SeqPacket::from_raw_fd(fds[0]), SeqPacket::from_raw_fd(fds[1]))
+        }
+    }
+
+    #[test]
+    fn message_boundaries() {
+        let (left, right) = pair();
+        left.send(b"one").unwrap();
+        left.send(b"two words").unwrap();
+        let mut buf = [0u8; 64];
+        let n = right.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"one");
+        let n = right.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"two words");
+        assert_eq!(right.recv(&mut buf).unwrap_err().kind(),
+            ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn truncation() {
+        let (left, right) = pair();
+        left.send(b"too long").unwrap();
+        left.send(b"fits").unwrap();
+        let mut buf = [0u8; 4];
+        assert_eq!(right.recv(&mut buf).unwrap_err().kind(),
+            ErrorKind::InvalidData);
+        // The rest of the truncated message is discarded
+        let n = right.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"fits");
+    }
+
+    #[test]
+    fn end_of_stream() {
+        let (left, right) = pair();
+        left.send(b"last").unwrap();
+        drop(left);
+        let mut buf = [0u8; 64];
+        assert_eq!(right.recv(&mut buf).unwrap(), 4);
+        assert_eq!(right.recv(&mut buf).unwrap(), 0);
+    }
+
+    /// Replies to every message with its length
+    struct Lengths;
+
+    impl BaseMachine for Lengths {
+        type Timeout = ();
+    }
+
+    impl Protocol<Vec<String>> for Lengths {
+        fn accepted(_log: &mut Vec<String>) -> Lengths {
+            Lengths
+        }
+        fn message_received(self, data: &[u8], transport: &mut Transport,
+            log: &mut Vec<String>)
+            -> Option<Lengths>
+        {
+            log.push(String::from_utf8_lossy(data).into_owned());
+            transport.send(data.len().to_string().as_bytes());
+            Some(self)
+        }
+        fn eof_received(self, log: &mut Vec<String>) {
+            log.push("eof".to_string());
+        }
+    }
+
+    #[test]
+    fn channel() {
+        let (sock, peer) = pair();
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::new(Vec::new(), &mut eloop);
+        let tok = handler.add_machine(&mut eloop,
+            Channel::<Lengths, _>::with_protocol(sock, Lengths)).unwrap();
+        peer.send(b"a").unwrap();
+        peer.send(b"bcd").unwrap();
+        mio::Handler::ready(&mut handler, &mut eloop, tok,
+            EventSet::readable() | EventSet::writable());
+        let mut buf = [0u8; 64];
+        let n = peer.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"1");
+        let n = peer.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"3");
+        drop(peer);
+        mio::Handler::ready(&mut handler, &mut eloop, tok,
+            EventSet::readable());
+        assert_eq!(*handler.context(), vec!["a", "bcd", "eof"]);
+        assert_eq!(handler.occupancy().0, 0);
+    }
+}
@@ -0,0 +1,373 @@
+//! Forwarding bytes between two sockets, e.g. for TCP proxies
+//!
+//! `Tunnel` owns both sockets, registered under the token of the machine,
+//! and moves the data in both directions until both are closed. The end of
+//! stream is passed on with `shutdown(SHUT_WR)`, so half-closed
+//! connections work:
+//!
+//! ```ignore
+//! Serve::with(listener, |sock: TcpStream, ctx: &mut Context| {
+//!     let upstream = TcpStream::connect(&ctx.backend).ok();
+//!     upstream.and_then(|up| Tunnel::new(sock, up).ok())
+//! })
+//! ```
+//!
+//! On Linux, when both sockets have a descriptor (see
+//! `StreamSocket::socket_fd`), the data is moved through a pipe with
+//! `splice(2)` and is never copied to the user space. Elsewhere, and for
+//! other objects, the data is copied through a buffer.
+use std::fmt;
+use std::io::{self, Error};
+use std::marker::PhantomData;
+use std::io::ErrorKind::{WouldBlock, Interrupted, NotConnected};
+use std::os::unix::io::RawFd;
+
+use libc;
+use mio::{EventSet, PollOpt};
+use netbuf::Buf;
+
+use {BaseMachine, EventMachine, Scope, Response};
+use super::StreamSocket;
+
+/// Maximum number of bytes moved by a single `splice` call
+const SPLICE_CHUNK: usize = 65536;
+
+
+/// Bytes moved by the `Tunnel`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Counters {
+    /// From the first socket to the second
+    pub forward: u64,
+    /// From the second socket to the first
+    pub backward: u64,
+}
+
+/// Receives the results of the `Tunnel`, implement it for the context
+pub trait Report {
+    /// Both directions are closed, or moving the data has failed
+    ///
+    /// Default action is to log error on the info level
+    fn tunnel_closed(&mut self, _counters: &Counters, error: Option<Error>) {
+        if let Some(e) = error {
+            info!("Error when forwarding data: {}", e);
+        }
+    }
+}
+
+/// The machine which forwards data between the sockets `A` and `B`
+pub struct Tunnel<A, B, C> {
+    first: A,
+    second: B,
+    forward: Half,
+    backward: Half,
+    counters: Counters,
+    phantom: PhantomData<fn(&mut C)>,
+}
+
+/// The state of a single direction
+struct Half {
+    /// The pipe for `splice`, if both sockets have a descriptor
+    pipe: Option<Pipe>,
+    /// Bytes in the pipe
+    in_pipe: usize,
+    /// The data on the way when there is no pipe
+    buf: Buf,
+    /// The source has reached end of stream
+    eof: bool,
+    /// The end of stream is passed to the destination
+    done: bool,
+}
+
+/// Reading and writing end of the pipe, closed on drop
+struct Pipe(RawFd, RawFd);
+
+impl<A, B, C> Tunnel<A, B, C>
+    where A: StreamSocket, B: StreamSocket
+{
+    /// Creates the machine for two connected sockets
+    ///
+    /// Fails if the pipes for `splice` can't be created
+    pub fn new(first: A, second: B) -> io::Result<Self> {
+        let splice = first.socket_fd().is_some() &&
+            second.socket_fd().is_some();
+        Ok(Tunnel {
+            first: first,
+            second: second,
+            forward: try!(Half::new(splice)),
+            backward: try!(Half::new(splice)),
+            counters: Counters::default(),
+            phantom: PhantomData,
+        })
+    }
+    /// Bytes moved so far
+    pub fn counters(&self) -> &Counters {
+        &self.counters
+    }
+    /// Moves the data in both directions until the sockets would block
+    ///
+    /// Both sockets share the token, so the event doesn't tell which one
+    /// is ready, and both directions are tried
+    fn process(&mut self) -> Result<(), Error> {
+        self.counters.forward += try!(self.forward.transfer(
+            &mut self.first, &mut self.second));
+        self.counters.backward += try!(self.backward.transfer(
+            &mut self.second, &mut self.first));
+        Ok(())
+    }
+    fn is_done(&self) -> bool {
+        self.forward.done && self.backward.done
+    }
+}
+
+impl Half {
+    fn new(splice: bool) -> io::Result<Half> {
+        Ok(Half {
+            pipe: if splice { try!(Pipe::new()) } else { None },
+            in_pipe: 0,
+            buf: Buf::new(),
+            eof: false,
+            done: false,
+        })
+    }
+    /// Moves the data from `src` to `dst`, returns the number of bytes
+    /// written to `dst`
+    fn transfer<S, D>(&mut self, src: &mut S, dst: &mut D) -> io::Result<u64>
+        where S: StreamSocket, D: StreamSocket
+    {
+        let mut moved = 0;
+        while !self.done {
+            // Flush everything before reading more, so the source is
+            // never read faster than the destination accepts the data
+            let (flushed, empty) = match self.pipe {
+                Some(ref pipe) => {
+                    let fd = dst.socket_fd().unwrap();
+                    let n = try!(drain_pipe(pipe, fd, &mut self.in_pipe));
+                    (n, self.in_pipe == 0)
+                }
+                None => {
+                    let n = try!(drain_buf(&mut self.buf, dst));
+                    (n, self.buf.len() == 0)
+                }
+            };
+            moved += flushed as u64;
+            if !empty {
+                break;
+            }
+            if self.eof {
+                try!(shutdown_write(dst.socket_fd()));
+                self.done = true;
+                break;
+            }
+            let result = match self.pipe {
+                Some(ref pipe) => {
+                    splice(src.socket_fd().unwrap(), pipe.1, SPLICE_CHUNK)
+                }
+                None => self.buf.read_from(src),
+            };
+            match result {
+                Ok(0) => self.eof = true,
+                Ok(n) => {
+                    if self.pipe.is_some() {
+                        self.in_pipe += n;
+                    }
+                }
+                Err(ref e) if e.kind() == WouldBlock => break,
+                Err(ref e) if e.kind() == Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(moved)
+    }
+}
+
+/// Writes the data in the pipe to `fd` until it's empty or `fd` would block
+fn drain_pipe(pipe: &Pipe, fd: RawFd, in_pipe: &mut usize)
+    -> io::Result<usize>
+{
+    let mut moved = 0;
+    while *in_pipe > 0 {
+        match splice(pipe.0, fd, *in_pipe) {
+            Ok(n) => {
+                *in_pipe -= n;
+                moved += n;
+            }
+            Err(ref e) if e.kind() == WouldBlock => break,
+            Err(ref e) if e.kind() == Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(moved)
+}
+
+/// Writes the buffer to `dst` until it's empty or `dst` would block
+fn drain_buf<D: StreamSocket>(buf: &mut Buf, dst: &mut D)
+    -> io::Result<usize>
+{
+    let mut moved = 0;
+    while buf.len() > 0 {
+        match buf.write_to(dst) {
+            Ok(0) => {
+                return Err(Error::new(io::ErrorKind::WriteZero,
+                                      "Can't write to the socket"));
+            }
+            Ok(n) => moved += n,
+            Err(ref e) if e.kind() == WouldBlock => break,
+            Err(ref e) if e.kind() == Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(moved)
+}
+
+/// Shuts down the writing side, objects without a descriptor are skipped
+fn shutdown_write(fd: Option<RawFd>) -> io::Result<()> {
+    if let Some(fd) = fd {
+        if unsafe { libc::shutdown(fd, libc::SHUT_WR) } < 0 {
+            let err = Error::last_os_error();
+            // The peer has closed the connection already
+            if err.kind() != NotConnected {
+                return Err(err);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os="linux")]
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let res = unsafe {
+        libc::splice(from, ::std::ptr::null_mut(), to, ::std::ptr::null_mut(),
+            len, libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK)
+    };
+    if res < 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(res as usize)
+    }
+}
+
+/// Never called, there are no pipes without `splice`
+#[cfg(not(target_os="linux"))]
+fn splice(_from: RawFd, _to: RawFd, _len: usize) -> io::Result<usize> {
+    unreachable!();
+}
+
+impl Pipe {
+    #[cfg(target_os="linux")]
+    fn new() -> io::Result<Option<Pipe>> {
+        let mut fds = [0; 2];
+        let flags = libc::O_NONBLOCK | libc::O_CLOEXEC;
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), flags) } < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(Some(Pipe(fds[0], fds[1])))
+    }
+    #[cfg(not(target_os="linux"))]
+    fn new() -> io::Result<Option<Pipe>> {
+        Ok(None)
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+            libc::close(self.1);
+        }
+    }
+}
+
+impl<A, B, C> BaseMachine for Tunnel<A, B, C> {
+    type Timeout = ();
+}
+
+impl<A, B, C> EventMachine<C> for Tunnel<A, B, C>
+    where A: StreamSocket, B: StreamSocket, C: Report
+{
+    fn ready<S>(mut self, _events: EventSet, context: &mut C,
+        _scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        match self.process() {
+            Ok(()) if self.is_done() => {
+                context.tunnel_closed(&self.counters, None);
+                Response::Remove
+            }
+            Ok(()) => Response::Continue(self),
+            Err(e) => {
+                context.tunnel_closed(&self.counters, Some(e));
+                Response::Remove
+            }
+        }
+    }
+
+    fn register<S>(&mut self, scope: &mut S) -> Result<(), Error>
+        where S: Scope<Self>
+    {
+        try!(scope.register(&self.first, EventSet::all(), PollOpt::edge()));
+        scope.register(&self.second, EventSet::all(), PollOpt::edge())
+    }
+
+    fn name(&self) -> &'static str {
+        "tunnel"
+    }
+    fn debug(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "forward={} backward={}",
+            self.counters.forward, self.counters.backward)
+    }
+
+    fn deregister<S>(&mut self, scope: &mut S) -> Result<(), Error>
+        where S: Scope<Self>
+    {
+        try!(scope.deregister(&self.first));
+        scope.deregister(&self.second)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    use std::os::unix::net;
+    use mio::unix::UnixStream;
+
+    use super::Half;
+
+    /// Returns the peer and the non-blocking socket for the tunnel
+    fn pair() -> (net::UnixStream, UnixStream) {
+        let (peer, sock) = net::UnixStream::pair().unwrap();
+        sock.set_nonblocking(true).unwrap();
+        (peer, unsafe { UnixStream::from_raw_fd(sock.into_raw_fd()) })
+    }
+
+    fn forward(splice: bool) {
+        let (mut client, mut first) = pair();
+        let (mut server, mut second) = pair();
+        let mut half = Half::new(splice).unwrap();
+        assert_eq!(half.transfer(&mut first, &mut second).unwrap(), 0);
+        client.write_all(b"hello").unwrap();
+        assert_eq!(half.transfer(&mut first, &mut second).unwrap(), 5);
+        let mut buf = [0u8; 5];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        // End of stream is passed on
+        drop(client);
+        assert_eq!(half.transfer(&mut first, &mut second).unwrap(), 0);
+        assert!(half.done);
+        let mut rest = Vec::new();
+        server.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest.len(), 0);
+    }
+
+    #[test]
+    fn copy() {
+        forward(false);
+    }
+
+    #[cfg(target_os="linux")]
+    #[test]
+    fn splice() {
+        forward(true);
+    }
+}
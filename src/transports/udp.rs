@@ -0,0 +1,1079 @@
+//! Datagram transport over the `mio::udp::UdpSocket`
+//!
+//! Every received packet is passed to the `Protocol::packet_received`
+//! along with the source address and, optionally, the ancillary data: the
+//! destination address (needed for correct replies when the socket is bound
+//! to `0.0.0.0` on a multihomed host), TTL (hop limit) and ECN bits. Enable
+//! the ancillary data with `Options`.
+//!
+//! Ancillary data is supported on Linux only, options are ignored with a
+//! warning on other systems.
+//!
+//! Packets may be sent right away with `Transport::send`, or put into the
+//! send queue with `Transport::queue`. The queue is drained when the socket
+//! is writable, at most `Options::send_budget` packets per dispatch, and
+//! interleaved with receiving. The queue may be bounded with
+//! `Options::max_queued` and `Options::max_queued_bytes`, packets which
+//! don't fit are dropped and the protocol is notified with
+//! `Protocol::send_queue_full`.
+//!
+//! The protocol may schedule a single timeout with `Transport::set_timeout`
+//! (or `Datagram::set_timeout` before the machine is added to the loop),
+//! which results in the `Protocol::timeout` call.
+//!
+//! On Linux, a protocol which sends many packets to the same peer (e.g.
+//! QUIC-like protocols) may hand a large buffer to
+//! `Transport::send_segments`, and the kernel splits it into packets
+//! (`UDP_SEGMENT`, known as GSO). Similarly, `Options::gro` lets the kernel
+//! coalesce received packets of the same flow, the transport splits them
+//! back before calling `Protocol::packet_received`. Both save a syscall
+//! per packet.
+//!
+//! Clients which talk to a known peer should restrict sources of the
+//! packets with `Datagram::allow_peer` or `Datagram::filter_peers`, so
+//! spoofed packets are dropped before they reach the protocol.
+use std::fmt;
+use std::io::{self, Error};
+use std::collections::VecDeque;
+use std::mem;
+use std::ptr;
+use std::cmp::min;
+use std::marker::PhantomData;
+use std::io::ErrorKind::{WouldBlock, Interrupted};
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::io::{RawFd, AsRawFd};
+
+use libc;
+use mio::{EventSet, PollOpt, Timeout, TimerError};
+use mio::udp::UdpSocket;
+
+use {BaseMachine, EventMachine, Scope, Response};
+
+/// Maximum size of the UDP packet
+const MAX_PACKET: usize = 65536;
+/// Large enough for pktinfo, ttl and tos control messages
+const CMSG_BUFFER: usize = 256;
+/// Buffers of queued packets up to this size are reused
+const SMALL_PACKET: usize = 1500;
+/// Maximum number of buffers kept for reuse
+const BUFFER_POOL: usize = 64;
+
+
+/// A received packet
+pub struct Packet<'a> {
+    pub data: &'a [u8],
+    pub source: SocketAddr,
+    pub meta: Meta,
+}
+
+/// Ancillary data of the received packet
+///
+/// Fields are `None` unless enabled in `Options`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Meta {
+    /// Destination address of the packet (`IP_PKTINFO`)
+    pub destination: Option<IpAddr>,
+    /// TTL or hop limit of the packet
+    pub ttl: Option<u8>,
+    /// ECN bits of the packet
+    pub ecn: Option<u8>,
+}
+
+/// Ancillary data to receive (all disabled by default) and tunables
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    pub pktinfo: bool,
+    pub ttl: bool,
+    pub ecn: bool,
+    /// Receive packets of the same flow with a single syscall
+    /// (`UDP_GRO`), the protocol still gets them one by one
+    pub gro: bool,
+    /// Maximum number of queued packets sent in a single dispatch, the
+    /// rest is sent on the next loop iteration
+    pub send_budget: usize,
+    /// Maximum number of packets in the send queue (unlimited by default)
+    pub max_queued: usize,
+    /// Maximum total size of the packets in the send queue
+    pub max_queued_bytes: usize,
+}
+
+/// A handle to send packets from the `Protocol` callbacks
+pub struct Transport<'a> {
+    sock: &'a UdpSocket,
+    queue: &'a mut SendQueue,
+    timer: &'a mut Option<TimerChange>,
+}
+
+/// A change of the timeout requested by the protocol
+enum TimerChange {
+    Set(u64),
+    Clear,
+}
+
+/// Packets waiting for the socket to become writable
+struct SendQueue {
+    packets: VecDeque<Outgoing>,
+    bytes: usize,
+    max_packets: usize,
+    max_bytes: usize,
+    /// Packets dropped because the queue is full
+    dropped: u64,
+    /// A packet is dropped since the protocol was notified
+    overflown: bool,
+    /// Buffers of the sent small packets
+    free: Vec<Vec<u8>>,
+}
+
+struct Outgoing {
+    data: Vec<u8>,
+    destination: SocketAddr,
+    source: Option<IpAddr>,
+    segment: Option<u16>,
+}
+
+/// This trait you should implement to handle the datagram protocol
+pub trait Protocol<C>: BaseMachine + Sized {
+    /// A packet has been received
+    fn packet_received(self, packet: &Packet, transport: &mut Transport,
+        ctx: &mut C)
+        -> Option<Self>;
+
+    /// Error receiving a packet or sending a queued packet
+    ///
+    /// The errors are usually not fatal for the datagram socket (e.g.
+    /// delayed ICMP errors), so default action is to log error on the info
+    /// level and continue
+    fn error_happened(self, e: Error, _ctx: &mut C) -> Option<Self> {
+        info!("Error when receiving or sending packet: {}", e);
+        Some(self)
+    }
+    /// Packets were dropped because the send queue is full
+    ///
+    /// Called after the callback which has queued the packets. The protocol
+    /// should stop producing packets (or send only the important ones)
+    /// until `Transport::queued` decreases.
+    fn send_queue_full(self, _transport: &mut Transport, _ctx: &mut C)
+        -> Option<Self>
+    {
+        Some(self)
+    }
+    /// The timeout set by `Transport::set_timeout` has fired
+    fn timeout(self, _transport: &mut Transport, _ctx: &mut C)
+        -> Option<Self>
+    {
+        Some(self)
+    }
+}
+
+/// A state machine which receives packets from the socket
+pub struct Datagram<P, C> {
+    sock: UdpSocket,
+    protocol: P,
+    buf: Vec<u8>,
+    queue: SendQueue,
+    readable: bool,
+    writable: bool,
+    send_budget: usize,
+    timer: Option<TimerChange>,
+    timeout: Option<Timeout>,
+    peers: Peers,
+    filtered: u64,
+    phantom: PhantomData<fn(&mut C)>,
+}
+
+/// Sources of the packets passed to the protocol
+enum Peers {
+    Any,
+    Single(SocketAddr),
+    Filter(Box<Fn(&SocketAddr) -> bool>),
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            pktinfo: false,
+            ttl: false,
+            ecn: false,
+            gro: false,
+            send_budget: 64,
+            max_queued: usize::MAX,
+            max_queued_bytes: usize::MAX,
+        }
+    }
+}
+
+impl<P: Protocol<C>, C> Datagram<P, C> {
+    /// Creates a state machine for the bound socket
+    ///
+    /// Fails if ancillary data from `options` can't be enabled
+    pub fn new(sock: UdpSocket, protocol: P, options: Options)
+        -> io::Result<Datagram<P, C>>
+    {
+        let v6 = match try!(sock.local_addr()) {
+            SocketAddr::V4(_) => false,
+            SocketAddr::V6(_) => true,
+        };
+        try!(enable_options(sock.as_raw_fd(), v6, &options));
+        Ok(Datagram {
+            sock: sock,
+            protocol: protocol,
+            buf: vec![0; MAX_PACKET],
+            queue: SendQueue::new(&options),
+            readable: false,
+            writable: true,
+            send_budget: options.send_budget,
+            timer: None,
+            timeout: None,
+            peers: Peers::Any,
+            filtered: 0,
+            phantom: PhantomData,
+        })
+    }
+    /// Drops packets from any address but `peer`
+    ///
+    /// IPv4-mapped IPv6 addresses match the IPv4 ones
+    pub fn allow_peer(&mut self, peer: SocketAddr) {
+        self.peers = Peers::Single(peer);
+    }
+    /// Drops packets from the addresses for which `filter` returns false
+    pub fn filter_peers<F>(&mut self, filter: F)
+        where F: Fn(&SocketAddr) -> bool + 'static
+    {
+        self.peers = Peers::Filter(Box::new(filter));
+    }
+    /// Number of packets dropped by the `allow_peer` or `filter_peers`
+    pub fn filtered(&self) -> u64 {
+        self.filtered
+    }
+    /// Number of packets dropped because the send queue is full
+    pub fn dropped(&self) -> u64 {
+        self.queue.dropped
+    }
+    /// Calls `Protocol::timeout` `ms` milliseconds after the registration
+    ///
+    /// Use it to start a protocol which sends packets first
+    pub fn set_timeout(&mut self, ms: u64) {
+        self.timer = Some(TimerChange::Set(ms));
+    }
+    fn update_timer<S>(&mut self, scope: &mut S) -> Result<(), TimerError>
+        where S: Scope<Self>
+    {
+        match self.timer.take() {
+            Some(change) => {
+                self.timeout.take().map(|t| scope.clear_timeout(t));
+                if let TimerChange::Set(ms) = change {
+                    self.timeout = Some(try!(scope.add_timeout_ms(ms, ())));
+                }
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl<P: Protocol<C>, C> BaseMachine for Datagram<P, C> {
+    type Timeout = ();
+}
+
+impl<P: Protocol<C>, C> EventMachine<C> for Datagram<P, C> {
+    fn ready<S>(mut self, events: EventSet, context: &mut C,
+        scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        if events.is_readable() {
+            self.readable = true;
+        }
+        if events.is_writable() {
+            self.writable = true;
+        }
+        let fd = self.sock.as_raw_fd();
+        let mut protocol = self.protocol;
+        let mut budget = self.send_budget;
+        // Sending is interleaved with receiving, so a deep send queue
+        // doesn't delay incoming packets and vice versa
+        loop {
+            let mut progress = false;
+            if self.writable && budget > 0 {
+                if let Some(pkt) = self.queue.pop() {
+                    progress = true;
+                    match send(fd, &pkt.data, &pkt.destination, pkt.source,
+                               pkt.segment)
+                    {
+                        Ok(()) => {
+                            budget -= 1;
+                            self.queue.recycle(pkt.data);
+                        }
+                        Err(ref e) if e.kind() == WouldBlock => {
+                            self.writable = false;
+                            progress = false;
+                            self.queue.push_front(pkt);
+                        }
+                        Err(ref e) if e.kind() == Interrupted => {
+                            self.queue.push_front(pkt);
+                        }
+                        Err(e) => {
+                            budget -= 1;
+                            protocol = match protocol.error_happened(e,
+                                context)
+                            {
+                                Some(p) => p,
+                                None => return Response::Remove,
+                            };
+                        }
+                    }
+                }
+            }
+            if self.readable {
+                progress = true;
+                match recv(fd, &mut self.buf) {
+                    Ok((_, source, _, _)) if !self.peers.allowed(&source) => {
+                        self.filtered += 1;
+                    }
+                    Ok((n, source, meta, segment)) => {
+                        // Packets coalesced by GRO are passed one by one
+                        let size = match segment {
+                            Some(size) if size > 0 && size < n => size,
+                            _ => n,
+                        };
+                        let mut offset = 0;
+                        loop {
+                            let end = min(offset + size, n);
+                            let packet = Packet {
+                                data: &self.buf[offset..end],
+                                source: source,
+                                meta: meta,
+                            };
+                            let mut transport = Transport {
+                                sock: &self.sock,
+                                queue: &mut self.queue,
+                                timer: &mut self.timer,
+                            };
+                            protocol = match protocol
+                                .packet_received(&packet, &mut transport,
+                                                 context)
+                                .and_then(|p| transport.check_full(p, context))
+                            {
+                                Some(p) => p,
+                                None => return Response::Remove,
+                            };
+                            offset = end;
+                            if offset >= n {
+                                break;
+                            }
+                        }
+                    }
+                    Err(ref e) if e.kind() == WouldBlock => {
+                        self.readable = false;
+                    }
+                    Err(ref e) if e.kind() == Interrupted => {}
+                    Err(e) => {
+                        protocol = match protocol.error_happened(e, context) {
+                            Some(p) => p,
+                            None => return Response::Remove,
+                        };
+                    }
+                }
+            }
+            if !progress {
+                break;
+            }
+        }
+        self.protocol = protocol;
+        if self.writable && self.queue.len() > 0 {
+            // Send budget is exhausted, continue after other machines
+            scope.request_tick();
+        }
+        if let Err(e) = self.update_timer(scope) {
+            return Response::Error(e.into());
+        }
+        Response::Continue(self)
+    }
+    fn timeout<S>(mut self, _timeout: (), context: &mut C, scope: &mut S)
+        -> Response<Self>
+        where S: Scope<Self>
+    {
+        self.timeout = None;
+        let mut transport = Transport {
+            sock: &self.sock,
+            queue: &mut self.queue,
+            timer: &mut self.timer,
+        };
+        self.protocol = match self.protocol.timeout(&mut transport, context)
+            .and_then(|p| transport.check_full(p, context))
+        {
+            Some(p) => p,
+            None => return Response::Remove,
+        };
+        // Sends the packets queued by the protocol
+        self.ready(EventSet::none(), context, scope)
+    }
+    fn tick<S>(self, context: &mut C, scope: &mut S) -> Response<Self>
+        where S: Scope<Self>
+    {
+        self.ready(EventSet::none(), context, scope)
+    }
+    fn register<S>(&mut self, scope: &mut S) -> Result<(), Error>
+        where S: Scope<Self>
+    {
+        try!(scope.register(&self.sock,
+            EventSet::readable() | EventSet::writable(), PollOpt::edge()));
+        self.update_timer(scope).map_err(|e| Error::new(io::ErrorKind::Other,
+            format!("Can't add timeout: {:?}", e)))
+    }
+    fn name(&self) -> &'static str {
+        "datagram"
+    }
+    fn debug(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "queued={} filtered={} dropped={}",
+            self.queue.len(), self.filtered, self.queue.dropped)
+    }
+    fn deregister<S>(&mut self, scope: &mut S) -> Result<(), Error>
+        where S: Scope<Self>
+    {
+        self.timeout.take().map(|t| scope.clear_timeout(t));
+        scope.deregister(&self.sock)
+    }
+}
+
+impl<'a> Transport<'a> {
+    /// Sends a packet to the `destination`
+    ///
+    /// The `source` address is useful for sockets bound to the unspecified
+    /// address, reply should usually be sent from `Meta::destination` of
+    /// the request. Returns `Ok(false)` when the packet is dropped because
+    /// the socket buffer is full.
+    pub fn send(&mut self, data: &[u8], destination: &SocketAddr,
+        source: Option<IpAddr>)
+        -> io::Result<bool>
+    {
+        self.send_to(data, destination, source, None)
+    }
+    /// Sends `data` as packets of `segment` bytes (the last one may be
+    /// shorter) with a single syscall
+    ///
+    /// The kernel limits the number of segments (64 on Linux) and the total
+    /// size to the maximum size of the UDP packet. Fails on other systems,
+    /// unless `data` fits a single segment. Otherwise, works as `send`.
+    pub fn send_segments(&mut self, data: &[u8], segment: u16,
+        destination: &SocketAddr, source: Option<IpAddr>)
+        -> io::Result<bool>
+    {
+        if segment == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "Segment size is zero"));
+        }
+        self.send_to(data, destination, source, Some(segment))
+    }
+    fn send_to(&mut self, data: &[u8], destination: &SocketAddr,
+        source: Option<IpAddr>, segment: Option<u16>)
+        -> io::Result<bool>
+    {
+        let fd = self.sock.as_raw_fd();
+        match send(fd, data, destination, source, segment) {
+            Ok(()) => Ok(true),
+            Err(ref e) if e.kind() == WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+    /// Puts a packet into the send queue
+    ///
+    /// See `send` for the meaning of `source`. Packets sent by `send` may
+    /// overtake the queued ones. Returns `false` when the packet is dropped
+    /// because the queue is full.
+    pub fn queue(&mut self, data: &[u8], destination: &SocketAddr,
+        source: Option<IpAddr>)
+        -> bool
+    {
+        self.queue_to(data, destination, source, None)
+    }
+    /// Puts packets of `segment` bytes into the send queue, see
+    /// `send_segments`
+    ///
+    /// The queued data is sent with a single syscall, failure is reported
+    /// to `Protocol::error_happened`. Fails right away if `segment` is
+    /// zero.
+    pub fn queue_segments(&mut self, data: &[u8], segment: u16,
+        destination: &SocketAddr, source: Option<IpAddr>)
+        -> io::Result<bool>
+    {
+        if segment == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "Segment size is zero"));
+        }
+        Ok(self.queue_to(data, destination, source, Some(segment)))
+    }
+    fn queue_to(&mut self, data: &[u8], destination: &SocketAddr,
+        source: Option<IpAddr>, segment: Option<u16>)
+        -> bool
+    {
+        if !self.queue.has_room(data.len()) {
+            self.queue.dropped += 1;
+            self.queue.overflown = true;
+            return false;
+        }
+        let data = self.queue.buffer(data);
+        self.queue.push_back(Outgoing {
+            data: data,
+            destination: *destination,
+            source: source,
+            segment: segment,
+        });
+        true
+    }
+    /// Calls `Protocol::send_queue_full` if packets were dropped by the
+    /// last callback
+    fn check_full<P, C>(&mut self, protocol: P, ctx: &mut C) -> Option<P>
+        where P: Protocol<C>
+    {
+        if !self.queue.overflown {
+            return Some(protocol);
+        }
+        self.queue.overflown = false;
+        protocol.send_queue_full(self, ctx)
+    }
+    /// Number of packets in the send queue
+    ///
+    /// Use it (or `queued_bytes`) to stop producing packets when the peer
+    /// or the network is slower than the protocol
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+    /// Total size of the packets in the send queue
+    pub fn queued_bytes(&self) -> usize {
+        self.queue.bytes
+    }
+    /// Number of packets which may be queued before the queue is full
+    pub fn queue_room(&self) -> usize {
+        self.queue.max_packets.saturating_sub(self.queue.len())
+    }
+    /// Number of bytes which may be queued before the queue is full
+    pub fn queue_room_bytes(&self) -> usize {
+        self.queue.max_bytes.saturating_sub(self.queue.bytes)
+    }
+    /// Calls `Protocol::timeout` after `ms` milliseconds
+    ///
+    /// Replaces the timeout set previously
+    pub fn set_timeout(&mut self, ms: u64) {
+        *self.timer = Some(TimerChange::Set(ms));
+    }
+    /// Cancels the timeout set by `set_timeout`
+    pub fn clear_timeout(&mut self) {
+        *self.timer = Some(TimerChange::Clear);
+    }
+}
+
+impl Peers {
+    fn allowed(&self, source: &SocketAddr) -> bool {
+        match *self {
+            Peers::Any => true,
+            Peers::Single(ref peer) => same_peer(peer, source),
+            Peers::Filter(ref filter) => filter(source),
+        }
+    }
+}
+
+/// Compares the addresses, ignoring flow info and IPv4 mapping
+fn same_peer(a: &SocketAddr, b: &SocketAddr) -> bool {
+    fn unmap(addr: &SocketAddr) -> IpAddr {
+        match *addr {
+            SocketAddr::V4(ref a) => IpAddr::V4(*a.ip()),
+            SocketAddr::V6(ref a) => match a.ip().segments() {
+                [0, 0, 0, 0, 0, 0xffff, hi, lo] => IpAddr::V4(
+                    Ipv4Addr::from((hi as u32) << 16 | lo as u32)),
+                _ => IpAddr::V6(*a.ip()),
+            },
+        }
+    }
+    a.port() == b.port() && unmap(a) == unmap(b)
+}
+
+impl<'a> Packet<'a> {
+    /// Sends `data` to the source of the packet, see `Transport::send`
+    ///
+    /// The reply is sent from the destination address of the packet when
+    /// `Options::pktinfo` is enabled
+    pub fn reply(&self, transport: &mut Transport, data: &[u8])
+        -> io::Result<bool>
+    {
+        transport.send(data, &self.source, self.meta.destination)
+    }
+    /// Puts the reply into the send queue, see `reply`
+    pub fn queue_reply(&self, transport: &mut Transport, data: &[u8])
+        -> bool
+    {
+        transport.queue(data, &self.source, self.meta.destination)
+    }
+}
+
+impl SendQueue {
+    fn new(options: &Options) -> SendQueue {
+        SendQueue {
+            packets: VecDeque::new(),
+            bytes: 0,
+            max_packets: options.max_queued,
+            max_bytes: options.max_queued_bytes,
+            dropped: 0,
+            overflown: false,
+            free: Vec::new(),
+        }
+    }
+    fn len(&self) -> usize {
+        self.packets.len()
+    }
+    /// Whether a packet of `size` bytes fits the limits
+    fn has_room(&self, size: usize) -> bool {
+        self.packets.len() < self.max_packets &&
+            size <= self.max_bytes.saturating_sub(self.bytes)
+    }
+    /// Copies the data into the buffer, reusing one if it's small
+    fn buffer(&mut self, data: &[u8]) -> Vec<u8> {
+        if data.len() > SMALL_PACKET {
+            return data.to_vec();
+        }
+        match self.free.pop() {
+            Some(mut buf) => {
+                buf.extend_from_slice(data);
+                buf
+            }
+            None => {
+                let mut buf = Vec::with_capacity(SMALL_PACKET);
+                buf.extend_from_slice(data);
+                buf
+            }
+        }
+    }
+    /// Keeps the buffer of the sent packet for reuse
+    fn recycle(&mut self, mut buf: Vec<u8>) {
+        if buf.capacity() <= 2 * SMALL_PACKET && self.free.len() < BUFFER_POOL
+        {
+            buf.clear();
+            self.free.push(buf);
+        }
+    }
+    fn push_back(&mut self, pkt: Outgoing) {
+        self.bytes += pkt.data.len();
+        self.packets.push_back(pkt);
+    }
+    fn push_front(&mut self, pkt: Outgoing) {
+        self.bytes += pkt.data.len();
+        self.packets.push_front(pkt);
+    }
+    fn pop(&mut self) -> Option<Outgoing> {
+        let pkt = self.packets.pop_front();
+        if let Some(ref pkt) = pkt {
+            self.bytes -= pkt.data.len();
+        }
+        pkt
+    }
+}
+
+/// Returns the size, the source and the ancillary data of the packet, and
+/// the size of the segments if the packets are coalesced by GRO
+fn recv(fd: RawFd, buf: &mut [u8])
+    -> io::Result<(usize, SocketAddr, Meta, Option<usize>)>
+{
+    unsafe {
+        let mut addr: libc::sockaddr_storage = mem::zeroed();
+        let mut cmsg = [0u8; CMSG_BUFFER];
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+        msg.msg_namelen = mem::size_of_val(&addr) as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = cmsg.len() as _;
+        let n = libc::recvmsg(fd, &mut msg, 0);
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let source = try!(to_socket_addr(&addr));
+        let (meta, segment) = parse_meta(&msg);
+        Ok((n as usize, source, meta, segment))
+    }
+}
+
+fn send(fd: RawFd, data: &[u8], destination: &SocketAddr,
+    source: Option<IpAddr>, segment: Option<u16>)
+    -> io::Result<()>
+{
+    // A single segment is sent as is, so works on any system
+    let segment = match segment {
+        Some(size) if (size as usize) < data.len() => Some(size),
+        _ => None,
+    };
+    unsafe {
+        let (mut addr, addrlen) = from_socket_addr(destination);
+        let mut cmsg = [0u8; CMSG_BUFFER];
+        let mut iov = libc::iovec {
+            iov_base: data.as_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+        msg.msg_namelen = addrlen;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        if source.is_some() || segment.is_some() {
+            msg.msg_control = cmsg.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = cmsg.len() as _;
+            let mut hdr = libc::CMSG_FIRSTHDR(&msg);
+            let mut len = 0;
+            if let Some(source) = source {
+                len += try!(set_source(hdr, source));
+                hdr = libc::CMSG_NXTHDR(&msg, hdr);
+            }
+            if let Some(size) = segment {
+                len += try!(set_segment(hdr, size));
+            }
+            msg.msg_controllen = len as _;
+        }
+        if libc::sendmsg(fd, &msg, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+unsafe fn to_socket_addr(addr: &libc::sockaddr_storage)
+    -> io::Result<SocketAddr>
+{
+    match addr.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let a = &*(addr as *const _ as *const libc::sockaddr_in);
+            Ok(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(a.sin_addr.s_addr)),
+                u16::from_be(a.sin_port))))
+        }
+        libc::AF_INET6 => {
+            let a = &*(addr as *const _ as *const libc::sockaddr_in6);
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(a.sin6_addr.s6_addr),
+                u16::from_be(a.sin6_port),
+                a.sin6_flowinfo, a.sin6_scope_id)))
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                "Unsupported address family")),
+    }
+}
+
+unsafe fn from_socket_addr(addr: &SocketAddr)
+    -> (libc::sockaddr_storage, libc::socklen_t)
+{
+    let mut storage: libc::sockaddr_storage = mem::zeroed();
+    let len = match *addr {
+        SocketAddr::V4(ref a) => {
+            let sin = &mut *(&mut storage as *mut _
+                             as *mut libc::sockaddr_in);
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = a.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*a.ip()).to_be();
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(ref a) => {
+            let sin = &mut *(&mut storage as *mut _
+                             as *mut libc::sockaddr_in6);
+            sin.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin.sin6_port = a.port().to_be();
+            sin.sin6_addr.s6_addr = a.ip().octets();
+            sin.sin6_flowinfo = a.flowinfo();
+            sin.sin6_scope_id = a.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+#[cfg(target_os="linux")]
+fn enable_options(fd: RawFd, v6: bool, options: &Options) -> io::Result<()> {
+    let (level, pktinfo, ttl, tos) = if v6 {
+        (libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO,
+         libc::IPV6_RECVHOPLIMIT, libc::IPV6_RECVTCLASS)
+    } else {
+        (libc::IPPROTO_IP, libc::IP_PKTINFO,
+         libc::IP_RECVTTL, libc::IP_RECVTOS)
+    };
+    for &(enabled, name) in &[(options.pktinfo, pktinfo),
+                              (options.ttl, ttl),
+                              (options.ecn, tos)]
+    {
+        if enabled {
+            try!(set_int_option(fd, level, name, 1));
+        }
+    }
+    if options.gro {
+        try!(set_int_option(fd, libc::SOL_UDP, libc::UDP_GRO, 1));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os="linux"))]
+fn enable_options(_fd: RawFd, _v6: bool, options: &Options)
+    -> io::Result<()>
+{
+    if options.pktinfo || options.ttl || options.ecn {
+        warn!("Ancillary data of UDP packets is not supported on this OS");
+    }
+    if options.gro {
+        warn!("UDP receive offload is not supported on this OS");
+    }
+    Ok(())
+}
+
+#[cfg(target_os="linux")]
+fn set_int_option(fd: RawFd, level: libc::c_int, name: libc::c_int,
+    value: libc::c_int)
+    -> io::Result<()>
+{
+    let res = unsafe {
+        libc::setsockopt(fd, level, name,
+            &value as *const _ as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Returns the ancillary data and the size of the GRO segments
+#[cfg(target_os="linux")]
+unsafe fn parse_meta(msg: &libc::msghdr) -> (Meta, Option<usize>) {
+    let mut meta = Meta::default();
+    let mut segment = None;
+    let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+    while !cmsg.is_null() {
+        let data = libc::CMSG_DATA(cmsg);
+        match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+            (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                let info = ptr::read_unaligned(
+                    data as *const libc::in_pktinfo);
+                meta.destination = Some(IpAddr::V4(Ipv4Addr::from(
+                    u32::from_be(info.ipi_addr.s_addr))));
+            }
+            (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                let info = ptr::read_unaligned(
+                    data as *const libc::in6_pktinfo);
+                meta.destination = Some(IpAddr::V6(Ipv6Addr::from(
+                    info.ipi6_addr.s6_addr)));
+            }
+            (libc::IPPROTO_IP, libc::IP_TTL) |
+            (libc::IPPROTO_IPV6, libc::IPV6_HOPLIMIT) => {
+                let val = ptr::read_unaligned(data as *const libc::c_int);
+                meta.ttl = Some(val as u8);
+            }
+            (libc::IPPROTO_IP, libc::IP_TOS) => {
+                meta.ecn = Some(*data & 0b11);
+            }
+            (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => {
+                let val = ptr::read_unaligned(data as *const libc::c_int);
+                meta.ecn = Some((val & 0b11) as u8);
+            }
+            (libc::SOL_UDP, libc::UDP_GRO) => {
+                let val = ptr::read_unaligned(data as *const libc::c_int);
+                segment = Some(val as usize);
+            }
+            _ => {}
+        }
+        cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+    }
+    (meta, segment)
+}
+
+#[cfg(not(target_os="linux"))]
+unsafe fn parse_meta(_msg: &libc::msghdr) -> (Meta, Option<usize>) {
+    (Meta::default(), None)
+}
+
+/// Fills the control message, returns the space it occupies
+#[cfg(target_os="linux")]
+unsafe fn set_source(cmsg: *mut libc::cmsghdr, source: IpAddr)
+    -> io::Result<usize>
+{
+    match source {
+        IpAddr::V4(ip) => {
+            let mut info: libc::in_pktinfo = mem::zeroed();
+            info.ipi_spec_dst.s_addr = u32::from(ip).to_be();
+            let size = mem::size_of::<libc::in_pktinfo>() as u32;
+            (*cmsg).cmsg_level = libc::IPPROTO_IP;
+            (*cmsg).cmsg_type = libc::IP_PKTINFO;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(size) as _;
+            ptr::write_unaligned(
+                libc::CMSG_DATA(cmsg) as *mut libc::in_pktinfo, info);
+            Ok(libc::CMSG_SPACE(size) as usize)
+        }
+        IpAddr::V6(ip) => {
+            let mut info: libc::in6_pktinfo = mem::zeroed();
+            info.ipi6_addr.s6_addr = ip.octets();
+            let size = mem::size_of::<libc::in6_pktinfo>() as u32;
+            (*cmsg).cmsg_level = libc::IPPROTO_IPV6;
+            (*cmsg).cmsg_type = libc::IPV6_PKTINFO;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(size) as _;
+            ptr::write_unaligned(
+                libc::CMSG_DATA(cmsg) as *mut libc::in6_pktinfo, info);
+            Ok(libc::CMSG_SPACE(size) as usize)
+        }
+    }
+}
+
+#[cfg(not(target_os="linux"))]
+unsafe fn set_source(_cmsg: *mut libc::cmsghdr, _source: IpAddr)
+    -> io::Result<usize>
+{
+    Err(io::Error::new(io::ErrorKind::Other,
+        "Setting source address is not supported on this OS"))
+}
+
+/// Fills the control message, returns the space it occupies
+#[cfg(target_os="linux")]
+unsafe fn set_segment(cmsg: *mut libc::cmsghdr, size: u16)
+    -> io::Result<usize>
+{
+    let len = mem::size_of::<u16>() as u32;
+    (*cmsg).cmsg_level = libc::SOL_UDP;
+    (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+    (*cmsg).cmsg_len = libc::CMSG_LEN(len) as _;
+    ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, size);
+    Ok(libc::CMSG_SPACE(len) as usize)
+}
+
+#[cfg(not(target_os="linux"))]
+unsafe fn set_segment(_cmsg: *mut libc::cmsghdr, _size: u16)
+    -> io::Result<usize>
+{
+    Err(io::Error::new(io::ErrorKind::Other,
+        "UDP segmentation offload is not supported on this OS"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::ErrorKind::InvalidInput;
+    #[cfg(target_os="linux")]
+    use std::os::unix::io::RawFd;
+    #[cfg(target_os="linux")]
+    use libc;
+    use mio::udp::UdpSocket;
+    use super::{same_peer, SendQueue, Outgoing, Options, Transport};
+
+    #[test]
+    fn reuse_buffers() {
+        let mut queue = SendQueue::new(&Options::default());
+        let buf = queue.buffer(b"hello");
+        let ptr = buf.as_ptr();
+        queue.recycle(buf);
+        let buf = queue.buffer(b"world");
+        assert_eq!(buf.as_ptr(), ptr);
+        assert_eq!(&buf[..], b"world");
+        let large = queue.buffer(&[0; 65536]);
+        queue.recycle(large);
+        assert_eq!(queue.free.len(), 0);
+    }
+
+    fn packet(size: usize) -> Outgoing {
+        Outgoing {
+            data: vec![0; size],
+            destination: "10.0.0.1:53".parse().unwrap(),
+            source: None,
+            segment: None,
+        }
+    }
+
+    #[test]
+    fn queue_limits() {
+        let mut queue = SendQueue::new(&Options {
+            max_queued: 2,
+            max_queued_bytes: 100,
+            ..Options::default()
+        });
+        assert!(queue.has_room(100));
+        assert!(!queue.has_room(101));
+        queue.push_back(packet(60));
+        assert!(queue.has_room(40));
+        assert!(!queue.has_room(41));
+        queue.push_back(packet(0));
+        assert!(!queue.has_room(0));
+        queue.pop();
+        assert!(queue.has_room(100));
+    }
+
+    #[test]
+    fn zero_segment() {
+        let sock = UdpSocket::bound(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut queue = SendQueue::new(&Options::default());
+        let mut timer = None;
+        let mut transport = Transport {
+            sock: &sock,
+            queue: &mut queue,
+            timer: &mut timer,
+        };
+        let peer = "127.0.0.1:53".parse().unwrap();
+        let err = transport.queue_segments(b"hello", 0, &peer, None)
+            .unwrap_err();
+        assert_eq!(err.kind(), InvalidInput);
+        let err = transport.send_segments(b"hello", 0, &peer, None)
+            .unwrap_err();
+        assert_eq!(err.kind(), InvalidInput);
+        assert_eq!(transport.queued(), 0);
+        assert!(transport.queue_segments(b"hello", 2, &peer, None).unwrap());
+        assert_eq!(transport.queued(), 1);
+    }
+
+    #[test]
+    fn mapped_peer() {
+        let peer = "10.0.0.1:53".parse().unwrap();
+        assert!(same_peer(&peer, &"10.0.0.1:53".parse().unwrap()));
+        assert!(same_peer(&peer, &"[::ffff:10.0.0.1]:53".parse().unwrap()));
+        assert!(!same_peer(&peer, &"10.0.0.1:54".parse().unwrap()));
+        assert!(!same_peer(&peer, &"[::10.0.0.1]:53".parse().unwrap()));
+    }
+
+    /// Returns false if the kernel doesn't know the UDP socket option
+    #[cfg(target_os="linux")]
+    fn udp_option_supported(fd: RawFd, name: libc::c_int) -> bool {
+        use super::set_int_option;
+
+        match set_int_option(fd, libc::SOL_UDP, name, 0) {
+            Ok(()) => true,
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOPROTOOPT) ||
+                          e.raw_os_error() == Some(libc::EINVAL) => false,
+            Err(e) => panic!("Can't probe UDP option {}: {}", name, e),
+        }
+    }
+
+    #[cfg(target_os="linux")]
+    #[test]
+    fn segmentation_offload() {
+        use std::net::UdpSocket;
+        use std::os::unix::io::AsRawFd;
+        use super::{Options, enable_options, send, recv};
+
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        // GSO needs Linux 4.18 and GRO needs 5.0, skip on older kernels
+        if !udp_option_supported(sender.as_raw_fd(), libc::UDP_SEGMENT) ||
+           !udp_option_supported(receiver.as_raw_fd(), libc::UDP_GRO)
+        {
+            return;
+        }
+        let options = Options { gro: true, ..Options::default() };
+        enable_options(receiver.as_raw_fd(), false, &options).unwrap();
+        let data = [7u8; 250];
+        send(sender.as_raw_fd(), &data, &receiver.local_addr().unwrap(),
+             None, Some(100)).unwrap();
+        // Depending on the kernel, segments come coalesced or one by one
+        let mut buf = [0u8; 1024];
+        let mut sizes = Vec::new();
+        while sizes.iter().sum::<usize>() < data.len() {
+            let (n, _, _, segment) = recv(receiver.as_raw_fd(), &mut buf)
+                .unwrap();
+            let size = segment.unwrap_or(n);
+            sizes.extend(buf[..n].chunks(size).map(|c| c.len()));
+        }
+        assert_eq!(sizes, vec![100, 100, 50]);
+    }
+}
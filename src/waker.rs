@@ -0,0 +1,178 @@
+//! A waker of the machines which doesn't use the notification queue of mio
+//!
+//! The mio notification queue is bounded, so a flood of wakeups may starve
+//! adding machines to the loop. Wakeups sent by `Notifier` are put into an
+//! unbounded queue instead, and the loop is woken up by writing to an
+//! eventfd (on Linux) or to a pipe (elsewhere). Writes are coalesced, so
+//! there is at most one pending write at any time.
+use std::io::{self, Read};
+use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError};
+use std::io::ErrorKind::{WouldBlock, Interrupted};
+use std::os::unix::io::{RawFd, FromRawFd};
+
+use libc;
+use mio::Io;
+
+use handler::MachineId;
+
+
+/// The sending side, may be cloned and sent to other threads
+pub struct Waker {
+    shared: Arc<Shared>,
+    queue: Sender<MachineId>,
+}
+
+/// The receiving side, registered in the event loop by the `Handler`
+pub struct Wakeups {
+    io: Io,
+    shared: Arc<Shared>,
+    queue: Receiver<MachineId>,
+}
+
+struct Shared {
+    fd: RawFd,
+    pending: AtomicBool,
+}
+
+/// Creates a connected pair of a waker and a receiver
+pub fn new() -> io::Result<(Waker, Wakeups)> {
+    let (read, write) = try!(create_fds());
+    let shared = Arc::new(Shared {
+        fd: write,
+        pending: AtomicBool::new(false),
+    });
+    let (tx, rx) = channel();
+    let io = unsafe { <Io as FromRawFd>::from_raw_fd(read) };
+    Ok((Waker { shared: shared.clone(), queue: tx },
+        Wakeups { io: io, shared: shared, queue: rx }))
+}
+
+#[cfg(target_os="linux")]
+fn create_fds() -> io::Result<(RawFd, RawFd)> {
+    unsafe {
+        let fd = libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Reading end is owned by mio, so the writing end is a copy
+        let copy = libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0);
+        if copy < 0 {
+            let err = io::Error::last_os_error();
+            libc::close(fd);
+            return Err(err);
+        }
+        Ok((fd, copy))
+    }
+}
+
+#[cfg(not(target_os="linux"))]
+fn create_fds() -> io::Result<(RawFd, RawFd)> {
+    unsafe {
+        let mut fds = [0; 2];
+        if libc::pipe(fds.as_mut_ptr()) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for &fd in &fds {
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            if flags < 0 ||
+                libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 ||
+                libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) < 0
+            {
+                let err = io::Error::last_os_error();
+                libc::close(fds[0]);
+                libc::close(fds[1]);
+                return Err(err);
+            }
+        }
+        Ok((fds[0], fds[1]))
+    }
+}
+
+impl Waker {
+    /// Schedules wakeup of the machine with `id`
+    ///
+    /// Fails with `BrokenPipe` if the event loop is shut down
+    pub fn wakeup(&self, id: MachineId) -> io::Result<()> {
+        if self.queue.send(id).is_err() {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe,
+                                      "Event loop is shut down"));
+        }
+        self.signal()
+    }
+    /// Wakes up the loop without adding anything to the queue
+    pub fn signal(&self) -> io::Result<()> {
+        if self.shared.pending.swap(true, Ordering::SeqCst) {
+            // Loop is not woken up since the previous write
+            return Ok(());
+        }
+        // Eventfd requires exactly 8 bytes, and it's fine for the pipe
+        let value: u64 = 1;
+        let res = unsafe {
+            libc::write(self.shared.fd,
+                        &value as *const u64 as *const libc::c_void,
+                        mem::size_of::<u64>())
+        };
+        if res < 0 {
+            let err = io::Error::last_os_error();
+            // Full pipe (or eventfd) wakes up the loop anyway
+            if err.kind() != WouldBlock {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Clone for Waker {
+    fn clone(&self) -> Waker {
+        Waker {
+            shared: self.shared.clone(),
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+impl Wakeups {
+    /// The object to register in the event loop (readable, level-triggered)
+    pub fn io(&self) -> &Io {
+        &self.io
+    }
+    /// Resets the wakeup signal, must be called before `next` calls
+    pub fn reset(&mut self) {
+        self.shared.pending.store(false, Ordering::SeqCst);
+        let mut buf = [0u8; 64];
+        loop {
+            match self.io.read(&mut buf) {
+                Ok(0) => break,
+                Ok(_) => continue,
+                Err(ref e) if e.kind() == WouldBlock => break,
+                Err(ref e) if e.kind() == Interrupted => continue,
+                Err(e) => {
+                    error!("Error reading waker fd: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+    /// Returns the id of the next machine to wake up
+    ///
+    /// The machine may be removed since the wakeup was sent, so the id must
+    /// be checked before the wakeup is delivered.
+    pub fn next(&mut self) -> Option<MachineId> {
+        match self.queue.try_recv() {
+            Ok(id) => Some(id),
+            Err(TryRecvError::Empty) => None,
+            // Can't happen, the handler owns a `Waker`
+            Err(TryRecvError::Disconnected) => None,
+        }
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd); }
+    }
+}